@@ -0,0 +1,164 @@
+#version 330 core
+// Edge-avoiding a-trous wavelet filter, see Denoise in src/denoiser.rs.
+out vec4 FragColor;
+
+in vec2 TexCoords;
+
+// accumulation sums on the prepare pass, afterwards color and variance of the previous pass
+uniform sampler2D colorTexture;
+uniform sampler2D momentsTexture;
+// albedo with depth in alpha, and normal with medium hits in alpha, summed over the samples
+uniform sampler2D albedoTexture;
+uniform sampler2D normalTexture;
+
+// Turns the accumulation sums into color and the variance of its mean.
+uniform bool prepare;
+// Writes a weight of one instead of the variance, for the screen pass.
+uniform bool last;
+uniform int stepSize;
+uniform float colorPhi;
+uniform float normalPhi;
+uniform float depthPhi;
+uniform float albedoPhi;
+
+const float KERNEL[3] = float[](3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0);
+const float MIN_VARIANCE_SAMPLES = 4.0;
+
+float luminance(vec3 color)
+{
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// Same as resolve in src/film.rs.
+vec3 resolve(vec4 sum)
+{
+    return sum.a > 0.0 ? max(sum.rgb / sum.a, 0.0) : vec3(0.0);
+}
+
+bool inside(ivec2 p, ivec2 size)
+{
+    return all(greaterThanEqual(p, ivec2(0))) && all(lessThan(p, size));
+}
+
+// Variance of the luminance in the 3x3 neighbourhood of p.
+float spatialVariance(ivec2 p, ivec2 size)
+{
+    float sum = 0.0, sumSquared = 0.0, n = 0.0;
+    for (int dy = -1; dy <= 1; dy++)
+    {
+        for (int dx = -1; dx <= 1; dx++)
+        {
+            ivec2 q = p + ivec2(dx, dy);
+            if (!inside(q, size))
+                continue;
+            float l = luminance(resolve(texelFetch(colorTexture, q, 0)));
+            sum += l;
+            sumSquared += l * l;
+            n += 1.0;
+        }
+    }
+    float mean = sum / n;
+    return max(sumSquared / n - mean * mean, 0.0);
+}
+
+// The variance around p smoothed with a 3x3 Gaussian, so a pixel whose few
+// samples happen to agree still blends with its neighbours.
+float blurredVariance(ivec2 p, ivec2 size)
+{
+    const float GAUSSIAN[2] = float[](1.0 / 4.0, 1.0 / 8.0);
+    float sum = 0.0, weights = 0.0;
+    for (int dy = -1; dy <= 1; dy++)
+    {
+        for (int dx = -1; dx <= 1; dx++)
+        {
+            ivec2 q = p + ivec2(dx, dy);
+            if (!inside(q, size))
+                continue;
+            float w = GAUSSIAN[abs(dx)] * GAUSSIAN[abs(dy)];
+            sum += w * texelFetch(colorTexture, q, 0).a;
+            weights += w;
+        }
+    }
+    return sum / weights;
+}
+
+void main()
+{
+    ivec2 p = ivec2(gl_FragCoord.xy);
+    ivec2 size = textureSize(colorTexture, 0);
+    if (prepare)
+    {
+        vec4 moments = texelFetch(momentsTexture, p, 0);
+        float n = max(moments.z, 1.0);
+        float variance;
+        if (n >= MIN_VARIANCE_SAMPLES)
+        {
+            float mean = moments.x / n;
+            variance = max(moments.y / n - mean * mean, 0.0) * n / (n - 1.0);
+        }
+        else
+        {
+            variance = spatialVariance(p, size);
+        }
+        FragColor = vec4(resolve(texelFetch(colorTexture, p, 0)), last ? 1.0 : variance / n);
+        return;
+    }
+
+    vec4 center = texelFetch(colorTexture, p, 0);
+    vec4 a = texelFetch(albedoTexture, p, 0);
+    vec4 normalA = texelFetch(normalTexture, p, 0);
+    vec3 na = normalA.xyz;
+    float n = max(texelFetch(momentsTexture, p, 0).z, 1.0);
+    bool mediumA = normalA.w > 0.0;
+    float za = a.w / n;
+    if (za <= 0.0)
+    {
+        FragColor = vec4(center.rgb, last ? 1.0 : center.a);
+        return;
+    }
+    vec3 albedoA = a.rgb / n;
+    na = length(na) > 0.0 ? normalize(na) : na;
+    float lc = luminance(center.rgb);
+    float sigma = colorPhi * sqrt(max(blurredVariance(p, size), 0.0)) + 1e-6;
+
+    vec3 sum = vec3(0.0);
+    float variance = 0.0;
+    float weights = 0.0;
+    for (int dy = -2; dy <= 2; dy++)
+    {
+        for (int dx = -2; dx <= 2; dx++)
+        {
+            ivec2 q = p + ivec2(dx, dy) * stepSize;
+            if (!inside(q, size))
+                continue;
+            vec4 c = texelFetch(colorTexture, q, 0);
+            float w = KERNEL[abs(dx)] * KERNEL[abs(dy)];
+            if (dx != 0 || dy != 0)
+            {
+                vec4 b = texelFetch(albedoTexture, q, 0);
+                vec4 normalB = texelFetch(normalTexture, q, 0);
+                vec3 nb = normalB.xyz;
+                float m = max(texelFetch(momentsTexture, q, 0).z, 1.0);
+                bool mediumB = normalB.w > 0.0;
+                float zb = b.w / m;
+                if (zb <= 0.0)
+                    continue;
+                nb = length(nb) > 0.0 ? normalize(nb) : nb;
+                float wl = exp(-abs(lc - luminance(c.rgb)) / sigma);
+                float wn = pow(max(dot(na, nb), 0.0), normalPhi);
+                // the depth and albedo of a medium are as noisy as its color
+                float wza = 1.0;
+                if (!mediumA || !mediumB)
+                {
+                    float wz = exp(-abs(za - zb) / (depthPhi * za * float(stepSize)));
+                    wza = wz * exp(-length(albedoA - b.rgb / m) / albedoPhi);
+                }
+                w *= wl * wn * wza;
+            }
+            sum += w * c.rgb;
+            variance += w * w * c.a;
+            weights += w;
+        }
+    }
+    FragColor = vec4(sum / weights, last ? 1.0 : variance / (weights * weights));
+}
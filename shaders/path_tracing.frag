@@ -1,5 +1,12 @@
 #version 330 core
-out vec4 FragColor;
+// filter weighted color sums with the weights in alpha
+layout(location = 0) out vec4 FragColor;
+// sums of the luminance, its square and the sample count
+layout(location = 1) out vec4 FragMoments;
+// sums of the first hit albedo with its depth in alpha, and of its normal
+// with the number of hits in a medium in alpha
+layout(location = 2) out vec4 FragAlbedo;
+layout(location = 3) out vec4 FragNormal;
 
 in vec2 TexCoords;
 
@@ -15,6 +22,9 @@ struct Camera
     float halfH;
     float halfW;
     vec3 leftbottom;
+    float aperture;
+    float focusDistance;
+    int blades;
     int LoopNum;
 };
 uniform Camera camera;
@@ -39,7 +49,12 @@ struct Mesh
     vec3 v[3];
     vec3 n[3];
     vec2 uv[3];
-    vec4 texID;
+    // layers of the diffuse, specular and normal maps, -1 for none
+    vec3 texID;
+    vec3 t[3];
+    // bitangents are the cross product of normal and tangent times this
+    float tangentSign;
+    vec3 albedo;
 };
 
 struct Triangle
@@ -62,6 +77,35 @@ struct boxVolume
     vec3 albedo;
 };
 
+// A disk, cylinder, cone or torus around the y axis of a frame of its own,
+// see Object::new_quadric in src/object.rs.
+struct Quadric
+{
+    // 6 disk, 7 cylinder, 8 cone, 9 torus
+    int shape;
+    // world to object space, the columns of the linear part and the translation
+    vec3 toObject[4];
+    // radius, height and minor radius
+    vec3 size;
+    vec3 albedo;
+};
+
+struct GridVolume
+{
+    // columns of the world to grid transform, which maps the box to [0, 1]^3
+    vec3 toGrid[4];
+    vec3 resolution;
+    // first slice in densityGrids
+    float offset;
+    // scales the densities of the grid to extinction
+    float scale;
+    // the largest extinction in the grid
+    float majorant;
+    vec3 emission;
+    float anisotropy;
+    vec3 albedo;
+};
+
 struct hitRecord
 {
     vec3 p;
@@ -72,7 +116,19 @@ struct hitRecord
     vec3 normal;
     int material;
     vec3 albedo;
-    vec3 light;
+    // of the triangle hit, zero for other shapes
+    vec3 barycentrics;
+    vec2 uv;
+    // index of the scene object
+    int object;
+    // filling the object hit, -1 for none
+    int medium;
+    // of the phase function of isotropic and medium scattering
+    float anisotropy;
+    // of grid volumes and emission textures, zero for everything else
+    vec3 emission;
+    // of pbr materials from a roughness texture, negative for their own
+    float roughness;
 };
 hitRecord rec;
 
@@ -87,13 +143,23 @@ struct LinearBVHNode
     float primitives_num;
     float axis;
     float child_offset;
-    float shape;
-    float constant;
-    float material;
 };
 
-uint wseed;
-float randcore(uint seed);
+// Keep in sync with NODE_STRIDE, PRIMITIVE_STRIDE and LIGHT_STRIDE in bvh.rs,
+// MATERIAL_STRIDE in material.rs and TEXTURE_SET_STRIDE in texture.rs.
+const int NODE_STRIDE = 3;
+const int PRIMITIVE_STRIDE = 16;
+const int MATERIAL_STRIDE = 2;
+const int MEDIUM_STRIDE = 3;
+const int TEXTURE_SET_STRIDE = 12;
+const int LIGHT_STRIDE = 2;
+
+const float PI = 3.14159265359;
+
+uint pixelSeed;
+uint sampleIndex;
+uint dimension;
+uint hash(uint x);
 float rand();
 
 float hitSphere(Sphere sphere, Ray r);
@@ -105,52 +171,246 @@ uniform sampler2D historyTexture;
 uniform sampler2D vertices_texture;
 uniform sampler2D bvh_texture;
 
-uniform sampler2D diffuse_texture0;
-uniform sampler2D diffuse_texture1;
-uniform sampler2D diffuse_texture2;
-uniform sampler2D diffuse_texture3;
-uniform sampler2D diffuse_texture4;
+// every image texture in the corner of its own layer
+uniform sampler2DArray textures;
+// (width, height, encoding) per layer, see TextureArray in src/texture.rs
+uniform sampler2D textureLayers;
+// every density grid, stacked along z
+uniform sampler3D densityGrids;
+uniform int gridsNum;
+
+// mode 0 is a constant horizon color, 1 a gradient and 2 the environment map
+struct Environment
+{
+    int mode;
+    vec3 horizon;
+    vec3 zenith;
+    vec3 ground;
+    float rotation;
+    float intensity;
+    float normalization;
+};
+uniform Environment environment;
+uniform sampler2D environmentMap;
+// conditional cdfs of the rows followed by the marginal cdf in the last row
+uniform sampler2D environmentCdf;
 uniform int verticesNum;
+uniform int lightsNum;
+uniform int lightsOffset;
+uniform int mediaOffset;
+uniform int textureSetsOffset;
 uniform int nodeNum;
-uniform float randOrigin;
+// index of the sample being accumulated, counting from 0
+uniform int frameIndex;
+// 0 is independent random numbers and 1 scrambled Sobol points, see src/sampler.rs
+uniform int samplerType;
 uniform int depths;
 uniform bool faceCull;
-uniform bool gamma;
+uniform sampler2D historyMoments;
+uniform sampler2D historyAlbedo;
+uniform sampler2D historyNormal;
+
+// What the camera ray hits first, set by shading. Zero depth and normal for a miss.
+vec3 firstAlbedo;
+vec3 firstNormal;
+float firstDepth;
+float firstMedium;
+
+// 0 is the radiance, the others show the first hit, see Channel in src/channel.rs
+uniform int channel;
+// one over the distance from the camera to the farthest corner of the scene bounds
+uniform float depthScale;
+// Keep in sync with HEATMAP_NODES and HEATMAP_PRIMITIVES in src/channel.rs.
+const float HEATMAP_NODES = 128.0;
+const float HEATMAP_PRIMITIVES = 32.0;
+// BVH nodes intersectBVH has visited and primitives it has tested, counted
+// for the heatmap channels.
+int nodesVisited;
+int primitivesTested;
+vec3 channelColor(Ray r);
+
+// 0 box, 1 tent, 2 gaussian, 3 mitchell, see src/film.rs
+uniform int filterType;
+uniform float filterRadius;
+
+struct Adaptive
+{
+    bool enabled;
+    float threshold;
+    int minSamples;
+};
+uniform Adaptive adaptive;
 
 vec3 getData(sampler2D dataTexture, float index);
+vec2 sampleAperture();
+float luminance(vec3 color);
 
-void main()
+float filterWeight1D(float x)
 {
-    wseed = uint(randOrigin * float(6.95857) * (TexCoords.x * TexCoords.y));
+    x = abs(x);
+    if (filterType == 1)
+        return max(1.0 - x / filterRadius, 0.0);
+    if (filterType == 2)
+        return max(exp(-2.0 * x * x) - exp(-2.0 * filterRadius * filterRadius), 0.0);
+    if (filterType == 3)
+    {
+        x = 2.0 * x / filterRadius;
+        const float B = 1.0 / 3.0, C = 1.0 / 3.0;
+        if (x < 1.0)
+            return ((12.0 - 9.0 * B - 6.0 * C) * x * x * x + (-18.0 + 12.0 * B + 6.0 * C) * x * x + (6.0 - 2.0 * B)) / 6.0;
+        if (x < 2.0)
+            return ((-B - 6.0 * C) * x * x * x + (6.0 * B + 30.0 * C) * x * x + (-12.0 * B - 48.0 * C) * x +
+                    (8.0 * B + 24.0 * C)) / 6.0;
+        return 0.0;
+    }
+    return 1.0;
+}
 
-    vec3 historyColor = texture(historyTexture, TexCoords).rgb;
+// Same test as Adaptive::is_converged in src/film.rs.
+bool isConverged(vec4 moments)
+{
+    float n = moments.z;
+    if (!adaptive.enabled || n < float(max(adaptive.minSamples, 2)))
+        return false;
+    float mean = moments.x / n;
+    float variance = max(moments.y / n - mean * mean, 0.0) * n / (n - 1.0);
+    return sqrt(variance / n) <= adaptive.threshold * max(mean, 0.01);
+}
+
+void main()
+{
+    uvec2 pixel = uvec2(gl_FragCoord.xy);
+    pixelSeed = hash(pixel.x ^ hash(pixel.y));
+    sampleIndex = uint(frameIndex);
+    dimension = 0u;
+
+    // The buffers still hold the previous image on the first sample.
+    vec4 history = vec4(0.0);
+    vec4 moments = vec4(0.0);
+    vec4 albedo = vec4(0.0);
+    vec4 normal = vec4(0.0);
+    if (camera.LoopNum > 1)
+    {
+        history = texture(historyTexture, TexCoords);
+        moments = texture(historyMoments, TexCoords);
+        albedo = texture(historyAlbedo, TexCoords);
+        normal = texture(historyNormal, TexCoords);
+    }
+    if (isConverged(moments))
+    {
+        FragColor = history;
+        FragMoments = moments;
+        FragAlbedo = albedo;
+        FragNormal = normal;
+        return;
+    }
 
-    vec2 offset = vec2((rand() - 0.5) / float(screenWidth), (rand() - 0.5) / float(screenHeight));
+    // in pixels from the pixel center, gathered over the whole filter support
+    // instead of splatted into the neighbours, see Filter in src/film.rs
+    vec2 offset = filterRadius * (2.0 * vec2(rand(), rand()) - 1.0);
+    vec2 uv = TexCoords + offset / vec2(screenWidth, screenHeight);
 
     Ray ray;
     ray.origin = camera.camPos;
-    ray.direction = normalize(camera.leftbottom + (2.0 * camera.halfW * (TexCoords.x + offset.x)) * camera.right +
-                              (2.0 * camera.halfH * (TexCoords.y + offset.y)) * camera.up);
+    ray.direction = normalize(camera.leftbottom + (2.0 * camera.halfW * uv.x) * camera.right +
+                              (2.0 * camera.halfH * uv.y) * camera.up);
+    if (camera.aperture > 0.0)
+    {
+        vec3 focus = camera.camPos + ray.direction * (camera.focusDistance / dot(ray.direction, camera.front));
+        vec2 lens = camera.aperture * sampleAperture();
+        ray.origin = camera.camPos + lens.x * camera.right + lens.y * camera.up;
+        ray.direction = normalize(focus - ray.origin);
+    }
     ray.hitMin = 3.402823466e+38;
 
-    vec3 color = shading(ray);
-    color = mix(historyColor, color, 1.0 / float(camera.LoopNum));
+    vec3 color = channel == 0 ? shading(ray) : channelColor(ray);
+    float weight = filterWeight1D(offset.x) * filterWeight1D(offset.y);
+    FragColor = history + vec4(weight * color, weight);
+    float l = luminance(color);
+    FragMoments = moments + vec4(l, l * l, 1.0, 0.0);
+    FragAlbedo = albedo + vec4(firstAlbedo, firstDepth);
+    FragNormal = normal + vec4(firstNormal, firstMedium);
+}
+
+// Sobol direction numbers of dimensions 1 to 3, dimension 0 is the bit reversed index.
+const uint SOBOL_DIRECTIONS[96] = uint[](
+    0x80000000u, 0xc0000000u, 0xa0000000u, 0xf0000000u, 0x88000000u, 0xcc000000u,
+    0xaa000000u, 0xff000000u, 0x80800000u, 0xc0c00000u, 0xa0a00000u, 0xf0f00000u,
+    0x88880000u, 0xcccc0000u, 0xaaaa0000u, 0xffff0000u, 0x80008000u, 0xc000c000u,
+    0xa000a000u, 0xf000f000u, 0x88008800u, 0xcc00cc00u, 0xaa00aa00u, 0xff00ff00u,
+    0x80808080u, 0xc0c0c0c0u, 0xa0a0a0a0u, 0xf0f0f0f0u, 0x88888888u, 0xccccccccu,
+    0xaaaaaaaau, 0xffffffffu, 0x80000000u, 0xc0000000u, 0x60000000u, 0x90000000u,
+    0xe8000000u, 0x5c000000u, 0x8e000000u, 0xc5000000u, 0x68800000u, 0x9cc00000u,
+    0xee600000u, 0x55900000u, 0x80680000u, 0xc09c0000u, 0x60ee0000u, 0x90550000u,
+    0xe8808000u, 0x5cc0c000u, 0x8e606000u, 0xc5909000u, 0x6868e800u, 0x9c9c5c00u,
+    0xeeee8e00u, 0x5555c500u, 0x8000e880u, 0xc0005cc0u, 0x60008e60u, 0x9000c590u,
+    0xe8006868u, 0x5c009c9cu, 0x8e00eeeeu, 0xc5005555u, 0x80000000u, 0xc0000000u,
+    0x20000000u, 0x50000000u, 0xf8000000u, 0x74000000u, 0xa2000000u, 0x93000000u,
+    0xd8800000u, 0x25400000u, 0x59e00000u, 0xe6d00000u, 0x78080000u, 0xb40c0000u,
+    0x82020000u, 0xc3050000u, 0x208f8000u, 0x51474000u, 0xfbea2000u, 0x75d93000u,
+    0xa0858800u, 0x914e5400u, 0xdbe79e00u, 0x25db6d00u, 0x58800080u, 0xe54000c0u,
+    0x79e00020u, 0xb6d00050u, 0x800800f8u, 0xc00c0074u, 0x200200a2u, 0x50050093u);
+
+uint hash(uint x)
+{
+    x ^= x >> 16;
+    x *= 0x7feb352du;
+    x ^= x >> 15;
+    x *= 0x846ca68bu;
+    x ^= x >> 16;
+    return x;
+}
+
+uint reverseBits(uint x)
+{
+    x = ((x >> 1) & 0x55555555u) | ((x & 0x55555555u) << 1);
+    x = ((x >> 2) & 0x33333333u) | ((x & 0x33333333u) << 2);
+    x = ((x >> 4) & 0x0f0f0f0fu) | ((x & 0x0f0f0f0fu) << 4);
+    x = ((x >> 8) & 0x00ff00ffu) | ((x & 0x00ff00ffu) << 8);
+    return (x >> 16) | (x << 16);
+}
 
-    FragColor = vec4(color, 1.0);
+uint sobol(uint index, uint dim)
+{
+    if (dim == 0u)
+        return reverseBits(index);
+    uint x = 0u;
+    int offset = int(dim - 1u) * 32;
+    for (int bit = 0; index != 0u; index >>= 1, bit++)
+    {
+        if ((index & 1u) != 0u)
+            x ^= SOBOL_DIRECTIONS[offset + bit];
+    }
+    return x;
 }
 
-float randcore(uint seed)
+uint nestedUniformScramble(uint x, uint seed)
 {
-    seed = (seed ^ uint(61)) ^ (seed >> uint(16));
-    seed *= uint(9);
-    seed = seed ^ (seed >> uint(4));
-    seed *= uint(0x27d4eb2d);
-    wseed = seed ^ (seed >> uint(15));
-    return float(wseed) * (1.0 / 4294967296.0);
+    x = reverseBits(x);
+    x += seed;
+    x ^= x * 0x6c50b47cu;
+    x ^= x * 0xb82f1e52u;
+    x ^= x * 0xc7afe638u;
+    x ^= x * 0x8d22f6e6u;
+    return reverseBits(x);
 }
+
+// The next dimension of this pixel's sample, drawn like PixelSampler in src/sampler.rs.
 float rand()
 {
-    return randcore(wseed);
+    uint x;
+    if (samplerType == 0)
+    {
+        x = hash(pixelSeed ^ hash(sampleIndex ^ hash(dimension)));
+    }
+    else
+    {
+        uint index = nestedUniformScramble(sampleIndex, hash(pixelSeed ^ hash(dimension / 4u)));
+        x = sobol(index, dimension % 4u);
+        x = nestedUniformScramble(x, hash(pixelSeed ^ hash(dimension ^ 0x9e3779b9u)));
+    }
+    dimension++;
+    return float(x >> 8) * (1.0 / 16777216.0);
 }
 
 vec3 random_in_unit_sphere()
@@ -190,12 +450,42 @@ vec3 getData(sampler2D dataTexture, float index)
     return texture(dataTexture, texCoord).rgb;
 }
 
+// Texel of an image from the corner of its layer, repeated beyond its size
+// and decoded to linear values: encoding 0 is the sRGB transfer curve, any
+// other the power the texels are raised to.
+vec3 getTexel(int layer, vec3 info, ivec2 texel)
+{
+    ivec2 size = ivec2(info.xy);
+    texel = (texel % size + size) % size;
+    vec3 c = texelFetch(textures, ivec3(texel, layer), 0).rgb;
+    if (info.z == 0.0)
+        return mix(c / 12.92, pow((c + 0.055) / 1.055, vec3(2.4)), step(0.04045, c));
+    if (info.z != 1.0)
+        return pow(c, vec3(info.z));
+    return c;
+}
+
+// Bilinearly filtered like TextureArray::sample in src/texture.rs.
+vec3 sampleTexture(float layer, vec2 uv)
+{
+    vec3 info = texelFetch(textureLayers, ivec2(int(layer), 0), 0).rgb;
+    vec2 p = uv * info.xy - 0.5;
+    vec2 f = p - floor(p);
+    ivec2 texel = ivec2(floor(p));
+    int l = int(layer);
+    vec3 a = getTexel(l, info, texel);
+    vec3 b = getTexel(l, info, texel + ivec2(1, 0));
+    vec3 c = getTexel(l, info, texel + ivec2(0, 1));
+    vec3 d = getTexel(l, info, texel + ivec2(1, 1));
+    return mix(mix(a, b, f.x), mix(c, d, f.x), f.y);
+}
+
 Sphere getSphere(int index)
 {
     Sphere sphere;
-    sphere.center = getData(vertices_texture, float(index * 11));
-    sphere.albedo = getData(vertices_texture, float(index * 11 + 1));
-    sphere.radius = getData(vertices_texture, float(index * 11 + 2)).x;
+    sphere.center = getData(vertices_texture, float(index * PRIMITIVE_STRIDE));
+    sphere.albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 1));
+    sphere.radius = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 2)).x;
 
     return sphere;
 }
@@ -205,12 +495,14 @@ Mesh getMesh(int index)
     Mesh mesh;
     for (int i = 0; i < 3; i++)
     {
-        mesh.v[i] = getData(vertices_texture, float(index * 11 + i * 3));
-        mesh.n[i] = getData(vertices_texture, float(index * 11 + i * 3 + 1));
-        mesh.uv[i] = getData(vertices_texture, float(index * 11 + i * 3 + 2)).xy;
+        mesh.v[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i * 3));
+        mesh.n[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i * 3 + 1));
+        mesh.uv[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i * 3 + 2)).xy;
+        mesh.t[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 10 + i));
     }
-    mesh.texID =
-        vec4(getData(vertices_texture, float(index * 11 + 9)), getData(vertices_texture, float(index * 11 + 10)).x);
+    mesh.tangentSign = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 2)).z;
+    mesh.texID = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 9));
+    mesh.albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 13));
     return mesh;
 }
 
@@ -219,10 +511,10 @@ Triangle getTriangle(int index)
     Triangle tri;
     for (int i = 0; i < 3; i++)
     {
-        tri.v[i] = getData(vertices_texture, float(index * 11 + i));
+        tri.v[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i));
     }
-    tri.n = getData(vertices_texture, float(index * 11 + 3));
-    tri.albedo = getData(vertices_texture, float(index * 11 + 4));
+    tri.n = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 3));
+    tri.albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 4));
     return tri;
 }
 
@@ -231,21 +523,21 @@ Rect getRect(int index)
     Rect rect;
     for (int i = 0; i < 4; i++)
     {
-        rect.v[i] = getData(vertices_texture, float(index * 11 + i));
+        rect.v[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i));
     }
-    rect.n = getData(vertices_texture, float(index * 11 + 4));
-    rect.albedo = getData(vertices_texture, float(index * 11 + 5));
+    rect.n = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 4));
+    rect.albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 5));
     return rect;
 }
 
 boxVolume getBoxVolume(int index)
 {
     boxVolume boxvolume;
-    vec3 x = getData(vertices_texture, float(index * 11));
-    vec3 y = getData(vertices_texture, float(index * 11 + 1));
-    vec3 z = getData(vertices_texture, float(index * 11 + 2));
-    vec3 o = getData(vertices_texture, float(index * 11 + 3));
-    vec3 albedo = getData(vertices_texture, float(index * 11 + 4));
+    vec3 x = getData(vertices_texture, float(index * PRIMITIVE_STRIDE));
+    vec3 y = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 1));
+    vec3 z = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 2));
+    vec3 o = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 3));
+    vec3 albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 4));
 
     vec3 _length = x - o;
     vec3 _width = y - o;
@@ -301,17 +593,113 @@ boxVolume getBoxVolume(int index)
 LinearBVHNode getBVHNode(int index)
 {
     LinearBVHNode node;
-    node.minb = getData(bvh_texture, float(index * 4));
-    node.maxb = getData(bvh_texture, float(index * 4 + 1));
-    node.child_offset = getData(bvh_texture, float(index * 4 + 2)).x;
-    node.primitives_num = getData(bvh_texture, float(index * 4 + 2)).y;
-    node.axis = getData(bvh_texture, float(index * 4 + 2)).z;
-    node.shape = getData(bvh_texture, float(index * 4 + 3)).x;
-    node.constant = getData(bvh_texture, float(index * 4 + 3)).y;
-    node.material = getData(bvh_texture, float(index * 4 + 3)).z;
+    node.minb = getData(bvh_texture, float(index * NODE_STRIDE));
+    node.maxb = getData(bvh_texture, float(index * NODE_STRIDE + 1));
+    vec3 info = getData(bvh_texture, float(index * NODE_STRIDE + 2));
+    node.child_offset = info.x;
+    node.primitives_num = info.y;
+    node.axis = info.z;
     return node;
 }
 
+Quadric getQuadric(int index, int shape)
+{
+    Quadric quadric;
+    quadric.shape = shape;
+    for (int i = 0; i < 4; i++)
+    {
+        quadric.toObject[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i));
+    }
+    quadric.size = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 4));
+    quadric.albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 5));
+    return quadric;
+}
+
+GridVolume getGridVolume(int index, float constant)
+{
+    GridVolume grid;
+    for (int i = 0; i < 4; i++)
+    {
+        grid.toGrid[i] = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + i));
+    }
+    grid.resolution = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 4));
+    vec3 info = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 5));
+    grid.offset = info.y;
+    grid.scale = constant;
+    grid.majorant = info.z * constant;
+    grid.emission = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 6));
+    grid.anisotropy = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 7)).x;
+    grid.albedo = getData(vertices_texture, float(index * PRIMITIVE_STRIDE + 8));
+    return grid;
+}
+
+// (shape, material, constant) of a primitive
+vec3 getPrimitiveInfo(int index)
+{
+    return getData(vertices_texture, float(index * PRIMITIVE_STRIDE + PRIMITIVE_STRIDE - 1));
+}
+
+// index of the scene object a primitive was built from
+int getPrimitiveObject(int index)
+{
+    return int(getData(vertices_texture, float(index * PRIMITIVE_STRIDE + PRIMITIVE_STRIDE - 2)).x);
+}
+
+// index into the medium table of what fills the object, -1 for none
+int getPrimitiveMedium(int index)
+{
+    return int(getData(vertices_texture, float(index * PRIMITIVE_STRIDE + PRIMITIVE_STRIDE - 2)).y);
+}
+
+// index into the texture set table of the object, -1 for none
+int getPrimitiveTextureSet(int index)
+{
+    return int(getData(vertices_texture, float(index * PRIMITIVE_STRIDE + PRIMITIVE_STRIDE - 2)).z);
+}
+
+struct Medium
+{
+    vec3 absorption;
+    vec3 scattering;
+    float anisotropy;
+};
+
+Medium getMedium(int index)
+{
+    int offset = mediaOffset + index * MEDIUM_STRIDE;
+    Medium medium;
+    medium.absorption = getData(vertices_texture, float(offset));
+    medium.scattering = getData(vertices_texture, float(offset + 1));
+    medium.anisotropy = getData(vertices_texture, float(offset + 2)).x;
+    return medium;
+}
+
+// Media the path is in, innermost last. Crossing the surface of an object
+// with a medium enters or leaves it.
+const int MAX_MEDIA = 4;
+int mediaStack[MAX_MEDIA];
+int mediaDepth;
+
+int currentMedium()
+{
+    return mediaDepth > 0 ? mediaStack[mediaDepth - 1] : -1;
+}
+
+// Updates the media for leaving rec.p along direction, which only matters
+// when it goes through the surface.
+void crossSurface(vec3 direction)
+{
+    if (rec.medium < 0 || dot(direction, rec.normal) >= 0.0)
+        return;
+    if (!rec.frontFace)
+        mediaDepth = max(mediaDepth - 1, 0);
+    else if (mediaDepth < MAX_MEDIA)
+        mediaStack[mediaDepth++] = rec.medium;
+}
+
+// Interfaces a path or a shadow ray may pass through before giving up.
+const int MAX_CROSSINGS = 16;
+
 vec3 diffuse()
 {
     vec3 out_dir = rec.normal + random_unit_vector();
@@ -345,14 +733,152 @@ vec3 dielectric(vec3 direction)
     return out_dir;
 }
 
-vec3 diffuse_light(vec3 normal)
+// (metallic, roughness, specular, ior) of an entry in the material table
+vec4 getPbrMaterial(int index)
+{
+    int offset = verticesNum * PRIMITIVE_STRIDE + index * MATERIAL_STRIDE;
+    vec3 a = getData(vertices_texture, float(offset));
+    vec3 b = getData(vertices_texture, float(offset + 1));
+    return vec4(a, b.x);
+}
+
+float luminance(vec3 color)
+{
+    return dot(color, vec3(0.2126, 0.7152, 0.0722));
+}
+
+float ggxD(float NdotH, float alpha2)
+{
+    float d = NdotH * NdotH * (alpha2 - 1.0) + 1.0;
+    return alpha2 / (PI * d * d);
+}
+
+float smithG1(float NdotX, float alpha2)
+{
+    return 2.0 * NdotX / (NdotX + sqrt(alpha2 + (1.0 - alpha2) * NdotX * NdotX));
+}
+
+vec3 fresnelSchlick(vec3 f0, float cosine)
+{
+    return f0 + (1.0 - f0) * pow(1.0 - cosine, 5.0);
+}
+
+struct PbrLobes
+{
+    vec3 f0;
+    vec3 diffuseColor;
+    float alpha2;
+    float specularProbability;
+};
+
+PbrLobes getPbrLobes(vec3 direction)
+{
+    vec4 params = getPbrMaterial(int(rec.constant));
+    float metallic = params.x;
+    float roughness = rec.roughness >= 0.0 ? rec.roughness : params.y;
+    float alpha = max(roughness * roughness, 0.001);
+    float r0 = (params.w - 1.0) / (params.w + 1.0);
+    PbrLobes lobes;
+    lobes.alpha2 = alpha * alpha;
+    lobes.f0 = mix(vec3(r0 * r0 * 2.0 * params.z), rec.albedo, metallic);
+    lobes.diffuseColor = rec.albedo * (1.0 - metallic);
+
+    float NdotV = max(dot(rec.normal, -direction), 1E-4);
+    float specularWeight = luminance(fresnelSchlick(lobes.f0, NdotV));
+    float diffuseWeight = luminance(lobes.diffuseColor);
+    lobes.specularProbability = clamp(specularWeight / (specularWeight + diffuseWeight + 1E-6), 0.05, 1.0);
+    return lobes;
+}
+
+// Samples either the GGX specular lobe or the cosine weighted diffuse lobe.
+vec3 pbr(PbrLobes lobes, vec3 direction)
+{
+    if (rand() < lobes.specularProbability)
+    {
+        vec3 n = rec.normal;
+        float phi = 2.0 * PI * rand();
+        float u = rand();
+        float cosTheta = sqrt((1.0 - u) / (1.0 + (lobes.alpha2 - 1.0) * u));
+        float sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+        vec3 tangent = normalize(abs(n.x) > 0.9 ? cross(n, vec3(0.0, 1.0, 0.0)) : cross(n, vec3(1.0, 0.0, 0.0)));
+        vec3 bitangent = cross(n, tangent);
+        vec3 h = normalize(sinTheta * cos(phi) * tangent + sinTheta * sin(phi) * bitangent + cosTheta * n);
+        return reflect(direction, h);
+    }
+    return diffuse();
+}
+
+// f * cos of both lobes and the pdf of sampling l with pbr()
+vec3 evalPbr(PbrLobes lobes, vec3 direction, vec3 l, out float pdf)
 {
-    return vec3(0.0, 0.0, 0.0);
+    vec3 n = rec.normal;
+    vec3 v = -direction;
+    float NdotL = dot(n, l);
+    pdf = 0.0;
+    if (NdotL <= 0.0)
+        return vec3(0.0);
+    float NdotV = max(dot(n, v), 1E-4);
+    vec3 h = normalize(v + l);
+    float NdotH = max(dot(n, h), 0.0);
+    float VdotH = max(dot(v, h), 1E-4);
+    float d = ggxD(NdotH, lobes.alpha2);
+    vec3 f = fresnelSchlick(lobes.f0, VdotH);
+    vec3 specular = d * smithG1(NdotV, lobes.alpha2) * smithG1(NdotL, lobes.alpha2) * f / (4.0 * NdotV * NdotL);
+    vec3 brdf = specular + (1.0 - f) * lobes.diffuseColor / PI;
+    pdf = lobes.specularProbability * d * NdotH / (4.0 * VdotH) + (1.0 - lobes.specularProbability) * NdotL / PI;
+    return brdf * NdotL;
 }
 
-vec3 isotropic()
+// Henyey-Greenstein phase function of the cosine between the direction a
+// ray travelled and the one it scatters to, which is also the pdf of phase().
+float henyeyGreenstein(float cosine, float g)
 {
-    return random_unit_vector();
+    float denominator = 1.0 + g * g - 2.0 * g * cosine;
+    return (1.0 - g * g) / (4.0 * PI * denominator * sqrt(denominator));
+}
+
+// f * cos and pdf of the non-specular materials, zero for the others
+vec3 evalMaterial(vec3 direction, vec3 l, out float pdf)
+{
+    pdf = 0.0;
+    switch (rec.material)
+    {
+    case 1:
+        float cosine = dot(rec.normal, l);
+        if (cosine <= 0.0)
+            return vec3(0.0);
+        pdf = cosine / PI;
+        return rec.albedo * cosine / PI;
+    case 5:
+        pdf = henyeyGreenstein(dot(direction, l), rec.anisotropy);
+        return rec.albedo * pdf;
+    case 6:
+        return evalPbr(getPbrLobes(direction), direction, l, pdf);
+    }
+    return vec3(0.0);
+}
+
+// Samples the phase function around the direction the ray travelled.
+vec3 phase(vec3 direction)
+{
+    float g = rec.anisotropy;
+    float u = rand();
+    float cosTheta;
+    if (abs(g) < 1E-3)
+    {
+        cosTheta = 1.0 - 2.0 * u;
+    }
+    else
+    {
+        float s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+        cosTheta = (1.0 + g * g - s * s) / (2.0 * g);
+    }
+    float sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+    float phi = 2.0 * PI * rand();
+    vec3 tangent = normalize(abs(direction.x) > 0.9 ? cross(direction, vec3(0.0, 1.0, 0.0))
+                                                    : cross(direction, vec3(1.0, 0.0, 0.0)));
+    vec3 bitangent = cross(direction, tangent);
+    return normalize(sinTheta * cos(phi) * tangent + sinTheta * sin(phi) * bitangent + cosTheta * direction);
 }
 
 vec3 centroidCoordinates(vec3 v0, vec3 v1, vec3 v2, vec3 p)
@@ -500,6 +1026,217 @@ float hitRect(Rect rect, Ray r)
     }
 }
 
+vec3 quadricVector(Quadric quadric, vec3 v)
+{
+    return quadric.toObject[0] * v.x + quadric.toObject[1] * v.y + quadric.toObject[2] * v.z;
+}
+
+vec3 quadricPoint(Quadric quadric, vec3 p)
+{
+    return quadricVector(quadric, p) + quadric.toObject[3];
+}
+
+// outward normal at p in object space, not normalized
+vec3 quadricNormal(Quadric quadric, vec3 p)
+{
+    float radius = quadric.size.x;
+    float height = quadric.size.y;
+    switch (quadric.shape)
+    {
+    case 6:
+        return vec3(0.0, 1.0, 0.0);
+    case 7:
+        return vec3(p.x, 0.0, p.z);
+    case 8:
+        float k = radius / height;
+        return vec3(p.x, k * k * (height - p.y), p.z);
+    default:
+        return p - radius * normalize(vec3(p.x, 0.0, p.z));
+    }
+}
+
+// u goes around the y axis, v from the center of a disk to its rim, from the
+// base of a cylinder or cone to its top and around the tube of a torus
+vec2 quadricUV(Quadric quadric, vec3 p)
+{
+    float u = 0.5 + atan(p.z, p.x) / (2.0 * PI);
+    switch (quadric.shape)
+    {
+    case 6:
+        return vec2(u, length(p.xz) / quadric.size.x);
+    case 7:
+    case 8:
+        return vec2(u, p.y / quadric.size.y);
+    default:
+        return vec2(u, 0.5 + atan(p.y, length(p.xz) - quadric.size.x) / (2.0 * PI));
+    }
+}
+
+float torusDistance(vec3 p, float radius, float minorRadius)
+{
+    return length(vec2(length(p.xz) - radius, p.y)) - minorRadius;
+}
+
+// Sphere traces the distance to the torus through its bounding sphere, a
+// quartic has no stable closed form in single precision.
+float hitTorus(Quadric quadric, vec3 o, vec3 d)
+{
+    float radius = quadric.size.x;
+    float minorRadius = quadric.size.z;
+    // marched in object space units along the normalized direction
+    float scale = length(d);
+    vec3 direction = d / scale;
+    float bound = radius + minorRadius;
+    float h = -dot(o, direction);
+    float discriminant = h * h - dot(o, o) + bound * bound;
+    if (discriminant < 0.0)
+        return -1.0;
+    float s = max(h - sqrt(discriminant), 0.0);
+    float end = h + sqrt(discriminant);
+    float epsilon = 1E-4 * minorRadius;
+    for (int i = 0; i < 128 && s < end; i++)
+    {
+        vec3 p = o + s * direction;
+        float distance = torusDistance(p, radius, minorRadius);
+        // Rays leaving the surface start out within epsilon of it.
+        if (abs(distance) < epsilon && s > 10.0 * epsilon)
+        {
+            if (faceCull && dot(quadricNormal(quadric, p), direction) >= 0.0)
+                return -1.0;
+            return s / scale;
+        }
+        s += max(abs(distance), epsilon);
+    }
+    return -1.0;
+}
+
+float hitQuadric(Quadric quadric, Ray r)
+{
+    vec3 o = quadricPoint(quadric, r.origin);
+    vec3 d = quadricVector(quadric, r.direction);
+    float radius = quadric.size.x;
+    float height = quadric.size.y;
+    if (quadric.shape == 9)
+        return hitTorus(quadric, o, d);
+    if (quadric.shape == 6)
+    {
+        if (abs(d.y) < 0.00001 || (faceCull && d.y >= 0.0))
+            return -1.0;
+        float t = -o.y / d.y;
+        vec3 p = o + t * d;
+        return t > 0.00001 && dot(p.xz, p.xz) <= radius * radius ? t : -1.0;
+    }
+    // a t^2 + 2 b t + c = 0, the cone has its apex at the height
+    float a, b, c;
+    if (quadric.shape == 7)
+    {
+        a = dot(d.xz, d.xz);
+        b = dot(o.xz, d.xz);
+        c = dot(o.xz, o.xz) - radius * radius;
+    }
+    else
+    {
+        float k = radius / height;
+        float y = height - o.y;
+        a = dot(d.xz, d.xz) - k * k * d.y * d.y;
+        b = dot(o.xz, d.xz) + k * k * y * d.y;
+        c = dot(o.xz, o.xz) - k * k * y * y;
+    }
+    float discriminant = b * b - a * c;
+    if (discriminant < 0.0 || abs(a) < 1E-12)
+        return -1.0;
+    float root = sqrt(discriminant);
+    float t0 = (-b - root) / a;
+    float t1 = (-b + root) / a;
+    if (t0 > t1)
+    {
+        float t = t0;
+        t0 = t1;
+        t1 = t;
+    }
+    for (int i = 0; i < 2; i++)
+    {
+        float t = i == 0 ? t0 : t1;
+        vec3 p = o + t * d;
+        if (t <= 0.00001 || p.y < 0.0 || p.y > height)
+            continue;
+        if (faceCull && dot(quadricNormal(quadric, p), d) >= 0.0)
+            continue;
+        return t;
+    }
+    return -1.0;
+}
+
+// How intersectBVH treats grid volumes: 0 delta tracks them like any other
+// primitive, 1 skips them and 2 ratio tracks them into gridTransmittance,
+// skipping everything else.
+int gridTracking = 0;
+float gridTransmittance;
+
+const int MAX_TRACKING_STEPS = 256;
+
+// Trilinearly filtered extinction at p in [0, 1]^3, kept off the neighbouring
+// grids in densityGrids.
+float gridDensity(GridVolume grid, vec3 p)
+{
+    vec3 texel = clamp(p * grid.resolution, vec3(0.5), grid.resolution - 0.5);
+    texel.z += grid.offset;
+    return grid.scale * textureLod(densityGrids, texel / vec3(textureSize(densityGrids, 0)), 0.0).r;
+}
+
+// Where r is in the box of the grid before r.hitMin, with r in grid space.
+bool gridInterval(GridVolume grid, Ray r, out vec3 o, out vec3 d, out float near, out float far)
+{
+    d = grid.toGrid[0] * r.direction.x + grid.toGrid[1] * r.direction.y + grid.toGrid[2] * r.direction.z;
+    o = grid.toGrid[0] * r.origin.x + grid.toGrid[1] * r.origin.y + grid.toGrid[2] * r.origin.z + grid.toGrid[3];
+    vec3 t0 = -o / d;
+    vec3 t1 = (1.0 - o) / d;
+    vec3 tMin = min(t0, t1);
+    vec3 tMax = max(t0, t1);
+    near = max(max(tMin.x, tMin.y), max(tMin.z, 0.0));
+    far = min(min(tMax.x, tMax.y), min(tMax.z, r.hitMin));
+    return near < far;
+}
+
+// Distance to the first real collision delta tracking finds in the grid, -1
+// when r gets through.
+float deltaTrack(GridVolume grid, Ray r)
+{
+    vec3 o, d;
+    float near, far;
+    if (grid.majorant <= 0.0 || !gridInterval(grid, r, o, d, near, far))
+        return -1.0;
+    float t = near;
+    for (int i = 0; i < MAX_TRACKING_STEPS; i++)
+    {
+        t -= log(1.0 - rand()) / grid.majorant;
+        if (t >= far)
+            break;
+        if (rand() * grid.majorant < gridDensity(grid, o + t * d))
+            return t;
+    }
+    return -1.0;
+}
+
+// Transmittance through the grid along r estimated by ratio tracking.
+float ratioTrack(GridVolume grid, Ray r)
+{
+    vec3 o, d;
+    float near, far;
+    if (grid.majorant <= 0.0 || !gridInterval(grid, r, o, d, near, far))
+        return 1.0;
+    float transmittance = 1.0;
+    float t = near;
+    for (int i = 0; i < MAX_TRACKING_STEPS; i++)
+    {
+        t -= log(1.0 - rand()) / grid.majorant;
+        if (t >= far)
+            break;
+        transmittance *= 1.0 - gridDensity(grid, o + t * d) / grid.majorant;
+    }
+    return transmittance;
+}
+
 float hitBoxVolume(boxVolume boxvolume, Ray r, bool near)
 {
     float t;
@@ -535,7 +1272,125 @@ float hitBoxVolume(boxVolume boxvolume, Ray r, bool near)
     return t;
 }
 
-void selectMaterial(LinearBVHNode node, int material)
+// Edge midpoint directions of a cube, the gradients Perlin noise picks from.
+const vec3 GRADIENTS[12] = vec3[](
+    vec3(1.0, 1.0, 0.0), vec3(-1.0, 1.0, 0.0), vec3(1.0, -1.0, 0.0), vec3(-1.0, -1.0, 0.0),
+    vec3(1.0, 0.0, 1.0), vec3(-1.0, 0.0, 1.0), vec3(1.0, 0.0, -1.0), vec3(-1.0, 0.0, -1.0),
+    vec3(0.0, 1.0, 1.0), vec3(0.0, -1.0, 1.0), vec3(0.0, 1.0, -1.0), vec3(0.0, -1.0, -1.0));
+
+// Gradient noise in about [-1, 1] with hashed lattice gradients.
+float perlin(vec3 p)
+{
+    vec3 cell = floor(p);
+    vec3 f = p - cell;
+    vec3 fade = f * f * f * (f * (f * 6.0 - 15.0) + 10.0);
+    float value = 0.0;
+    for (int corner = 0; corner < 8; corner++)
+    {
+        vec3 side = vec3(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+        uvec3 lattice = uvec3(ivec3(cell + side));
+        vec3 gradient = GRADIENTS[int(hash(lattice.x ^ hash(lattice.y ^ hash(lattice.z))) % 12u)];
+        vec3 weights = mix(1.0 - fade, fade, side);
+        value += weights.x * weights.y * weights.z * dot(gradient, f - side);
+    }
+    return value;
+}
+
+// Seven octaves of the magnitude of perlin().
+float turbulence(vec3 p)
+{
+    float sum = 0.0;
+    float weight = 1.0;
+    for (int i = 0; i < 7; i++)
+    {
+        sum += weight * abs(perlin(p));
+        weight *= 0.5;
+        p *= 2.0;
+    }
+    return sum;
+}
+
+// Slots of a texture set.
+const int ALBEDO_TEXTURE = 0;
+const int ROUGHNESS_TEXTURE = 1;
+const int EMISSION_TEXTURE = 2;
+const int NORMAL_TEXTURE = 3;
+
+// (kind, scale, layer) of a texture of a set, kind 0 for none
+vec3 getTextureInfo(int set, int slot)
+{
+    return getData(vertices_texture, float(textureSetsOffset + set * TEXTURE_SET_STRIDE + slot * 3));
+}
+
+// Keep the kinds in sync with TextureKind in texture.rs.
+vec3 evalTexture(int set, int slot, vec2 uv, vec3 p)
+{
+    int offset = textureSetsOffset + set * TEXTURE_SET_STRIDE + slot * 3;
+    vec3 info = getData(vertices_texture, float(offset));
+    vec3 from = getData(vertices_texture, float(offset + 1));
+    vec3 to = getData(vertices_texture, float(offset + 2));
+    float scale = info.y;
+    switch (int(info.x))
+    {
+    case 1:
+        return sampleTexture(info.z, uv * scale);
+    case 2:
+        return mod(floor(uv.x * scale) + floor(uv.y * scale), 2.0) == 0.0 ? from : to;
+    case 3:
+        return mix(from, to, 0.5 * (1.0 + perlin(p * scale)));
+    case 4:
+        return mix(from, to, 0.5 * (1.0 + sin(scale * p.z + 10.0 * turbulence(p))));
+    case 5:
+        return mix(from, to, clamp(uv.y, 0.0, 1.0));
+    default:
+        return vec3(0.0);
+    }
+}
+
+// Replaces what the texture set has textures for at rec.uv and rec.p, set
+// -1 for none. Normal textures, or else the normal map of a model in the
+// texture array layer normalLayer, bend rec.normal in the tangent frame of
+// dpdu and dpdv, the surface derivatives along the texture coordinates, and
+// need them nonzero.
+void applyTextures(int set, vec3 direction, vec3 dpdu, vec3 dpdv, float normalLayer)
+{
+    bool normalTexture = false;
+    if (set >= 0)
+    {
+        if (getTextureInfo(set, ALBEDO_TEXTURE).x > 0.0)
+            rec.albedo = evalTexture(set, ALBEDO_TEXTURE, rec.uv, rec.p);
+        if (getTextureInfo(set, ROUGHNESS_TEXTURE).x > 0.0)
+            rec.roughness = evalTexture(set, ROUGHNESS_TEXTURE, rec.uv, rec.p).x;
+        if (getTextureInfo(set, EMISSION_TEXTURE).x > 0.0)
+        {
+            vec3 emission = evalTexture(set, EMISSION_TEXTURE, rec.uv, rec.p);
+            if (rec.material == 4)
+                rec.albedo = emission;
+            else
+                rec.emission += emission;
+        }
+        normalTexture = getTextureInfo(set, NORMAL_TEXTURE).x > 0.0;
+    }
+    if (!normalTexture && normalLayer < 0.0)
+        return;
+    vec3 n = rec.normal;
+    vec3 t = dpdu - n * dot(n, dpdu);
+    if (dot(t, t) < 1E-12)
+        return;
+    t = normalize(t);
+    vec3 b = cross(n, t);
+    if (dot(b, dpdv) < 0.0)
+        b = -b;
+    vec3 m = normalTexture ? evalTexture(set, NORMAL_TEXTURE, rec.uv, rec.p)
+                           : sampleTexture(normalLayer, rec.uv);
+    m = m * 2.0 - 1.0;
+    vec3 bent = normalize(m.x * t + m.y * b + m.z * n);
+    // a normal turned away from the ray would let light through
+    if (dot(bent, direction) < 0.0)
+        rec.normal = bent;
+}
+
+void selectMaterial(int material, float constant)
 {
     switch (material)
     {
@@ -547,10 +1402,22 @@ void selectMaterial(LinearBVHNode node, int material)
         break;
     case 3:
         rec.material = 3;
-        rec.constant = node.constant;
+        rec.constant = constant;
         break;
     case 4:
         rec.material = 4;
+        rec.constant = constant;
+        break;
+    case 5:
+        rec.material = 5;
+        rec.anisotropy = 0.0;
+        break;
+    case 6:
+        rec.material = 6;
+        rec.constant = constant;
+        break;
+    case 7:
+        rec.material = 7;
         break;
     default:
         rec.material = 0;
@@ -563,6 +1430,7 @@ bool intersectBVH(Ray r)
     vec3 invDir = 1.0 / r.direction;
     bool hit = false;
     int hitShape = 0;
+    int hitIndex = 0;
     int dirIsNeg[3];
     dirIsNeg[0] = invDir.x < 0.0 ? 1 : 0;
     dirIsNeg[1] = invDir.y < 0.0 ? 1 : 0;
@@ -574,8 +1442,11 @@ bool intersectBVH(Ray r)
     Triangle tri;
     Rect rect;
     boxVolume boxvolume;
+    Quadric quadric;
+    GridVolume grid;
     while (true)
     {
+        nodesVisited++;
         LinearBVHNode node = getBVHNode(currentNodeIndex);
         aabb box;
         box.minb = node.minb;
@@ -586,13 +1457,21 @@ bool intersectBVH(Ray r)
             {
                 for (int i = 0; i < node.primitives_num; i++)
                 {
+                    primitivesTested++;
                     float dis_t;
-                    switch (int(node.shape))
+                    int index = int(node.child_offset) + i;
+                    vec3 info = getPrimitiveInfo(index);
+                    int material = int(info.y);
+                    float constant = info.z;
+                    bool isGrid = int(info.x) == 10;
+                    if (gridTracking == 1 && isGrid || gridTracking == 2 && !isGrid)
+                        continue;
+                    switch (int(info.x))
                     {
                     case 0:
                         break;
                     case 1:
-                        Sphere sphere_t = getSphere(int(node.child_offset + i));
+                        Sphere sphere_t = getSphere(index);
                         dis_t = hitSphere(sphere_t, r);
                         if (dis_t > 0.0 && dis_t < r.hitMin - 0.00001)
                         {
@@ -600,11 +1479,12 @@ bool intersectBVH(Ray r)
                             hit = true;
                             sphere = sphere_t;
                             hitShape = 1;
-                            selectMaterial(node, int(node.material));
+                            hitIndex = index;
+                            selectMaterial(material, constant);
                         }
                         break;
                     case 2:
-                        Mesh mesh_t = getMesh(int(node.child_offset + i));
+                        Mesh mesh_t = getMesh(index);
                         dis_t = hitMesh(mesh_t, r);
                         if (dis_t > 0.0 && dis_t < r.hitMin - 0.00001)
                         {
@@ -612,11 +1492,12 @@ bool intersectBVH(Ray r)
                             hit = true;
                             mesh = mesh_t;
                             hitShape = 2;
-                            selectMaterial(node, int(node.material));
+                            hitIndex = index;
+                            selectMaterial(material, constant);
                         }
                         break;
                     case 3:
-                        Triangle tri_t = getTriangle(int(node.child_offset + i));
+                        Triangle tri_t = getTriangle(index);
                         dis_t = hitTriangle(tri_t, r);
                         if (dis_t > 0.0 && dis_t < r.hitMin - 0.00001)
                         {
@@ -624,11 +1505,12 @@ bool intersectBVH(Ray r)
                             hit = true;
                             tri = tri_t;
                             hitShape = 3;
-                            selectMaterial(node, int(node.material));
+                            hitIndex = index;
+                            selectMaterial(material, constant);
                         }
                         break;
                     case 4:
-                        Rect rect_t = getRect(int(node.child_offset + i));
+                        Rect rect_t = getRect(index);
                         dis_t = hitRect(rect_t, r);
                         if (dis_t > 0.0 && dis_t < r.hitMin - 0.00001)
                         {
@@ -636,11 +1518,12 @@ bool intersectBVH(Ray r)
                             hit = true;
                             rect = rect_t;
                             hitShape = 4;
-                            selectMaterial(node, int(node.material));
+                            hitIndex = index;
+                            selectMaterial(material, constant);
                         }
                         break;
                     case 5:
-                        boxVolume boxvolume_t = getBoxVolume(int(node.child_offset + i));
+                        boxVolume boxvolume_t = getBoxVolume(index);
                         dis_t = hitBoxVolume(boxvolume_t, r, true);
 
                         float dis_t2 = hitBoxVolume(boxvolume_t, r, false);
@@ -654,14 +1537,49 @@ bool intersectBVH(Ray r)
                         }
 
                         float dist = dis_t2 - dis_t;
-                        float hit_dist = (-1.0 / node.constant) * log(rand());
+                        float hit_dist = (-1.0 / constant) * log(rand());
                         if (hit_dist < dist - 0.00001)
                         {
                             r.hitMin = dis_t + hit_dist;
                             hit = true;
                             boxvolume = boxvolume_t;
                             hitShape = 5;
-                            selectMaterial(node, int(node.material));
+                            hitIndex = index;
+                            selectMaterial(material, constant);
+                        }
+                        break;
+                    case 6:
+                    case 7:
+                    case 8:
+                    case 9:
+                        Quadric quadric_t = getQuadric(index, int(info.x));
+                        dis_t = hitQuadric(quadric_t, r);
+                        if (dis_t > 0.0 && dis_t < r.hitMin - 0.00001)
+                        {
+                            r.hitMin = dis_t;
+                            hit = true;
+                            quadric = quadric_t;
+                            hitShape = 6;
+                            hitIndex = index;
+                            selectMaterial(material, constant);
+                        }
+                        break;
+                    case 10:
+                        GridVolume grid_t = getGridVolume(index, constant);
+                        if (gridTracking == 2)
+                        {
+                            gridTransmittance *= ratioTrack(grid_t, r);
+                            break;
+                        }
+                        dis_t = deltaTrack(grid_t, r);
+                        if (dis_t > 0.0 && dis_t < r.hitMin - 0.00001)
+                        {
+                            r.hitMin = dis_t;
+                            hit = true;
+                            grid = grid_t;
+                            hitShape = 7;
+                            hitIndex = index;
+                            selectMaterial(material, constant);
                         }
                         break;
                     default:
@@ -695,11 +1613,24 @@ bool intersectBVH(Ray r)
     }
     if (hit)
     {
+        rec.object = getPrimitiveObject(hitIndex);
+        rec.medium = getPrimitiveMedium(hitIndex);
+        rec.barycentrics = vec3(0.0);
+        rec.uv = vec2(0.0);
+        rec.emission = vec3(0.0);
+        rec.roughness = -1.0;
+        vec3 dpdu = vec3(0.0);
+        vec3 dpdv = vec3(0.0);
+        float normalLayer = -1.0;
         switch (hitShape)
         {
         case 1:
             rec.p = r.origin + r.hitMin * r.direction;
             rec.normal = normalize(rec.p - sphere.center);
+            rec.uv = vec2(0.5 + atan(rec.normal.z, rec.normal.x) / (2.0 * PI),
+                          0.5 + asin(clamp(rec.normal.y, -1.0, 1.0)) / PI);
+            dpdu = vec3(-rec.normal.z, 0.0, rec.normal.x);
+            dpdv = vec3(0.0, 1.0, 0.0) - rec.normal * rec.normal.y;
             rec.albedo = sphere.albedo;
             rec.hitMin = r.hitMin;
             setNormal(r);
@@ -708,28 +1639,15 @@ bool intersectBVH(Ray r)
             rec.p = r.origin + r.hitMin * r.direction;
             vec3 centroidC = centroidCoordinates(mesh.v[0], mesh.v[1], mesh.v[2], rec.p);
             rec.normal = normalize(centroidC.x * mesh.n[0] + centroidC.y * mesh.n[1] + centroidC.z * mesh.n[2]);
-            vec2 uv = centroidC.x * mesh.uv[0] + centroidC.y * mesh.uv[1] + centroidC.z * mesh.uv[2];
-            switch (int(mesh.texID.x))
-            {
-            case 0:
-                rec.albedo = texture(diffuse_texture0, uv).rgb;
-                break;
-            case 1:
-                rec.albedo = texture(diffuse_texture1, uv).rgb;
-                break;
-            case 2:
-                rec.albedo = texture(diffuse_texture2, uv).rgb;
-                break;
-            case 3:
-                rec.albedo = texture(diffuse_texture3, uv).rgb;
-                break;
-            case 4:
-                rec.albedo = texture(diffuse_texture4, uv).rgb;
-                break;
-            default:
-                rec.albedo = vec3(0.5, 0.5, 1.0);
-                break;
-            }
+            rec.barycentrics = centroidC;
+            rec.uv = centroidC.x * mesh.uv[0] + centroidC.y * mesh.uv[1] + centroidC.z * mesh.uv[2];
+            dpdu = centroidC.x * mesh.t[0] + centroidC.y * mesh.t[1] + centroidC.z * mesh.t[2];
+            dpdv = mesh.tangentSign * cross(rec.normal, dpdu);
+            normalLayer = mesh.texID.z;
+            if (mesh.texID.x >= 0.0)
+                rec.albedo = sampleTexture(mesh.texID.x, rec.uv);
+            else
+                rec.albedo = mesh.albedo;
             rec.hitMin = r.hitMin;
             setNormal(r);
             break;
@@ -737,6 +1655,10 @@ bool intersectBVH(Ray r)
             rec.p = r.origin + r.hitMin * r.direction;
             rec.normal = normalize(tri.n);
             rec.albedo = tri.albedo;
+            rec.barycentrics = centroidCoordinates(tri.v[0], tri.v[1], tri.v[2], rec.p);
+            rec.uv = rec.barycentrics.yz;
+            dpdu = tri.v[1] - tri.v[0];
+            dpdv = tri.v[2] - tri.v[0];
             rec.hitMin = r.hitMin;
             setNormal(r);
             break;
@@ -744,6 +1666,17 @@ bool intersectBVH(Ray r)
             rec.p = r.origin + r.hitMin * r.direction;
             rec.normal = normalize(rect.n);
             rec.albedo = rect.albedo;
+            // of the half split off by the diagonal from v[0] to v[2] that p is in
+            rec.barycentrics = centroidCoordinates(rect.v[0], rect.v[1], rect.v[2], rec.p);
+            // u runs from v[0] to v[1] and v from v[0] to v[3]
+            rec.uv = rec.barycentrics.y * vec2(1.0, 0.0) + rec.barycentrics.z * vec2(1.0, 1.0);
+            if (rec.barycentrics.y < 0.0)
+            {
+                rec.barycentrics = centroidCoordinates(rect.v[0], rect.v[2], rect.v[3], rec.p);
+                rec.uv = rec.barycentrics.y * vec2(1.0, 1.0) + rec.barycentrics.z * vec2(0.0, 1.0);
+            }
+            dpdu = rect.v[1] - rect.v[0];
+            dpdv = rect.v[3] - rect.v[0];
             rec.hitMin = r.hitMin;
             setNormal(r);
             break;
@@ -754,9 +1687,32 @@ bool intersectBVH(Ray r)
             rec.hitMin = r.hitMin;
             rec.frontFace = true;
             break;
+        case 6:
+            rec.p = r.origin + r.hitMin * r.direction;
+            vec3 local = quadricPoint(quadric, rec.p);
+            vec3 n = quadricNormal(quadric, local);
+            // by the transpose of the world to object transform
+            rec.normal = normalize(vec3(dot(quadric.toObject[0], n), dot(quadric.toObject[1], n),
+                                        dot(quadric.toObject[2], n)));
+            rec.albedo = quadric.albedo;
+            rec.uv = quadricUV(quadric, local);
+            rec.hitMin = r.hitMin;
+            setNormal(r);
+            break;
+        case 7:
+            rec.p = r.origin + r.hitMin * r.direction;
+            rec.normal = vec3(1.0);
+            rec.albedo = grid.albedo;
+            rec.anisotropy = grid.anisotropy;
+            // what the collision absorbs glows
+            rec.emission = (1.0 - grid.albedo) * grid.emission;
+            rec.hitMin = r.hitMin;
+            rec.frontFace = true;
+            break;
         default:
             break;
         }
+        applyTextures(getPrimitiveTextureSet(hitIndex), r.direction, dpdu, dpdv, normalLayer);
     }
     return hit;
 }
@@ -772,69 +1728,468 @@ bool hitWorld(Ray r)
     return false;
 }
 
-vec3 shading(Ray r)
+vec3 rotateY(vec3 d, float angle)
 {
-    vec3 color = vec3(0.0, 0.0, 0.0);
-    vec3 attenuation = vec3(1.0, 1.0, 1.0);
-    vec3 direction = vec3(0.0, 0.0, 0.0);
-    bool hit = false;
-    bool light = false;
-    for (int i = 0; i < depths; i++)
+    float s = sin(angle);
+    float c = cos(angle);
+    return vec3(c * d.x - s * d.z, d.y, s * d.x + c * d.z);
+}
+
+ivec2 environmentTexel(vec3 direction)
+{
+    vec3 d = rotateY(normalize(direction), -environment.rotation);
+    ivec2 size = textureSize(environmentMap, 0);
+    float u = 0.5 + atan(d.z, d.x) / (2.0 * PI);
+    float v = acos(clamp(d.y, -1.0, 1.0)) / PI;
+    return min(ivec2(vec2(u, v) * vec2(size)), size - 1);
+}
+
+vec3 environmentRadiance(vec3 direction)
+{
+    if (environment.mode == 2)
+        return texelFetch(environmentMap, environmentTexel(direction), 0).rgb * environment.intensity;
+    if (environment.mode == 1)
     {
-        if (hitWorld(r) && !light)
-        {
-            switch (rec.material)
-            {
-            case 1:
-                r.direction = diffuse();
-                rec.light = vec3(0.0, 0.0, 0.0);
-                break;
+        float y = normalize(direction).y;
+        vec3 other = y >= 0.0 ? environment.zenith : environment.ground;
+        return mix(environment.horizon, other, abs(y)) * environment.intensity;
+    }
+    return environment.horizon * environment.intensity;
+}
 
-            case 2:
-                r.direction = metal(r.direction);
-                rec.light = vec3(0.0, 0.0, 0.0);
-                break;
-            case 3:
-                r.direction = dielectric(r.direction);
-                rec.light = vec3(0.0, 0.0, 0.0);
-                break;
-            case 4:
-                direction = diffuse_light(rec.normal);
-                if (direction == vec3(0.0, 0.0, 0.0))
-                {
-                    rec.light = rec.albedo;
-                    light = true;
-                    break;
-                }
-                rec.light = vec3(0.0, 0.0, 0.0);
-                r.direction = direction;
-                break;
-            case 5:
-                r.direction = isotropic();
-                rec.light = vec3(0.0, 0.0, 0.0);
-                break;
-            }
+float environmentPdf(vec3 direction)
+{
+    if (environment.mode != 2)
+        return 0.0;
+    return luminance(texelFetch(environmentMap, environmentTexel(direction), 0).rgb) * environment.normalization;
+}
 
-            r.origin = rec.p;
-            r.hitMin = 3.402823466e+38;
-            hit = true;
-            color += rec.light;
-            if (!light)
-                attenuation *= rec.albedo;
-            color *= attenuation;
+// Chance of sampleLight() picking the environment instead of a light.
+float environmentProbability()
+{
+    if (environment.mode != 2)
+        return 0.0;
+    return lightsNum > 0 ? 0.5 : 1.0;
+}
+
+// Picks a direction proportional to the luminance of the environment map.
+vec3 sampleEnvironment(out float pdf)
+{
+    ivec2 size = textureSize(environmentMap, 0);
+    float u = rand();
+    int low = 0;
+    int high = size.y - 1;
+    while (low < high)
+    {
+        int middle = (low + high) / 2;
+        if (texelFetch(environmentCdf, ivec2(middle, size.y), 0).r < u)
+            low = middle + 1;
+        else
+            high = middle;
+    }
+    int y = low;
+    u = rand();
+    low = 0;
+    high = size.x - 1;
+    while (low < high)
+    {
+        int middle = (low + high) / 2;
+        if (texelFetch(environmentCdf, ivec2(middle, y), 0).r < u)
+            low = middle + 1;
+        else
+            high = middle;
+    }
+    int x = low;
+    pdf = luminance(texelFetch(environmentMap, ivec2(x, y), 0).rgb) * environment.normalization;
+
+    float phi = ((float(x) + rand()) / float(size.x) - 0.5) * 2.0 * PI;
+    float theta = (float(y) + rand()) / float(size.y) * PI;
+    vec3 d = vec3(sin(theta) * cos(phi), cos(theta), sin(theta) * sin(phi));
+    return rotateY(d, environment.rotation);
+}
+
+float powerHeuristic(float pdf, float otherPdf)
+{
+    return pdf * pdf / (pdf * pdf + otherPdf * otherPdf);
+}
+
+vec3 sampleTriangle(vec3 v0, vec3 v1, vec3 v2, out vec3 barycentric)
+{
+    float su = sqrt(rand());
+    float v = rand() * su;
+    barycentric = vec3(1.0 - su, v, su - v);
+    return barycentric.x * v0 + barycentric.y * v1 + barycentric.z * v2;
+}
+
+// Picks a uniformly distributed point on an emissive primitive.
+void samplePrimitive(int index, out vec3 point, out vec3 normal, out vec3 emission)
+{
+    vec3 barycentric;
+    vec2 uv = vec2(0.0);
+    switch (int(getPrimitiveInfo(index).x))
+    {
+    case 1:
+        Sphere sphere = getSphere(index);
+        normal = random_unit_vector();
+        point = sphere.center + sphere.radius * normal;
+        uv = vec2(0.5 + atan(normal.z, normal.x) / (2.0 * PI),
+                  0.5 + asin(clamp(normal.y, -1.0, 1.0)) / PI);
+        emission = sphere.albedo;
+        break;
+    case 2:
+        Mesh mesh = getMesh(index);
+        point = sampleTriangle(mesh.v[0], mesh.v[1], mesh.v[2], barycentric);
+        normal = normalize(cross(mesh.v[1] - mesh.v[0], mesh.v[2] - mesh.v[0]));
+        uv = barycentric.x * mesh.uv[0] + barycentric.y * mesh.uv[1] + barycentric.z * mesh.uv[2];
+        if (mesh.texID.x >= 0.0)
+            emission = sampleTexture(mesh.texID.x, uv);
+        else
+            emission = mesh.albedo;
+        break;
+    case 3:
+        Triangle tri = getTriangle(index);
+        point = sampleTriangle(tri.v[0], tri.v[1], tri.v[2], barycentric);
+        uv = barycentric.yz;
+        normal = normalize(tri.n);
+        emission = tri.albedo;
+        break;
+    case 4:
+        Rect rect = getRect(index);
+        float area1 = length(cross(rect.v[1] - rect.v[0], rect.v[2] - rect.v[0]));
+        float area2 = length(cross(rect.v[2] - rect.v[0], rect.v[3] - rect.v[0]));
+        if (rand() * (area1 + area2) < area1)
+        {
+            point = sampleTriangle(rect.v[0], rect.v[1], rect.v[2], barycentric);
+            uv = vec2(barycentric.y + barycentric.z, barycentric.z);
         }
         else
         {
+            point = sampleTriangle(rect.v[0], rect.v[2], rect.v[3], barycentric);
+            uv = vec2(barycentric.y, barycentric.y + barycentric.z);
+        }
+        normal = normalize(rect.n);
+        emission = rect.albedo;
+        break;
+    default:
+        point = vec3(0.0);
+        normal = vec3(0.0, 1.0, 0.0);
+        emission = vec3(0.0);
+        return;
+    }
+    int set = getPrimitiveTextureSet(index);
+    if (set >= 0 && getTextureInfo(set, EMISSION_TEXTURE).x > 0.0)
+        emission = evalTexture(set, EMISSION_TEXTURE, uv, point);
+}
+
+// Solid angle pdf of sampleLight() choosing the light hit by the current ray.
+float lightPdf(Ray r)
+{
+    int light = int(rec.constant);
+    if (light < 0)
+        return 0.0;
+    vec3 info = getData(vertices_texture, float(lightsOffset + light * LIGHT_STRIDE));
+    float area = getData(vertices_texture, float(lightsOffset + light * LIGHT_STRIDE + 1)).x;
+    float cosLight = abs(dot(rec.normal, r.direction));
+    return (1.0 - environmentProbability()) * info.z * rec.hitMin * rec.hitMin / max(cosLight * area, 1E-8);
+}
+
+// Of the media between origin and distance along l, zero when something other
+// than an interface is in the way.
+vec3 transmittance(vec3 origin, vec3 l, float distance)
+{
+    hitRecord hit = rec;
+    int stack[MAX_MEDIA] = mediaStack;
+    int depth = mediaDepth;
+    vec3 result = vec3(0.0);
+    vec3 throughput = vec3(1.0);
+    Ray shadow;
+    shadow.origin = origin;
+    shadow.direction = l;
+    for (int i = 0; i < MAX_CROSSINGS; i++)
+    {
+        shadow.hitMin = distance;
+        gridTracking = 1;
+        bool occluded = intersectBVH(shadow);
+        float t = occluded ? rec.hitMin : distance;
+        if (gridsNum > 0)
+        {
+            shadow.hitMin = t;
+            gridTracking = 2;
+            gridTransmittance = 1.0;
+            intersectBVH(shadow);
+            throughput *= gridTransmittance;
+        }
+        gridTracking = 0;
+        int medium = currentMedium();
+        if (medium >= 0)
+        {
+            Medium m = getMedium(medium);
+            throughput *= exp(-(m.absorption + m.scattering) * t);
+        }
+        if (!occluded)
+        {
+            result = throughput;
             break;
         }
+        if (rec.material != 7)
+            break;
+        crossSurface(l);
+        shadow.origin = rec.p;
+        distance -= t;
     }
+    rec = hit;
+    mediaStack = stack;
+    mediaDepth = depth;
+    return result;
+}
+
+// Samples where r scatters in the medium before tMax, with the distance
+// drawn for one random color channel and weighted against the pdfs of all
+// three. Returns tMax when r gets through, and weights throughput by the
+// transmittance, times the scattering coefficient for a scattering, over the
+// pdf.
+float sampleMedium(int index, Ray r, float tMax, inout vec3 throughput)
+{
+    Medium medium = getMedium(index);
+    vec3 extinction = medium.absorption + medium.scattering;
+    float sigma = extinction[min(int(rand() * 3.0), 2)];
+    float t = sigma > 0.0 ? -log(1.0 - rand()) / sigma : tMax;
+    bool scattered = t < tMax;
+    t = min(t, tMax);
+    vec3 transmittance = exp(-extinction * t);
+    vec3 density = scattered ? extinction * transmittance : transmittance;
+    float pdf = (density.x + density.y + density.z) / 3.0;
+    if (pdf <= 0.0)
+    {
+        throughput = vec3(0.0);
+        return t;
+    }
+    throughput *= (scattered ? medium.scattering : vec3(1.0)) * transmittance / pdf;
+    return t;
+}
+
+// Next event estimation: the light arriving at rec.p from a point picked on
+// one of the lights, weighted against sampling the material.
+vec3 sampleLight(vec3 direction)
+{
+    float environmentChance = environmentProbability();
+    if (lightsNum == 0 && environmentChance == 0.0)
+        return vec3(0.0);
+
+    vec3 l, emission;
+    float pdf, distance;
+    if (rand() < environmentChance)
+    {
+        l = sampleEnvironment(pdf);
+        pdf *= environmentChance;
+        emission = environmentRadiance(l);
+        distance = 3.402823466e+38;
+    }
+    else
+    {
+        float u = rand();
+        int low = 0;
+        int high = lightsNum - 1;
+        while (low < high)
+        {
+            int middle = (low + high) / 2;
+            if (getData(vertices_texture, float(lightsOffset + middle * LIGHT_STRIDE)).y < u)
+                low = middle + 1;
+            else
+                high = middle;
+        }
+        vec3 info = getData(vertices_texture, float(lightsOffset + low * LIGHT_STRIDE));
+        float area = getData(vertices_texture, float(lightsOffset + low * LIGHT_STRIDE + 1)).x;
+
+        vec3 point, normal;
+        samplePrimitive(int(info.x), point, normal, emission);
+        vec3 toLight = point - rec.p;
+        float distance2 = dot(toLight, toLight);
+        distance = sqrt(distance2);
+        l = toLight / distance;
+        float cosLight = abs(dot(normal, l));
+        if (cosLight < 1E-6)
+            return vec3(0.0);
+        pdf = (1.0 - environmentChance) * info.z * distance2 / (cosLight * area);
+        distance *= 1.0 - 1E-3;
+    }
+    if (pdf <= 0.0)
+        return vec3(0.0);
+
+    float materialPdf;
+    vec3 f = evalMaterial(direction, l, materialPdf);
+    if (materialPdf == 0.0)
+        return vec3(0.0);
+
+    return f * emission * transmittance(rec.p, l, distance) * powerHeuristic(pdf, materialPdf) / pdf;
+}
+
+// Blue to cyan, green, yellow and red as t goes from 0 to 1.
+vec3 heatmap(float t)
+{
+    t = clamp(t, 0.0, 1.0);
+    return clamp(1.5 - abs(4.0 * t - vec3(3.0, 2.0, 1.0)), 0.0, 1.0);
+}
+
+// a random but stable color for an object or material index
+vec3 idColor(uint id)
+{
+    uint x = hash(id + 1u);
+    return vec3(uvec3(x, x >> 8, x >> 16) & 255u) / 255.0;
+}
+
+// What the camera ray hits first instead of the radiance, see Channel in src/channel.rs.
+vec3 channelColor(Ray r)
+{
+    firstAlbedo = vec3(0.0);
+    firstNormal = vec3(0.0);
+    firstDepth = 0.0;
+    firstMedium = 0.0;
+    nodesVisited = 0;
+    primitivesTested = 0;
+    bool hit = hitWorld(r);
+    if (channel == 6)
+        return heatmap(float(nodesVisited) / HEATMAP_NODES);
+    if (channel == 7)
+        return heatmap(float(primitivesTested) / HEATMAP_PRIMITIVES);
     if (!hit)
+        return vec3(0.0);
+    switch (channel)
     {
-        color *= vec3(0.0, 0.0, 0.0);
+    case 1:
+        return vec3(length(rec.p - r.origin) * depthScale);
+    case 2:
+        return rec.normal * 0.5 + 0.5;
+    case 3:
+        return clamp(rec.albedo, 0.0, 1.0);
+    case 4:
+        return rec.barycentrics;
+    case 5:
+        return vec3(rec.uv, 0.0);
+    case 8:
+        return idColor(uint(rec.object));
+    default:
+        return idColor(uint(rec.material));
     }
-    if (gamma)
+}
+
+vec3 shading(Ray r)
+{
+    vec3 color = vec3(0.0, 0.0, 0.0);
+    vec3 throughput = vec3(1.0, 1.0, 1.0);
+    // pdf of the material sample that produced r, zero for camera rays and
+    // specular bounces which the light sampling cannot reach
+    float materialPdf = 0.0;
+    firstAlbedo = vec3(0.0);
+    firstNormal = vec3(0.0);
+    firstDepth = 0.0;
+    firstMedium = 0.0;
+    mediaDepth = 0;
+    int crossings = 0;
+    // from the last bounce to the interface r starts at
+    float crossed = 0.0;
+    for (int i = 0; i < depths; i++)
     {
-        return pow(color, vec3(1.0 / 2.2));
+        bool hit = hitWorld(r);
+        int medium = currentMedium();
+        if (medium >= 0)
+        {
+            float tMax = hit ? rec.hitMin : 3.402823466e+38;
+            float t = sampleMedium(medium, r, tMax, throughput);
+            if (t < tMax)
+            {
+                hit = true;
+                rec.p = r.origin + t * r.direction;
+                rec.hitMin = t;
+                rec.normal = -r.direction;
+                rec.frontFace = true;
+                rec.material = 5;
+                rec.albedo = vec3(1.0);
+                rec.anisotropy = getMedium(medium).anisotropy;
+                rec.medium = -1;
+                rec.emission = vec3(0.0);
+            }
+        }
+        if (!hit)
+        {
+            if (i == 0)
+                firstAlbedo = clamp(environmentRadiance(r.direction), 0.0, 1.0);
+            float weight = 1.0;
+            if (materialPdf > 0.0 && environment.mode == 2)
+                weight = powerHeuristic(materialPdf, environmentProbability() * environmentPdf(r.direction));
+            color += throughput * environmentRadiance(r.direction) * weight;
+            break;
+        }
+        // Passing an interface is no bounce, the path goes on as it was.
+        if (rec.material == 7 && crossings < MAX_CROSSINGS)
+        {
+            crossings++;
+            crossed += rec.hitMin;
+            crossSurface(r.direction);
+            r.origin = rec.p;
+            r.hitMin = 3.402823466e+38;
+            i--;
+            continue;
+        }
+        if (i == 0)
+        {
+            firstAlbedo = clamp(rec.albedo, 0.0, 1.0);
+            // A medium has no surface, it faces the camera for the denoiser.
+            firstNormal = rec.material == 5 ? -normalize(r.direction) : rec.normal;
+            firstMedium = rec.material == 5 ? 1.0 : 0.0;
+            firstDepth = crossed + length(rec.p - r.origin);
+        }
+        color += throughput * rec.emission;
+        if (rec.material == 4)
+        {
+            rec.hitMin += crossed;
+            float weight = materialPdf > 0.0 ? powerHeuristic(materialPdf, lightPdf(r)) : 1.0;
+            color += throughput * rec.albedo * weight;
+            break;
+        }
+
+        vec3 direction = r.direction;
+        bool specular = rec.material == 2 || rec.material == 3;
+        if (!specular)
+            color += throughput * sampleLight(direction);
+
+        switch (rec.material)
+        {
+        case 1:
+            r.direction = diffuse();
+            break;
+        case 2:
+            r.direction = metal(direction);
+            break;
+        case 3:
+            r.direction = dielectric(direction);
+            break;
+        case 5:
+            r.direction = phase(direction);
+            break;
+        case 6:
+            r.direction = pbr(getPbrLobes(direction), direction);
+            break;
+        default:
+            return color;
+        }
+
+        if (specular)
+        {
+            throughput *= rec.albedo;
+            materialPdf = 0.0;
+        }
+        else
+        {
+            vec3 f = evalMaterial(direction, r.direction, materialPdf);
+            if (materialPdf == 0.0)
+                break;
+            throughput *= f / materialPdf;
+        }
+        crossSurface(r.direction);
+        crossed = 0.0;
+        r.origin = rec.p;
+        r.hitMin = 3.402823466e+38;
     }
     return color;
 }
@@ -866,3 +2221,22 @@ bool intersectAABB(Ray r, aabb box, vec3 invDir, int dirIsNeg[3])
         tmax = tzmax;
     return tmax > 0.0;
 }
+
+// Uniform point on the unit disk, or on the unit polygon with camera.blades
+// corners.
+vec2 sampleAperture()
+{
+    if (camera.blades < 3)
+    {
+        float r = sqrt(rand());
+        float phi = 2.0 * PI * rand();
+        return r * vec2(cos(phi), sin(phi));
+    }
+    float blades = float(camera.blades);
+    float s = sqrt(rand());
+    float t = rand();
+    float blade = min(floor(rand() * blades), blades - 1.0);
+    float a0 = 2.0 * PI * blade / blades;
+    float a1 = 2.0 * PI * (blade + 1.0) / blades;
+    return s * ((1.0 - t) * vec2(cos(a0), sin(a0)) + t * vec2(cos(a1), sin(a1)));
+}
@@ -1,12 +1,75 @@
 #version 330 core
-out vec4 FragColor;
+layout(location = 0) out vec4 FragColor;
+layout(location = 1) out vec4 FragMoments;
+layout(location = 2) out vec4 FragAlbedo;
+layout(location = 3) out vec4 FragNormal;
 
 in vec2 TexCoords;
 
+// filter weighted color sums with the weights in alpha
 uniform sampler2D screenTexture;
+// luminance moments and the AOVs, only copied
+uniform sampler2D momentsTexture;
+uniform sampler2D albedoTexture;
+uniform sampler2D normalTexture;
+// Low resolution earlier image, shown while the accumulation converges
+uniform sampler2D previewTexture;
+uniform float previewWeight;
+
+// false when copying between the accumulation buffers, which stay linear
+uniform bool tonemap;
+// 0 clamp, 1 reinhard, 2 aces, 3 filmic
+uniform int tonemapper;
+// in stops
+uniform float exposure;
+uniform bool srgb;
+
+vec3 hable(vec3 x) {
+    const float a = 0.15, b = 0.50, c = 0.10, d = 0.20, e = 0.02, f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+vec3 tonemapColor(vec3 x) {
+    switch (tonemapper) {
+        case 1:
+            return x / (1.0 + x);
+        case 2:
+            return (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+        case 3:
+            return hable(2.0 * x) / hable(vec3(11.2));
+        default:
+            return x;
+    }
+}
+
+vec3 srgbEncode(vec3 c) {
+    vec3 low = 12.92 * c;
+    vec3 high = 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055;
+    return mix(high, low, vec3(lessThanEqual(c, vec3(0.0031308))));
+}
+
+// Same as resolve in src/film.rs.
+vec3 resolve(vec4 sum) {
+    return sum.a > 0.0 ? max(sum.rgb / sum.a, 0.0) : vec3(0.0);
+}
 
 void main() {
-    vec3 col = texture(screenTexture, TexCoords).rgb;
+    vec4 sum = texture(screenTexture, TexCoords);
+    if (!tonemap) {
+        FragColor = sum;
+        FragMoments = texture(momentsTexture, TexCoords);
+        FragAlbedo = texture(albedoTexture, TexCoords);
+        FragNormal = texture(normalTexture, TexCoords);
+        return;
+    }
+    vec3 col = resolve(sum);
+    col = mix(col, resolve(texture(previewTexture, TexCoords)), previewWeight);
+    col = clamp(tonemapColor(max(col, vec3(0.0)) * exp2(exposure)), 0.0, 1.0);
+    if (srgb) {
+        col = srgbEncode(col);
+    }
     FragColor = vec4(col, 1.0);
+    FragMoments = vec4(0.0);
+    FragAlbedo = vec4(0.0);
+    FragNormal = vec4(0.0);
 }
-
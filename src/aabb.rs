@@ -1,6 +1,8 @@
+use cgmath::{Matrix4, SquareMatrix, Vector4};
+
 use crate::utils::MATERIAL::NONE;
-use crate::utils::SHAPE::{RT_MESH, RT_RECTANGLE, RT_SPHERE, RT_VOLUME};
-use crate::utils::{MATERIAL, MAX_FLOAT, MIN_FLOAT, SHAPE};
+use crate::utils::SHAPE::{RT_GRID_VOLUME, RT_MESH, RT_RECTANGLE, RT_SPHERE, RT_TORUS, RT_VOLUME};
+use crate::utils::{translated, MATERIAL, MAX_FLOAT, MIN_FLOAT, SHAPE};
 
 #[derive(Clone)]
 pub struct AABB {
@@ -11,17 +13,37 @@ pub struct AABB {
     pub material: MATERIAL,
 }
 
+impl Default for AABB {
+    fn default() -> Self {
+        AABB::new()
+    }
+}
+
 impl AABB {
     pub fn new() -> AABB {
-        let aabb = AABB {
+        AABB {
             min: [MAX_FLOAT, MAX_FLOAT, MAX_FLOAT],
             max: [MIN_FLOAT, MIN_FLOAT, MIN_FLOAT],
             shape: SHAPE::NONE,
             constant: 0.0,
             material: NONE,
-        };
+        }
+    }
 
-        aabb
+    pub fn surface_area(&self) -> f32 {
+        let d = [
+            self.max[0] - self.min[0],
+            self.max[1] - self.min[1],
+            self.max[2] - self.min[2],
+        ];
+        2.0 * (d[0] * d[1] + d[0] * d[2] + d[1] * d[2])
+    }
+
+    pub fn extend(&mut self, point: &[f32; 3]) {
+        for (i, value) in point.iter().enumerate() {
+            self.min[i] = self.min[i].min(*value);
+            self.max[i] = self.max[i].max(*value);
+        }
     }
 
     pub fn new_mesh(vertices: Vec<[f32; 3]>, constant: f32, material: MATERIAL) -> AABB {
@@ -34,29 +56,20 @@ impl AABB {
         };
 
         for vertex in vertices {
-            for i in 0..3 {
-                if vertex[i] < aabb.min[i] {
-                    aabb.min[i] = vertex[i];
-                }
-                if vertex[i] > aabb.max[i] {
-                    aabb.max[i] = vertex[i];
-                }
-            }
+            aabb.extend(&vertex);
         }
 
         aabb
     }
 
     pub fn new_sphere(center: [f32; 3], radius: f32, constant: f32, material: MATERIAL) -> AABB {
-        let aabb = AABB {
+        AABB {
             min: [center[0] - radius, center[1] - radius, center[2] - radius],
             max: [center[0] + radius, center[1] + radius, center[2] + radius],
             shape: RT_SPHERE,
             constant,
             material,
-        };
-
-        aabb
+        }
     }
 
     pub fn new_triangle(vertices: Vec<[f32; 3]>, constant: f32, material: MATERIAL) -> AABB {
@@ -69,14 +82,7 @@ impl AABB {
         };
 
         for vertex in vertices {
-            for i in 0..3 {
-                if vertex[i] < aabb.min[i] {
-                    aabb.min[i] = vertex[i];
-                }
-                if vertex[i] > aabb.max[i] {
-                    aabb.max[i] = vertex[i];
-                }
-            }
+            aabb.extend(&vertex);
         }
 
         aabb
@@ -92,12 +98,60 @@ impl AABB {
         };
 
         for vertex in vertices {
-            for i in 0..3 {
-                if vertex[i] < aabb.min[i] {
-                    aabb.min[i] = vertex[i];
+            aabb.extend(&vertex);
+        }
+
+        aabb
+    }
+
+    /// Bounds of a disk, cylinder, cone or torus from `Object::new_quadric`:
+    /// those of its box in object space moved into the world.
+    pub fn new_quadric(
+        shape: SHAPE,
+        vertices: &[[f32; 3]],
+        constant: f32,
+        material: MATERIAL,
+    ) -> AABB {
+        let model = from_columns(vertices).invert().unwrap();
+        let [radius, height, minor_radius] = vertices[4];
+        let (outer, bottom, top) = match shape {
+            RT_TORUS => (radius + minor_radius, -minor_radius, minor_radius),
+            _ => (radius, 0.0, height),
+        };
+        let mut aabb = AABB {
+            min: [MAX_FLOAT, MAX_FLOAT, MAX_FLOAT],
+            max: [MIN_FLOAT, MIN_FLOAT, MIN_FLOAT],
+            shape,
+            constant,
+            material,
+        };
+
+        for x in [-outer, outer] {
+            for y in [bottom, top] {
+                for z in [-outer, outer] {
+                    aabb.extend(&translated(&[x, y, z], &model));
                 }
-                if vertex[i] > aabb.max[i] {
-                    aabb.max[i] = vertex[i];
+            }
+        }
+
+        aabb
+    }
+
+    /// The box of a grid volume, [0, 1]^3 in grid space.
+    pub fn new_grid_volume(vertices: &[[f32; 3]], constant: f32, material: MATERIAL) -> AABB {
+        let model = from_columns(vertices).invert().unwrap();
+        let mut aabb = AABB {
+            min: [MAX_FLOAT, MAX_FLOAT, MAX_FLOAT],
+            max: [MIN_FLOAT, MIN_FLOAT, MIN_FLOAT],
+            shape: RT_GRID_VOLUME,
+            constant,
+            material,
+        };
+
+        for x in [0.0, 1.0] {
+            for y in [0.0, 1.0] {
+                for z in [0.0, 1.0] {
+                    aabb.extend(&translated(&[x, y, z], &model));
                 }
             }
         }
@@ -157,20 +211,24 @@ impl AABB {
         ];
 
         for vertex in vertices {
-            for i in 0..3 {
-                if vertex[i] < aabb.min[i] {
-                    aabb.min[i] = vertex[i];
-                }
-                if vertex[i] > aabb.max[i] {
-                    aabb.max[i] = vertex[i];
-                }
-            }
+            aabb.extend(&vertex);
         }
 
         aabb
     }
 }
 
+/// The affine transform whose columns are the first four `vertices`.
+fn from_columns(vertices: &[[f32; 3]]) -> Matrix4<f32> {
+    let column = |v: [f32; 3], w: f32| Vector4::new(v[0], v[1], v[2], w);
+    Matrix4::from_cols(
+        column(vertices[0], 0.0),
+        column(vertices[1], 0.0),
+        column(vertices[2], 0.0),
+        column(vertices[3], 1.0),
+    )
+}
+
 pub fn merge_aabb(a: &AABB, b: &AABB) -> AABB {
     let mut aabb = AABB::new();
 
@@ -182,12 +240,27 @@ pub fn merge_aabb(a: &AABB, b: &AABB) -> AABB {
     aabb
 }
 
-pub fn merge_vec3(a: &AABB, b: &[f32; 3]) -> AABB {
+/// The box both `a` and `b` cover, `None` when they are disjoint.
+pub fn overlap_aabb(a: &AABB, b: &AABB) -> Option<AABB> {
     let mut aabb = AABB::new();
 
     for i in 0..3 {
-        aabb.min[i] = a.min[i].min(b[i]);
-        aabb.max[i] = a.max[i].max(b[i]);
+        aabb.min[i] = a.min[i].max(b.min[i]);
+        aabb.max[i] = a.max[i].min(b.max[i]);
+        if aabb.min[i] > aabb.max[i] {
+            return None;
+        }
+    }
+
+    Some(aabb)
+}
+
+pub fn merge_vec3(a: &AABB, b: &[f32; 3]) -> AABB {
+    let mut aabb = AABB::new();
+
+    for (i, value) in b.iter().enumerate() {
+        aabb.min[i] = a.min[i].min(*value);
+        aabb.max[i] = a.max[i].max(*value);
     }
 
     aabb
@@ -0,0 +1,173 @@
+use std::time::Duration;
+
+/// Progress of the image being accumulated in the screen buffer: how many
+/// samples per pixel it holds, when to stop adding more and how fast they come.
+pub struct Accumulation {
+    samples: i32,
+    /// Stop once the image has this many samples per pixel, 0 for never.
+    pub target: i32,
+    pub paused: bool,
+    elapsed: Duration,
+    /// Samples and time since `rate` was last updated.
+    window: (i32, Duration),
+    rate: f32,
+}
+
+impl Accumulation {
+    pub fn new(target: i32) -> Accumulation {
+        Accumulation {
+            samples: 0,
+            target,
+            paused: false,
+            elapsed: Duration::ZERO,
+            window: (0, Duration::ZERO),
+            rate: 0.0,
+        }
+    }
+
+    /// Starts a new image, keeping the target and whether it is paused.
+    pub fn reset(&mut self) {
+        self.samples = 0;
+        self.elapsed = Duration::ZERO;
+    }
+
+    pub fn samples(&self) -> i32 {
+        self.samples
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.target > 0 && self.samples >= self.target
+    }
+
+    /// Whether the renderer should add samples this frame.
+    pub fn wants_samples(&self) -> bool {
+        !self.paused && !self.is_done()
+    }
+
+    pub fn add_sample(&mut self) {
+        self.samples += 1;
+        self.window.0 += 1;
+    }
+
+    /// Records the time spent rendering the samples added since the last call.
+    pub fn add_time(&mut self, time: Duration) {
+        self.elapsed += time;
+        self.window.1 += time;
+        if self.window.1 >= Duration::from_millis(500) {
+            self.rate = self.window.0 as f32 / self.window.1.as_secs_f32();
+            self.window = (0, Duration::ZERO);
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn samples_per_second(&self) -> f32 {
+        if self.wants_samples() {
+            self.rate
+        } else {
+            0.0
+        }
+    }
+
+    /// One line summary for the UI.
+    pub fn status(&self) -> String {
+        let samples = if self.target > 0 {
+            format!("{} / {} spp", self.samples, self.target)
+        } else {
+            format!("{} spp", self.samples)
+        };
+        let state = if self.paused {
+            ", paused"
+        } else if self.is_done() {
+            ", done"
+        } else {
+            ""
+        };
+        format!(
+            "{}, {:.1} spp/s, {:.1} s{}",
+            samples,
+            self.samples_per_second(),
+            self.elapsed.as_secs_f32(),
+            state
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds `samples` taking `millis` each.
+    fn render(accumulation: &mut Accumulation, samples: i32, millis: u64) {
+        for _ in 0..samples {
+            if accumulation.wants_samples() {
+                accumulation.add_sample();
+                accumulation.add_time(Duration::from_millis(millis));
+            }
+        }
+    }
+
+    #[test]
+    fn stops_at_the_target() {
+        let mut accumulation = Accumulation::new(4);
+        render(&mut accumulation, 10, 100);
+        assert_eq!(accumulation.samples(), 4);
+        assert!(accumulation.is_done() && !accumulation.wants_samples());
+        assert_eq!(accumulation.samples_per_second(), 0.0);
+
+        accumulation.reset();
+        assert!(!accumulation.is_done() && accumulation.wants_samples());
+        assert_eq!(accumulation.elapsed(), Duration::ZERO);
+        assert_eq!(accumulation.target, 4);
+    }
+
+    #[test]
+    fn no_target_never_stops() {
+        let mut accumulation = Accumulation::new(0);
+        render(&mut accumulation, 100, 1);
+        assert_eq!(accumulation.samples(), 100);
+        assert!(!accumulation.is_done() && accumulation.wants_samples());
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut accumulation = Accumulation::new(10);
+        render(&mut accumulation, 3, 100);
+        accumulation.paused = true;
+        render(&mut accumulation, 3, 100);
+        assert_eq!(accumulation.samples(), 3);
+        assert_eq!(accumulation.samples_per_second(), 0.0);
+        // Paused images restart paused.
+        accumulation.reset();
+        assert!(accumulation.paused && !accumulation.wants_samples());
+        accumulation.paused = false;
+        render(&mut accumulation, 3, 100);
+        assert_eq!(accumulation.samples(), 3);
+    }
+
+    #[test]
+    fn rate_is_measured_over_half_seconds() {
+        let mut accumulation = Accumulation::new(0);
+        render(&mut accumulation, 4, 100);
+        assert_eq!(accumulation.samples_per_second(), 0.0);
+        render(&mut accumulation, 1, 100);
+        assert!((accumulation.samples_per_second() - 10.0).abs() < 1E-4);
+    }
+
+    #[test]
+    fn status() {
+        let mut accumulation = Accumulation::new(8);
+        assert_eq!(accumulation.status(), "0 / 8 spp, 0.0 spp/s, 0.0 s");
+        render(&mut accumulation, 5, 100);
+        assert_eq!(accumulation.status(), "5 / 8 spp, 10.0 spp/s, 0.5 s");
+        accumulation.paused = true;
+        assert_eq!(accumulation.status(), "5 / 8 spp, 0.0 spp/s, 0.5 s, paused");
+        accumulation.paused = false;
+        render(&mut accumulation, 5, 100);
+        assert_eq!(accumulation.status(), "8 / 8 spp, 0.0 spp/s, 0.8 s, done");
+        accumulation.target = 0;
+        assert_eq!(accumulation.status(), "8 spp, 10.0 spp/s, 0.8 s");
+    }
+}
@@ -0,0 +1,264 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+use crate::scene::CameraDesc;
+
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Linear,
+    /// Passes through every keyframe with a velocity that is continuous in
+    /// time, however unevenly the keyframes are spaced.
+    #[default]
+    CatmullRom,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Keyframe {
+    /// In seconds.
+    pub time: f32,
+    #[serde(flatten)]
+    pub camera: CameraDesc,
+}
+
+/// A camera path through keyframes, rendered offline as one image per frame.
+#[derive(Clone, Deserialize)]
+pub struct Animation {
+    #[serde(default = "default_fps", deserialize_with = "deserialize_fps")]
+    pub fps: f32,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    /// Sorted by time when loaded.
+    #[serde(deserialize_with = "deserialize_keyframes")]
+    pub keyframes: Vec<Keyframe>,
+}
+
+fn default_fps() -> f32 {
+    24.0
+}
+
+fn deserialize_fps<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let fps = f32::deserialize(deserializer)?;
+    if fps > 0.0 {
+        Ok(fps)
+    } else {
+        Err(D::Error::custom(format!(
+            "fps must be positive, got {}",
+            fps
+        )))
+    }
+}
+
+fn deserialize_keyframes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Keyframe>, D::Error> {
+    let mut keyframes = Vec::<Keyframe>::deserialize(deserializer)?;
+    if keyframes.is_empty() {
+        return Err(D::Error::custom("an animation needs at least one keyframe"));
+    }
+    if let Some(keyframe) = keyframes.iter().find(|k| !k.time.is_finite()) {
+        return Err(D::Error::custom(format!(
+            "keyframe time {} is not a number of seconds",
+            keyframe.time
+        )));
+    }
+    keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(keyframes)
+}
+
+impl Animation {
+    /// Frames from the first keyframe up to and including the last one.
+    pub fn frames(&self) -> usize {
+        match (self.keyframes.first(), self.keyframes.last()) {
+            (Some(first), Some(last)) => ((last.time - first.time) * self.fps).floor() as usize + 1,
+            _ => 0,
+        }
+    }
+
+    pub fn camera_at_frame(&self, frame: usize) -> CameraDesc {
+        self.camera_at(self.keyframes[0].time + frame as f32 / self.fps)
+    }
+
+    /// The camera at `time`, held at the first and last keyframes outside
+    /// of them.
+    pub fn camera_at(&self, time: f32) -> CameraDesc {
+        let keys = &self.keyframes;
+        if keys.len() == 1 {
+            return keys[0].camera.clone();
+        }
+        let next = keys
+            .partition_point(|k| k.time <= time)
+            .clamp(1, keys.len() - 1);
+        // The segment between the middle two, and their neighbours, repeated
+        // at the ends.
+        let k = [
+            next.saturating_sub(2),
+            next - 1,
+            next,
+            (next + 1).min(keys.len() - 1),
+        ]
+        .map(|i| &keys[i]);
+        let times = k.map(|k| k.time);
+        let time = time.clamp(times[1], times[2]);
+        let interpolate = |p: [f32; 4]| match self.interpolation {
+            Interpolation::Linear => {
+                let span = times[2] - times[1];
+                if span > 0.0 {
+                    p[1] + (p[2] - p[1]) * (time - times[1]) / span
+                } else {
+                    p[2]
+                }
+            }
+            Interpolation::CatmullRom => catmull_rom(p, times, time),
+        };
+        let value = |f: fn(&CameraDesc) -> f32| interpolate(k.map(|k| f(&k.camera)));
+        // Each yaw within half a turn of its neighbour, so the camera turns
+        // the short way round.
+        let mut yaw = k.map(|k| k.camera.yaw);
+        yaw[0] = yaw[1] + wrap_degrees(yaw[0] - yaw[1]);
+        yaw[2] = yaw[1] + wrap_degrees(yaw[2] - yaw[1]);
+        yaw[3] = yaw[2] + wrap_degrees(yaw[3] - yaw[2]);
+        CameraDesc {
+            position: [
+                value(|c| c.position[0]),
+                value(|c| c.position[1]),
+                value(|c| c.position[2]),
+            ],
+            yaw: interpolate(yaw),
+            pitch: value(|c| c.pitch),
+            fov: value(|c| c.fov),
+            aperture: value(|c| c.aperture).max(0.0),
+            focus_distance: value(|c| c.focus_distance),
+            blades: k[1].camera.blades,
+        }
+    }
+}
+
+/// An angle in degrees to [-180, 180).
+fn wrap_degrees(angle: f32) -> f32 {
+    (angle + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// The Hermite curve from `p[1]` to `p[2]` at `time` between `times[1]` and
+/// `times[2]`, its velocity at either end the slope between the neighbouring
+/// keyframes, or of the segment itself at the ends of the path.
+fn catmull_rom(p: [f32; 4], times: [f32; 4], time: f32) -> f32 {
+    let span = times[2] - times[1];
+    if span <= 0.0 {
+        return p[2];
+    }
+    let slope = |a: usize, b: usize| {
+        let dt = times[b] - times[a];
+        if dt > 0.0 {
+            (p[b] - p[a]) / dt
+        } else {
+            0.0
+        }
+    };
+    let (m1, m2) = (slope(0, 2) * span, slope(1, 3) * span);
+    let t = (time - times[1]) / span;
+    let (t2, t3) = (t * t, t * t * t);
+    (2.0 * t3 - 3.0 * t2 + 1.0) * p[1]
+        + (t3 - 2.0 * t2 + t) * m1
+        + (3.0 * t2 - 2.0 * t3) * p[2]
+        + (t3 - t2) * m2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn animation(source: &str) -> Animation {
+        toml::from_str(source).unwrap()
+    }
+
+    /// Unevenly spaced keyframes, out of order, moving along x at one unit
+    /// and turning 20 degrees per second.
+    const ANIMATION: &str = r#"
+        fps = 10.0
+        [[keyframes]]
+        time = 2.0
+        position = [2.0, 0.0, 0.0]
+        yaw = 10.0
+        [[keyframes]]
+        time = 0.0
+        position = [0.0, 0.0, 0.0]
+        yaw = 330.0
+        [[keyframes]]
+        time = 1.0
+        position = [1.0, 0.0, 0.0]
+        yaw = 350.0
+        [[keyframes]]
+        time = 4.0
+        position = [4.0, 0.0, 0.0]
+        yaw = 50.0
+    "#;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1E-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn keyframes_are_sorted_by_time() {
+        let animation = animation(ANIMATION);
+        let times: Vec<f32> = animation.keyframes.iter().map(|k| k.time).collect();
+        assert_eq!(times, [0.0, 1.0, 2.0, 4.0]);
+        // 4 seconds at 10 frames per second and the last keyframe.
+        assert_eq!(animation.frames(), 41);
+        assert_close(animation.camera_at_frame(25).position[0], 2.5);
+    }
+
+    #[test]
+    fn frames_of_a_single_keyframe() {
+        let animation = animation("[[keyframes]]\ntime = 3.0");
+        assert_eq!(animation.frames(), 1);
+        assert_eq!(animation.camera_at(0.0).yaw, -90.0);
+    }
+
+    #[test]
+    fn rejects_bad_fps_and_empty_paths() {
+        let error = toml::from_str::<Animation>("fps = 0.0\n[[keyframes]]\ntime = 0.0");
+        assert!(error
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("fps must be positive"));
+        let error = toml::from_str::<Animation>("keyframes = []");
+        assert!(error
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("at least one keyframe"));
+    }
+
+    #[test]
+    fn passes_through_the_keyframes() {
+        for interpolation in ["linear", "catmull_rom"] {
+            let source = format!("interpolation = \"{}\"\n{}", interpolation, ANIMATION);
+            let animation = animation(&source);
+            for time in [0.0, 1.0, 2.0, 4.0] {
+                assert_close(animation.camera_at(time).position[0], time);
+            }
+            // Held outside of the path.
+            assert_close(animation.camera_at(-1.0).position[0], 0.0);
+            assert_close(animation.camera_at(9.0).position[0], 4.0);
+            assert_close(animation.camera_at(9.0).yaw.rem_euclid(360.0), 50.0);
+        }
+    }
+
+    #[test]
+    fn midpoints_keep_the_velocity() {
+        for interpolation in ["linear", "catmull_rom"] {
+            let source = format!("interpolation = \"{}\"\n{}", interpolation, ANIMATION);
+            let animation = animation(&source);
+            // Also in the segment twice as long as the others, which a
+            // curve spaced by keyframes instead of time would overshoot.
+            for time in [0.5, 1.5, 2.5, 3.0, 3.5] {
+                assert_close(animation.camera_at(time).position[0], time);
+            }
+            // The short way from 350 to 10 degrees, through 0.
+            assert_close(animation.camera_at(1.5).yaw.rem_euclid(360.0), 0.0);
+            assert_close(animation.camera_at(3.0).yaw.rem_euclid(360.0), 30.0);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::path::Path;
+use std::process::exit;
+use std::time::Instant;
+
+use ray_tracer::bvh::BVHStats;
+use ray_tracer::channel::Channel;
+use ray_tracer::cpu_renderer::CpuRenderer;
+use ray_tracer::display::Tonemapper;
+use ray_tracer::film::Filter;
+use ray_tracer::headless::HeadlessContext;
+use ray_tracer::output::save_image;
+use ray_tracer::renderer::Renderer;
+use ray_tracer::sampler::Sampler;
+use ray_tracer::scene::{CameraDesc, Scene};
+
+const USAGE: &str = "Usage: render [options] <scene.toml>
+
+Options:
+    -o, --output <file>     output image, .png/.jpg are 8 bit, .exr/.hdr float (default: render.png)
+    -w, --width <pixels>    image width (default: scene settings)
+    -h, --height <pixels>   image height (default: scene settings)
+    -s, --samples <count>   samples per pixel (default: scene settings)
+    -d, --depths <count>    maximum path depth (default: scene settings)
+    -e, --exposure <ev>     exposure of 8 bit images in stops (default: scene settings)
+        --tonemap <name>    clamp, reinhard, aces or filmic (default: scene settings)
+        --sampler <name>    random or sobol (default: scene settings)
+        --filter <name>     box, tent, gaussian or mitchell (default: scene settings)
+        --adaptive <error>  stop sampling pixels once their relative error is below
+                            <error>, e.g. 0.05
+        --denoise           denoise the image before writing it
+        --channel <name>    beauty, depth, normal, albedo, barycentrics, uv,
+                            nodes, primitives, object or material (default: scene settings)
+        --bvh-stats         print statistics of the BVH once it is built
+        --animation         render every frame of the scene's [animation] camera path
+                            to <output stem>_0000.<ext> and so on
+        --cpu               trace on the CPU instead of OpenGL
+        --help              print this message";
+
+struct Args {
+    scene: String,
+    output: String,
+    width: Option<i32>,
+    height: Option<i32>,
+    samples: Option<i32>,
+    depths: Option<i32>,
+    exposure: Option<f32>,
+    tonemapper: Option<Tonemapper>,
+    sampler: Option<Sampler>,
+    filter: Option<Filter>,
+    adaptive: Option<f32>,
+    denoise: bool,
+    channel: Option<Channel>,
+    bvh_stats: bool,
+    animation: bool,
+    cpu: bool,
+}
+
+fn parse_args() -> Args {
+    let mut args = std::env::args().skip(1);
+    let mut scene = None;
+    let mut output = String::from("render.png");
+    let mut width = None;
+    let mut height = None;
+    let mut samples = None;
+    let mut depths = None;
+    let mut exposure = None;
+    let mut tonemapper = None;
+    let mut sampler = None;
+    let mut filter = None;
+    let mut adaptive = None;
+    let mut denoise = false;
+    let mut channel = None;
+    let mut bvh_stats = false;
+    let mut animation = false;
+    let mut cpu = false;
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| usage_error(&format!("missing value for {}", arg)))
+        };
+        match arg.as_str() {
+            "-o" | "--output" => output = value(),
+            "-w" | "--width" => width = Some(parse_number(&value())),
+            "-h" | "--height" => height = Some(parse_number(&value())),
+            "-s" | "--samples" => samples = Some(parse_number(&value())),
+            "-d" | "--depths" => depths = Some(parse_number(&value())),
+            "-e" | "--exposure" => {
+                let value = value();
+                exposure =
+                    Some(value.parse().unwrap_or_else(|_| {
+                        usage_error(&format!("expected a number, got {}", value))
+                    }));
+            }
+            "--tonemap" => {
+                let value = value();
+                tonemapper = Some(
+                    Tonemapper::from_name(&value)
+                        .unwrap_or_else(|| usage_error(&format!("unknown tonemapper {}", value))),
+                );
+            }
+            "--sampler" => {
+                let value = value();
+                sampler = Some(
+                    Sampler::from_name(&value)
+                        .unwrap_or_else(|| usage_error(&format!("unknown sampler {}", value))),
+                );
+            }
+            "--filter" => {
+                let value = value();
+                filter = Some(
+                    Filter::from_name(&value)
+                        .unwrap_or_else(|| usage_error(&format!("unknown filter {}", value))),
+                );
+            }
+            "--adaptive" => {
+                let value = value();
+                adaptive =
+                    Some(value.parse().unwrap_or_else(|_| {
+                        usage_error(&format!("expected a number, got {}", value))
+                    }));
+            }
+            "--denoise" => denoise = true,
+            "--channel" => {
+                let value = value();
+                channel = Some(
+                    Channel::from_name(&value)
+                        .unwrap_or_else(|| usage_error(&format!("unknown channel {}", value))),
+                );
+            }
+            "--bvh-stats" => bvh_stats = true,
+            "--animation" => animation = true,
+            "--cpu" => cpu = true,
+            "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ => scene = Some(arg),
+        }
+    }
+
+    Args {
+        scene: scene.unwrap_or_else(|| usage_error("no scene given")),
+        output,
+        width,
+        height,
+        samples,
+        depths,
+        exposure,
+        tonemapper,
+        sampler,
+        filter,
+        adaptive,
+        denoise,
+        channel,
+        bvh_stats,
+        animation,
+        cpu,
+    }
+}
+
+fn parse_number(value: &str) -> i32 {
+    match value.parse() {
+        Ok(number) if number > 0 => number,
+        _ => usage_error(&format!("expected a positive number, got {}", value)),
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n\n{}", message, USAGE);
+    exit(2);
+}
+
+enum Backend {
+    Cpu(Box<CpuRenderer>),
+    // The renderer is dropped before the context it uses.
+    Gpu {
+        renderer: Box<Renderer>,
+        _context: HeadlessContext,
+    },
+}
+
+impl Backend {
+    fn bvh_stats(&self) -> BVHStats {
+        match self {
+            Backend::Cpu(renderer) => renderer.bvh_stats(),
+            Backend::Gpu { renderer, .. } => renderer.bvh_stats(),
+        }
+    }
+
+    /// Renders `samples` samples per pixel from `camera`, or from the current
+    /// camera, and returns the width, height and pixels.
+    fn render(&mut self, camera: Option<&CameraDesc>, samples: i32) -> (i32, i32, Vec<f32>) {
+        match self {
+            Backend::Cpu(renderer) => {
+                if let Some(camera) = camera {
+                    renderer.set_camera(camera);
+                }
+                renderer.render_samples(samples);
+                (renderer.width(), renderer.height(), renderer.read_pixels())
+            }
+            Backend::Gpu { renderer, .. } => {
+                if let Some(camera) = camera {
+                    renderer.set_camera(camera);
+                }
+                renderer.render_samples(samples);
+                (renderer.width(), renderer.height(), renderer.read_pixels())
+            }
+        }
+    }
+}
+
+/// `render.png` becomes `render_0007.png` for frame 7.
+fn frame_path(output: &str, frame: usize) -> String {
+    let path = Path::new(output);
+    let stem = path.with_extension("");
+    match path.extension() {
+        Some(extension) => format!(
+            "{}_{:04}.{}",
+            stem.to_string_lossy(),
+            frame,
+            extension.to_string_lossy()
+        ),
+        None => format!("{}_{:04}", stem.to_string_lossy(), frame),
+    }
+}
+
+fn main() {
+    let args = parse_args();
+
+    let mut scene = Scene::load(&args.scene);
+    let settings = &mut scene.settings;
+    settings.width = args.width.unwrap_or(settings.width);
+    settings.height = args.height.unwrap_or(settings.height);
+    settings.samples = args.samples.unwrap_or(settings.samples);
+    settings.depths = args.depths.unwrap_or(settings.depths);
+    settings.sampler = args.sampler.unwrap_or(settings.sampler);
+    settings.filter = args.filter.unwrap_or(settings.filter);
+    settings.denoise.enabled |= args.denoise;
+    settings.channel = args.channel.unwrap_or(settings.channel);
+    if let Some(threshold) = args.adaptive {
+        settings.adaptive.enabled = true;
+        settings.adaptive.threshold = threshold;
+    }
+    let display = &mut settings.display;
+    display.exposure = args.exposure.unwrap_or(display.exposure);
+    display.tonemapper = args.tonemapper.unwrap_or(display.tonemapper);
+    let display = settings.channel.display(*display);
+
+    let frames: Vec<(Option<CameraDesc>, String)> = if args.animation {
+        let animation = match &scene.animation {
+            Some(animation) if !animation.keyframes.is_empty() => animation,
+            _ => usage_error(&format!("{} has no [animation] keyframes", args.scene)),
+        };
+        (0..animation.frames())
+            .map(|frame| {
+                (
+                    Some(animation.camera_at_frame(frame)),
+                    frame_path(&args.output, frame),
+                )
+            })
+            .collect()
+    } else {
+        vec![(None, args.output.clone())]
+    };
+
+    let mut backend = if args.cpu {
+        Backend::Cpu(Box::new(CpuRenderer::new(&scene)))
+    } else {
+        let (context, gl) = HeadlessContext::new();
+        Backend::Gpu {
+            renderer: Box::new(Renderer::new(gl, &scene)),
+            _context: context,
+        }
+    };
+    if args.bvh_stats {
+        println!("{}", backend.bvh_stats());
+    }
+    for (camera, output) in &frames {
+        let start = Instant::now();
+        let (width, height, pixels) = backend.render(camera.as_ref(), scene.settings.samples);
+        save_image(output, width as u32, height as u32, &pixels, &display);
+        println!(
+            "{}: {}x{}, {} samples in {:.2}s",
+            output,
+            width,
+            height,
+            scene.settings.samples,
+            start.elapsed().as_secs_f32()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_paths_are_zero_padded_before_the_extension() {
+        assert_eq!(frame_path("render.png", 7), "render_0007.png");
+        assert_eq!(frame_path("out/shot.exr", 123), "out/shot_0123.exr");
+        assert_eq!(
+            frame_path("frames/render.v2.png", 0),
+            "frames/render.v2_0000.png"
+        );
+        assert_eq!(frame_path("render", 12345), "render_12345");
+    }
+}
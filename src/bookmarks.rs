@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::scene::CameraDesc;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Bookmark {
+    pub name: String,
+    #[serde(flatten)]
+    pub camera: CameraDesc,
+}
+
+/// Named camera poses of a scene, kept in `<scene>.bookmarks.toml` next to it.
+#[derive(Default, Deserialize, Serialize)]
+pub struct Bookmarks {
+    #[serde(default, rename = "bookmarks")]
+    entries: Vec<Bookmark>,
+    #[serde(skip)]
+    path: String,
+}
+
+impl Bookmarks {
+    /// The bookmark file belonging to the scene at `scene_path`.
+    pub fn path_for(scene_path: &str) -> String {
+        let stem = Path::new(scene_path).with_extension("");
+        format!("{}.bookmarks.toml", stem.to_string_lossy())
+    }
+
+    /// Reads the bookmarks at `path`, none when the file does not exist yet.
+    pub fn load(path: &str) -> Bookmarks {
+        let mut bookmarks: Bookmarks = match fs::read_to_string(path) {
+            Ok(source) => toml::from_str(&source)
+                .unwrap_or_else(|e| panic!("Failed to parse {}: {}", path, e)),
+            Err(_) => Bookmarks::default(),
+        };
+        bookmarks.path = path.into();
+        bookmarks
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let source = toml::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(&self.path, source).map_err(|e| format!("{}: {}", self.path, e))
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.entries.iter().map(|b| b.name.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&CameraDesc> {
+        self.entries
+            .iter()
+            .find(|b| b.name == name)
+            .map(|b| &b.camera)
+    }
+
+    /// Adds a bookmark, replacing the one with the same name.
+    pub fn set(&mut self, name: &str, camera: CameraDesc) {
+        match self.entries.iter_mut().find(|b| b.name == name) {
+            Some(bookmark) => bookmark.camera = camera,
+            None => self.entries.push(Bookmark {
+                name: name.into(),
+                camera,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let directory = std::env::temp_dir().join("bookmarks_test");
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join(name);
+        let _ = fs::remove_file(&path);
+        path.to_str().unwrap().into()
+    }
+
+    #[test]
+    fn bookmark_files_sit_next_to_the_scene() {
+        assert_eq!(
+            Bookmarks::path_for("scenes/cornell_box.toml"),
+            "scenes/cornell_box.bookmarks.toml"
+        );
+    }
+
+    #[test]
+    fn missing_files_have_no_bookmarks() {
+        let bookmarks = Bookmarks::load(&temp_path("missing.bookmarks.toml"));
+        assert!(bookmarks.names().is_empty());
+        assert!(bookmarks.get("door").is_none());
+    }
+
+    #[test]
+    fn bookmarks_survive_a_round_trip() {
+        let path = temp_path("round_trip.bookmarks.toml");
+        let mut bookmarks = Bookmarks::load(&path);
+        let door = CameraDesc {
+            position: [1.0, 2.0, 3.0],
+            yaw: 45.0,
+            aperture: 0.1,
+            blades: 6,
+            ..CameraDesc::default()
+        };
+        bookmarks.set("door", CameraDesc::default());
+        bookmarks.set("window", CameraDesc::default());
+        bookmarks.set("door", door);
+        bookmarks.save().unwrap();
+
+        let bookmarks = Bookmarks::load(&path);
+        assert_eq!(bookmarks.names(), ["door", "window"]);
+        let door = bookmarks.get("door").unwrap();
+        assert_eq!(door.position, [1.0, 2.0, 3.0]);
+        assert_eq!((door.yaw, door.aperture, door.blades), (45.0, 0.1, 6));
+        assert_eq!(bookmarks.get("window").unwrap().yaw, -90.0);
+    }
+}
@@ -1,15 +1,38 @@
-use crate::aabb::{aabb_axis, merge_aabb, merge_vec3, AABB};
+use crate::aabb::{aabb_axis, merge_aabb, merge_vec3, overlap_aabb, AABB};
+use crate::environment::{Environment, EnvironmentDesc};
+use crate::material::{Medium, PbrMaterial, MATERIAL_STRIDE, MEDIUM_STRIDE};
 use crate::object::Object;
 use crate::shader::Shader;
-use crate::utils::SHAPE;
+use crate::texture::{TextureSet, TEXTURE_SET_STRIDE};
+use crate::utils::{MATERIAL, MAX_FLOAT, SHAPE};
 use bytemuck::cast_slice;
 use glow::{
     Context, HasContext, Texture, CLAMP_TO_EDGE, FLOAT, NEAREST, NO_ERROR, RGB, RGB32F, TEXTURE1,
     TEXTURE2, TEXTURE_2D, TEXTURE_MAG_FILTER, TEXTURE_MIN_FILTER, TEXTURE_WRAP_S, TEXTURE_WRAP_T,
 };
+use serde::Deserialize;
 use std::cell::RefCell;
+use std::fmt;
 use std::rc::Rc;
 
+/// Texels per node in `bvh_texture`: min, max and (offset, count, axis).
+pub const NODE_STRIDE: i32 = 3;
+/// Texels per primitive in `vertices_texture`: up to fourteen texels of shape
+/// data followed by (object, medium, texture set) and (shape, material,
+/// constant). The constant of `pbr` primitives is their index into the
+/// material table stored after the primitives, the one of emissive primitives
+/// their index into the light table. The medium is an index into the medium
+/// table after the material table and the texture set one into the texture
+/// set table after that, both -1 for objects without one.
+pub const PRIMITIVE_STRIDE: i32 = 16;
+
+/// Texels per entry of the light table that follows the texture set table:
+/// (primitive, cdf, pmf) and (area, 0, 0).
+pub const LIGHT_STRIDE: i32 = 2;
+
+/// Cost of visiting an interior node relative to testing one primitive.
+const SAH_TRAVERSAL_COST: f32 = 0.125;
+
 pub struct BVHNode {
     pub aabb: AABB,
     pub left: Option<Rc<RefCell<BVHNode>>>,
@@ -19,6 +42,12 @@ pub struct BVHNode {
     pub axis: i32,
 }
 
+impl Default for BVHNode {
+    fn default() -> Self {
+        BVHNode::new()
+    }
+}
+
 impl BVHNode {
     pub fn new() -> BVHNode {
         BVHNode {
@@ -96,79 +125,228 @@ impl BVHPrimitiveInfo {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitMethod {
+    /// Split every node at the median centroid, one primitive per leaf.
+    Median,
+    /// Binned surface area heuristic with leaves of up to `max_leaf_size`.
+    SAH,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct BVHSettings {
+    pub split_method: SplitMethod,
+    pub bins: i32,
+    pub max_leaf_size: i32,
+}
+
+impl Default for BVHSettings {
+    fn default() -> Self {
+        BVHSettings {
+            split_method: SplitMethod::SAH,
+            bins: 12,
+            max_leaf_size: 4,
+        }
+    }
+}
+
+/// Shape and quality of a built tree, see `BVHTree::stats`.
+#[derive(Clone, Default)]
+pub struct BVHStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    /// Depth of the deepest leaf, the root is at depth 0.
+    pub max_depth: usize,
+    pub average_depth: f32,
+    /// Expected primitive tests of a ray through the tree by the surface area
+    /// heuristic, with a node visit costing `SAH_TRAVERSAL_COST`.
+    pub sah_cost: f32,
+    /// `leaf_sizes[n]` leaves hold `n` primitives.
+    pub leaf_sizes: Vec<usize>,
+    /// Surface area of the box two siblings share relative to that of their
+    /// parent, averaged over the interior nodes.
+    pub sibling_overlap: f32,
+}
+
+impl fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} nodes, {} leaves, depth {} max, {:.1} average",
+            self.nodes, self.leaves, self.max_depth, self.average_depth
+        )?;
+        writeln!(
+            f,
+            "SAH cost {:.2}, sibling overlap {:.1}%",
+            self.sah_cost,
+            self.sibling_overlap * 100.0
+        )?;
+        write!(f, "Primitives per leaf:")?;
+        for (size, count) in self.leaf_sizes.iter().enumerate() {
+            if *count > 0 {
+                write!(f, " {}: {}", size, count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An emissive primitive, picked for next event estimation with probability
+/// `pmf`, proportional to its power.
+#[derive(Clone, Copy)]
+pub struct Light {
+    pub primitive: usize,
+    pub area: f32,
+    pub pmf: f32,
+    /// Sum of the `pmf` of this and all previous lights.
+    pub cdf: f32,
+}
+
 pub struct BVHTree {
+    settings: BVHSettings,
     primitives: Vec<Object>,
+    lights: Vec<Light>,
+    environment: Environment,
     linear_bvh_node: Vec<LinearBVHNode>,
-    bvh_texture: Texture,
-    vertices_texture: Texture,
+    bvh_texture: Option<Texture>,
+    vertices_texture: Option<Texture>,
     node_number: i32,
     vertices_number: i32,
+    media_offset: i32,
+    texture_sets_offset: i32,
+    lights_offset: i32,
+}
+
+impl Default for BVHTree {
+    fn default() -> Self {
+        BVHTree::new()
+    }
 }
 
 impl BVHTree {
-    pub fn new(gl: &Context) -> BVHTree {
-        let bvh_tree = BVHTree {
+    pub fn new() -> BVHTree {
+        BVHTree::with_settings(BVHSettings::default())
+    }
+
+    pub fn with_settings(settings: BVHSettings) -> BVHTree {
+        BVHTree {
+            settings,
             primitives: Vec::new(),
+            lights: Vec::new(),
+            environment: Environment::new(&EnvironmentDesc::default()),
             linear_bvh_node: Vec::new(),
-            bvh_texture: unsafe { gl.create_texture().unwrap() },
-            vertices_texture: unsafe { gl.create_texture().unwrap() },
+            bvh_texture: None,
+            vertices_texture: None,
             node_number: 0,
             vertices_number: 0,
-        };
-        bvh_tree
+            media_offset: 0,
+            texture_sets_offset: 0,
+            lights_offset: 0,
+        }
     }
 
-    pub fn build(&mut self, primitives: &Vec<Object>) {
-        self.primitives = primitives.clone();
-        let mut primitive_info = Vec::new();
-        for (i, primitive) in self.primitives.iter().enumerate() {
-            let mut aabb = AABB::new();
-            match primitive.shape {
-                SHAPE::NONE => {}
-                SHAPE::RT_SPHERE => {
-                    aabb = AABB::new_sphere(
-                        primitive.center,
-                        primitive.radius,
-                        primitive.constant,
-                        primitive.material.clone(),
-                    );
-                }
-                SHAPE::RT_MESH => {
-                    let mut vertex = Vec::new();
-                    for i in 0..3 {
-                        vertex.push(primitive.vertices[i * 3]);
-                    }
-                    aabb = AABB::new_mesh(vertex, primitive.constant, primitive.material.clone());
-                }
-                SHAPE::RT_TRIANGLE => {
-                    let mut vertex = Vec::new();
-                    for i in 0..3 {
-                        vertex.push(primitive.vertices[i]);
-                    }
-                    aabb =
-                        AABB::new_triangle(vertex, primitive.constant, primitive.material.clone());
-                }
-                SHAPE::RT_RECTANGLE => {
-                    let mut vertex = Vec::new();
-                    for i in 0..4 {
-                        vertex.push(primitive.vertices[i]);
-                    }
-                    aabb =
-                        AABB::new_rectangle(vertex, primitive.constant, primitive.material.clone());
+    pub fn nodes(&self) -> &[LinearBVHNode] {
+        &self.linear_bvh_node
+    }
+
+    /// The primitives in the order the leaves of `nodes` refer to them.
+    pub fn primitives(&self) -> &[Object] {
+        &self.primitives
+    }
+
+    /// What rays see when they leave the scene, black unless set.
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = environment;
+    }
+
+    pub fn environment(&self) -> &Environment {
+        &self.environment
+    }
+
+    /// The emissive primitives, by index into `primitives`.
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    /// Bounds of the whole scene, those of the root node.
+    pub fn bounds(&self) -> Option<&AABB> {
+        self.linear_bvh_node.first().map(|node| &node.aabb)
+    }
+
+    /// Measures the built tree, all zero before `build`.
+    pub fn stats(&self) -> BVHStats {
+        let nodes = &self.linear_bvh_node;
+        let mut stats = BVHStats {
+            nodes: nodes.len(),
+            ..BVHStats::default()
+        };
+        let root_area = match nodes.first() {
+            Some(root) => root.aabb.surface_area(),
+            None => return stats,
+        };
+        let mut depth_sum = 0;
+        let mut overlap_sum = 0.0;
+        let mut stack = vec![(0, 0)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &nodes[index];
+            // Flat scenes have no area, every node is then hit equally often.
+            let probability = if root_area > 0.0 {
+                node.aabb.surface_area() / root_area
+            } else {
+                1.0
+            };
+            if node.n_primitives > 0 {
+                let size = node.n_primitives as usize;
+                if stats.leaf_sizes.len() <= size {
+                    stats.leaf_sizes.resize(size + 1, 0);
                 }
-                SHAPE::RT_VOLUME => {
-                    let mut vertex = Vec::new();
-                    for i in 0..4 {
-                        vertex.push(primitive.vertices[i]);
+                stats.leaf_sizes[size] += 1;
+                stats.leaves += 1;
+                stats.max_depth = stats.max_depth.max(depth);
+                depth_sum += depth;
+                stats.sah_cost += probability * node.n_primitives as f32;
+            } else {
+                let (left, right) = (index + 1, node.offset as usize);
+                let area = node.aabb.surface_area();
+                if area > 0.0 {
+                    if let Some(overlap) = overlap_aabb(&nodes[left].aabb, &nodes[right].aabb) {
+                        overlap_sum += overlap.surface_area() / area;
                     }
-                    aabb = AABB::new_box_volume(
-                        vertex,
-                        primitive.constant,
-                        primitive.material.clone(),
-                    );
                 }
+                stats.sah_cost += probability * SAH_TRAVERSAL_COST;
+                stack.push((left, depth + 1));
+                stack.push((right, depth + 1));
             }
+        }
+        stats.average_depth = depth_sum as f32 / stats.leaves as f32;
+        let interior = stats.nodes - stats.leaves;
+        if interior > 0 {
+            stats.sibling_overlap = overlap_sum / interior as f32;
+        }
+        stats
+    }
+
+    /// Bounds of the primitives built from the scene object `object`.
+    pub fn object_bounds(&self, object: usize) -> Option<AABB> {
+        self.primitives
+            .iter()
+            .filter(|primitive| primitive.object == object)
+            .map(primitive_bounds)
+            .reduce(|a, b| merge_aabb(&a, &b))
+    }
 
+    pub fn build(&mut self, primitives: &[Object]) {
+        self.primitives = primitives.to_vec();
+        self.linear_bvh_node.clear();
+        self.lights.clear();
+        if self.primitives.is_empty() {
+            return;
+        }
+        let mut primitive_info = Vec::new();
+        for (i, primitive) in self.primitives.iter().enumerate() {
+            let aabb = primitive_bounds(primitive);
             primitive_info.push(BVHPrimitiveInfo::new(i as i32, aabb));
         }
         let mut ordered_prims = Vec::new();
@@ -185,105 +363,162 @@ impl BVHTree {
 
         let mut offset = 0;
         self.flatten_bvh(&root, &mut offset);
+        self.collect_lights();
+    }
+
+    fn collect_lights(&mut self) {
+        let mut total_power = 0.0;
+        for (i, primitive) in self.primitives.iter().enumerate() {
+            let area = primitive.area();
+            if !matches!(primitive.material, MATERIAL::DIFFUSE_LIGHT) || area <= 0.0 {
+                continue;
+            }
+            let [r, g, b] = primitive.albedo;
+            let power = area * (0.2126 * r + 0.7152 * g + 0.0722 * b);
+            if power <= 0.0 {
+                continue;
+            }
+            total_power += power;
+            self.lights.push(Light {
+                primitive: i,
+                area,
+                pmf: power,
+                cdf: total_power,
+            });
+        }
+        for light in &mut self.lights {
+            light.pmf /= total_power;
+            light.cdf /= total_power;
+        }
+        if let Some(last) = self.lights.last_mut() {
+            last.cdf = 1.0;
+        }
     }
 
     pub fn set_texture(&mut self, gl: &Context) {
+        self.environment.set_texture(gl);
+        if self.bvh_texture.is_none() {
+            self.create_texture(gl);
+        }
         let mut node_data = Vec::new();
         for node in &self.linear_bvh_node {
-            node_data.push(node.aabb.min[0]);
-            node_data.push(node.aabb.min[1]);
-            node_data.push(node.aabb.min[2]);
-            node_data.push(node.aabb.max[0]);
-            node_data.push(node.aabb.max[1]);
-            node_data.push(node.aabb.max[2]);
+            node_data.extend_from_slice(&node.aabb.min);
+            node_data.extend_from_slice(&node.aabb.max);
             node_data.push(node.offset as f32);
             node_data.push(node.n_primitives as f32);
             node_data.push(node.axis as f32);
-            node_data.push(node.aabb.shape.clone() as u32 as f32);
-            node_data.push(node.aabb.constant);
-            node_data.push(node.aabb.material.clone() as u32 as f32);
-            self.node_number += 1;
         }
+        self.node_number = self.linear_bvh_node.len() as i32;
         let mut vertex_data = Vec::new();
-        for primitive in &self.primitives {
+        let mut materials: Vec<PbrMaterial> = Vec::new();
+        let mut media: Vec<Medium> = Vec::new();
+        let mut texture_sets: Vec<TextureSet> = Vec::new();
+        for (i, primitive) in self.primitives.iter().enumerate() {
+            let start = vertex_data.len();
             match primitive.shape {
                 SHAPE::NONE => {}
                 SHAPE::RT_SPHERE => {
-                    for i in 0..3 {
-                        vertex_data.push(primitive.center[i]);
-                    }
-                    for i in 0..3 {
-                        vertex_data.push(primitive.albedo[i]);
-                    }
+                    vertex_data.extend_from_slice(&primitive.center);
+                    vertex_data.extend_from_slice(&primitive.albedo);
                     vertex_data.push(primitive.radius);
-                    for _i in 0..26 {
-                        vertex_data.push(0.0);
-                    }
-                    self.vertices_number += 1;
-                }
-                SHAPE::RT_MESH => {
-                    for vertex in &primitive.vertices {
-                        vertex_data.push(vertex[0]);
-                        vertex_data.push(vertex[1]);
-                        vertex_data.push(vertex[2]);
-                    }
-                    self.vertices_number += 1;
                 }
-                SHAPE::RT_TRIANGLE => {
+                SHAPE::RT_MESH
+                | SHAPE::RT_TRIANGLE
+                | SHAPE::RT_RECTANGLE
+                | SHAPE::RT_VOLUME
+                | SHAPE::RT_DISK
+                | SHAPE::RT_CYLINDER
+                | SHAPE::RT_CONE
+                | SHAPE::RT_TORUS
+                | SHAPE::RT_GRID_VOLUME => {
                     for vertex in &primitive.vertices {
-                        vertex_data.push(vertex[0]);
-                        vertex_data.push(vertex[1]);
-                        vertex_data.push(vertex[2]);
-                    }
-                    for i in 0..3 {
-                        vertex_data.push(primitive.albedo[i]);
+                        vertex_data.extend_from_slice(vertex);
                     }
-                    for _i in 0..18 {
-                        vertex_data.push(0.0);
-                    }
-                    self.vertices_number += 1;
+                    vertex_data.extend_from_slice(&primitive.albedo);
                 }
-                SHAPE::RT_RECTANGLE => {
-                    for vertex in &primitive.vertices {
-                        vertex_data.push(vertex[0]);
-                        vertex_data.push(vertex[1]);
-                        vertex_data.push(vertex[2]);
-                    }
-                    for i in 0..3 {
-                        vertex_data.push(primitive.albedo[i]);
+            }
+            vertex_data.resize(start + (PRIMITIVE_STRIDE as usize - 2) * 3, 0.0);
+            let medium = primitive.medium.map_or(-1.0, |medium| {
+                let index = match media.iter().position(|m| *m == medium) {
+                    Some(index) => index,
+                    None => {
+                        media.push(medium);
+                        media.len() - 1
                     }
-                    for _i in 0..15 {
-                        vertex_data.push(0.0);
+                };
+                index as f32
+            });
+            let texture_set = if primitive.textures.is_empty() {
+                -1.0
+            } else {
+                let index = match texture_sets.iter().position(|t| *t == primitive.textures) {
+                    Some(index) => index,
+                    None => {
+                        texture_sets.push(primitive.textures);
+                        texture_sets.len() - 1
                     }
-                    self.vertices_number += 1;
+                };
+                index as f32
+            };
+            vertex_data.extend_from_slice(&[primitive.object as f32, medium, texture_set]);
+            vertex_data.push(primitive.shape.clone() as u32 as f32);
+            vertex_data.push(primitive.material.clone() as u32 as f32);
+            match primitive.material {
+                MATERIAL::PBR => {
+                    let index = match materials.iter().position(|m| *m == primitive.pbr) {
+                        Some(index) => index,
+                        None => {
+                            materials.push(primitive.pbr);
+                            materials.len() - 1
+                        }
+                    };
+                    vertex_data.push(index as f32);
                 }
-                SHAPE::RT_VOLUME => {
-                    for vertex in &primitive.vertices {
-                        vertex_data.push(vertex[0]);
-                        vertex_data.push(vertex[1]);
-                        vertex_data.push(vertex[2]);
-                    }
-                    for i in 0..3 {
-                        vertex_data.push(primitive.albedo[i]);
-                    }
-                    for _i in 0..18 {
-                        vertex_data.push(0.0);
-                    }
-                    self.vertices_number += 1;
+                MATERIAL::DIFFUSE_LIGHT => {
+                    let light = self
+                        .lights
+                        .binary_search_by_key(&i, |light| light.primitive);
+                    vertex_data.push(light.map_or(-1.0, |index| index as f32));
                 }
+                _ => vertex_data.push(primitive.constant),
             }
         }
-        let bvh_texture_size = self.node_number * 4;
-        let vertex_texture_size = self.vertices_number * 11;
+        for material in &materials {
+            vertex_data.extend_from_slice(&material.to_texels());
+        }
+        self.media_offset = vertex_data.len() as i32 / 3;
+        for medium in &media {
+            vertex_data.extend_from_slice(&medium.to_texels());
+        }
+        self.texture_sets_offset = vertex_data.len() as i32 / 3;
+        for texture_set in &texture_sets {
+            vertex_data.extend_from_slice(&texture_set.to_texels());
+        }
+        self.lights_offset = vertex_data.len() as i32 / 3;
+        for light in &self.lights {
+            vertex_data.extend_from_slice(&[light.primitive as f32, light.cdf, light.pmf]);
+            vertex_data.extend_from_slice(&[light.area, 0.0, 0.0]);
+        }
+        self.vertices_number = self.primitives.len() as i32;
+        let bvh_texture_size = self.node_number * NODE_STRIDE;
+        let vertex_texture_size = self.vertices_number * PRIMITIVE_STRIDE
+            + materials.len() as i32 * MATERIAL_STRIDE
+            + media.len() as i32 * MEDIUM_STRIDE
+            + texture_sets.len() as i32 * TEXTURE_SET_STRIDE
+            + self.lights.len() as i32 * LIGHT_STRIDE;
+        let bvh_length = get_length(bvh_texture_size);
+        let vertex_length = get_length(vertex_texture_size);
+        node_data.resize((bvh_length * bvh_length * 3) as usize, 0.0);
+        vertex_data.resize((vertex_length * vertex_length * 3) as usize, 0.0);
         unsafe {
-            gl.bind_texture(TEXTURE_2D, Some(self.bvh_texture));
+            gl.bind_texture(TEXTURE_2D, self.bvh_texture);
             assert_eq!(gl.get_error(), NO_ERROR);
             gl.tex_image_2d(
                 TEXTURE_2D,
                 0,
                 RGB32F as i32,
-                get_length(bvh_texture_size),
-                get_length(bvh_texture_size),
+                bvh_length,
+                bvh_length,
                 0,
                 RGB,
                 FLOAT,
@@ -295,14 +530,14 @@ impl BVHTree {
             gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as i32);
             gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST as i32);
 
-            gl.bind_texture(TEXTURE_2D, Some(self.vertices_texture));
+            gl.bind_texture(TEXTURE_2D, self.vertices_texture);
             assert_eq!(gl.get_error(), NO_ERROR);
             gl.tex_image_2d(
                 TEXTURE_2D,
                 0,
                 RGB32F as i32,
-                get_length(vertex_texture_size),
-                get_length(vertex_texture_size),
+                vertex_length,
+                vertex_length,
                 0,
                 RGB,
                 FLOAT,
@@ -320,89 +555,201 @@ impl BVHTree {
         unsafe {
             shader.use_program(gl);
             gl.active_texture(TEXTURE1);
-            gl.bind_texture(TEXTURE_2D, Some(self.vertices_texture));
-            shader.set_int(&gl, "vertices_texture", 1);
+            gl.bind_texture(TEXTURE_2D, self.vertices_texture);
+            shader.set_int(gl, "vertices_texture", 1);
+            shader.set_int(gl, "verticesNum", self.vertices_number);
+            shader.set_int(gl, "lightsNum", self.lights.len() as i32);
+            shader.set_int(gl, "lightsOffset", self.lights_offset);
+            shader.set_int(gl, "mediaOffset", self.media_offset);
+            shader.set_int(gl, "textureSetsOffset", self.texture_sets_offset);
+            self.environment.use_texture(gl, shader);
             gl.active_texture(TEXTURE2);
             shader.set_int(gl, "bvh_texture", 2);
-            gl.bind_texture(TEXTURE_2D, Some(self.bvh_texture));
+            gl.bind_texture(TEXTURE_2D, self.bvh_texture);
             assert_eq!(gl.get_error(), NO_ERROR);
         }
     }
 
     pub fn delete_texture(&self, gl: &Context) {
         unsafe {
-            gl.delete_texture(self.bvh_texture);
-            gl.delete_texture(self.vertices_texture);
+            if let Some(texture) = self.bvh_texture {
+                gl.delete_texture(texture);
+            }
+            if let Some(texture) = self.vertices_texture {
+                gl.delete_texture(texture);
+            }
         }
+        self.environment.delete_texture(gl);
     }
 
     pub fn create_texture(&mut self, gl: &Context) {
-        self.bvh_texture = unsafe { gl.create_texture().unwrap() };
-        self.vertices_texture = unsafe { gl.create_texture().unwrap() };
+        self.bvh_texture = Some(unsafe { gl.create_texture().unwrap() });
+        self.vertices_texture = Some(unsafe { gl.create_texture().unwrap() });
     }
 
     fn recursive_build(
         &self,
-        primitive_info: &mut Vec<BVHPrimitiveInfo>,
+        primitive_info: &mut [BVHPrimitiveInfo],
         start: i32,
         end: i32,
         total_nodes: &mut i32,
         ordered_primitives: &mut Vec<Object>,
     ) -> BVHNode {
-        #[allow(unused_assignments)]
-        let mut node = BVHNode::new();
         let mut aabb = AABB::new();
         for i in start..end {
             aabb = merge_aabb(&aabb, &primitive_info[i as usize].aabb);
         }
+        *total_nodes += 1;
         let primitives_number = end - start;
         if primitives_number == 1 {
-            let first_offset = ordered_primitives.len() as i32;
-            #[allow(unused_assignments)]
-            let mut primitive_number = 0;
-            for i in start..end {
-                primitive_number = primitive_info[i as usize].primitive_number;
-                ordered_primitives.push(self.primitives[primitive_number as usize].clone());
+            return self.create_leaf(primitive_info, start, end, aabb, ordered_primitives);
+        }
+
+        let mut centroid_aabb = AABB::new();
+        for i in start..end {
+            centroid_aabb = merge_vec3(&centroid_aabb, &primitive_info[i as usize].centroid);
+        }
+        let dim = aabb_axis(&centroid_aabb);
+
+        let mid = match self.settings.split_method {
+            SplitMethod::Median => {
+                let mid = (start + end) / 2;
+                partition_by_median(primitive_info, start, mid, end, dim);
+                mid
             }
-            *total_nodes += 1;
-            node = BVHNode::new_leaf(
-                primitives_number,
-                first_offset,
-                primitive_info[start as usize].aabb.clone(),
-            );
-            node
-        } else {
-            let mut centroid_aabb = AABB::new();
-            for i in start..end {
-                centroid_aabb = merge_vec3(&centroid_aabb, &primitive_info[i as usize].centroid);
+            SplitMethod::SAH => {
+                match self.partition_by_sah(primitive_info, start, end, dim, &aabb, &centroid_aabb)
+                {
+                    Some(mid) => mid,
+                    None => {
+                        return self.create_leaf(
+                            primitive_info,
+                            start,
+                            end,
+                            aabb,
+                            ordered_primitives,
+                        )
+                    }
+                }
             }
-            let dim = aabb_axis(&centroid_aabb);
-
-            let mid = (start + end) / 2;
-
-            partition_by_median(primitive_info, start, mid, end, dim);
-
-            *total_nodes += 1;
-            node = BVHNode::new_interior(
-                Some(Rc::new(RefCell::from(self.recursive_build(
-                    primitive_info,
-                    start,
-                    mid,
-                    total_nodes,
-                    ordered_primitives,
-                )))),
-                Some(Rc::new(RefCell::from(self.recursive_build(
-                    primitive_info,
-                    mid,
-                    end,
-                    total_nodes,
-                    ordered_primitives,
-                )))),
-                aabb,
-                dim,
-            );
-            node
+        };
+
+        BVHNode::new_interior(
+            Some(Rc::new(RefCell::from(self.recursive_build(
+                primitive_info,
+                start,
+                mid,
+                total_nodes,
+                ordered_primitives,
+            )))),
+            Some(Rc::new(RefCell::from(self.recursive_build(
+                primitive_info,
+                mid,
+                end,
+                total_nodes,
+                ordered_primitives,
+            )))),
+            aabb,
+            dim,
+        )
+    }
+
+    fn create_leaf(
+        &self,
+        primitive_info: &[BVHPrimitiveInfo],
+        start: i32,
+        end: i32,
+        aabb: AABB,
+        ordered_primitives: &mut Vec<Object>,
+    ) -> BVHNode {
+        let first_offset = ordered_primitives.len() as i32;
+        for info in &primitive_info[start as usize..end as usize] {
+            ordered_primitives.push(self.primitives[info.primitive_number as usize].clone());
         }
+        BVHNode::new_leaf(end - start, first_offset, aabb)
+    }
+
+    /// Splits `start..end` along `dim` where the binned surface area heuristic
+    /// is cheapest and returns the first index of the right half, or `None`
+    /// when a leaf is cheaper than any split.
+    fn partition_by_sah(
+        &self,
+        primitive_info: &mut [BVHPrimitiveInfo],
+        start: i32,
+        end: i32,
+        dim: i32,
+        aabb: &AABB,
+        centroid_aabb: &AABB,
+    ) -> Option<i32> {
+        let dim = dim as usize;
+        let primitives_number = end - start;
+        let max_leaf_size = self.settings.max_leaf_size.max(1);
+        let range = &mut primitive_info[start as usize..end as usize];
+        let extent = centroid_aabb.max[dim] - centroid_aabb.min[dim];
+        if extent <= 0.0 {
+            // Every centroid coincides, so no plane can separate them.
+            if primitives_number <= max_leaf_size {
+                return None;
+            }
+            let mid = range.len() / 2;
+            range.select_nth_unstable_by(mid, |a, b| a.centroid[dim].total_cmp(&b.centroid[dim]));
+            return Some(start + mid as i32);
+        }
+
+        let bins = self.settings.bins.max(2) as usize;
+        let bin_index = |centroid: f32| -> usize {
+            let offset = (centroid - centroid_aabb.min[dim]) / extent;
+            ((offset * bins as f32) as usize).min(bins - 1)
+        };
+        let mut counts = vec![0; bins];
+        let mut bounds = vec![AABB::new(); bins];
+        for info in range.iter() {
+            let b = bin_index(info.centroid[dim]);
+            counts[b] += 1;
+            bounds[b] = merge_aabb(&bounds[b], &info.aabb);
+        }
+
+        // Sweep from the right to get the cost of everything past each split,
+        // then from the left to combine it with the cost of the near side.
+        let mut right_cost = vec![0.0; bins];
+        let mut right_bounds = AABB::new();
+        let mut right_count = 0;
+        for b in (1..bins).rev() {
+            right_bounds = merge_aabb(&right_bounds, &bounds[b]);
+            right_count += counts[b];
+            right_cost[b - 1] = area_cost(right_count, &right_bounds);
+        }
+        let mut left_bounds = AABB::new();
+        let mut left_count = 0;
+        let mut min_cost = MAX_FLOAT;
+        let mut min_bin = 0;
+        for b in 0..bins - 1 {
+            left_bounds = merge_aabb(&left_bounds, &bounds[b]);
+            left_count += counts[b];
+            let cost = area_cost(left_count, &left_bounds) + right_cost[b];
+            if cost < min_cost {
+                min_cost = cost;
+                min_bin = b;
+            }
+        }
+        let min_cost = SAH_TRAVERSAL_COST + min_cost / aabb.surface_area();
+        let leaf_cost = primitives_number as f32;
+        if primitives_number <= max_leaf_size && leaf_cost <= min_cost {
+            return None;
+        }
+
+        let mut mid = 0;
+        for i in 0..range.len() {
+            if bin_index(range[i].centroid[dim]) <= min_bin {
+                range.swap(mid, i);
+                mid += 1;
+            }
+        }
+        if mid == 0 || mid == range.len() {
+            mid = range.len() / 2;
+            range.select_nth_unstable_by(mid, |a, b| a.centroid[dim].total_cmp(&b.centroid[dim]));
+        }
+        Some(start + mid as i32)
     }
 
     fn flatten_bvh(&mut self, node: &BVHNode, offset: &mut i32) -> i32 {
@@ -427,8 +774,68 @@ impl BVHTree {
     }
 }
 
+/// Bounds of a single primitive, as used to build the tree.
+fn primitive_bounds(primitive: &Object) -> AABB {
+    let mut aabb = AABB::new();
+    match primitive.shape {
+        SHAPE::NONE => {}
+        SHAPE::RT_SPHERE => {
+            aabb = AABB::new_sphere(
+                primitive.center,
+                primitive.radius,
+                primitive.constant,
+                primitive.material.clone(),
+            );
+        }
+        SHAPE::RT_MESH => {
+            let mut vertex = Vec::new();
+            for i in 0..3 {
+                vertex.push(primitive.vertices[i * 3]);
+            }
+            aabb = AABB::new_mesh(vertex, primitive.constant, primitive.material.clone());
+        }
+        SHAPE::RT_TRIANGLE => {
+            let mut vertex = Vec::new();
+            for i in 0..3 {
+                vertex.push(primitive.vertices[i]);
+            }
+            aabb = AABB::new_triangle(vertex, primitive.constant, primitive.material.clone());
+        }
+        SHAPE::RT_RECTANGLE => {
+            let mut vertex = Vec::new();
+            for i in 0..4 {
+                vertex.push(primitive.vertices[i]);
+            }
+            aabb = AABB::new_rectangle(vertex, primitive.constant, primitive.material.clone());
+        }
+        SHAPE::RT_VOLUME => {
+            let mut vertex = Vec::new();
+            for i in 0..4 {
+                vertex.push(primitive.vertices[i]);
+            }
+            aabb = AABB::new_box_volume(vertex, primitive.constant, primitive.material.clone());
+        }
+        SHAPE::RT_DISK | SHAPE::RT_CYLINDER | SHAPE::RT_CONE | SHAPE::RT_TORUS => {
+            aabb = AABB::new_quadric(
+                primitive.shape.clone(),
+                &primitive.vertices,
+                primitive.constant,
+                primitive.material.clone(),
+            );
+        }
+        SHAPE::RT_GRID_VOLUME => {
+            aabb = AABB::new_grid_volume(
+                &primitive.vertices,
+                primitive.constant,
+                primitive.material.clone(),
+            );
+        }
+    }
+    aabb
+}
+
 fn partition_by_median(
-    primitive_info: &mut Vec<BVHPrimitiveInfo>,
+    primitive_info: &mut [BVHPrimitiveInfo],
     start: i32,
     mid: i32,
     end: i32,
@@ -456,6 +863,14 @@ fn partition_by_median(
     }
 }
 
+fn area_cost(count: i32, aabb: &AABB) -> f32 {
+    if count == 0 {
+        0.0
+    } else {
+        count as f32 * aabb.surface_area()
+    }
+}
+
 fn get_length(size: i32) -> i32 {
     let mut length = 1;
     let edge = (size as f32).sqrt().ceil() as i32;
@@ -464,3 +879,174 @@ fn get_length(size: i32) -> i32 {
     }
     length
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::hash;
+    use cgmath::vec3;
+
+    /// Spheres scattered over a few clusters, each tagged with its index in
+    /// `object` to find it again after the build reorders them.
+    fn spheres(count: usize) -> Vec<Object> {
+        let random = |i: usize, axis: u32| hash(hash(i as u32) ^ axis) as f32 / u32::MAX as f32;
+        let transform = [
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 0.0),
+            vec3(1.0, 1.0, 1.0),
+        ];
+        (0..count)
+            .map(|i| {
+                let cluster = (i % 3) as f32 * 10.0;
+                let center = [0, 1, 2].map(|axis| cluster + random(i, axis));
+                let mut sphere = Object::new_sphere(
+                    center,
+                    0.05 + 0.1 * random(i, 3),
+                    [0.5; 3],
+                    &transform,
+                    0.0,
+                    MATERIAL::DIFFUSE,
+                );
+                sphere.object = i;
+                sphere
+            })
+            .collect()
+    }
+
+    fn build(primitives: &[Object], split_method: SplitMethod, max_leaf_size: i32) -> BVHTree {
+        let mut tree = BVHTree::with_settings(BVHSettings {
+            split_method,
+            bins: 12,
+            max_leaf_size,
+        });
+        tree.build(primitives);
+        tree
+    }
+
+    fn contains(outer: &AABB, inner: &AABB) -> bool {
+        (0..3).all(|i| outer.min[i] <= inner.min[i] && inner.max[i] <= outer.max[i])
+    }
+
+    /// Walks the flattened tree from the root like the traversal does and
+    /// returns the tags of the primitives its leaves hold, checking that no
+    /// leaf holds more than `max_leaf_size` and that every node bounds its
+    /// children and primitives.
+    fn reachable(tree: &BVHTree, max_leaf_size: i32) -> Vec<usize> {
+        let nodes = tree.nodes();
+        let mut found = Vec::new();
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &nodes[index];
+            if node.n_primitives > 0 {
+                assert!(node.n_primitives <= max_leaf_size);
+                let first = node.offset as usize;
+                for primitive in &tree.primitives()[first..first + node.n_primitives as usize] {
+                    assert!(contains(&node.aabb, &primitive_bounds(primitive)));
+                    found.push(primitive.object);
+                }
+            } else {
+                let (left, right) = (index + 1, node.offset as usize);
+                assert!(right > left && right < nodes.len());
+                assert!(contains(&node.aabb, &nodes[left].aabb));
+                assert!(contains(&node.aabb, &nodes[right].aabb));
+                stack.push(left);
+                stack.push(right);
+            }
+        }
+        found.sort_unstable();
+        found
+    }
+
+    #[test]
+    fn every_primitive_is_reachable() {
+        let primitives = spheres(200);
+        let all: Vec<usize> = (0..primitives.len()).collect();
+        for (split_method, max_leaf_size) in [(SplitMethod::Median, 1), (SplitMethod::SAH, 4)] {
+            let tree = build(&primitives, split_method, max_leaf_size);
+            assert_eq!(tree.primitives().len(), primitives.len());
+            assert_eq!(reachable(&tree, max_leaf_size), all);
+        }
+    }
+
+    #[test]
+    fn sah_is_cheaper_than_median() {
+        let primitives = spheres(200);
+        let median = build(&primitives, SplitMethod::Median, 1).stats();
+        let sah = build(&primitives, SplitMethod::SAH, 4).stats();
+        assert!(sah.leaves <= median.leaves);
+        assert!(
+            sah.sah_cost < median.sah_cost,
+            "SAH {} against median {}",
+            sah.sah_cost,
+            median.sah_cost
+        );
+    }
+
+    #[test]
+    fn coincident_centroids_respect_the_leaf_size() {
+        // No plane separates them, so only the leaf size forces splits.
+        let mut primitives = spheres(1);
+        for i in 1..37 {
+            let mut sphere = primitives[0].clone();
+            sphere.object = i;
+            primitives.push(sphere);
+        }
+        let tree = build(&primitives, SplitMethod::SAH, 4);
+        assert_eq!(reachable(&tree, 4), (0..37).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn single_primitive_is_a_leaf() {
+        let tree = build(&spheres(1), SplitMethod::SAH, 4);
+        assert_eq!(tree.nodes().len(), 1);
+        assert_eq!(tree.nodes()[0].n_primitives, 1);
+    }
+
+    fn node(min: [f32; 3], max: [f32; 3], offset: i32, n_primitives: i32) -> LinearBVHNode {
+        let aabb = AABB {
+            min,
+            max,
+            ..AABB::new()
+        };
+        LinearBVHNode::new(aabb, offset, n_primitives, 0)
+    }
+
+    #[test]
+    fn stats_of_a_hand_built_tree() {
+        // A root of area 10 over a leaf of two primitives and an interior
+        // node of area 6, whose leaves of one and three primitives overlap
+        // in a box of area 3.
+        let mut tree = BVHTree::new();
+        tree.linear_bvh_node = vec![
+            node([0.0; 3], [2.0, 1.0, 1.0], 2, 0),
+            node([0.0; 3], [0.9, 1.0, 1.0], 0, 2),
+            node([1.0, 0.0, 0.0], [2.0, 1.0, 1.0], 4, 0),
+            node([1.0, 0.0, 0.0], [1.5, 1.0, 1.0], 2, 1),
+            node([1.25, 0.0, 0.0], [2.0, 1.0, 1.0], 3, 3),
+        ];
+        let stats = tree.stats();
+        assert_eq!(stats.nodes, 5);
+        assert_eq!(stats.leaves, 3);
+        assert_eq!(stats.max_depth, 2);
+        assert!((stats.average_depth - 5.0 / 3.0).abs() < 1E-6);
+        assert_eq!(stats.leaf_sizes, vec![0, 1, 1, 1]);
+        // Both interior nodes cost a visit, 1 + 0.6 of them, and each leaf
+        // its primitives in proportion to its area, 0.56 * 2 + 0.4 + 0.5 * 3.
+        let cost = 1.6 * SAH_TRAVERSAL_COST + 1.12 + 0.4 + 1.5;
+        assert!((stats.sah_cost - cost).abs() < 1E-5, "{}", stats.sah_cost);
+        // Disjoint at the root, half of the interior node.
+        assert!((stats.sibling_overlap - 0.25).abs() < 1E-6);
+        let text = stats.to_string();
+        assert!(text.starts_with("5 nodes, 3 leaves, depth 2 max, 1.7 average"));
+        assert!(text.ends_with("Primitives per leaf: 1: 1 2: 1 3: 1"));
+    }
+
+    #[test]
+    fn stats_of_an_empty_tree() {
+        let mut tree = BVHTree::new();
+        tree.build(&[]);
+        let stats = tree.stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.max_depth), (0, 0, 0));
+        assert_eq!(stats.sah_cost, 0.0);
+    }
+}
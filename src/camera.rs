@@ -1,11 +1,22 @@
 use crate::shader::Shader;
-use crate::utils::random_float;
 use crate::App;
 use cgmath::*;
 use glow::Context;
 use slint::PhysicalSize;
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    /// Mouse looks around, the keys move the camera.
+    Fly,
+    /// Mouse rotates around `target`, the wheel moves towards it.
+    Orbit,
+}
 
 pub struct Camera {
+    pub mode: CameraMode,
+    /// The point orbited around, kept in front of the camera.
+    pub target: Point3<f32>,
     pub position: Point3<f32>,
     pub front: Vector3<f32>,
     pub right: Vector3<f32>,
@@ -17,9 +28,18 @@ pub struct Camera {
     pub fov: f32,
     pub wheel_sensitivity: f32,
 
+    /// Lens radius, zero for a pinhole.
+    pub aperture: f32,
+    /// Distance along `front` of the plane in focus.
+    pub focus_distance: f32,
+    /// Number of aperture blades for polygonal bokeh, a round lens below 3.
+    pub blades: i32,
+
     pub pitch: f32,
     pub yaw: f32,
     pub mouse_sensitivity: f32,
+    /// Moving the mouse up looks down.
+    pub invert_mouse: bool,
 
     pub first_mouse: bool,
     pub last_x: f32,
@@ -28,12 +48,15 @@ pub struct Camera {
     pub width: i32,
     pub height: i32,
 
-    pub render_loop: i32,
+    /// Set whenever the view changes, see `take_changed`.
+    changed: bool,
 }
 
 impl Default for Camera {
     fn default() -> Self {
         let mut camera = Self {
+            mode: CameraMode::Fly,
+            target: Point3::new(0.0, 0.0, -1.0),
             position: Point3::new(0.0, 0.0, 0.0),
             front: Vector3::new(0.0, 0.0, -1.0),
             up: Vector3::zero(),
@@ -45,9 +68,14 @@ impl Default for Camera {
             fov: 60.0,
             wheel_sensitivity: 0.1,
 
+            aperture: 0.0,
+            focus_distance: 3.0,
+            blades: 0,
+
             pitch: 0.0,
             yaw: -90.0,
             mouse_sensitivity: 0.1,
+            invert_mouse: false,
 
             first_mouse: true,
             last_x: 0.0,
@@ -56,7 +84,7 @@ impl Default for Camera {
             width: 0,
             height: 0,
 
-            render_loop: 0,
+            changed: true,
         };
         camera.update_camera_vectors();
         camera
@@ -64,46 +92,59 @@ impl Default for Camera {
 }
 
 impl Camera {
+    pub fn new(position: Point3<f32>, yaw: f32, pitch: f32, fov: f32) -> Camera {
+        let mut camera = Camera {
+            position,
+            yaw,
+            pitch,
+            fov,
+            ..Camera::default()
+        };
+        camera.update_camera_vectors();
+        camera
+    }
+
     pub fn get_view_matrix(&self) -> Matrix4<f32> {
-        return Matrix4::look_at_rh(self.position, self.position + self.front, self.up);
+        Matrix4::look_at_rh(self.position, self.position + self.front, self.up)
     }
 
     pub fn process_keyboard(&mut self, app: &App, delta_time: f32) {
-        let camera_speed = self.movement_speed * delta_time;
+        let boost = if app.get_speed_boost() { 4.0 } else { 1.0 };
+        let camera_speed = self.movement_speed * boost * delta_time;
         let front = vec3(self.front.x, 0.0, self.front.z).normalize();
         let right = vec3(self.right.x, 0.0, self.right.z).normalize();
         let up = vec3(0.0, self.up.y, 0.0).normalize();
-        if app.get_movement_up() {
-            self.position += front * camera_speed;
-            self.update_camera_vectors();
+        let mut movement = Vector3::zero();
+        if app.get_movement_forward() {
+            movement += front * camera_speed;
         }
-        if app.get_movement_down() {
-            self.position -= front * camera_speed;
-            self.update_camera_vectors();
+        if app.get_movement_back() {
+            movement -= front * camera_speed;
         }
         if app.get_movement_left() {
-            self.position -= right * camera_speed;
-            self.update_camera_vectors();
+            movement -= right * camera_speed;
         }
         if app.get_movement_right() {
-            self.position += right * camera_speed;
-            self.update_camera_vectors();
+            movement += right * camera_speed;
         }
-        if app.get_movement_float() {
-            self.position += up * camera_speed;
-            self.update_camera_vectors();
+        if app.get_movement_up() {
+            movement += up * camera_speed;
         }
-        if app.get_movement_drown() {
-            self.position -= up * camera_speed;
-            self.update_camera_vectors();
+        if app.get_movement_down() {
+            movement -= up * camera_speed;
+        }
+        if movement != Vector3::zero() {
+            self.translate(movement);
         }
     }
 
     pub fn process_mouse_movement(&mut self, app: &App) {
-        if app.get_mouse_pressed() == true {
-            let current_x = app.get_mouse_position_x() as f32;
-            let current_y = app.get_mouse_position_y() as f32;
-            if self.first_mouse == true {
+        let rotating = app.get_mouse_pressed() && !app.get_focus_pick();
+        let panning = app.get_mouse_panning();
+        if rotating || panning {
+            let current_x = app.get_mouse_position_x();
+            let current_y = app.get_mouse_position_y();
+            if self.first_mouse {
                 self.last_x = current_x;
                 self.last_y = current_y;
                 self.first_mouse = false;
@@ -114,27 +155,41 @@ impl Camera {
             self.last_x = current_x;
             self.last_y = current_y;
 
+            if panning {
+                self.pan(x_offset, y_offset);
+                return;
+            }
+
             x_offset *= self.mouse_sensitivity;
             y_offset *= self.mouse_sensitivity;
+            if self.invert_mouse {
+                y_offset = -y_offset;
+            }
 
             self.yaw += x_offset;
             self.pitch -= y_offset;
 
-            if self.pitch >= 90.0 {
-                self.pitch = 90.0;
-            }
-            if self.pitch <= -90.0 {
-                self.pitch = -90.0;
-            }
+            self.pitch = self.pitch.clamp(-90.0, 90.0);
 
+            let distance = (self.target - self.position).magnitude();
             self.update_camera_vectors();
+            if self.mode == CameraMode::Orbit {
+                self.position = self.target - self.front * distance;
+            }
         } else {
             self.first_mouse = true;
         }
     }
 
     pub fn process_mouse_wheel(&mut self, app: &App) {
-        let offset = self.wheel_sensitivity * app.get_mouse_wheel_offset() as f32;
+        let offset = self.wheel_sensitivity * app.get_mouse_wheel_offset();
+        app.set_mouse_wheel_offset(0.0);
+        if self.mode == CameraMode::Orbit {
+            if offset != 0.0 {
+                self.dolly(offset);
+            }
+            return;
+        }
         let mut scolled = false;
         self.fov += offset;
 
@@ -142,17 +197,61 @@ impl Camera {
             scolled = true;
         }
 
-        if self.fov < 1.0 {
-            self.fov = 1.0;
-        }
-        if self.fov > 90.0 {
-            self.fov = 90.0;
-        }
+        self.fov = self.fov.clamp(1.0, 90.0);
         if scolled {
             self.update_camera_vectors();
         }
     }
 
+    /// Switches between flying and orbiting, orbiting around `target`.
+    pub fn set_mode(&mut self, mode: CameraMode, target: Point3<f32>) {
+        self.mode = mode;
+        self.target = target;
+    }
+
+    /// Moves the camera back along its view direction until the box from
+    /// `min` to `max` fits in view, and orbits around its center.
+    pub fn frame(&mut self, min: [f32; 3], max: [f32; 3]) {
+        let (min, max) = (Point3::from(min), Point3::from(max));
+        let center = min.midpoint(max);
+        let radius = (max - min).magnitude() / 2.0;
+        let half_h = (self.fov / 2.0).to_radians();
+        let half_w = (half_h.tan() * self.width.max(1) as f32 / self.height.max(1) as f32).atan();
+        let distance = radius / half_h.min(half_w).sin();
+        self.target = center;
+        self.position = center - self.front * distance;
+        self.update_camera_vectors();
+    }
+
+    fn translate(&mut self, movement: Vector3<f32>) {
+        self.position += movement;
+        if self.mode == CameraMode::Orbit {
+            self.target += movement;
+        }
+        self.update_camera_vectors();
+    }
+
+    /// Moves the camera in its image plane by a mouse offset in pixels, so
+    /// that points at the distance of `target` follow the mouse.
+    fn pan(&mut self, x_offset: f32, y_offset: f32) {
+        let distance = (self.target - self.position).magnitude();
+        let pixel =
+            2.0 * distance * (self.fov / 2.0).to_radians().tan() / self.height.max(1) as f32;
+        let movement = (self.up * y_offset - self.right * x_offset) * pixel;
+        self.position += movement;
+        self.target += movement;
+        self.update_camera_vectors();
+    }
+
+    /// Scales the distance to `target`, a wheel step that would change the
+    /// field of view by x degrees changes it by about x percent.
+    fn dolly(&mut self, offset: f32) {
+        let distance = (self.target - self.position).magnitude();
+        let distance = (distance * (offset / 100.0).exp()).max(0.01);
+        self.position = self.target - self.front * distance;
+        self.update_camera_vectors();
+    }
+
     pub fn update_ratio(&mut self, width: i32, height: i32) {
         if self.width != width || self.height != height {
             self.width = width;
@@ -161,11 +260,15 @@ impl Camera {
         }
     }
 
-    pub fn update_loop(&mut self) {
-        self.render_loop = self.render_loop + 1;
+    /// Whether the view changed since the last call, which makes the image
+    /// accumulated so far stale.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
     }
 
-    pub fn use_camera(&self, gl: &Context, shader: &Shader, size: &PhysicalSize) {
+    /// Sets the camera uniforms for drawing sample number `sample` of the
+    /// image, counting from 1.
+    pub fn use_camera(&self, gl: &Context, shader: &Shader, size: &PhysicalSize, sample: i32) {
         shader.set_int(gl, "screenWidth", size.width as i32);
         shader.set_int(gl, "screenHeight", size.height as i32);
         shader.set_vector3(gl, "camera.camPos", &self.position.to_vec());
@@ -174,15 +277,57 @@ impl Camera {
         shader.set_vector3(gl, "camera.up", &self.up);
         shader.set_vector3(gl, "camera.leftbottom", &self.left_bottom);
 
-        shader.set_float(&gl, "camera.halfH", (self.fov / 2.0).to_radians().tan());
+        shader.set_float(gl, "camera.halfH", (self.fov / 2.0).to_radians().tan());
         shader.set_float(
             gl,
             "camera.halfW",
             (size.width as f32 / size.height as f32) * (self.fov / 2.0).to_radians().tan(),
         );
-        shader.set_int(gl, "camera.LoopNum", self.render_loop);
+        shader.set_float(gl, "camera.aperture", self.aperture);
+        shader.set_float(gl, "camera.focusDistance", self.focus_distance);
+        shader.set_int(gl, "camera.blades", self.blades);
         shader.set_int(gl, "historyTexture", 0);
-        shader.set_float(gl, "randOrigin", 674764.0 * (1.0 + random_float()));
+        shader.set_int(gl, "camera.LoopNum", sample);
+        shader.set_int(gl, "frameIndex", sample - 1);
+    }
+
+    /// Direction of the pinhole ray through (`u`, `v`) in [0, 1], origin at
+    /// the bottom left of the image.
+    pub fn direction(&self, u: f32, v: f32) -> Vector3<f32> {
+        let half_h = (self.fov / 2.0).to_radians().tan();
+        let half_w = (self.width as f32 / self.height as f32) * half_h;
+        (self.left_bottom + (2.0 * half_w * u) * self.right + (2.0 * half_h * v) * self.up)
+            .normalize()
+    }
+
+    /// Origin and direction of a ray through (`u`, `v`) leaving the lens at a
+    /// point picked by three uniform numbers, like `main` in the shader.
+    pub fn ray(&self, u: f32, v: f32, lens: [f32; 3]) -> (Vector3<f32>, Vector3<f32>) {
+        let direction = self.direction(u, v);
+        let origin = self.position.to_vec();
+        if self.aperture <= 0.0 {
+            return (origin, direction);
+        }
+        let focus = origin + direction * (self.focus_distance / direction.dot(self.front));
+        let (x, y) = self.sample_aperture(lens);
+        let origin = origin + self.right * (x * self.aperture) + self.up * (y * self.aperture);
+        (origin, (focus - origin).normalize())
+    }
+
+    /// Uniform point on the unit disk, or on the unit polygon with `blades`
+    /// corners.
+    fn sample_aperture(&self, u: [f32; 3]) -> (f32, f32) {
+        if self.blades < 3 {
+            let (r, phi) = (u[0].sqrt(), 2.0 * PI * u[1]);
+            return (r * phi.cos(), r * phi.sin());
+        }
+        let blades = self.blades as f32;
+        let blade = (u[2] * blades).floor().min(blades - 1.0);
+        let (a0, a1) = (2.0 * PI * blade / blades, 2.0 * PI * (blade + 1.0) / blades);
+        let s = u[0].sqrt();
+        let x = s * ((1.0 - u[1]) * a0.cos() + u[1] * a1.cos());
+        let y = s * ((1.0 - u[1]) * a0.sin() + u[1] * a1.sin());
+        (x, y)
     }
 
     fn update_camera_vectors(&mut self) {
@@ -200,6 +345,6 @@ impl Camera {
                 * (self.width as f32 / self.height as f32)
                 * (self.fov / 2.0).to_radians().tan()
             - self.up * (self.fov / 2.0).to_radians().tan();
-        self.render_loop = 0;
+        self.changed = true;
     }
 }
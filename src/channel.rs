@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+
+use crate::aabb::AABB;
+use crate::display::{Display, Tonemapper};
+use crate::sampler::hash;
+
+/// What the renderers put into the image: the path traced radiance, or a
+/// property of what the camera ray hits first for looking into a scene.
+/// Mirrored by `channelColor` in `shaders/path_tracing.frag`.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    #[default]
+    Beauty,
+    /// Distance from the camera divided by the distance to the farthest
+    /// corner of the scene bounds.
+    Depth,
+    /// World space normal facing the ray, mapped from [-1, 1] to [0, 1].
+    Normal,
+    Albedo,
+    /// Of the triangle hit, black for shapes that are not made of triangles.
+    Barycentrics,
+    /// Surface parameterization in red and green, from the mesh texture
+    /// coordinates or the shape itself.
+    Uv,
+    /// BVH nodes the traversal visited, from blue for none to red for
+    /// `HEATMAP_NODES` or more.
+    Nodes,
+    /// Primitives the traversal tested for a hit, in the colors of `Nodes` up
+    /// to `HEATMAP_PRIMITIVES`.
+    Primitives,
+    /// A random color per scene object.
+    Object,
+    /// A random color per material kind.
+    Material,
+}
+
+/// Node visits shown in the hottest color of `Channel::Nodes`.
+pub const HEATMAP_NODES: f32 = 128.0;
+/// Primitive tests shown in the hottest color of `Channel::Primitives`.
+pub const HEATMAP_PRIMITIVES: f32 = 32.0;
+
+impl Channel {
+    pub const ALL: [Channel; 10] = [
+        Channel::Beauty,
+        Channel::Depth,
+        Channel::Normal,
+        Channel::Albedo,
+        Channel::Barycentrics,
+        Channel::Uv,
+        Channel::Nodes,
+        Channel::Primitives,
+        Channel::Object,
+        Channel::Material,
+    ];
+
+    pub fn from_index(index: i32) -> Channel {
+        Channel::ALL[index.clamp(0, 9) as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Channel> {
+        match name {
+            "beauty" => Some(Channel::Beauty),
+            "depth" => Some(Channel::Depth),
+            "normal" => Some(Channel::Normal),
+            "albedo" => Some(Channel::Albedo),
+            "barycentrics" => Some(Channel::Barycentrics),
+            "uv" => Some(Channel::Uv),
+            "nodes" => Some(Channel::Nodes),
+            "primitives" => Some(Channel::Primitives),
+            "object" => Some(Channel::Object),
+            "material" => Some(Channel::Material),
+            _ => None,
+        }
+    }
+
+    /// How the channel is presented: the radiance goes through `display`,
+    /// the other channels are colors already and are shown as they are.
+    pub fn display(self, display: Display) -> Display {
+        match self {
+            Channel::Beauty => display,
+            _ => Display {
+                tonemapper: Tonemapper::Clamp,
+                exposure: 0.0,
+                srgb: false,
+            },
+        }
+    }
+}
+
+/// Scales distances from `eye` for `Channel::Depth`, one over the distance
+/// to the farthest corner of `bounds`.
+pub fn depth_scale(bounds: Option<&AABB>, eye: [f32; 3]) -> f32 {
+    let farthest = bounds.map_or(0.0, |bounds| {
+        (0..3)
+            .map(|i| {
+                let near = (bounds.min[i] - eye[i]).abs();
+                let far = (bounds.max[i] - eye[i]).abs();
+                near.max(far).powi(2)
+            })
+            .sum::<f32>()
+            .sqrt()
+    });
+    if farthest > 0.0 {
+        1.0 / farthest
+    } else {
+        1.0
+    }
+}
+
+/// Blue to cyan, green, yellow and red as `t` goes from 0 to 1.
+pub fn heatmap(t: f32) -> [f32; 3] {
+    let t = t.clamp(0.0, 1.0);
+    [3.0, 2.0, 1.0].map(|offset: f32| (1.5 - (4.0 * t - offset).abs()).clamp(0.0, 1.0))
+}
+
+/// A random but stable color for an object or material index.
+pub fn id_color(id: u32) -> [f32; 3] {
+    let x = hash(id.wrapping_add(1));
+    [x, x >> 8, x >> 16].map(|c| (c & 255) as f32 / 255.0)
+}
@@ -0,0 +1,1647 @@
+use cgmath::{vec3, ElementWise, InnerSpace, Vector3};
+use rand::Rng;
+use rayon::prelude::*;
+use std::f32::consts::PI;
+
+use crate::bvh::{BVHStats, BVHTree, LinearBVHNode};
+use crate::camera::Camera;
+use crate::channel::{depth_scale, heatmap, id_color, Channel, HEATMAP_NODES, HEATMAP_PRIMITIVES};
+use crate::denoiser::{denoise, Denoise};
+use crate::environment::Environment;
+use crate::film::{Adaptive, Aov, FilmPixel, Filter};
+use crate::grid::{DensityGrid, GridAtlas};
+use crate::material::{Medium, PbrMaterial};
+use crate::object::Object;
+use crate::sampler::{PixelSampler, Sampler};
+use crate::scene::{CameraDesc, Scene};
+use crate::texture::{TextureArray, TextureSet};
+use crate::utils::{MATERIAL, MAX_FLOAT, SHAPE};
+
+type Vec3 = Vector3<f32>;
+
+const EPSILON: f32 = 0.00001;
+
+#[derive(Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+        Ray { origin, direction }
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + t * self.direction
+    }
+}
+
+pub struct HitRecord {
+    pub p: Vec3,
+    pub normal: Vec3,
+    pub front_face: bool,
+    pub t: f32,
+    pub albedo: Vec3,
+    pub constant: f32,
+    pub material: MATERIAL,
+    pub pbr: PbrMaterial,
+    /// Index into `BVHTree::primitives`.
+    pub primitive: usize,
+    /// Of the triangle hit, zero for other shapes.
+    pub barycentrics: Vec3,
+    pub uv: [f32; 2],
+    /// Filling the object hit.
+    pub medium: Option<Medium>,
+    /// Of the phase function of isotropic and medium scattering.
+    pub anisotropy: f32,
+    /// Of grid volumes and emission textures, zero for everything else.
+    pub emission: Vec3,
+}
+
+/// A CPU port of `shaders/path_tracing.frag`, traversing the same flattened
+/// BVH. It is slow, but needs no OpenGL context and serves as the ground truth
+/// for the GPU renderer.
+pub struct CpuRenderer {
+    bvh_tree: BVHTree,
+    textures: TextureArray,
+    grids: GridAtlas,
+    camera: Camera,
+    film: Vec<FilmPixel>,
+    width: i32,
+    height: i32,
+    depths: i32,
+    face_cull: bool,
+    sampler: Sampler,
+    filter: Filter,
+    adaptive: Adaptive,
+    denoise: Denoise,
+    channel: Channel,
+    /// Samples per pixel in `film`, fewer for pixels `adaptive` stopped.
+    samples: i32,
+}
+
+impl CpuRenderer {
+    pub fn new(scene: &Scene) -> CpuRenderer {
+        let settings = &scene.settings;
+        let mut camera = scene.camera();
+        camera.update_ratio(settings.width, settings.height);
+        let mut bvh_tree = BVHTree::with_settings(scene.bvh.clone());
+        let (primitives, textures, grids) = scene.build();
+        bvh_tree.build(&primitives);
+        bvh_tree.set_environment(Environment::new(&scene.environment));
+        CpuRenderer {
+            bvh_tree,
+            textures,
+            grids,
+            camera,
+            film: vec![FilmPixel::default(); (settings.width * settings.height) as usize],
+            width: settings.width,
+            height: settings.height,
+            depths: settings.depths,
+            face_cull: settings.face_cull,
+            sampler: settings.sampler,
+            filter: settings.filter,
+            adaptive: settings.adaptive,
+            denoise: settings.denoise,
+            channel: settings.channel,
+            samples: 0,
+        }
+    }
+
+    /// Accumulates `samples` more samples per pixel, spread over all cores.
+    pub fn render_samples(&mut self, samples: i32) {
+        let width = self.width as usize;
+        for _i in 0..samples {
+            let index = self.samples as u32;
+            self.samples += 1;
+            let (sampler, filter, adaptive) = (self.sampler, self.filter, self.adaptive);
+            let channel = self.channel;
+            let depth_scale = depth_scale(self.bvh_tree.bounds(), self.camera.position.into());
+            let tracer = Tracer {
+                bvh_tree: &self.bvh_tree,
+                textures: &self.textures,
+                grids: &self.grids,
+                depths: self.depths,
+                face_cull: self.face_cull,
+            };
+            let camera = &self.camera;
+            let (width_f, height_f) = (self.width as f32, self.height as f32);
+            self.film
+                .par_chunks_mut(width)
+                .enumerate()
+                .for_each(|(y, row)| {
+                    for (x, pixel) in row.iter_mut().enumerate() {
+                        if adaptive.is_converged(pixel) {
+                            continue;
+                        }
+                        let mut rng = PixelSampler::new(sampler, x as u32, y as u32, index);
+                        let radius = filter.radius();
+                        let dx = radius * (2.0 * rng.gen::<f32>() - 1.0);
+                        let dy = radius * (2.0 * rng.gen::<f32>() - 1.0);
+                        let u = (x as f32 + 0.5 + dx) / width_f;
+                        let v = (y as f32 + 0.5 + dy) / height_f;
+                        let (origin, direction) = camera.ray(u, v, rng.gen());
+                        let ray = Ray::new(origin, direction);
+                        let mut aov = Aov::default();
+                        let color = match channel {
+                            Channel::Beauty => tracer.shading(ray, &mut rng, &mut aov),
+                            _ => tracer.channel_color(ray, channel, depth_scale, &mut rng),
+                        };
+                        pixel.add(color.into(), filter.weight(dx, dy), &aov);
+                    }
+                });
+        }
+    }
+
+    /// Moves the camera and restarts the accumulation.
+    pub fn set_camera(&mut self, camera: &CameraDesc) {
+        self.camera = camera.to_camera();
+        self.camera.update_ratio(self.width, self.height);
+        self.film.fill(FilmPixel::default());
+        self.samples = 0;
+    }
+
+    /// Returns the accumulated image as RGB floats, bottom row first like
+    /// `Renderer::read_pixels`, denoised when the denoiser is enabled and the
+    /// image is the radiance.
+    pub fn read_pixels(&self) -> Vec<f32> {
+        if self.denoise.enabled && self.channel == Channel::Beauty {
+            let (width, height) = (self.width as usize, self.height as usize);
+            return denoise(&self.denoise, width, height, &self.film);
+        }
+        self.film.iter().flat_map(|pixel| pixel.resolve()).collect()
+    }
+
+    pub fn bvh_stats(&self) -> BVHStats {
+        self.bvh_tree.stats()
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// Finds the closest hit along `ray`, or `None` when it leaves the scene.
+    pub fn intersect(&self, ray: &Ray) -> Option<HitRecord> {
+        intersect(
+            &self.bvh_tree,
+            &self.textures,
+            &self.grids,
+            self.face_cull,
+            ray,
+        )
+    }
+}
+
+/// Finds the closest hit along `ray` in a built `bvh_tree`, which lets the
+/// GPU renderer pick objects without a `CpuRenderer`.
+pub fn intersect(
+    bvh_tree: &BVHTree,
+    textures: &TextureArray,
+    grids: &GridAtlas,
+    face_cull: bool,
+    ray: &Ray,
+) -> Option<HitRecord> {
+    let tracer = Tracer {
+        bvh_tree,
+        textures,
+        grids,
+        depths: 0,
+        face_cull,
+    };
+    let mut rng = PixelSampler::new(Sampler::Random, 0, 0, 0);
+    tracer.intersect_bvh(ray, MAX_FLOAT, &mut rng)
+}
+
+/// How `Tracer::traverse` treats grid volumes, see `gridTracking` in the
+/// shader.
+enum Tracking {
+    /// Delta tracks them like any other primitive.
+    Delta,
+    /// Skips them.
+    Skip,
+    /// Ratio tracks them into the transmittance, skipping everything else.
+    Ratio(f32),
+}
+
+/// BVH nodes visited and primitives tested by `Tracer::traverse`.
+#[derive(Default)]
+struct TraversalCost {
+    nodes: u32,
+    primitives: u32,
+}
+
+struct Tracer<'a> {
+    bvh_tree: &'a BVHTree,
+    textures: &'a TextureArray,
+    grids: &'a GridAtlas,
+    depths: i32,
+    face_cull: bool,
+}
+
+impl Tracer<'_> {
+    /// Traces a camera ray, filling `aov` with what it hits first.
+    fn shading(&self, mut r: Ray, rng: &mut PixelSampler, aov: &mut Aov) -> Vec3 {
+        let mut color = vec3(0.0, 0.0, 0.0);
+        let mut throughput = vec3(1.0, 1.0, 1.0);
+        // Zero for camera rays and specular bounces, see `shading` in the shader.
+        let mut material_pdf = 0.0;
+        let mut media = MediaStack::default();
+        let mut crossings = 0;
+        // From the last bounce to the interface r starts at.
+        let mut crossed = 0.0;
+        let mut i = 0;
+        while i < self.depths {
+            let mut hit = self.intersect_bvh(&r, MAX_FLOAT, rng);
+            if let Some(medium) = media.current() {
+                let t_max = hit.as_ref().map_or(MAX_FLOAT, |rec| rec.t);
+                let t = sample_medium(&medium, t_max, &mut throughput, rng);
+                if t < t_max {
+                    hit = Some(medium_scattering(&r, t, &medium));
+                }
+            }
+            let mut rec = match hit {
+                Some(rec) => rec,
+                None => {
+                    let environment = self.bvh_tree.environment();
+                    if i == 0 {
+                        let radiance: [f32; 3] = environment.radiance(r.direction).into();
+                        aov.albedo = radiance.map(|c| c.clamp(0.0, 1.0));
+                    }
+                    let weight = if material_pdf > 0.0 && environment.has_map() {
+                        power_heuristic(
+                            material_pdf,
+                            self.environment_probability() * environment.pdf(r.direction),
+                        )
+                    } else {
+                        1.0
+                    };
+                    color +=
+                        throughput.mul_element_wise(environment.radiance(r.direction)) * weight;
+                    break;
+                }
+            };
+            // Passing an interface is no bounce, the path goes on as it was.
+            if matches!(rec.material, MATERIAL::INTERFACE) && crossings < MAX_CROSSINGS {
+                crossings += 1;
+                crossed += rec.t;
+                media.cross(&rec, r.direction);
+                r.origin = rec.p;
+                continue;
+            }
+            if i == 0 {
+                let albedo: [f32; 3] = rec.albedo.into();
+                aov.albedo = albedo.map(|c| c.clamp(0.0, 1.0));
+                // A medium has no surface, it faces the camera for the denoiser.
+                if let MATERIAL::ISOTROPIC = rec.material {
+                    aov.normal = (-r.direction.normalize()).into();
+                    aov.medium = 1.0;
+                } else {
+                    aov.normal = rec.normal.into();
+                }
+                aov.depth = crossed + (rec.p - r.origin).magnitude();
+            }
+            color += throughput.mul_element_wise(rec.emission);
+            if let MATERIAL::DIFFUSE_LIGHT = rec.material {
+                rec.t += crossed;
+                let weight = if material_pdf > 0.0 {
+                    power_heuristic(material_pdf, self.light_pdf(&rec, &r))
+                } else {
+                    1.0
+                };
+                color += throughput.mul_element_wise(rec.albedo) * weight;
+                break;
+            }
+
+            let direction = r.direction;
+            let specular = matches!(rec.material, MATERIAL::METAL | MATERIAL::DIELECTRIC);
+            if !specular {
+                color +=
+                    throughput.mul_element_wise(self.sample_light(&rec, direction, &media, rng));
+            }
+            r.direction = match rec.material {
+                MATERIAL::DIFFUSE => diffuse(&rec, rng),
+                MATERIAL::METAL => reflect(direction, rec.normal),
+                MATERIAL::DIELECTRIC => dielectric(&rec, direction, rng),
+                MATERIAL::ISOTROPIC => sample_phase(direction, rec.anisotropy, rng),
+                MATERIAL::PBR => sample_pbr(&PbrLobes::new(&rec, direction), &rec, direction, rng),
+                MATERIAL::DIFFUSE_LIGHT | MATERIAL::INTERFACE | MATERIAL::NONE => break,
+            };
+
+            if specular {
+                throughput.mul_assign_element_wise(rec.albedo);
+                material_pdf = 0.0;
+            } else {
+                let (f, pdf) = eval_material(&rec, direction, r.direction);
+                if pdf == 0.0 {
+                    break;
+                }
+                throughput.mul_assign_element_wise(f / pdf);
+                material_pdf = pdf;
+            }
+            media.cross(&rec, r.direction);
+            crossed = 0.0;
+            r.origin = rec.p;
+            i += 1;
+        }
+        color
+    }
+
+    /// What a camera ray hits first instead of its radiance, see `Channel`.
+    fn channel_color(
+        &self,
+        r: Ray,
+        channel: Channel,
+        depth_scale: f32,
+        rng: &mut PixelSampler,
+    ) -> Vec3 {
+        let (rec, cost) = self.traverse(&r, MAX_FLOAT, &mut Tracking::Delta, rng);
+        let color = match (channel, rec) {
+            (Channel::Nodes, _) => heatmap(cost.nodes as f32 / HEATMAP_NODES),
+            (Channel::Primitives, _) => heatmap(cost.primitives as f32 / HEATMAP_PRIMITIVES),
+            (_, None) => [0.0; 3],
+            (Channel::Depth, Some(rec)) => [(rec.p - r.origin).magnitude() * depth_scale; 3],
+            (Channel::Normal, Some(rec)) => (rec.normal * 0.5 + vec3(0.5, 0.5, 0.5)).into(),
+            (Channel::Albedo, Some(rec)) => {
+                let albedo: [f32; 3] = rec.albedo.into();
+                albedo.map(|c| c.clamp(0.0, 1.0))
+            }
+            (Channel::Barycentrics, Some(rec)) => rec.barycentrics.into(),
+            (Channel::Uv, Some(rec)) => [rec.uv[0], rec.uv[1], 0.0],
+            (Channel::Object, Some(rec)) => {
+                id_color(self.bvh_tree.primitives()[rec.primitive].object as u32)
+            }
+            (Channel::Beauty | Channel::Material, Some(rec)) => id_color(rec.material as u32),
+        };
+        to_vec3(color)
+    }
+
+    /// Solid angle pdf of `sample_light` picking the light hit by `r`.
+    fn light_pdf(&self, rec: &HitRecord, r: &Ray) -> f32 {
+        let lights = self.bvh_tree.lights();
+        match lights.binary_search_by_key(&rec.primitive, |light| light.primitive) {
+            Ok(index) => {
+                let light = &lights[index];
+                let cos_light = rec.normal.dot(r.direction).abs();
+                (1.0 - self.environment_probability()) * light.pmf * rec.t * rec.t
+                    / (cos_light * light.area).max(1E-8)
+            }
+            Err(_) => 0.0,
+        }
+    }
+
+    /// Chance of `sample_light` picking the environment instead of a light.
+    fn environment_probability(&self) -> f32 {
+        match (
+            self.bvh_tree.environment().has_map(),
+            self.bvh_tree.lights().is_empty(),
+        ) {
+            (false, _) => 0.0,
+            (true, false) => 0.5,
+            (true, true) => 1.0,
+        }
+    }
+
+    /// Next event estimation, mirroring `sampleLight` in the shader.
+    fn sample_light(
+        &self,
+        rec: &HitRecord,
+        direction: Vec3,
+        media: &MediaStack,
+        rng: &mut PixelSampler,
+    ) -> Vec3 {
+        let zero = vec3(0.0, 0.0, 0.0);
+        let lights = self.bvh_tree.lights();
+        let environment_chance = self.environment_probability();
+        if lights.is_empty() && environment_chance == 0.0 {
+            return zero;
+        }
+
+        let (l, pdf, emission, distance) = if rng.gen::<f32>() < environment_chance {
+            let environment = self.bvh_tree.environment();
+            let (l, pdf) = environment.sample(rng.gen());
+            (
+                l,
+                pdf * environment_chance,
+                environment.radiance(l),
+                MAX_FLOAT,
+            )
+        } else {
+            let u = rng.gen::<f32>();
+            let index = lights
+                .partition_point(|light| light.cdf < u)
+                .min(lights.len() - 1);
+            let light = &lights[index];
+            let primitive = &self.bvh_tree.primitives()[light.primitive];
+            let (point, normal, emission) = sample_primitive(primitive, self.textures, rng);
+
+            let to_light = point - rec.p;
+            let distance2 = to_light.magnitude2();
+            let distance = distance2.sqrt();
+            let l = to_light / distance;
+            let cos_light = normal.dot(l).abs();
+            if cos_light < 1E-6 {
+                return zero;
+            }
+            let pdf = (1.0 - environment_chance) * light.pmf * distance2 / (cos_light * light.area);
+            (l, pdf, emission, distance * (1.0 - 1E-3))
+        };
+        if pdf <= 0.0 {
+            return zero;
+        }
+
+        let (f, material_pdf) = eval_material(rec, direction, l);
+        if material_pdf == 0.0 {
+            return zero;
+        }
+        let transmittance = self.transmittance(rec.p, l, distance, *media, rng);
+        f.mul_element_wise(emission).mul_element_wise(transmittance)
+            * power_heuristic(pdf, material_pdf)
+            / pdf
+    }
+
+    /// Of the media between `origin` and `distance` along `l`, zero when
+    /// something other than an interface is in the way.
+    fn transmittance(
+        &self,
+        mut origin: Vec3,
+        l: Vec3,
+        mut distance: f32,
+        mut media: MediaStack,
+        rng: &mut PixelSampler,
+    ) -> Vec3 {
+        let mut throughput = vec3(1.0, 1.0, 1.0);
+        for _ in 0..MAX_CROSSINGS {
+            let shadow = Ray::new(origin, l);
+            let hit = self.traverse(&shadow, distance, &mut Tracking::Skip, rng).0;
+            let t = hit.as_ref().map_or(distance, |rec| rec.t);
+            if !self.grids.is_empty() {
+                let mut tracking = Tracking::Ratio(1.0);
+                self.traverse(&shadow, t, &mut tracking, rng);
+                if let Tracking::Ratio(transmittance) = tracking {
+                    throughput *= transmittance;
+                }
+            }
+            if let Some(medium) = media.current() {
+                let extinction = to_vec3(medium.extinction());
+                throughput.mul_assign_element_wise((-extinction * t).map(f32::exp));
+            }
+            let rec = match hit {
+                None => return throughput,
+                Some(rec) => rec,
+            };
+            if !matches!(rec.material, MATERIAL::INTERFACE) {
+                break;
+            }
+            media.cross(&rec, l);
+            origin = rec.p;
+            distance -= t;
+        }
+        vec3(0.0, 0.0, 0.0)
+    }
+
+    fn intersect_bvh(&self, r: &Ray, t_max: f32, rng: &mut PixelSampler) -> Option<HitRecord> {
+        self.traverse(r, t_max, &mut Tracking::Delta, rng).0
+    }
+
+    /// The closest hit and what it cost to find it.
+    fn traverse(
+        &self,
+        r: &Ray,
+        t_max: f32,
+        tracking: &mut Tracking,
+        rng: &mut PixelSampler,
+    ) -> (Option<HitRecord>, TraversalCost) {
+        let nodes = self.bvh_tree.nodes();
+        let primitives = self.bvh_tree.primitives();
+        let mut cost = TraversalCost::default();
+        if nodes.is_empty() {
+            return (None, cost);
+        }
+        let inv_dir = vec3(
+            1.0 / r.direction.x,
+            1.0 / r.direction.y,
+            1.0 / r.direction.z,
+        );
+        let dir_is_neg = [inv_dir.x < 0.0, inv_dir.y < 0.0, inv_dir.z < 0.0];
+        let mut hit_min = t_max;
+        let mut hit = None;
+        let mut nodes_to_visit = Vec::with_capacity(64);
+        let mut current = 0;
+        loop {
+            cost.nodes += 1;
+            let node = &nodes[current];
+            if intersect_aabb(r, node, inv_dir, dir_is_neg) {
+                if node.n_primitives > 0 {
+                    for i in 0..node.n_primitives {
+                        cost.primitives += 1;
+                        let index = (node.offset + i) as usize;
+                        let primitive = &primitives[index];
+                        let grid = primitive.shape == SHAPE::RT_GRID_VOLUME;
+                        match tracking {
+                            Tracking::Skip if grid => continue,
+                            Tracking::Ratio(_) if !grid => continue,
+                            _ => {}
+                        }
+                        if let Some(t) = self.hit_primitive(primitive, r, hit_min, tracking, rng) {
+                            hit_min = t;
+                            hit = Some(index);
+                        }
+                    }
+                    match nodes_to_visit.pop() {
+                        Some(next) => current = next,
+                        None => break,
+                    }
+                } else if dir_is_neg[node.axis as usize] {
+                    nodes_to_visit.push(current + 1);
+                    current = node.offset as usize;
+                } else {
+                    nodes_to_visit.push(node.offset as usize);
+                    current += 1;
+                }
+            } else {
+                match nodes_to_visit.pop() {
+                    Some(next) => current = next,
+                    None => break,
+                }
+            }
+        }
+        let rec = hit.map(|index| {
+            hit_record(
+                &primitives[index],
+                index,
+                self.textures,
+                self.grids,
+                r,
+                hit_min,
+            )
+        });
+        (rec, cost)
+    }
+
+    fn hit_primitive(
+        &self,
+        primitive: &Object,
+        r: &Ray,
+        hit_min: f32,
+        tracking: &mut Tracking,
+        rng: &mut PixelSampler,
+    ) -> Option<f32> {
+        let t = match primitive.shape {
+            SHAPE::RT_GRID_VOLUME => {
+                let grid = GridVolume::new(primitive, self.grids);
+                if let Tracking::Ratio(transmittance) = tracking {
+                    *transmittance *= grid.ratio_track(r, hit_min, rng);
+                    return None;
+                }
+                grid.delta_track(r, hit_min, rng)
+            }
+            SHAPE::NONE => -1.0,
+            SHAPE::RT_SPHERE => self.hit_sphere(primitive, r),
+            SHAPE::RT_MESH => {
+                let v = &primitive.vertices;
+                let (v0, v1, v2) = (to_vec3(v[0]), to_vec3(v[3]), to_vec3(v[6]));
+                self.hit_triangle(v0, v1, v2, (v1 - v0).cross(v2 - v0), r)
+            }
+            SHAPE::RT_TRIANGLE => {
+                let v = &primitive.vertices;
+                self.hit_triangle(
+                    to_vec3(v[0]),
+                    to_vec3(v[1]),
+                    to_vec3(v[2]),
+                    to_vec3(v[3]),
+                    r,
+                )
+            }
+            SHAPE::RT_RECTANGLE => {
+                let v: Vec<Vec3> = primitive.vertices.iter().map(|v| to_vec3(*v)).collect();
+                self.hit_rect(&[v[0], v[1], v[2], v[3]], v[4], r)
+            }
+            SHAPE::RT_DISK | SHAPE::RT_CYLINDER | SHAPE::RT_CONE | SHAPE::RT_TORUS => {
+                Quadric::new(primitive).hit(r, self.face_cull)
+            }
+            SHAPE::RT_VOLUME => {
+                let mut near = self.hit_box_volume(primitive, r, true);
+                let mut far = self.hit_box_volume(primitive, r, false);
+                if near < 0.0 {
+                    near = EPSILON;
+                }
+                if far > hit_min - EPSILON {
+                    far = hit_min - EPSILON;
+                }
+                let hit_dist = (-1.0 / primitive.constant) * rng.gen::<f32>().ln();
+                if hit_dist < far - near - EPSILON {
+                    return Some(near + hit_dist);
+                }
+                -1.0
+            }
+        };
+        if t > 0.0 && t < hit_min - EPSILON {
+            Some(t)
+        } else {
+            None
+        }
+    }
+
+    fn hit_sphere(&self, sphere: &Object, r: &Ray) -> f32 {
+        let oc = r.origin - to_vec3(sphere.center);
+        let a = r.direction.dot(r.direction);
+        let h = -oc.dot(r.direction);
+        let c = oc.dot(oc) - sphere.radius * sphere.radius;
+        let discriminant = h * h - a * c;
+        if discriminant < 0.0 {
+            return -1.0;
+        }
+        let dist = (h - discriminant.sqrt()) / a;
+        if dist > EPSILON {
+            return dist;
+        }
+        if self.face_cull {
+            return -1.0;
+        }
+        let dist = (h + discriminant.sqrt()) / a;
+        if dist > EPSILON {
+            dist
+        } else {
+            -1.0
+        }
+    }
+
+    fn hit_triangle(&self, v0: Vec3, v1: Vec3, v2: Vec3, n: Vec3, r: &Ray) -> f32 {
+        let n = n.normalize();
+        if self.face_cull {
+            if n.dot(r.direction) >= 0.0 {
+                return -1.0;
+            }
+        } else if n.dot(r.direction).abs() < EPSILON {
+            return -1.0;
+        }
+        let e1 = v1 - v0;
+        let e2 = v2 - v0;
+        let s = r.origin - v0;
+        let s1 = r.direction.cross(e2);
+        let s2 = s.cross(e1);
+        let denominator = s1.dot(e1);
+        let t = s2.dot(e2) / denominator;
+        let u = s1.dot(s) / denominator;
+        let v = s2.dot(r.direction) / denominator;
+        if u >= 0.0 && v >= 0.0 && u + v <= 1.0 && t > EPSILON {
+            t
+        } else {
+            -1.0
+        }
+    }
+
+    fn hit_rect(&self, v: &[Vec3; 4], n: Vec3, r: &Ray) -> f32 {
+        let t1 = self.hit_triangle(v[0], v[1], v[2], n, r);
+        if t1 > 0.0 {
+            return t1;
+        }
+        let t2 = self.hit_triangle(v[0], v[2], v[3], n, r);
+        if t2 > 0.0 {
+            t2
+        } else {
+            -1.0
+        }
+    }
+
+    fn hit_box_volume(&self, volume: &Object, r: &Ray, near: bool) -> f32 {
+        let mut t = if near { MAX_FLOAT } else { -MAX_FLOAT };
+        for (rect, n) in box_faces(volume) {
+            let t1 = self.hit_rect(&rect, n, r);
+            if near && t1 < t && t1 != -1.0 || !near && t1 > t {
+                t = t1;
+            }
+        }
+        if near && t == MAX_FLOAT {
+            -1.0
+        } else {
+            t
+        }
+    }
+}
+
+fn box_faces(volume: &Object) -> [([Vec3; 4], Vec3); 6] {
+    let x = to_vec3(volume.vertices[0]);
+    let y = to_vec3(volume.vertices[1]);
+    let z = to_vec3(volume.vertices[2]);
+    let o = to_vec3(volume.vertices[3]);
+    let (l, w, h) = (x - o, y - o, z - o);
+    [
+        ([o, o + l, o + l + w, o + w], l.cross(w)),
+        ([o, o + l, o + l + h, o + h], h.cross(l)),
+        ([o, o + w, o + w + h, o + h], w.cross(h)),
+        ([o + l, o + l + w, o + l + w + h, o + l + h], h.cross(w)),
+        ([o + w, o + w + h, o + w + h + l, o + w + l], l.cross(h)),
+        ([o + h, o + h + l, o + h + l + w, o + h + w], w.cross(l)),
+    ]
+}
+
+fn hit_record(
+    primitive: &Object,
+    index: usize,
+    textures: &TextureArray,
+    grids: &GridAtlas,
+    r: &Ray,
+    t: f32,
+) -> HitRecord {
+    let p = r.at(t);
+    let mut rec = HitRecord {
+        p,
+        normal: vec3(1.0, 1.0, 1.0),
+        front_face: true,
+        t,
+        albedo: to_vec3(primitive.albedo),
+        constant: primitive.constant,
+        material: primitive.material.clone(),
+        pbr: primitive.pbr,
+        primitive: index,
+        barycentrics: vec3(0.0, 0.0, 0.0),
+        uv: [0.0, 0.0],
+        medium: primitive.medium,
+        anisotropy: 0.0,
+        emission: vec3(0.0, 0.0, 0.0),
+    };
+    // Surface derivatives along the texture coordinates, for normal textures.
+    let mut tangents = None;
+    let mut normal_layer = -1;
+    let normal = match primitive.shape {
+        SHAPE::RT_SPHERE => {
+            let n = (p - to_vec3(primitive.center)).normalize();
+            rec.uv = sphere_uv(n);
+            tangents = Some((vec3(-n.z, 0.0, n.x), vec3(0.0, 1.0, 0.0) - n * n.y));
+            Some(n)
+        }
+        SHAPE::RT_MESH => {
+            let v = &primitive.vertices;
+            let c = centroid_coordinates(to_vec3(v[0]), to_vec3(v[3]), to_vec3(v[6]), p);
+            rec.barycentrics = c;
+            let uv = c.x * to_vec3(v[2]) + c.y * to_vec3(v[5]) + c.z * to_vec3(v[8]);
+            rec.uv = [uv.x, uv.y];
+            let layer = v[9][0] as i32;
+            if layer >= 0 {
+                rec.albedo = to_vec3(textures.sample(layer, rec.uv));
+            }
+            normal_layer = v[9][2] as i32;
+            let n = (c.x * to_vec3(v[1]) + c.y * to_vec3(v[4]) + c.z * to_vec3(v[7])).normalize();
+            let t = c.x * to_vec3(v[10]) + c.y * to_vec3(v[11]) + c.z * to_vec3(v[12]);
+            tangents = Some((t, v[2][2] * n.cross(t)));
+            Some(n)
+        }
+        SHAPE::RT_TRIANGLE => {
+            let v: Vec<Vec3> = primitive.vertices.iter().map(|v| to_vec3(*v)).collect();
+            rec.barycentrics = centroid_coordinates(v[0], v[1], v[2], p);
+            rec.uv = [rec.barycentrics.y, rec.barycentrics.z];
+            tangents = Some((v[1] - v[0], v[2] - v[0]));
+            Some(v[3].normalize())
+        }
+        SHAPE::RT_RECTANGLE => {
+            let v: Vec<Vec3> = primitive.vertices.iter().map(|v| to_vec3(*v)).collect();
+            // Of the half split off by the diagonal from v[0] to v[2] that p is
+            // in, u runs from v[0] to v[1] and v from v[0] to v[3].
+            let c = centroid_coordinates(v[0], v[1], v[2], p);
+            rec.barycentrics = c;
+            rec.uv = [c.y + c.z, c.z];
+            if c.y < 0.0 {
+                let c = centroid_coordinates(v[0], v[2], v[3], p);
+                rec.barycentrics = c;
+                rec.uv = [c.y, c.y + c.z];
+            }
+            tangents = Some((v[1] - v[0], v[3] - v[0]));
+            Some(v[4].normalize())
+        }
+        SHAPE::RT_DISK | SHAPE::RT_CYLINDER | SHAPE::RT_CONE | SHAPE::RT_TORUS => {
+            let quadric = Quadric::new(primitive);
+            let local = quadric.point(p);
+            rec.uv = quadric.uv(local);
+            Some(quadric.world_normal(quadric.normal(local)))
+        }
+        SHAPE::RT_GRID_VOLUME => {
+            let grid = GridVolume::new(primitive, grids);
+            rec.anisotropy = grid.anisotropy;
+            // What the collision absorbs glows.
+            rec.emission = (vec3(1.0, 1.0, 1.0) - rec.albedo).mul_element_wise(grid.emission);
+            None
+        }
+        SHAPE::RT_VOLUME | SHAPE::NONE => None,
+    };
+    if let Some(normal) = normal {
+        rec.front_face = r.direction.dot(normal) < 0.0;
+        rec.normal = if rec.front_face { normal } else { -normal };
+    }
+    apply_textures(
+        &mut rec,
+        &primitive.textures,
+        textures,
+        r.direction,
+        tangents,
+        normal_layer,
+    );
+    rec
+}
+
+/// Replaces what `set` has textures for like `applyTextures` in the shader.
+/// Normal textures, or else the model normal map in `normal_layer`, need the
+/// `tangents` of the surface.
+fn apply_textures(
+    rec: &mut HitRecord,
+    set: &TextureSet,
+    textures: &TextureArray,
+    direction: Vec3,
+    tangents: Option<(Vec3, Vec3)>,
+    normal_layer: i32,
+) {
+    let p = rec.p.into();
+    if let Some(albedo) = set.albedo {
+        rec.albedo = to_vec3(albedo.eval(textures, rec.uv, p));
+    }
+    if let Some(roughness) = set.roughness {
+        rec.pbr.roughness = roughness.eval(textures, rec.uv, p)[0];
+    }
+    if let Some(emission) = set.emission {
+        let emission = to_vec3(emission.eval(textures, rec.uv, p));
+        if matches!(rec.material, MATERIAL::DIFFUSE_LIGHT) {
+            rec.albedo = emission;
+        } else {
+            rec.emission += emission;
+        }
+    }
+    let Some((dpdu, dpdv)) = tangents else {
+        return;
+    };
+    let m = match set.normal {
+        Some(normal) => normal.eval(textures, rec.uv, p),
+        None if normal_layer >= 0 => textures.sample(normal_layer, rec.uv),
+        None => return,
+    };
+    let n = rec.normal;
+    let t = dpdu - n * n.dot(dpdu);
+    if t.magnitude2() < 1E-12 {
+        return;
+    }
+    let t = t.normalize();
+    let mut b = n.cross(t);
+    if b.dot(dpdv) < 0.0 {
+        b = -b;
+    }
+    let m = to_vec3(m) * 2.0 - vec3(1.0, 1.0, 1.0);
+    let bent = (m.x * t + m.y * b + m.z * n).normalize();
+    // A normal turned away from the ray would let light through.
+    if bent.dot(direction) < 0.0 {
+        rec.normal = bent;
+    }
+}
+
+fn sphere_uv(n: Vec3) -> [f32; 2] {
+    [
+        0.5 + n.z.atan2(n.x) / (2.0 * PI),
+        0.5 + n.y.clamp(-1.0, 1.0).asin() / PI,
+    ]
+}
+
+fn intersect_aabb(r: &Ray, node: &LinearBVHNode, inv_dir: Vec3, dir_is_neg: [bool; 3]) -> bool {
+    let bounds = |negative: bool| {
+        if negative {
+            node.aabb.max
+        } else {
+            node.aabb.min
+        }
+    };
+    let origin: [f32; 3] = r.origin.into();
+    let inv_dir: [f32; 3] = inv_dir.into();
+    let mut t_min = (bounds(dir_is_neg[0])[0] - origin[0]) * inv_dir[0];
+    let mut t_max = (bounds(!dir_is_neg[0])[0] - origin[0]) * inv_dir[0];
+    for axis in 1..3 {
+        let axis_min = (bounds(dir_is_neg[axis])[axis] - origin[axis]) * inv_dir[axis];
+        let axis_max = (bounds(!dir_is_neg[axis])[axis] - origin[axis]) * inv_dir[axis];
+        if t_min > axis_max || axis_min > t_max {
+            return false;
+        }
+        if axis_min > t_min {
+            t_min = axis_min;
+        }
+        if axis_max < t_max {
+            t_max = axis_max;
+        }
+    }
+    t_max > 0.0
+}
+
+fn centroid_coordinates(v0: Vec3, v1: Vec3, v2: Vec3, p: Vec3) -> Vec3 {
+    let v0v1 = v1 - v0;
+    let v0v2 = v2 - v0;
+    let v0p = p - v0;
+    let d00 = v0v1.dot(v0v1);
+    let d01 = v0v1.dot(v0v2);
+    let d11 = v0v2.dot(v0v2);
+    let d20 = v0p.dot(v0v1);
+    let d21 = v0p.dot(v0v2);
+    let denom = d00 * d11 - d01 * d01;
+    let v = (d11 * d20 - d01 * d21) / denom;
+    let w = (d00 * d21 - d01 * d20) / denom;
+    vec3(1.0 - v - w, v, w)
+}
+
+fn to_vec3(v: [f32; 3]) -> Vec3 {
+    Vec3::from(v)
+}
+
+fn random_in_unit_sphere(rng: &mut PixelSampler) -> Vec3 {
+    loop {
+        let p = 2.0 * vec3(rng.gen::<f32>(), rng.gen(), rng.gen()) - vec3(1.0, 1.0, 1.0);
+        if p.dot(p) < 1.0 {
+            return p;
+        }
+    }
+}
+
+fn random_unit_vector(rng: &mut PixelSampler) -> Vec3 {
+    random_in_unit_sphere(rng).normalize()
+}
+
+fn reflect(direction: Vec3, normal: Vec3) -> Vec3 {
+    direction - 2.0 * normal.dot(direction) * normal
+}
+
+fn refract(direction: Vec3, normal: Vec3, eta: f32) -> Vec3 {
+    let cos = normal.dot(direction);
+    let k = 1.0 - eta * eta * (1.0 - cos * cos);
+    if k < 0.0 {
+        vec3(0.0, 0.0, 0.0)
+    } else {
+        eta * direction - (eta * cos + k.sqrt()) * normal
+    }
+}
+
+fn reflectance(cosine: f32, ref_idx: f32) -> f32 {
+    let r0 = (1.0 - ref_idx) / (1.0 + ref_idx);
+    let r0 = r0 * r0;
+    r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
+}
+
+fn diffuse(rec: &HitRecord, rng: &mut PixelSampler) -> Vec3 {
+    let out_dir = rec.normal + random_unit_vector(rng);
+    if out_dir.x.abs() < 1E-8 || out_dir.y.abs() < 1E-8 || out_dir.z.abs() < 1E-8 {
+        return rec.normal;
+    }
+    out_dir.normalize()
+}
+
+fn dielectric(rec: &HitRecord, direction: Vec3, rng: &mut PixelSampler) -> Vec3 {
+    let refraction_ratio = if rec.front_face {
+        1.0 / rec.constant
+    } else {
+        rec.constant
+    };
+    let cos_theta = (-direction).dot(rec.normal).min(1.0);
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+    let cannot_refract = refraction_ratio * sin_theta > 1.0;
+    if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.gen() {
+        reflect(direction, rec.normal)
+    } else {
+        refract(direction, rec.normal, refraction_ratio)
+    }
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(vec3(0.2126, 0.7152, 0.0722))
+}
+
+fn ggx_d(n_dot_h: f32, alpha2: f32) -> f32 {
+    let d = n_dot_h * n_dot_h * (alpha2 - 1.0) + 1.0;
+    alpha2 / (PI * d * d)
+}
+
+fn smith_g1(n_dot_x: f32, alpha2: f32) -> f32 {
+    2.0 * n_dot_x / (n_dot_x + (alpha2 + (1.0 - alpha2) * n_dot_x * n_dot_x).sqrt())
+}
+
+fn fresnel_schlick(f0: Vec3, cosine: f32) -> Vec3 {
+    f0 + (vec3(1.0, 1.0, 1.0) - f0) * (1.0 - cosine).powi(5)
+}
+
+struct PbrLobes {
+    f0: Vec3,
+    diffuse_color: Vec3,
+    alpha2: f32,
+    specular_probability: f32,
+}
+
+impl PbrLobes {
+    fn new(rec: &HitRecord, direction: Vec3) -> PbrLobes {
+        let material = &rec.pbr;
+        let alpha = (material.roughness * material.roughness).max(0.001);
+        let r0 = (material.ior - 1.0) / (material.ior + 1.0);
+        let dielectric_f0 = r0 * r0 * 2.0 * material.specular;
+        let f0 = vec3(dielectric_f0, dielectric_f0, dielectric_f0) * (1.0 - material.metallic)
+            + rec.albedo * material.metallic;
+        let diffuse_color = rec.albedo * (1.0 - material.metallic);
+
+        let n_dot_v = rec.normal.dot(-direction).max(1E-4);
+        let specular_weight = luminance(fresnel_schlick(f0, n_dot_v));
+        let diffuse_weight = luminance(diffuse_color);
+        PbrLobes {
+            f0,
+            diffuse_color,
+            alpha2: alpha * alpha,
+            specular_probability: (specular_weight / (specular_weight + diffuse_weight + 1E-6))
+                .clamp(0.05, 1.0),
+        }
+    }
+}
+
+/// Samples the GGX or the diffuse lobe like `pbr` in the shader.
+fn sample_pbr(lobes: &PbrLobes, rec: &HitRecord, direction: Vec3, rng: &mut PixelSampler) -> Vec3 {
+    if rng.gen::<f32>() >= lobes.specular_probability {
+        return diffuse(rec, rng);
+    }
+    let n = rec.normal;
+    let phi = 2.0 * PI * rng.gen::<f32>();
+    let u = rng.gen::<f32>();
+    let cos_theta = ((1.0 - u) / (1.0 + (lobes.alpha2 - 1.0) * u)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let tangent = if n.x.abs() > 0.9 {
+        n.cross(vec3(0.0, 1.0, 0.0))
+    } else {
+        n.cross(vec3(1.0, 0.0, 0.0))
+    }
+    .normalize();
+    let bitangent = n.cross(tangent);
+    let h = (sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * n)
+        .normalize();
+    reflect(direction, h)
+}
+
+/// f * cos of both lobes and the pdf of `sample_pbr` choosing `l`.
+fn eval_pbr(lobes: &PbrLobes, rec: &HitRecord, direction: Vec3, l: Vec3) -> (Vec3, f32) {
+    let n = rec.normal;
+    let v = -direction;
+    let n_dot_l = n.dot(l);
+    if n_dot_l <= 0.0 {
+        return (vec3(0.0, 0.0, 0.0), 0.0);
+    }
+    let n_dot_v = n.dot(v).max(1E-4);
+    let h = (v + l).normalize();
+    let n_dot_h = n.dot(h).max(0.0);
+    let v_dot_h = v.dot(h).max(1E-4);
+    let d = ggx_d(n_dot_h, lobes.alpha2);
+    let f = fresnel_schlick(lobes.f0, v_dot_h);
+    let specular = f
+        * (d * smith_g1(n_dot_v, lobes.alpha2) * smith_g1(n_dot_l, lobes.alpha2)
+            / (4.0 * n_dot_v * n_dot_l));
+    let brdf = specular + (vec3(1.0, 1.0, 1.0) - f).mul_element_wise(lobes.diffuse_color) / PI;
+    let pdf = lobes.specular_probability * d * n_dot_h / (4.0 * v_dot_h)
+        + (1.0 - lobes.specular_probability) * n_dot_l / PI;
+    (brdf * n_dot_l, pdf)
+}
+
+/// f * cos and pdf of the non-specular materials, zero for the others.
+fn eval_material(rec: &HitRecord, direction: Vec3, l: Vec3) -> (Vec3, f32) {
+    match rec.material {
+        MATERIAL::DIFFUSE => {
+            let cosine = rec.normal.dot(l);
+            if cosine <= 0.0 {
+                return (vec3(0.0, 0.0, 0.0), 0.0);
+            }
+            (rec.albedo * cosine / PI, cosine / PI)
+        }
+        MATERIAL::ISOTROPIC => {
+            let pdf = henyey_greenstein(direction.dot(l), rec.anisotropy);
+            (rec.albedo * pdf, pdf)
+        }
+        MATERIAL::PBR => eval_pbr(&PbrLobes::new(rec, direction), rec, direction, l),
+        _ => (vec3(0.0, 0.0, 0.0), 0.0),
+    }
+}
+
+/// Henyey-Greenstein phase function of the cosine between the direction a ray
+/// travelled and the one it scatters to, which is also the pdf of
+/// `sample_phase`.
+fn henyey_greenstein(cosine: f32, g: f32) -> f32 {
+    let denominator = 1.0 + g * g - 2.0 * g * cosine;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+/// Samples the phase function around the direction the ray travelled.
+fn sample_phase(direction: Vec3, g: f32, rng: &mut PixelSampler) -> Vec3 {
+    let u = rng.gen::<f32>();
+    let cos_theta = if g.abs() < 1E-3 {
+        1.0 - 2.0 * u
+    } else {
+        let s = (1.0 - g * g) / (1.0 - g + 2.0 * g * u);
+        (1.0 + g * g - s * s) / (2.0 * g)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f32>();
+    let tangent = if direction.x.abs() > 0.9 {
+        direction.cross(vec3(0.0, 1.0, 0.0))
+    } else {
+        direction.cross(vec3(1.0, 0.0, 0.0))
+    }
+    .normalize();
+    let bitangent = direction.cross(tangent);
+    (sin_theta * phi.cos() * tangent + sin_theta * phi.sin() * bitangent + cos_theta * direction)
+        .normalize()
+}
+
+/// Interfaces a path or a shadow ray may pass through before giving up.
+const MAX_CROSSINGS: usize = 16;
+const MAX_MEDIA: usize = 4;
+
+/// Media a path is in, innermost last, see `crossSurface` in the shader.
+#[derive(Clone, Copy, Default)]
+struct MediaStack {
+    media: [Medium; MAX_MEDIA],
+    depth: usize,
+}
+
+impl MediaStack {
+    fn current(&self) -> Option<Medium> {
+        self.depth.checked_sub(1).map(|top| self.media[top])
+    }
+
+    /// Enters or leaves the medium of the surface hit when `direction` goes
+    /// through it.
+    fn cross(&mut self, rec: &HitRecord, direction: Vec3) {
+        let medium = match rec.medium {
+            Some(medium) if direction.dot(rec.normal) < 0.0 => medium,
+            _ => return,
+        };
+        if !rec.front_face {
+            self.depth = self.depth.saturating_sub(1);
+        } else if self.depth < MAX_MEDIA {
+            self.media[self.depth] = medium;
+            self.depth += 1;
+        }
+    }
+}
+
+/// Samples where a ray scatters in `medium` before `t_max` like
+/// `sampleMedium` in the shader, weighting `throughput`.
+fn sample_medium(
+    medium: &Medium,
+    t_max: f32,
+    throughput: &mut Vec3,
+    rng: &mut PixelSampler,
+) -> f32 {
+    let extinction = to_vec3(medium.extinction());
+    let sigma = extinction[((rng.gen::<f32>() * 3.0) as usize).min(2)];
+    let t = if sigma > 0.0 {
+        -(1.0 - rng.gen::<f32>()).ln() / sigma
+    } else {
+        t_max
+    };
+    let scattered = t < t_max;
+    let t = t.min(t_max);
+    let transmittance = (-extinction * t).map(f32::exp);
+    let density = if scattered {
+        extinction.mul_element_wise(transmittance)
+    } else {
+        transmittance
+    };
+    let pdf = (density.x + density.y + density.z) / 3.0;
+    if pdf <= 0.0 {
+        *throughput = vec3(0.0, 0.0, 0.0);
+        return t;
+    }
+    let scattering = if scattered {
+        to_vec3(medium.scattering)
+    } else {
+        vec3(1.0, 1.0, 1.0)
+    };
+    throughput.mul_assign_element_wise(scattering.mul_element_wise(transmittance) / pdf);
+    t
+}
+
+/// A scattering event in `medium` at `t` along `r`.
+fn medium_scattering(r: &Ray, t: f32, medium: &Medium) -> HitRecord {
+    HitRecord {
+        p: r.at(t),
+        normal: -r.direction,
+        front_face: true,
+        t,
+        albedo: vec3(1.0, 1.0, 1.0),
+        constant: 0.0,
+        material: MATERIAL::ISOTROPIC,
+        pbr: PbrMaterial::default(),
+        primitive: usize::MAX,
+        barycentrics: vec3(0.0, 0.0, 0.0),
+        uv: [0.0, 0.0],
+        medium: None,
+        anisotropy: medium.anisotropy,
+        emission: vec3(0.0, 0.0, 0.0),
+    }
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    pdf * pdf / (pdf * pdf + other_pdf * other_pdf)
+}
+
+fn sample_triangle(v0: Vec3, v1: Vec3, v2: Vec3, rng: &mut PixelSampler) -> (Vec3, Vec3) {
+    let su = rng.gen::<f32>().sqrt();
+    let v = rng.gen::<f32>() * su;
+    let barycentric = vec3(1.0 - su, v, su - v);
+    (
+        barycentric.x * v0 + barycentric.y * v1 + barycentric.z * v2,
+        barycentric,
+    )
+}
+
+/// A uniformly distributed point on an emissive primitive with its normal and
+/// emission.
+fn sample_primitive(
+    primitive: &Object,
+    textures: &TextureArray,
+    rng: &mut PixelSampler,
+) -> (Vec3, Vec3, Vec3) {
+    let v: Vec<Vec3> = primitive.vertices.iter().map(|v| to_vec3(*v)).collect();
+    let emission = to_vec3(primitive.albedo);
+    let (point, normal, emission, uv) = match primitive.shape {
+        SHAPE::RT_SPHERE => {
+            let normal = random_unit_vector(rng);
+            let point = to_vec3(primitive.center) + primitive.radius * normal;
+            (point, normal, emission, sphere_uv(normal))
+        }
+        SHAPE::RT_MESH => {
+            let (point, c) = sample_triangle(v[0], v[3], v[6], rng);
+            let normal = (v[3] - v[0]).cross(v[6] - v[0]).normalize();
+            let uv = c.x * v[2] + c.y * v[5] + c.z * v[8];
+            let uv = [uv.x, uv.y];
+            let layer = v[9].x as i32;
+            if layer >= 0 {
+                (point, normal, to_vec3(textures.sample(layer, uv)), uv)
+            } else {
+                (point, normal, emission, uv)
+            }
+        }
+        SHAPE::RT_TRIANGLE => {
+            let (point, c) = sample_triangle(v[0], v[1], v[2], rng);
+            (point, v[3].normalize(), emission, [c.y, c.z])
+        }
+        SHAPE::RT_RECTANGLE => {
+            let area1 = (v[1] - v[0]).cross(v[2] - v[0]).magnitude();
+            let area2 = (v[2] - v[0]).cross(v[3] - v[0]).magnitude();
+            let (point, uv) = if rng.gen::<f32>() * (area1 + area2) < area1 {
+                let (point, c) = sample_triangle(v[0], v[1], v[2], rng);
+                (point, [c.y + c.z, c.z])
+            } else {
+                let (point, c) = sample_triangle(v[0], v[2], v[3], rng);
+                (point, [c.y, c.y + c.z])
+            };
+            (point, v[4].normalize(), emission, uv)
+        }
+        // Not lights, see `Object::area`.
+        SHAPE::RT_VOLUME
+        | SHAPE::RT_GRID_VOLUME
+        | SHAPE::RT_DISK
+        | SHAPE::RT_CYLINDER
+        | SHAPE::RT_CONE
+        | SHAPE::RT_TORUS
+        | SHAPE::NONE => return (vec3(0.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), emission),
+    };
+    match primitive.textures.emission {
+        Some(texture) => (
+            point,
+            normal,
+            to_vec3(texture.eval(textures, uv, point.into())),
+        ),
+        None => (point, normal, emission),
+    }
+}
+
+/// A density grid as `Object::new_grid_volume` stores it, see `GridVolume` in
+/// the shader.
+struct GridVolume<'a> {
+    grid: &'a DensityGrid,
+    /// Columns of the world to grid transform.
+    to_grid: [Vec3; 4],
+    scale: f32,
+    majorant: f32,
+    emission: Vec3,
+    anisotropy: f32,
+}
+
+impl GridVolume<'_> {
+    fn new<'a>(primitive: &Object, grids: &'a GridAtlas) -> GridVolume<'a> {
+        let v = &primitive.vertices;
+        let grid = grids.grid(v[5][0] as usize);
+        GridVolume {
+            grid,
+            to_grid: [to_vec3(v[0]), to_vec3(v[1]), to_vec3(v[2]), to_vec3(v[3])],
+            scale: primitive.constant,
+            majorant: grid.max * primitive.constant,
+            emission: to_vec3(v[6]),
+            anisotropy: v[7][0],
+        }
+    }
+
+    fn density(&self, p: Vec3) -> f32 {
+        self.scale * self.grid.density(p.into())
+    }
+
+    /// Where `r` is in the box of the grid before `t_max`, with `r` in grid
+    /// space.
+    fn interval(&self, r: &Ray, t_max: f32) -> Option<(Vec3, Vec3, f32, f32)> {
+        let [x, y, z, w] = self.to_grid;
+        let d = x * r.direction.x + y * r.direction.y + z * r.direction.z;
+        let o = x * r.origin.x + y * r.origin.y + z * r.origin.z + w;
+        let (mut near, mut far) = (0.0f32, t_max);
+        for axis in 0..3 {
+            let t0 = -o[axis] / d[axis];
+            let t1 = (1.0 - o[axis]) / d[axis];
+            near = near.max(t0.min(t1));
+            far = far.min(t0.max(t1));
+        }
+        (near < far).then_some((o, d, near, far))
+    }
+
+    /// See `deltaTrack` in the shader.
+    fn delta_track(&self, r: &Ray, t_max: f32, rng: &mut PixelSampler) -> f32 {
+        let (o, d, near, far) = match self.interval(r, t_max) {
+            Some(interval) if self.majorant > 0.0 => interval,
+            _ => return -1.0,
+        };
+        let mut t = near;
+        for _ in 0..MAX_TRACKING_STEPS {
+            t -= (1.0 - rng.gen::<f32>()).ln() / self.majorant;
+            if t >= far {
+                break;
+            }
+            if rng.gen::<f32>() * self.majorant < self.density(o + t * d) {
+                return t;
+            }
+        }
+        -1.0
+    }
+
+    /// See `ratioTrack` in the shader.
+    fn ratio_track(&self, r: &Ray, t_max: f32, rng: &mut PixelSampler) -> f32 {
+        let (o, d, near, far) = match self.interval(r, t_max) {
+            Some(interval) if self.majorant > 0.0 => interval,
+            _ => return 1.0,
+        };
+        let mut transmittance = 1.0;
+        let mut t = near;
+        for _ in 0..MAX_TRACKING_STEPS {
+            t -= (1.0 - rng.gen::<f32>()).ln() / self.majorant;
+            if t >= far {
+                break;
+            }
+            transmittance *= 1.0 - self.density(o + t * d) / self.majorant;
+        }
+        transmittance
+    }
+}
+
+const MAX_TRACKING_STEPS: usize = 256;
+
+/// A disk, cylinder, cone or torus as `Object::new_quadric` stores it, see
+/// `hitQuadric` in the shader.
+struct Quadric {
+    shape: SHAPE,
+    /// Columns of the world to object transform.
+    to_object: [Vec3; 4],
+    radius: f32,
+    height: f32,
+    minor_radius: f32,
+}
+
+impl Quadric {
+    fn new(primitive: &Object) -> Quadric {
+        let v = &primitive.vertices;
+        Quadric {
+            shape: primitive.shape.clone(),
+            to_object: [to_vec3(v[0]), to_vec3(v[1]), to_vec3(v[2]), to_vec3(v[3])],
+            radius: v[4][0],
+            height: v[4][1],
+            minor_radius: v[4][2],
+        }
+    }
+
+    fn vector(&self, v: Vec3) -> Vec3 {
+        self.to_object[0] * v.x + self.to_object[1] * v.y + self.to_object[2] * v.z
+    }
+
+    fn point(&self, p: Vec3) -> Vec3 {
+        self.vector(p) + self.to_object[3]
+    }
+
+    /// Outward normal at `p` in object space, not normalized.
+    fn normal(&self, p: Vec3) -> Vec3 {
+        match self.shape {
+            SHAPE::RT_DISK => vec3(0.0, 1.0, 0.0),
+            SHAPE::RT_CYLINDER => vec3(p.x, 0.0, p.z),
+            SHAPE::RT_CONE => {
+                let k = self.radius / self.height;
+                vec3(p.x, k * k * (self.height - p.y), p.z)
+            }
+            _ => p - self.radius * vec3(p.x, 0.0, p.z).normalize(),
+        }
+    }
+
+    /// An object space normal in the world, by the transpose of `to_object`.
+    fn world_normal(&self, n: Vec3) -> Vec3 {
+        let [x, y, z, _] = self.to_object;
+        vec3(x.dot(n), y.dot(n), z.dot(n)).normalize()
+    }
+
+    /// See `quadricUV` in the shader.
+    fn uv(&self, p: Vec3) -> [f32; 2] {
+        let u = 0.5 + p.z.atan2(p.x) / (2.0 * PI);
+        let ring = (p.x * p.x + p.z * p.z).sqrt();
+        let v = match self.shape {
+            SHAPE::RT_DISK => ring / self.radius,
+            SHAPE::RT_CYLINDER | SHAPE::RT_CONE => p.y / self.height,
+            _ => 0.5 + p.y.atan2(ring - self.radius) / (2.0 * PI),
+        };
+        [u, v]
+    }
+
+    fn hit(&self, r: &Ray, face_cull: bool) -> f32 {
+        let o = self.point(r.origin);
+        let d = self.vector(r.direction);
+        let (radius, height) = (self.radius, self.height);
+        match self.shape {
+            SHAPE::RT_TORUS => return self.hit_torus(o, d, face_cull),
+            SHAPE::RT_DISK => {
+                if d.y.abs() < EPSILON || (face_cull && d.y >= 0.0) {
+                    return -1.0;
+                }
+                let t = -o.y / d.y;
+                let p = o + t * d;
+                return if t > EPSILON && p.x * p.x + p.z * p.z <= radius * radius {
+                    t
+                } else {
+                    -1.0
+                };
+            }
+            _ => {}
+        }
+        // a t^2 + 2 b t + c = 0, the cone has its apex at the height.
+        let (a, b, c) = if let SHAPE::RT_CYLINDER = self.shape {
+            (
+                d.x * d.x + d.z * d.z,
+                o.x * d.x + o.z * d.z,
+                o.x * o.x + o.z * o.z - radius * radius,
+            )
+        } else {
+            let k = radius / height;
+            let y = height - o.y;
+            (
+                d.x * d.x + d.z * d.z - k * k * d.y * d.y,
+                o.x * d.x + o.z * d.z + k * k * y * d.y,
+                o.x * o.x + o.z * o.z - k * k * y * y,
+            )
+        };
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 || a.abs() < 1E-12 {
+            return -1.0;
+        }
+        let root = discriminant.sqrt();
+        let (t0, t1) = ((-b - root) / a, (-b + root) / a);
+        for t in [t0.min(t1), t0.max(t1)] {
+            let p = o + t * d;
+            if t <= EPSILON || p.y < 0.0 || p.y > height {
+                continue;
+            }
+            if face_cull && self.normal(p).dot(d) >= 0.0 {
+                continue;
+            }
+            return t;
+        }
+        -1.0
+    }
+
+    /// Sphere traces the distance to the torus through its bounding sphere,
+    /// see `hitTorus` in the shader.
+    fn hit_torus(&self, o: Vec3, d: Vec3, face_cull: bool) -> f32 {
+        let (radius, minor_radius) = (self.radius, self.minor_radius);
+        let distance = |p: Vec3| {
+            let ring = (p.x * p.x + p.z * p.z).sqrt() - radius;
+            (ring * ring + p.y * p.y).sqrt() - minor_radius
+        };
+        // Marched in object space units along the normalized direction.
+        let scale = d.magnitude();
+        let direction = d / scale;
+        let bound = radius + minor_radius;
+        let h = -o.dot(direction);
+        let discriminant = h * h - o.dot(o) + bound * bound;
+        if discriminant < 0.0 {
+            return -1.0;
+        }
+        let mut s = (h - discriminant.sqrt()).max(0.0);
+        let end = h + discriminant.sqrt();
+        let epsilon = 1E-4 * minor_radius;
+        for _ in 0..128 {
+            if s >= end {
+                break;
+            }
+            let p = o + s * direction;
+            let distance = distance(p);
+            // Rays leaving the surface start out within epsilon of it.
+            if distance.abs() < epsilon && s > 10.0 * epsilon {
+                if face_cull && self.normal(p).dot(direction) >= 0.0 {
+                    return -1.0;
+                }
+                return s / scale;
+            }
+            s += distance.abs().max(epsilon);
+        }
+        -1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cornell_box(width: i32, height: i32) -> CpuRenderer {
+        let mut scene = Scene::load("scenes/cornell_box.toml");
+        scene.settings.width = width;
+        scene.settings.height = height;
+        CpuRenderer::new(&scene)
+    }
+
+    /// Mean of the pixels of an RGB image `width` wide in the columns and rows
+    /// `x` and `y`, given as fractions of the image.
+    fn mean(pixels: &[f32], width: usize, x: [f32; 2], y: [f32; 2]) -> [f32; 3] {
+        let height = pixels.len() / 3 / width;
+        let (mut sum, mut count) = ([0.0; 3], 0.0);
+        for (i, rgb) in pixels.chunks_exact(3).enumerate() {
+            let (u, v) = (
+                (i % width) as f32 / width as f32,
+                (i / width) as f32 / height as f32,
+            );
+            if (x[0]..x[1]).contains(&u) && (y[0]..y[1]).contains(&v) {
+                for c in 0..3 {
+                    sum[c] += rgb[c];
+                }
+                count += 1.0;
+            }
+        }
+        sum.map(|c| c / count)
+    }
+
+    #[test]
+    fn renders_the_cornell_box() {
+        let (width, height) = (32, 24);
+        let mut renderer = cornell_box(width, height);
+        renderer.render_samples(4);
+        let pixels = renderer.read_pixels();
+        assert_eq!(pixels.len(), (width * height * 3) as usize);
+        assert!(pixels.iter().all(|c| c.is_finite() && *c >= 0.0));
+
+        let width = width as usize;
+        let all = mean(&pixels, width, [0.0, 1.0], [0.0, 1.0]);
+        assert!(all.iter().all(|c| *c > 0.05), "too dark: {:?}", all);
+        // The red wall on the right and the green one on the left.
+        let [r, g, _] = mean(&pixels, width, [0.7, 0.8], [0.4, 0.6]);
+        assert!(r > 2.0 * g, "right wall is not red: {} {}", r, g);
+        let [r, g, _] = mean(&pixels, width, [0.2, 0.3], [0.4, 0.6]);
+        assert!(g > 2.0 * r, "left wall is not green: {} {}", r, g);
+    }
+
+    #[test]
+    fn isotropic_phase_is_uniform() {
+        for cosine in [-1.0, -0.3, 0.0, 0.7, 1.0] {
+            let p = henyey_greenstein(cosine, 0.0);
+            assert!((p - 1.0 / (4.0 * PI)).abs() < 1E-7);
+        }
+    }
+
+    #[test]
+    fn phase_stays_finite_near_the_limits() {
+        let direction = vec3(0.0, 0.0, 1.0);
+        for g in [-0.999, -0.99, -0.5, 0.0005, 0.5, 0.99, 0.999] {
+            for cosine in [-1.0, 0.0, 1.0] {
+                let p = henyey_greenstein(cosine, g);
+                assert!(p.is_finite() && p > 0.0, "{} {}", g, cosine);
+            }
+            for i in 0..256 {
+                let mut rng = PixelSampler::new(Sampler::Random, i, 0, 0);
+                let scattered = sample_phase(direction, g, &mut rng);
+                assert!(
+                    (scattered.magnitude() - 1.0).abs() < 1E-4,
+                    "{} {:?}",
+                    g,
+                    scattered
+                );
+                let p = henyey_greenstein(direction.dot(scattered), g);
+                assert!(p.is_finite(), "{} {:?}", g, scattered);
+            }
+        }
+    }
+
+    #[test]
+    fn renders_deterministically() {
+        let mut a = cornell_box(16, 12);
+        let mut b = cornell_box(16, 12);
+        a.render_samples(2);
+        b.render_samples(2);
+        assert!(a.read_pixels() == b.read_pixels());
+    }
+}
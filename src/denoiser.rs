@@ -0,0 +1,329 @@
+use glow::{Context, TEXTURE0};
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::fbo::ScreenFBO;
+use crate::film::{luminance, Aov, FilmPixel};
+use crate::screen::{Screen, ATTACHMENT_UNITS};
+use crate::shader::Shader;
+
+/// Edge-avoiding à-trous wavelet filter (Dammertz et al. 2010) guided by the
+/// first hit albedo, normal and depth and, as in SVGF, by the variance of each
+/// pixel, so it smooths less as the image converges. Between pixels that both
+/// see a medium only the luminance and normal stop it. Runs in
+/// `shaders/denoise.frag`, and in `denoise` for the CPU renderer.
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Denoise {
+    pub enabled: bool,
+    /// Passes of the 5x5 kernel, each spreading its taps twice as far, within
+    /// `ITERATIONS` as in the UI.
+    #[serde(deserialize_with = "deserialize_iterations")]
+    pub iterations: i32,
+    /// How many standard deviations of luminance difference are still blended.
+    pub color_phi: f32,
+    /// Exponent on the cosine between normals.
+    pub normal_phi: f32,
+    /// Depth difference relative to the depth, per pixel of tap distance.
+    pub depth_phi: f32,
+    pub albedo_phi: f32,
+}
+
+/// The passes the UI offers, the taps of the last one 128 pixels apart.
+pub const ITERATIONS: std::ops::RangeInclusive<i32> = 1..=8;
+
+fn deserialize_iterations<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    Ok(i32::deserialize(deserializer)?.clamp(*ITERATIONS.start(), *ITERATIONS.end()))
+}
+
+impl Denoise {
+    /// `iterations` within `ITERATIONS`, however it was set.
+    pub fn passes(&self) -> i32 {
+        self.iterations
+            .clamp(*ITERATIONS.start(), *ITERATIONS.end())
+    }
+}
+
+impl Default for Denoise {
+    fn default() -> Self {
+        Denoise {
+            enabled: false,
+            iterations: 5,
+            color_phi: 4.0,
+            normal_phi: 64.0,
+            depth_phi: 0.05,
+            albedo_phi: 0.1,
+        }
+    }
+}
+
+/// B3 spline weights of the taps 0, 1 and 2 steps from the center.
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Below this many samples the variance of a pixel is estimated from its
+/// neighbours instead.
+const MIN_VARIANCE_SAMPLES: f32 = 4.0;
+
+/// Denoises the image accumulated in `film`, `width` pixels per row, returns
+/// RGB floats in the same order.
+pub fn denoise(settings: &Denoise, width: usize, height: usize, film: &[FilmPixel]) -> Vec<f32> {
+    let aovs: Vec<Aov> = film.iter().map(|pixel| pixel.aov()).collect();
+    let colors: Vec<[f32; 3]> = film.iter().map(|pixel| pixel.resolve()).collect();
+    // Color and the variance of its mean per pixel.
+    let mut image: Vec<[f32; 4]> = (0..film.len())
+        .into_par_iter()
+        .map(|i| {
+            let [r, g, b] = colors[i];
+            let n = film[i].count.max(1.0);
+            let variance = if n >= MIN_VARIANCE_SAMPLES {
+                film[i].variance()
+            } else {
+                spatial_variance(&colors, width, height, i)
+            };
+            [r, g, b, variance / n]
+        })
+        .collect();
+    for iteration in 0..settings.passes() {
+        image = atrous(settings, width, height, &image, &aovs, 1 << iteration);
+    }
+    image.iter().flat_map(|p| [p[0], p[1], p[2]]).collect()
+}
+
+/// Variance of the luminance in the 3x3 neighbourhood of pixel `i`.
+fn spatial_variance(colors: &[[f32; 3]], width: usize, height: usize, i: usize) -> f32 {
+    let (x, y) = ((i % width) as i32, (i / width) as i32);
+    let (mut sum, mut sum_squared, mut n) = (0.0, 0.0, 0.0);
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let (qx, qy) = (x + dx, y + dy);
+            if qx < 0 || qy < 0 || qx >= width as i32 || qy >= height as i32 {
+                continue;
+            }
+            let l = luminance(colors[qy as usize * width + qx as usize]);
+            sum += l;
+            sum_squared += l * l;
+            n += 1.0;
+        }
+    }
+    let mean = sum / n;
+    (sum_squared / n - mean * mean).max(0.0)
+}
+
+/// The variance around pixel `x`, `y` smoothed with a 3x3 Gaussian, so a
+/// pixel whose few samples happen to agree still blends with its neighbours.
+fn blurred_variance(image: &[[f32; 4]], width: usize, height: usize, x: i32, y: i32) -> f32 {
+    const GAUSSIAN: [f32; 2] = [1.0 / 4.0, 1.0 / 8.0];
+    let (mut sum, mut weights) = (0.0, 0.0);
+    for dy in -1i32..=1 {
+        for dx in -1i32..=1 {
+            let (qx, qy) = (x + dx, y + dy);
+            if qx < 0 || qy < 0 || qx >= width as i32 || qy >= height as i32 {
+                continue;
+            }
+            let w = GAUSSIAN[dx.unsigned_abs() as usize] * GAUSSIAN[dy.unsigned_abs() as usize];
+            sum += w * image[qy as usize * width + qx as usize][3];
+            weights += w;
+        }
+    }
+    sum / weights
+}
+
+fn atrous(
+    settings: &Denoise,
+    width: usize,
+    height: usize,
+    image: &[[f32; 4]],
+    aovs: &[Aov],
+    step: i32,
+) -> Vec<[f32; 4]> {
+    (0..image.len())
+        .into_par_iter()
+        .map(|i| {
+            let center = image[i];
+            let a = &aovs[i];
+            if a.depth <= 0.0 {
+                return center;
+            }
+            let (x, y) = ((i % width) as i32, (i / width) as i32);
+            let lc = luminance([center[0], center[1], center[2]]);
+            let variance = blurred_variance(image, width, height, x, y);
+            let sigma = settings.color_phi * variance.max(0.0).sqrt() + 1e-6;
+            let (mut sum, mut variance, mut weights) = ([0.0; 3], 0.0, 0.0);
+            for dy in -2i32..=2 {
+                for dx in -2i32..=2 {
+                    let (qx, qy) = (x + dx * step, y + dy * step);
+                    if qx < 0 || qy < 0 || qx >= width as i32 || qy >= height as i32 {
+                        continue;
+                    }
+                    let j = qy as usize * width + qx as usize;
+                    let q = image[j];
+                    let b = &aovs[j];
+                    let h = KERNEL[dx.unsigned_abs() as usize] * KERNEL[dy.unsigned_abs() as usize];
+                    let w = if j == i {
+                        h
+                    } else {
+                        if b.depth <= 0.0 {
+                            continue;
+                        }
+                        let lq = luminance([q[0], q[1], q[2]]);
+                        let cosine: f32 = (0..3).map(|c| a.normal[c] * b.normal[c]).sum();
+                        let albedo = (0..3)
+                            .map(|c| (a.albedo[c] - b.albedo[c]).powi(2))
+                            .sum::<f32>()
+                            .sqrt();
+                        let wl = (-(lc - lq).abs() / sigma).exp();
+                        let wn = cosine.max(0.0).powf(settings.normal_phi);
+                        // Where rays scatter in a medium and which of them get
+                        // through varies from sample to sample, so its depth and
+                        // albedo are as noisy as the color.
+                        let wza = if a.medium > 0.0 && b.medium > 0.0 {
+                            1.0
+                        } else {
+                            let wz = (-(a.depth - b.depth).abs()
+                                / (settings.depth_phi * a.depth * step as f32))
+                                .exp();
+                            wz * (-albedo / settings.albedo_phi).exp()
+                        };
+                        h * wl * wn * wza
+                    };
+                    for c in 0..3 {
+                        sum[c] += w * q[c];
+                    }
+                    variance += w * w * q[3];
+                    weights += w;
+                }
+            }
+            [
+                sum[0] / weights,
+                sum[1] / weights,
+                sum[2] / weights,
+                variance / (weights * weights),
+            ]
+        })
+        .collect()
+}
+
+/// Runs `Denoise` on the GPU between two buffers of its own.
+pub struct Denoiser {
+    shader: Shader,
+    buffers: [ScreenFBO; 2],
+}
+
+impl Denoiser {
+    pub fn new(gl: &Context, width: i32, height: i32) -> Self {
+        Denoiser {
+            shader: Shader::new(gl, "shaders/screen.vert", "shaders/denoise.frag"),
+            buffers: [
+                ScreenFBO::new(gl, width, height),
+                ScreenFBO::new(gl, width, height),
+            ],
+        }
+    }
+
+    pub fn resize(&mut self, gl: &Context, width: i32, height: i32) {
+        self.delete(gl);
+        self.buffers = [
+            ScreenFBO::new(gl, width, height),
+            ScreenFBO::new(gl, width, height),
+        ];
+    }
+
+    /// Filters the image bound by `ScreenBuffer::bind_latest`, returns the
+    /// buffer holding the result as a color with a weight of one.
+    pub fn apply(&self, gl: &Context, screen: &Screen, settings: &Denoise) -> &ScreenFBO {
+        let shader = &self.shader;
+        shader.use_program(gl);
+        shader.set_int(gl, "colorTexture", ATTACHMENT_UNITS[0]);
+        shader.set_int(gl, "momentsTexture", ATTACHMENT_UNITS[1]);
+        shader.set_int(gl, "albedoTexture", ATTACHMENT_UNITS[2]);
+        shader.set_int(gl, "normalTexture", ATTACHMENT_UNITS[3]);
+        shader.set_float(gl, "colorPhi", settings.color_phi);
+        shader.set_float(gl, "normalPhi", settings.normal_phi);
+        shader.set_float(gl, "depthPhi", settings.depth_phi);
+        shader.set_float(gl, "albedoPhi", settings.albedo_phi);
+
+        let iterations = settings.passes();
+        self.buffers[0].bind(gl);
+        shader.set_bool(gl, "prepare", true);
+        shader.set_bool(gl, "last", iterations == 0);
+        screen.draw_shader(gl, shader);
+        shader.set_bool(gl, "prepare", false);
+        for iteration in 0..iterations {
+            let source = &self.buffers[iteration as usize % 2];
+            let target = &self.buffers[(iteration as usize + 1) % 2];
+            target.bind(gl);
+            source.bind_attachment(gl, 0, TEXTURE0);
+            shader.set_int(gl, "stepSize", 1 << iteration);
+            shader.set_bool(gl, "last", iteration == iterations - 1);
+            screen.draw_shader(gl, shader);
+        }
+        &self.buffers[iterations as usize % 2]
+    }
+
+    pub fn delete(&self, gl: &Context) {
+        self.buffers[0].delete(gl);
+        self.buffers[1].delete(gl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::hash;
+
+    /// A noisy 16 x 12 image of two walls meeting at the middle column.
+    fn film() -> Vec<FilmPixel> {
+        (0..16 * 12)
+            .map(|i: u32| {
+                let mut pixel = FilmPixel::default();
+                let left = i % 16 < 8;
+                let aov = Aov {
+                    albedo: if left {
+                        [0.8, 0.1, 0.1]
+                    } else {
+                        [0.1, 0.8, 0.1]
+                    },
+                    normal: if left {
+                        [1.0, 0.0, 0.0]
+                    } else {
+                        [0.0, 0.0, 1.0]
+                    },
+                    depth: 2.0,
+                    medium: 0.0,
+                };
+                for s in 0..2 {
+                    let noise = hash(i * 2 + s) as f32 / u32::MAX as f32;
+                    pixel.add([noise; 3], 1.0, &aov);
+                }
+                pixel
+            })
+            .collect()
+    }
+
+    #[test]
+    fn iterations_are_clamped_when_parsed() {
+        let parse = |source| toml::from_str::<Denoise>(source).unwrap().iterations;
+        assert_eq!(parse("iterations = 40"), 8);
+        assert_eq!(parse("iterations = -3"), 1);
+        assert_eq!(parse("iterations = 3"), 3);
+        assert_eq!(parse(""), 5);
+    }
+
+    #[test]
+    fn many_iterations_stop_at_the_clamp() {
+        let film = film();
+        let run = |iterations| {
+            let settings = Denoise {
+                iterations,
+                ..Denoise::default()
+            };
+            denoise(&settings, 16, 12, &film)
+        };
+        let clamped = run(8);
+        assert_eq!(run(32), clamped);
+        assert_eq!(run(i32::MAX), clamped);
+        assert_eq!(run(-1), run(1));
+        assert_ne!(run(2), run(1));
+        assert!(clamped.iter().all(|c| c.is_finite()));
+    }
+}
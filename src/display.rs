@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+
+/// Maps the linear radiance of the accumulation buffer into [0, 1].
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tonemapper {
+    #[default]
+    Clamp,
+    Reinhard,
+    Aces,
+    Filmic,
+}
+
+impl Tonemapper {
+    pub const ALL: [Tonemapper; 4] = [
+        Tonemapper::Clamp,
+        Tonemapper::Reinhard,
+        Tonemapper::Aces,
+        Tonemapper::Filmic,
+    ];
+
+    pub fn from_index(index: i32) -> Tonemapper {
+        Tonemapper::ALL[index.clamp(0, 3) as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Tonemapper> {
+        match name {
+            "clamp" => Some(Tonemapper::Clamp),
+            "reinhard" => Some(Tonemapper::Reinhard),
+            "aces" => Some(Tonemapper::Aces),
+            "filmic" => Some(Tonemapper::Filmic),
+            _ => None,
+        }
+    }
+
+    fn map(self, x: f32) -> f32 {
+        match self {
+            Tonemapper::Clamp => x,
+            Tonemapper::Reinhard => x / (1.0 + x),
+            Tonemapper::Aces => (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14),
+            Tonemapper::Filmic => {
+                const WHITE: f32 = 11.2;
+                hable(2.0 * x) / hable(WHITE)
+            }
+        }
+    }
+}
+
+/// Uncharted 2 filmic curve by John Hable.
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+/// How the accumulation buffer is turned into displayable colors. Applied in
+/// `shaders/screen.frag`, and by `apply` when writing 8 bit images.
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Display {
+    pub tonemapper: Tonemapper,
+    /// In stops, the radiance is scaled by 2^exposure.
+    pub exposure: f32,
+    pub srgb: bool,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display {
+            tonemapper: Tonemapper::Clamp,
+            exposure: 0.0,
+            srgb: true,
+        }
+    }
+}
+
+impl Display {
+    pub fn apply(&self, color: [f32; 3]) -> [f32; 3] {
+        let scale = self.exposure.exp2();
+        color.map(|c| {
+            let c = self.tonemapper.map(c.max(0.0) * scale).clamp(0.0, 1.0);
+            if self.srgb {
+                srgb_encode(c)
+            } else {
+                c
+            }
+        })
+    }
+}
+
+/// The sRGB transfer curve, from linear values to those stored in images.
+pub fn srgb_encode(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(tonemapper: Tonemapper, exposure: f32, srgb: bool) -> Display {
+        Display {
+            tonemapper,
+            exposure,
+            srgb,
+        }
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(
+            a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1E-4),
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn black_stays_black_and_large_values_saturate() {
+        for tonemapper in Tonemapper::ALL {
+            for srgb in [false, true] {
+                let display = display(tonemapper, 0.0, srgb);
+                assert_close(display.apply([0.0, -1.0, 0.0]), [0.0; 3]);
+                assert_close(display.apply([1E6; 3]), [1.0; 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn tonemappers_at_known_inputs() {
+        let linear = |tonemapper| display(tonemapper, 0.0, false);
+        assert_close(
+            linear(Tonemapper::Clamp).apply([0.25, 1.0, 4.0]),
+            [0.25, 1.0, 1.0],
+        );
+        assert_close(
+            linear(Tonemapper::Reinhard).apply([1.0, 3.0, 0.0]),
+            [0.5, 0.75, 0.0],
+        );
+        assert_close(linear(Tonemapper::Aces).apply([1.0; 3]), [0.8038; 3]);
+        // Half the white point is doubled onto it.
+        assert_close(linear(Tonemapper::Filmic).apply([5.6; 3]), [1.0; 3]);
+    }
+
+    #[test]
+    fn exposure_scales_by_stops_before_the_curve() {
+        let reinhard = display(Tonemapper::Reinhard, 1.0, false);
+        assert_close(reinhard.apply([1.0; 3]), [2.0 / 3.0; 3]);
+        let clamp = display(Tonemapper::Clamp, -2.0, true);
+        assert_close(clamp.apply([2.0; 3]), [srgb_encode(0.5); 3]);
+        assert!((srgb_encode(0.5) - 0.7354).abs() < 1E-4);
+        assert_close(
+            display(Tonemapper::Clamp, 0.0, true).apply([1.0; 3]),
+            [1.0; 3],
+        );
+    }
+}
@@ -0,0 +1,420 @@
+use std::f32::consts::PI;
+
+use bytemuck::cast_slice;
+use cgmath::{vec3, InnerSpace, Vector3};
+use glow::*;
+use serde::Deserialize;
+
+use crate::shader::Shader;
+
+/// What rays that leave the scene see when there is no environment map.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Sky {
+    Color {
+        color: [f32; 3],
+    },
+    /// Blends from `horizon` to `zenith` above and to `ground` below.
+    Gradient {
+        horizon: [f32; 3],
+        zenith: [f32; 3],
+        ground: [f32; 3],
+    },
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky::Color {
+            color: [0.0, 0.0, 0.0],
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct EnvironmentDesc {
+    /// Equirectangular .hdr or .exr image, `sky` is used without one.
+    pub path: Option<String>,
+    /// Rotation around the y axis in degrees.
+    pub rotation: f32,
+    pub intensity: f32,
+    pub sky: Sky,
+}
+
+impl Default for EnvironmentDesc {
+    fn default() -> Self {
+        EnvironmentDesc {
+            path: None,
+            rotation: 0.0,
+            intensity: 1.0,
+            sky: Sky::default(),
+        }
+    }
+}
+
+/// An equirectangular environment map with the distributions for sampling it
+/// proportional to its luminance.
+struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    pixels: Vec<f32>,
+    /// Per row, the cdf over the pixels of the row.
+    conditional: Vec<f32>,
+    /// The cdf over the rows.
+    marginal: Vec<f32>,
+    /// Turns the luminance of a pixel into the solid angle pdf of sampling it.
+    normalization: f32,
+}
+
+impl EnvironmentMap {
+    fn load(path: &str) -> EnvironmentMap {
+        let image = image::open(path)
+            .unwrap_or_else(|e| panic!("Failed to load environment {}: {}", path, e))
+            .into_rgb32f();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        EnvironmentMap::new(width, height, image.into_raw())
+    }
+
+    /// Builds the distributions over the RGB `pixels`, top row first.
+    fn new(width: usize, height: usize, pixels: Vec<f32>) -> EnvironmentMap {
+        let mut conditional = vec![0.0; width * height];
+        let mut marginal = vec![0.0; height];
+        let mut total = 0.0;
+        for y in 0..height {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let mut row_sum = 0.0;
+            for x in 0..width {
+                let i = (y * width + x) * 3;
+                row_sum += luminance(&pixels[i..i + 3]) * sin_theta;
+                conditional[y * width + x] = row_sum;
+            }
+            for value in &mut conditional[y * width..(y + 1) * width] {
+                *value = if row_sum > 0.0 { *value / row_sum } else { 1.0 };
+            }
+            conditional[y * width + width - 1] = 1.0;
+            total += row_sum;
+            marginal[y] = total;
+        }
+        for value in &mut marginal {
+            *value = if total > 0.0 { *value / total } else { 1.0 };
+        }
+        marginal[height - 1] = 1.0;
+        let normalization = if total > 0.0 {
+            (width * height) as f32 / (2.0 * PI * PI * total)
+        } else {
+            0.0
+        };
+
+        EnvironmentMap {
+            width,
+            height,
+            pixels,
+            conditional,
+            marginal,
+            normalization,
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> &[f32] {
+        let i = (y * self.width + x) * 3;
+        &self.pixels[i..i + 3]
+    }
+
+    fn texel(&self, u: f32, v: f32) -> (usize, usize) {
+        let x = ((u * self.width as f32) as usize).min(self.width - 1);
+        let y = ((v * self.height as f32) as usize).min(self.height - 1);
+        (x, y)
+    }
+}
+
+pub struct Environment {
+    map: Option<EnvironmentMap>,
+    sky: Sky,
+    rotation: f32,
+    intensity: f32,
+    textures: Option<[NativeTexture; 2]>,
+}
+
+impl Environment {
+    pub fn new(desc: &EnvironmentDesc) -> Environment {
+        Environment {
+            map: desc.path.as_deref().map(EnvironmentMap::load),
+            sky: desc.sky.clone(),
+            rotation: desc.rotation.to_radians(),
+            intensity: desc.intensity,
+            textures: None,
+        }
+    }
+
+    /// Whether `sample` can be used, only environment maps are sampled.
+    pub fn has_map(&self) -> bool {
+        self.map.is_some()
+    }
+
+    pub fn radiance(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        match &self.map {
+            Some(map) => {
+                let (u, v) = self.to_uv(direction);
+                let (x, y) = map.texel(u, v);
+                Vector3::from(<[f32; 3]>::try_from(map.pixel(x, y)).unwrap()) * self.intensity
+            }
+            None => match &self.sky {
+                Sky::Color { color } => Vector3::from(*color) * self.intensity,
+                Sky::Gradient {
+                    horizon,
+                    zenith,
+                    ground,
+                } => {
+                    let y = direction.normalize().y;
+                    let (horizon, other) = (Vector3::from(*horizon), Vector3::from(*zenith));
+                    let color = if y >= 0.0 {
+                        horizon + (other - horizon) * y
+                    } else {
+                        horizon + (Vector3::from(*ground) - horizon) * -y
+                    };
+                    color * self.intensity
+                }
+            },
+        }
+    }
+
+    /// Picks a direction proportional to the luminance of the map from four
+    /// uniform numbers and returns it with its solid angle pdf.
+    pub fn sample(&self, u: [f32; 4]) -> (Vector3<f32>, f32) {
+        let map = self
+            .map
+            .as_ref()
+            .expect("Only environment maps can be sampled");
+        let y = map
+            .marginal
+            .partition_point(|&c| c < u[0])
+            .min(map.height - 1);
+        let row = &map.conditional[y * map.width..(y + 1) * map.width];
+        let x = row.partition_point(|&c| c < u[1]).min(map.width - 1);
+        let u_map = (x as f32 + u[2]) / map.width as f32;
+        let v_map = (y as f32 + u[3]) / map.height as f32;
+        let direction = self.uv_direction(u_map, v_map);
+        (direction, luminance(map.pixel(x, y)) * map.normalization)
+    }
+
+    /// Solid angle pdf of `sample` returning `direction`.
+    pub fn pdf(&self, direction: Vector3<f32>) -> f32 {
+        match &self.map {
+            Some(map) => {
+                let (u, v) = self.to_uv(direction);
+                let (x, y) = map.texel(u, v);
+                luminance(map.pixel(x, y)) * map.normalization
+            }
+            None => 0.0,
+        }
+    }
+
+    fn to_uv(&self, direction: Vector3<f32>) -> (f32, f32) {
+        let d = direction.normalize();
+        let (sin, cos) = self.rotation.sin_cos();
+        let d = vec3(cos * d.x + sin * d.z, d.y, -sin * d.x + cos * d.z);
+        let u = 0.5 + d.z.atan2(d.x) / (2.0 * PI);
+        let v = d.y.clamp(-1.0, 1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn uv_direction(&self, u: f32, v: f32) -> Vector3<f32> {
+        let phi = (u - 0.5) * 2.0 * PI;
+        let theta = v * PI;
+        let d = vec3(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        );
+        let (sin, cos) = self.rotation.sin_cos();
+        vec3(cos * d.x - sin * d.z, d.y, sin * d.x + cos * d.z)
+    }
+
+    pub fn set_texture(&mut self, gl: &Context) {
+        let map = match &self.map {
+            Some(map) => map,
+            None => return,
+        };
+        // Conditional cdfs in the first rows, the marginal cdf in the last one.
+        let cdf_width = map.width.max(map.height);
+        let mut cdf = vec![0.0f32; cdf_width * (map.height + 1)];
+        for y in 0..map.height {
+            cdf[y * cdf_width..y * cdf_width + map.width]
+                .copy_from_slice(&map.conditional[y * map.width..(y + 1) * map.width]);
+        }
+        cdf[map.height * cdf_width..map.height * cdf_width + map.height]
+            .copy_from_slice(&map.marginal);
+        unsafe {
+            let textures = *self.textures.get_or_insert_with(|| {
+                [gl.create_texture().unwrap(), gl.create_texture().unwrap()]
+            });
+            gl.bind_texture(TEXTURE_2D, Some(textures[0]));
+            gl.tex_image_2d(
+                TEXTURE_2D,
+                0,
+                RGB32F as i32,
+                map.width as i32,
+                map.height as i32,
+                0,
+                RGB,
+                FLOAT,
+                Some(cast_slice(&map.pixels)),
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_S, REPEAT as i32);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_T, CLAMP_TO_EDGE as i32);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as i32);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST as i32);
+
+            gl.bind_texture(TEXTURE_2D, Some(textures[1]));
+            gl.tex_image_2d(
+                TEXTURE_2D,
+                0,
+                R32F as i32,
+                cdf_width as i32,
+                map.height as i32 + 1,
+                0,
+                RED,
+                FLOAT,
+                Some(cast_slice(&cdf)),
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as i32);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST as i32);
+            gl.bind_texture(TEXTURE_2D, None);
+        }
+    }
+
+    pub fn use_texture(&self, gl: &Context, shader: &Shader) {
+        shader.set_float(gl, "environment.rotation", self.rotation);
+        shader.set_float(gl, "environment.intensity", self.intensity);
+        match (&self.map, self.textures) {
+            (Some(map), Some(textures)) => unsafe {
+                shader.set_int(gl, "environment.mode", 2);
+                shader.set_float(gl, "environment.normalization", map.normalization);
+                gl.active_texture(TEXTURE4);
+                gl.bind_texture(TEXTURE_2D, Some(textures[0]));
+                shader.set_int(gl, "environmentMap", 4);
+                gl.active_texture(TEXTURE5);
+                gl.bind_texture(TEXTURE_2D, Some(textures[1]));
+                shader.set_int(gl, "environmentCdf", 5);
+            },
+            _ => match &self.sky {
+                Sky::Color { color } => {
+                    shader.set_int(gl, "environment.mode", 0);
+                    shader.set_vector3(gl, "environment.horizon", &Vector3::from(*color));
+                }
+                Sky::Gradient {
+                    horizon,
+                    zenith,
+                    ground,
+                } => {
+                    shader.set_int(gl, "environment.mode", 1);
+                    shader.set_vector3(gl, "environment.horizon", &Vector3::from(*horizon));
+                    shader.set_vector3(gl, "environment.zenith", &Vector3::from(*zenith));
+                    shader.set_vector3(gl, "environment.ground", &Vector3::from(*ground));
+                }
+            },
+        }
+    }
+
+    pub fn delete_texture(&self, gl: &Context) {
+        if let Some(textures) = self.textures {
+            unsafe {
+                gl.delete_texture(textures[0]);
+                gl.delete_texture(textures[1]);
+            }
+        }
+    }
+}
+
+fn luminance(color: &[f32]) -> f32 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::hash;
+
+    fn environment(map: EnvironmentMap, rotation: f32) -> Environment {
+        Environment {
+            map: Some(map),
+            sky: Sky::default(),
+            rotation,
+            intensity: 1.0,
+            textures: None,
+        }
+    }
+
+    /// A row of bright pixels above darker ones that brighten to the right.
+    fn gradient_map(width: usize, height: usize) -> EnvironmentMap {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let value = if y == 1 { 20.0 } else { 0.1 + x as f32 };
+                pixels.extend_from_slice(&[value; 3]);
+            }
+        }
+        EnvironmentMap::new(width, height, pixels)
+    }
+
+    fn numbers(i: u32) -> [f32; 4] {
+        // Kept off the texel borders, where the direction may round to the
+        // neighbouring texel.
+        [0, 1, 2, 3].map(|d| 0.05 + 0.9 * (hash(hash(i) ^ d) as f32 / u32::MAX as f32))
+    }
+
+    #[test]
+    fn uniform_map_is_sampled_uniformly() {
+        let (width, height) = (64, 32);
+        let environment = environment(
+            EnvironmentMap::new(width, height, vec![0.5; width * height * 3]),
+            0.0,
+        );
+        for i in 0..256 {
+            let (direction, pdf) = environment.sample(numbers(i));
+            assert!((direction.magnitude() - 1.0).abs() < 1E-5);
+            assert!((pdf - environment.pdf(direction)).abs() < 1E-6);
+            // Uniform over the sphere, up to the rows of the map.
+            assert!((pdf * 4.0 * PI - 1.0).abs() < 0.01, "pdf {}", pdf);
+        }
+    }
+
+    #[test]
+    fn sampled_pdf_matches_the_lookup() {
+        let environment = environment(gradient_map(16, 8), 1.0);
+        for i in 0..256 {
+            let (direction, pdf) = environment.sample(numbers(i));
+            let lookup = environment.pdf(direction);
+            assert!(
+                (pdf - lookup).abs() <= 1E-4 * pdf,
+                "{} against {}",
+                pdf,
+                lookup
+            );
+        }
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let environment = environment(gradient_map(16, 8), 0.0);
+        // Midpoint rule over the sphere in the angles of the map.
+        let (steps_theta, steps_phi) = (256, 512);
+        let mut integral = 0.0;
+        for i in 0..steps_theta {
+            let theta = PI * (i as f32 + 0.5) / steps_theta as f32;
+            for j in 0..steps_phi {
+                let phi = 2.0 * PI * (j as f32 + 0.5) / steps_phi as f32;
+                let d = vec3(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                integral += environment.pdf(d) * theta.sin();
+            }
+        }
+        integral *= PI / steps_theta as f32 * 2.0 * PI / steps_phi as f32;
+        assert!((integral - 1.0).abs() < 0.01, "integral {}", integral);
+    }
+}
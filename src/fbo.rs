@@ -2,45 +2,70 @@ use glow::*;
 
 pub struct ScreenFBO {
     pub fbo: Framebuffer,
-    texture: Texture,
+    textures: Vec<Texture>,
+    width: i32,
+    height: i32,
 }
 
 impl ScreenFBO {
     pub fn new(gl: &Context, width: i32, height: i32) -> Self {
+        Self::with_attachments(gl, width, height, 1)
+    }
+
+    /// A framebuffer with `count` RGBA float color attachments, all drawn to
+    /// at once.
+    pub fn with_attachments(gl: &Context, width: i32, height: i32, count: u32) -> Self {
         let fbo = unsafe { gl.create_framebuffer().unwrap() };
-        let texture = unsafe { gl.create_texture().unwrap() };
+        let textures: Vec<Texture> = (0..count)
+            .map(|_| unsafe { gl.create_texture().unwrap() })
+            .collect();
         unsafe {
             assert_eq!(gl.get_error(), NO_ERROR);
         }
 
         unsafe {
             gl.bind_framebuffer(FRAMEBUFFER, Some(fbo));
-            gl.bind_texture(TEXTURE_2D, Some(texture));
-            gl.tex_image_2d(
-                TEXTURE_2D,
-                0,
-                RGB32F as i32,
-                width,
-                height,
-                0,
-                RGB,
-                FLOAT,
-                None,
-            );
-            assert_eq!(gl.get_error(), NO_ERROR);
+            for (i, texture) in textures.iter().enumerate() {
+                gl.bind_texture(TEXTURE_2D, Some(*texture));
+                gl.tex_image_2d(
+                    TEXTURE_2D,
+                    0,
+                    RGBA32F as i32,
+                    width,
+                    height,
+                    0,
+                    RGBA,
+                    FLOAT,
+                    None,
+                );
+                assert_eq!(gl.get_error(), NO_ERROR);
 
-            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as i32);
-            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST as i32);
-            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_S, CLAMP_TO_EDGE as i32);
-            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_T, CLAMP_TO_EDGE as i32);
+                gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as i32);
+                gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST as i32);
+                gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_S, CLAMP_TO_EDGE as i32);
+                gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_T, CLAMP_TO_EDGE as i32);
 
-            gl.framebuffer_texture_2d(FRAMEBUFFER, COLOR_ATTACHMENT0, TEXTURE_2D, Some(texture), 0);
+                gl.framebuffer_texture_2d(
+                    FRAMEBUFFER,
+                    COLOR_ATTACHMENT0 + i as u32,
+                    TEXTURE_2D,
+                    Some(*texture),
+                    0,
+                );
+            }
+            let buffers: Vec<u32> = (0..count).map(|i| COLOR_ATTACHMENT0 + i).collect();
+            gl.draw_buffers(&buffers);
 
             if gl.check_framebuffer_status(FRAMEBUFFER) != FRAMEBUFFER_COMPLETE {
                 println!("Framebuffer not complete!");
             }
 
-            let screen_fbo = ScreenFBO { fbo, texture };
+            let screen_fbo = ScreenFBO {
+                fbo,
+                textures,
+                width,
+                height,
+            };
 
             screen_fbo.unbind(gl);
 
@@ -61,18 +86,88 @@ impl ScreenFBO {
         }
     }
 
+    /// Switches the texture to bilinear filtering, for images shown larger
+    /// than they are.
+    pub fn set_linear_filter(&self, gl: &Context) {
+        unsafe {
+            for texture in &self.textures {
+                gl.bind_texture(TEXTURE_2D, Some(*texture));
+                gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, LINEAR as i32);
+                gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR as i32);
+            }
+            gl.bind_texture(TEXTURE_2D, None);
+        }
+    }
+
+    /// Copies the first color attachment into the first one of `target`,
+    /// scaled to its size.
+    pub fn blit_to(&self, gl: &Context, target: &ScreenFBO) {
+        unsafe {
+            gl.bind_framebuffer(READ_FRAMEBUFFER, Some(self.fbo));
+            gl.read_buffer(COLOR_ATTACHMENT0);
+            gl.bind_framebuffer(DRAW_FRAMEBUFFER, Some(target.fbo));
+            gl.blit_framebuffer(
+                0,
+                0,
+                self.width,
+                self.height,
+                0,
+                0,
+                target.width,
+                target.height,
+                COLOR_BUFFER_BIT,
+                LINEAR,
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.bind_framebuffer(READ_FRAMEBUFFER, None);
+            gl.bind_framebuffer(DRAW_FRAMEBUFFER, None);
+        }
+    }
+
     pub fn bind_texture(&self, gl: &Context) {
+        self.bind_texture_unit(gl, TEXTURE0);
+    }
+
+    pub fn bind_texture_unit(&self, gl: &Context, unit: u32) {
+        self.bind_attachment(gl, 0, unit);
+    }
+
+    /// Binds color attachment number `attachment` to texture `unit`.
+    pub fn bind_attachment(&self, gl: &Context, attachment: usize, unit: u32) {
         unsafe {
-            gl.active_texture(TEXTURE0);
-            gl.bind_texture(TEXTURE_2D, Some(self.texture))
+            gl.active_texture(unit);
+            gl.bind_texture(TEXTURE_2D, Some(self.textures[attachment]))
         }
     }
 
+    /// Reads the first color attachment back as RGBA floats, bottom row first.
+    pub fn read_pixels(&self, gl: &Context) -> Vec<f32> {
+        let mut rgba = vec![0.0f32; (self.width * self.height * 4) as usize];
+        unsafe {
+            gl.bind_framebuffer(READ_FRAMEBUFFER, Some(self.fbo));
+            gl.read_buffer(COLOR_ATTACHMENT0);
+            gl.read_pixels(
+                0,
+                0,
+                self.width,
+                self.height,
+                RGBA,
+                FLOAT,
+                PixelPackData::Slice(bytemuck::cast_slice_mut(&mut rgba)),
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.bind_framebuffer(READ_FRAMEBUFFER, None);
+        }
+        rgba
+    }
+
     pub fn delete(&self, gl: &Context) {
         unsafe {
             self.unbind(gl);
             gl.delete_framebuffer(self.fbo);
-            gl.delete_texture(self.texture);
+            for texture in &self.textures {
+                gl.delete_texture(*texture);
+            }
         }
     }
 }
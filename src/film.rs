@@ -0,0 +1,331 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Reconstruction filter weighting the samples of a pixel by their offset
+/// from its center. Mirrored by `filterWeight1D` in `shaders/path_tracing.frag`.
+///
+/// Instead of splatting every sample into the pixels its filter covers, each
+/// pixel jitters its own samples over [-radius, radius] around its center and
+/// divides their weighted sum by the sum of the weights, see `resolve`. The
+/// result converges to the same filtered image, while a pixel only ever
+/// writes its own sums, so the fragment shader needs no scattered writes and
+/// adaptive sampling can stop a pixel without leaving holes in its
+/// neighbours.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filter {
+    #[default]
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+}
+
+impl Filter {
+    pub const ALL: [Filter; 4] = [
+        Filter::Box,
+        Filter::Tent,
+        Filter::Gaussian,
+        Filter::Mitchell,
+    ];
+
+    pub fn from_index(index: i32) -> Filter {
+        Filter::ALL[index.clamp(0, 3) as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(Filter::Box),
+            "tent" => Some(Filter::Tent),
+            "gaussian" => Some(Filter::Gaussian),
+            "mitchell" => Some(Filter::Mitchell),
+            _ => None,
+        }
+    }
+
+    /// Half width of the support in pixels, samples are spread over it.
+    pub fn radius(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0,
+        }
+    }
+
+    /// Weight of a sample `dx`, `dy` pixels away from the pixel center.
+    pub fn weight(self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(self, x: f32) -> f32 {
+        let x = x.abs();
+        let radius = self.radius();
+        match self {
+            Filter::Box => 1.0,
+            Filter::Tent => (1.0 - x / radius).max(0.0),
+            Filter::Gaussian => {
+                const ALPHA: f32 = 2.0;
+                ((-ALPHA * x * x).exp() - (-ALPHA * radius * radius).exp()).max(0.0)
+            }
+            // B = C = 1/3, the support of [-2, 2] is scaled to the radius.
+            Filter::Mitchell => {
+                let x = 2.0 * x / radius;
+                let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                } else if x < 2.0 {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+/// Adaptive sampling: pixels stop taking samples once their estimate is
+/// precise enough, so later passes only trace the noisy ones.
+#[derive(Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Adaptive {
+    pub enabled: bool,
+    /// Relative standard error of the luminance at which a pixel is done.
+    pub threshold: f32,
+    /// Samples every pixel takes before its error estimate is trusted, at
+    /// least two for there to be a variance.
+    #[serde(deserialize_with = "deserialize_min_samples")]
+    pub min_samples: i32,
+}
+
+impl Default for Adaptive {
+    fn default() -> Self {
+        Adaptive {
+            enabled: false,
+            threshold: 0.05,
+            min_samples: 16,
+        }
+    }
+}
+
+fn deserialize_min_samples<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i32, D::Error> {
+    Ok(i32::deserialize(deserializer)?.max(2))
+}
+
+impl Adaptive {
+    pub fn is_converged(&self, pixel: &FilmPixel) -> bool {
+        if !self.enabled || pixel.count < self.min_samples.max(2) as f32 {
+            return false;
+        }
+        let n = pixel.count;
+        let mean = pixel.luminance / n;
+        (pixel.variance() / n).sqrt() <= self.threshold * mean.max(0.01)
+    }
+}
+
+/// What a camera ray hits first, the guides of the denoiser.
+#[derive(Clone, Copy, Default)]
+pub struct Aov {
+    /// The environment color for rays that miss, clamped to [0, 1].
+    pub albedo: [f32; 3],
+    /// Facing the ray, zero for rays that miss.
+    pub normal: [f32; 3],
+    /// Distance from the camera, zero for rays that miss.
+    pub depth: f32,
+    /// One when the ray scatters in a medium, zero otherwise. Averaged, the
+    /// fraction of such samples.
+    pub medium: f32,
+}
+
+/// Running sums of one pixel: the filter weighted color and the weights in
+/// `color[3]`, the luminance moments of the samples for `Adaptive` and the
+/// AOVs of the samples. The screen buffers keep the same sums in their four
+/// color attachments.
+#[derive(Clone, Copy, Default)]
+pub struct FilmPixel {
+    pub color: [f32; 4],
+    pub luminance: f32,
+    pub luminance_squared: f32,
+    pub count: f32,
+    albedo: [f32; 3],
+    normal: [f32; 3],
+    depth: f32,
+    medium: f32,
+}
+
+impl FilmPixel {
+    pub fn add(&mut self, color: [f32; 3], weight: f32, aov: &Aov) {
+        for (sum, c) in self.color.iter_mut().zip(color) {
+            *sum += weight * c;
+        }
+        self.color[3] += weight;
+        let l = luminance(color);
+        self.luminance += l;
+        self.luminance_squared += l * l;
+        self.count += 1.0;
+        for c in 0..3 {
+            self.albedo[c] += aov.albedo[c];
+            self.normal[c] += aov.normal[c];
+        }
+        self.depth += aov.depth;
+        self.medium += aov.medium;
+    }
+
+    /// The filtered color, black while the weights do not add up to anything.
+    pub fn resolve(&self) -> [f32; 3] {
+        resolve(self.color)
+    }
+
+    /// Unbiased variance of the luminance of the samples, zero below two.
+    pub fn variance(&self) -> f32 {
+        let n = self.count;
+        if n < 2.0 {
+            return 0.0;
+        }
+        let mean = self.luminance / n;
+        (self.luminance_squared / n - mean * mean).max(0.0) * n / (n - 1.0)
+    }
+
+    /// The AOVs averaged over the samples, with the normal normalized.
+    pub fn aov(&self) -> Aov {
+        let n = self.count.max(1.0);
+        let length = self.normal.iter().map(|c| c * c).sum::<f32>().sqrt();
+        Aov {
+            albedo: self.albedo.map(|c| c / n),
+            normal: if length > 0.0 {
+                self.normal.map(|c| c / length)
+            } else {
+                [0.0; 3]
+            },
+            depth: self.depth / n,
+            medium: self.medium / n,
+        }
+    }
+}
+
+/// Divides filter weighted color sums by the sum of the weights in alpha.
+/// The negative lobes of `Filter::Mitchell` can leave a pixel of a few
+/// samples with negative sums, so the weights only count once they add up to
+/// something and the colors are clamped to non-negative values.
+pub fn resolve(sum: [f32; 4]) -> [f32; 3] {
+    if sum[3] > 0.0 {
+        [0, 1, 2].map(|c| (sum[c] / sum[3]).max(0.0))
+    } else {
+        [0.0; 3]
+    }
+}
+
+pub fn luminance(color: [f32; 3]) -> f32 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::hash;
+
+    #[test]
+    fn filters_fall_to_zero_at_their_radius() {
+        for filter in Filter::ALL {
+            let radius = filter.radius();
+            let center = filter.weight(0.0, 0.0);
+            assert!(center > 0.0);
+            for x in [0.1, 0.25, 0.5, 1.0, 1.5, 2.0] {
+                if x < radius {
+                    assert!(filter.weight(x, 0.0) <= center);
+                    assert_eq!(filter.weight(x, 0.0), filter.weight(-x, 0.0));
+                    assert_eq!(filter.weight(x, 0.3), filter.weight(0.3, x));
+                }
+            }
+            if filter == Filter::Box {
+                assert_eq!(filter.weight(radius, radius), center);
+            } else {
+                assert!(filter.weight(radius, 0.0).abs() < 1E-6, "{}", radius);
+                assert!(filter.weight(0.0, -radius).abs() < 1E-6, "{}", radius);
+            }
+        }
+    }
+
+    fn pixel(luminances: &[f32]) -> FilmPixel {
+        let mut pixel = FilmPixel::default();
+        for &l in luminances {
+            pixel.add([l; 3], 1.0, &Aov::default());
+        }
+        pixel
+    }
+
+    #[test]
+    fn variance_is_unbiased() {
+        assert_eq!(pixel(&[]).variance(), 0.0);
+        assert_eq!(pixel(&[3.0]).variance(), 0.0);
+        assert!(pixel(&[0.5; 8]).variance().abs() < 1E-6);
+        // Luminance of grey is the grey, so this is the sample variance of
+        // 1, 2, 3 and 4.
+        let variance = pixel(&[1.0, 2.0, 3.0, 4.0]).variance();
+        assert!((variance - 5.0 / 3.0).abs() < 1E-5, "{}", variance);
+    }
+
+    #[test]
+    fn resolve_divides_by_the_weights() {
+        let mut pixel = FilmPixel::default();
+        pixel.add([1.0, 0.0, 0.0], 3.0, &Aov::default());
+        pixel.add([0.0, 1.0, 0.0], 1.0, &Aov::default());
+        assert_eq!(pixel.resolve(), [0.75, 0.25, 0.0]);
+        assert_eq!(FilmPixel::default().resolve(), [0.0; 3]);
+    }
+
+    #[test]
+    fn constant_images_resolve_to_the_constant() {
+        let color = [0.2, 0.5, 3.0];
+        for filter in Filter::ALL {
+            let radius = filter.radius();
+            let mut pixel = FilmPixel::default();
+            for i in 0..64 {
+                // Offsets jittered over the support as the renderers do.
+                let [dx, dy] = [2 * i, 2 * i + 1]
+                    .map(|k| radius * (2.0 * (hash(k) as f32 / u32::MAX as f32) - 1.0));
+                pixel.add(color, filter.weight(dx, dy), &Aov::default());
+                if pixel.color[3] > 0.0 {
+                    let resolved = pixel.resolve();
+                    for c in 0..3 {
+                        assert!((resolved[c] - color[c]).abs() < 1E-3 * color[c], "{}", i);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn negative_weights_never_resolve_below_zero() {
+        // A bright sample in a negative lobe of Mitchell.
+        let mut pixel = FilmPixel::default();
+        pixel.add([1.0; 3], -0.03, &Aov::default());
+        assert_eq!(pixel.resolve(), [0.0; 3]);
+        pixel.add([0.1; 3], 0.04, &Aov::default());
+        assert_eq!(pixel.resolve(), [0.0; 3]);
+        pixel.add([0.1; 3], 0.49, &Aov::default());
+        assert!((pixel.resolve()[0] - 0.023 / 0.5).abs() < 1E-6);
+    }
+
+    #[test]
+    fn one_sample_never_converges() {
+        let adaptive = Adaptive {
+            enabled: true,
+            threshold: 1.0,
+            min_samples: 1,
+        };
+        assert!(!adaptive.is_converged(&pixel(&[0.5])));
+        assert!(adaptive.is_converged(&pixel(&[0.5, 0.5])));
+
+        let parsed: Adaptive = toml::from_str("enabled = true\nmin_samples = 0").unwrap();
+        assert_eq!(parsed.min_samples, 2);
+        let parsed: Adaptive = toml::from_str("enabled = true").unwrap();
+        assert_eq!(parsed.min_samples, 16);
+    }
+}
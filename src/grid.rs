@@ -0,0 +1,351 @@
+use std::fs;
+
+use glow::*;
+
+use crate::shader::Shader;
+
+/// A dense grid of densities, x varying fastest, then y, then z.
+pub struct DensityGrid {
+    pub resolution: [usize; 3],
+    pub data: Vec<f32>,
+    /// Largest density, which bounds the tracking through the grid.
+    pub max: f32,
+}
+
+impl DensityGrid {
+    /// Loads a Mitsuba `.vol` grid, or raw little endian 32 bit floats of
+    /// `resolution` for any other extension. Dense grids exported from
+    /// OpenVDB come in either form.
+    pub fn load(path: &str, resolution: Option<[usize; 3]>) -> DensityGrid {
+        let bytes =
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to load grid {}: {}", path, e));
+        let (resolution, data) = if path.ends_with(".vol") {
+            read_vol(path, &bytes)
+        } else {
+            let resolution =
+                resolution.unwrap_or_else(|| panic!("The raw grid {} needs a resolution", path));
+            (resolution, read_floats(&bytes))
+        };
+        let voxels = resolution.iter().product::<usize>();
+        if voxels == 0 || data.len() != voxels {
+            panic!(
+                "The grid {} has {} values for a resolution of {:?}",
+                path,
+                data.len(),
+                resolution
+            );
+        }
+        // Delta and ratio tracking need densities between zero and `max`.
+        if let Some(voxel) = data
+            .iter()
+            .position(|density| density.is_nan() || *density < 0.0)
+        {
+            panic!(
+                "The grid {} has the density {} at voxel {}, densities must not be negative",
+                path, data[voxel], voxel
+            );
+        }
+        let max = data.iter().fold(0.0f32, |max, &density| max.max(density));
+        DensityGrid {
+            resolution,
+            data,
+            max,
+        }
+    }
+
+    fn voxel(&self, x: usize, y: usize, z: usize) -> f32 {
+        let [width, height, _] = self.resolution;
+        self.data[(z * height + y) * width + x]
+    }
+
+    /// Trilinearly filtered density at `p` in [0, 1]^3, matching `gridDensity`
+    /// in the shader.
+    pub fn density(&self, p: [f32; 3]) -> f32 {
+        let mut index = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let size = self.resolution[axis] as f32;
+            let x = (p[axis] * size).clamp(0.5, size - 0.5) - 0.5;
+            index[axis] = x as usize;
+            fraction[axis] = x - x.floor();
+        }
+        let next = |axis: usize| (index[axis] + 1).min(self.resolution[axis] - 1);
+        let mut density = 0.0;
+        for corner in 0..8 {
+            let mut weight = 1.0;
+            let mut voxel = [0; 3];
+            for axis in 0..3 {
+                if corner >> axis & 1 == 1 {
+                    voxel[axis] = next(axis);
+                    weight *= fraction[axis];
+                } else {
+                    voxel[axis] = index[axis];
+                    weight *= 1.0 - fraction[axis];
+                }
+            }
+            density += weight * self.voxel(voxel[0], voxel[1], voxel[2]);
+        }
+        density
+    }
+}
+
+/// The header of a `.vol` file is "VOL", version 3, the encoding (1 for 32
+/// bit floats, 3 for bytes), the resolution, the channels and a bounding box,
+/// the data of the first channel is used.
+fn read_vol(path: &str, bytes: &[u8]) -> ([usize; 3], Vec<f32>) {
+    if bytes.len() < 48 || &bytes[0..3] != b"VOL" || bytes[3] != 3 {
+        panic!("{} is not a version 3 .vol file", path);
+    }
+    let int = |offset: usize| i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let encoding = int(4);
+    let resolution = [int(8), int(12), int(16)].map(|n| n.max(0) as usize);
+    let channels = int(20).max(1) as usize;
+    let values: Vec<f32> = match encoding {
+        1 => read_floats(&bytes[48..]),
+        3 => bytes[48..].iter().map(|&b| b as f32 / 255.0).collect(),
+        _ => panic!("{} has the unsupported encoding {}", path, encoding),
+    };
+    (resolution, values.into_iter().step_by(channels).collect())
+}
+
+fn read_floats(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+        .collect()
+}
+
+/// Every density grid of the scene, stacked along z in one `sampler3D` as
+/// wide and high as the largest of them.
+pub struct GridAtlas {
+    grids: Vec<DensityGrid>,
+    /// What each grid was loaded from, a raw file read with two resolutions
+    /// being two grids.
+    sources: Vec<(String, Option<[usize; 3]>)>,
+    /// First slice of each grid in the texture.
+    offsets: Vec<usize>,
+    texture: Option<NativeTexture>,
+}
+
+impl Default for GridAtlas {
+    fn default() -> Self {
+        GridAtlas::new()
+    }
+}
+
+impl GridAtlas {
+    pub fn new() -> GridAtlas {
+        GridAtlas {
+            grids: Vec::new(),
+            sources: Vec::new(),
+            offsets: Vec::new(),
+            texture: None,
+        }
+    }
+
+    /// Loads the grid at `path` once per `resolution` and returns its index.
+    /// Objects scale its densities themselves, so they share it whatever
+    /// their other settings.
+    pub fn add(&mut self, path: &str, resolution: Option<[usize; 3]>) -> usize {
+        let source = (path.to_string(), resolution);
+        if let Some(index) = self.sources.iter().position(|s| *s == source) {
+            return index;
+        }
+        let grid = DensityGrid::load(path, resolution);
+        let offset = self.grids.last().map_or(0, |last| {
+            self.offsets[self.grids.len() - 1] + last.resolution[2]
+        });
+        self.grids.push(grid);
+        self.sources.push(source);
+        self.offsets.push(offset);
+        self.grids.len() - 1
+    }
+
+    pub fn grid(&self, index: usize) -> &DensityGrid {
+        &self.grids[index]
+    }
+
+    pub fn offset(&self, index: usize) -> usize {
+        self.offsets[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.grids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.grids.is_empty()
+    }
+
+    pub fn set_texture(&mut self, gl: &Context) {
+        let width = self
+            .grids
+            .iter()
+            .map(|g| g.resolution[0])
+            .max()
+            .unwrap_or(1);
+        let height = self
+            .grids
+            .iter()
+            .map(|g| g.resolution[1])
+            .max()
+            .unwrap_or(1);
+        let depth = self
+            .grids
+            .iter()
+            .map(|g| g.resolution[2])
+            .sum::<usize>()
+            .max(1);
+        let max_size = unsafe { gl.get_parameter_i32(MAX_3D_TEXTURE_SIZE) }.max(0) as usize;
+        if width.max(height).max(depth) > max_size {
+            panic!(
+                "The density grids of the scene need a {}x{}x{} texture, more than the \
+                 {} texels per side OpenGL allows",
+                width, height, depth, max_size
+            );
+        }
+        let mut data = vec![0.0f32; width * height * depth];
+        for (grid, offset) in self.grids.iter().zip(&self.offsets) {
+            let [grid_width, grid_height, grid_depth] = grid.resolution;
+            for z in 0..grid_depth {
+                for y in 0..grid_height {
+                    let source = (z * grid_height + y) * grid_width;
+                    let target = ((offset + z) * height + y) * width;
+                    data[target..target + grid_width]
+                        .copy_from_slice(&grid.data[source..source + grid_width]);
+                }
+            }
+        }
+        unsafe {
+            if self.texture.is_none() {
+                self.texture = Some(gl.create_texture().unwrap());
+            }
+            gl.bind_texture(TEXTURE_3D, self.texture);
+            gl.tex_image_3d(
+                TEXTURE_3D,
+                0,
+                R32F as i32,
+                width as i32,
+                height as i32,
+                depth as i32,
+                0,
+                RED,
+                FLOAT,
+                Some(bytemuck::cast_slice(&data)),
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.tex_parameter_i32(TEXTURE_3D, TEXTURE_WRAP_S, CLAMP_TO_EDGE as i32);
+            gl.tex_parameter_i32(TEXTURE_3D, TEXTURE_WRAP_T, CLAMP_TO_EDGE as i32);
+            gl.tex_parameter_i32(TEXTURE_3D, TEXTURE_WRAP_R, CLAMP_TO_EDGE as i32);
+            gl.tex_parameter_i32(TEXTURE_3D, TEXTURE_MIN_FILTER, LINEAR as i32);
+            gl.tex_parameter_i32(TEXTURE_3D, TEXTURE_MAG_FILTER, LINEAR as i32);
+            gl.bind_texture(TEXTURE_3D, None);
+        }
+    }
+
+    pub fn use_texture(&self, gl: &Context, shader: &Shader) {
+        unsafe {
+            gl.active_texture(TEXTURE9);
+            gl.bind_texture(TEXTURE_3D, self.texture);
+            shader.set_int(gl, "densityGrids", 9);
+            shader.set_int(gl, "gridsNum", self.grids.len() as i32);
+        }
+    }
+
+    pub fn delete_texture(&self, gl: &Context) {
+        if let Some(texture) = self.texture {
+            unsafe {
+                gl.delete_texture(texture);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 3 `.vol` file of `resolution` and `channels`, its bounding
+    /// box zero.
+    fn vol(encoding: i32, resolution: [i32; 3], channels: i32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"VOL\x03".to_vec();
+        for int in [
+            encoding,
+            resolution[0],
+            resolution[1],
+            resolution[2],
+            channels,
+        ] {
+            bytes.extend(int.to_le_bytes());
+        }
+        bytes.extend([0; 24]);
+        bytes.extend(data);
+        bytes
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn temp_file(name: &str, bytes: &[u8]) -> String {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, bytes).unwrap();
+        path.to_str().unwrap().into()
+    }
+
+    #[test]
+    fn reads_vol_floats_and_the_first_channel() {
+        let bytes = vol(1, [2, 1, 1], 2, &floats(&[0.5, 9.0, 0.25, 9.0]));
+        assert_eq!(bytes.len(), 48 + 16);
+        let (resolution, data) = read_vol("grid.vol", &bytes);
+        assert_eq!(resolution, [2, 1, 1]);
+        assert_eq!(data, [0.5, 0.25]);
+    }
+
+    #[test]
+    fn reads_vol_bytes() {
+        let (resolution, data) = read_vol("grid.vol", &vol(3, [1, 1, 3], 1, &[0, 51, 255]));
+        assert_eq!(resolution, [1, 1, 3]);
+        assert_eq!(data, [0.0, 0.2, 1.0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "grid.vol is not a version 3 .vol file")]
+    fn rejects_other_vol_versions() {
+        let mut bytes = vol(1, [1, 1, 1], 1, &floats(&[1.0]));
+        bytes[3] = 2;
+        read_vol("grid.vol", &bytes);
+    }
+
+    #[test]
+    fn loads_vol_files() {
+        let path = temp_file(
+            "grid_test.vol",
+            &vol(1, [2, 2, 1], 1, &floats(&[0.0, 1.0, 2.0, 3.0])),
+        );
+        let grid = DensityGrid::load(&path, None);
+        assert_eq!((grid.resolution, grid.max), ([2, 2, 1], 3.0));
+        // The middle of the grid blends all four voxels.
+        assert_eq!(grid.density([0.5, 0.5, 0.5]), 1.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "has the density -0.5 at voxel 2, densities must not be negative")]
+    fn rejects_negative_densities() {
+        let path = temp_file("negative_grid_test.raw", &floats(&[0.0, 1.0, -0.5, 0.0]));
+        DensityGrid::load(&path, Some([2, 2, 1]));
+    }
+
+    #[test]
+    fn shares_grids_of_the_same_source() {
+        let path = temp_file("shared_grid_test.raw", &floats(&[1.0; 8]));
+        let mut atlas = GridAtlas::new();
+        let cube = atlas.add(&path, Some([2, 2, 2]));
+        let slab = atlas.add(&path, Some([4, 2, 1]));
+        assert_eq!((cube, slab), (0, 1));
+        assert_eq!(atlas.add(&path, Some([2, 2, 2])), cube);
+        assert_eq!(atlas.len(), 2);
+        assert_eq!(atlas.grid(slab).resolution, [4, 2, 1]);
+        assert_eq!((atlas.offset(cube), atlas.offset(slab)), (0, 2));
+    }
+}
@@ -0,0 +1,52 @@
+use glow::Context;
+use glutin::api::egl::context::PossiblyCurrentContext;
+use glutin::api::egl::device::Device;
+use glutin::api::egl::display::Display;
+use glutin::config::{ConfigSurfaceTypes, ConfigTemplateBuilder};
+use glutin::context::{ContextApi, ContextAttributesBuilder, GlProfile, Version};
+use glutin::display::GlDisplay;
+
+/// An OpenGL 3.3 core context without any window or surface, created on the
+/// first EGL device. Keep it alive for as long as the glow context is used.
+pub struct HeadlessContext {
+    _context: PossiblyCurrentContext,
+    _display: Display,
+}
+
+impl HeadlessContext {
+    pub fn new() -> (HeadlessContext, Context) {
+        let device = Device::query_devices()
+            .expect("Failed to query EGL devices")
+            .next()
+            .expect("No EGL device found");
+        let display =
+            unsafe { Display::with_device(&device, None) }.expect("Failed to create EGL display");
+
+        let template = ConfigTemplateBuilder::new()
+            .with_surface_type(ConfigSurfaceTypes::empty())
+            .build();
+        let config = unsafe { display.find_configs(template) }
+            .expect("Failed to query EGL configs")
+            .next()
+            .expect("No surfaceless EGL config found");
+
+        let attributes = ContextAttributesBuilder::new()
+            .with_context_api(ContextApi::OpenGl(Some(Version::new(3, 3))))
+            .with_profile(GlProfile::Core)
+            .build(None);
+        let context = unsafe { display.create_context(&config, &attributes) }
+            .expect("Failed to create OpenGL context")
+            .make_current_surfaceless()
+            .expect("Failed to make OpenGL context current");
+
+        let gl = unsafe { Context::from_loader_function_cstr(|s| display.get_proc_address(s)) };
+
+        (
+            HeadlessContext {
+                _context: context,
+                _display: display,
+            },
+            gl,
+        )
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+use std::fs;
+
+use serde::Deserialize;
+use slint::platform::Key;
+
+use crate::App;
+
+/// Keys bound to every action, read from `bindings.toml`. Keys are written as
+/// the character they type ("w", "1") or by name ("up", "space", "shift",
+/// "f5", see `key_name`).
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Bindings {
+    pub forward: Vec<String>,
+    pub back: Vec<String>,
+    pub strafe_left: Vec<String>,
+    pub strafe_right: Vec<String>,
+    pub up: Vec<String>,
+    pub down: Vec<String>,
+    /// Held to move faster.
+    pub speed_boost: Vec<String>,
+    pub reset_camera: Vec<String>,
+    pub save_image: Vec<String>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let keys = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect();
+        Bindings {
+            forward: keys(&["w", "up"]),
+            back: keys(&["s", "down"]),
+            strafe_left: keys(&["a", "left"]),
+            strafe_right: keys(&["d", "right"]),
+            up: keys(&["e", "space"]),
+            down: keys(&["q"]),
+            speed_boost: keys(&["shift"]),
+            reset_camera: keys(&["r"]),
+            save_image: keys(&["f12"]),
+        }
+    }
+}
+
+impl Bindings {
+    /// Reads the bindings at `path`, actions it leaves out keep their default
+    /// keys and so does everything when the file does not exist.
+    pub fn load(path: &str) -> Bindings {
+        match fs::read_to_string(path) {
+            Ok(source) => Bindings::parse(&source)
+                .unwrap_or_else(|e| panic!("Failed to parse {}: {}", path, e)),
+            Err(_) => Bindings::default(),
+        }
+    }
+
+    /// Parses bindings, lowercasing the keys as `key_name` does.
+    pub fn parse(source: &str) -> Result<Bindings, toml::de::Error> {
+        let mut bindings: Bindings = toml::from_str(source)?;
+        for keys in bindings.actions_mut() {
+            for key in keys.iter_mut() {
+                *key = key.to_lowercase();
+            }
+        }
+        Ok(bindings)
+    }
+
+    fn actions(&self) -> [&Vec<String>; 9] {
+        [
+            &self.forward,
+            &self.back,
+            &self.strafe_left,
+            &self.strafe_right,
+            &self.up,
+            &self.down,
+            &self.speed_boost,
+            &self.reset_camera,
+            &self.save_image,
+        ]
+    }
+
+    fn actions_mut(&mut self) -> [&mut Vec<String>; 9] {
+        [
+            &mut self.forward,
+            &mut self.back,
+            &mut self.strafe_left,
+            &mut self.strafe_right,
+            &mut self.up,
+            &mut self.down,
+            &mut self.speed_boost,
+            &mut self.reset_camera,
+            &mut self.save_image,
+        ]
+    }
+}
+
+/// Turns the key events of the UI into the movement properties and requests
+/// of `App` according to `Bindings`.
+pub struct Input {
+    bindings: Bindings,
+    pressed: HashSet<String>,
+}
+
+impl Input {
+    pub fn new(bindings: Bindings) -> Input {
+        Input {
+            bindings,
+            pressed: HashSet::new(),
+        }
+    }
+
+    /// Handles the press or release of the key typing `text`, returns whether
+    /// it is bound to anything. Letters match whatever the case, so Shift
+    /// going down or up while one is held does not leave it stuck.
+    pub fn key_event(&mut self, app: &App, text: &str, pressed: bool) -> bool {
+        let key = key_name(text);
+        let b = &self.bindings;
+        let bound = b.actions().iter().any(|keys| keys.contains(&key));
+        if !bound {
+            return false;
+        }
+        if pressed {
+            if self.pressed.insert(key.clone()) {
+                if b.reset_camera.contains(&key) {
+                    app.set_reset_camera_requested(true);
+                }
+                if b.save_image.contains(&key) {
+                    app.set_save_requested(true);
+                }
+            }
+        } else {
+            self.pressed.remove(&key);
+        }
+        self.update_movement(app);
+        true
+    }
+
+    /// Lets go of every key, for when the window loses the focus and would
+    /// miss their release.
+    pub fn release_all(&mut self, app: &App) {
+        self.pressed.clear();
+        self.update_movement(app);
+    }
+
+    fn update_movement(&self, app: &App) {
+        let b = &self.bindings;
+        let held = |keys: &[String]| keys.iter().any(|k| self.pressed.contains(k));
+        app.set_movement_forward(held(&b.forward));
+        app.set_movement_back(held(&b.back));
+        app.set_movement_left(held(&b.strafe_left));
+        app.set_movement_right(held(&b.strafe_right));
+        app.set_movement_up(held(&b.up));
+        app.set_movement_down(held(&b.down));
+        app.set_speed_boost(held(&b.speed_boost));
+    }
+}
+
+/// The name of the key typing `text` as used in `bindings.toml`.
+pub fn key_name(text: &str) -> String {
+    const NAMES: [(Key, &str); 23] = [
+        (Key::UpArrow, "up"),
+        (Key::DownArrow, "down"),
+        (Key::LeftArrow, "left"),
+        (Key::RightArrow, "right"),
+        (Key::Space, "space"),
+        (Key::Shift, "shift"),
+        (Key::ShiftR, "shift"),
+        (Key::Control, "control"),
+        (Key::ControlR, "control"),
+        (Key::Alt, "alt"),
+        (Key::AltGr, "alt"),
+        (Key::Meta, "meta"),
+        (Key::MetaR, "meta"),
+        (Key::Return, "return"),
+        (Key::Escape, "escape"),
+        (Key::Tab, "tab"),
+        (Key::Backspace, "backspace"),
+        (Key::Delete, "delete"),
+        (Key::Insert, "insert"),
+        (Key::Home, "home"),
+        (Key::End, "end"),
+        (Key::PageUp, "page_up"),
+        (Key::PageDown, "page_down"),
+    ];
+    let mut chars = text.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if let Some((_, name)) = NAMES.iter().find(|(key, _)| char::from(*key) == c) {
+            return name.to_string();
+        }
+        let f1 = char::from(Key::F1) as u32;
+        if (f1..f1 + 24).contains(&(c as u32)) {
+            return format!("f{}", c as u32 - f1 + 1);
+        }
+    }
+    text.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key) -> String {
+        char::from(key).to_string()
+    }
+
+    #[test]
+    fn keys_are_named_like_the_bindings() {
+        assert_eq!(key_name("w"), "w");
+        assert_eq!(key_name("W"), "w");
+        assert_eq!(key_name("1"), "1");
+        assert_eq!(key_name(&key(Key::UpArrow)), "up");
+        assert_eq!(key_name(&key(Key::Shift)), "shift");
+        assert_eq!(key_name(&key(Key::ShiftR)), "shift");
+        assert_eq!(key_name(&key(Key::PageDown)), "page_down");
+        assert_eq!(key_name(&key(Key::F1)), "f1");
+        assert_eq!(key_name(&key(Key::F12)), "f12");
+        assert_eq!(key_name(&key(Key::Space)), "space");
+    }
+
+    #[test]
+    fn default_bindings() {
+        let bindings = Bindings::default();
+        assert_eq!(bindings.forward, ["w", "up"]);
+        assert_eq!(bindings.speed_boost, ["shift"]);
+        assert_eq!(bindings.save_image, ["f12"]);
+        assert_eq!(key_name(&key(Key::F12)), bindings.save_image[0]);
+    }
+
+    #[test]
+    fn bindings_override_only_the_listed_actions() {
+        let bindings = Bindings::parse("forward = [\"I\", \"Up\"]\nup = []").unwrap();
+        assert_eq!(bindings.forward, ["i", "up"]);
+        assert!(bindings.up.is_empty());
+        assert_eq!(bindings.back, ["s", "down"]);
+        assert_eq!(bindings.reset_camera, ["r"]);
+        assert!(Bindings::parse("forward = \"w\"").is_err());
+    }
+
+    #[test]
+    fn missing_bindings_files_keep_the_defaults() {
+        let path = std::env::temp_dir().join("missing_bindings_test.toml");
+        let bindings = Bindings::load(path.to_str().unwrap());
+        assert_eq!(bindings.strafe_left, ["a", "left"]);
+    }
+}
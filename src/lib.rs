@@ -1,12 +1,29 @@
 slint::include_modules!();
 pub mod aabb;
+pub mod accumulation;
+pub mod animation;
+pub mod bookmarks;
 pub mod bvh;
 pub mod camera;
+pub mod channel;
+pub mod cpu_renderer;
+pub mod denoiser;
+pub mod display;
+pub mod environment;
 pub mod fbo;
+pub mod film;
+pub mod grid;
+pub mod headless;
+pub mod input;
+pub mod material;
 pub mod mesh;
 pub mod model;
 pub mod object;
+pub mod output;
 pub mod renderer;
+pub mod sampler;
+pub mod scene;
 pub mod screen;
 pub mod shader;
+pub mod texture;
 pub mod utils;
@@ -1,10 +1,61 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use glow::*;
+use ray_tracer::bookmarks::Bookmarks;
+use ray_tracer::input::{Bindings, Input};
 use ray_tracer::renderer::Renderer;
+use ray_tracer::scene::Scene;
 use ray_tracer::App;
 use slint::ComponentHandle;
 
+const DEFAULT_SCENE: &str = "scenes/cornell_box.toml";
+const BINDINGS: &str = "bindings.toml";
+
 pub fn main() {
+    let scene_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_SCENE.to_string());
+    let scene = Scene::load(&scene_path);
+    let bookmarks_path = Bookmarks::path_for(&scene_path);
+
     let app = App::new().unwrap();
+    app.set_depths(scene.settings.depths as f32);
+    app.set_sample_counts(scene.settings.samples as f32);
+    app.set_target_samples(scene.settings.target_samples);
+    app.set_face_cull(scene.settings.face_cull);
+    app.set_sampler(scene.settings.sampler as i32);
+    app.set_filter(scene.settings.filter as i32);
+    app.set_adaptive(scene.settings.adaptive.enabled);
+    app.set_adaptive_threshold(scene.settings.adaptive.threshold);
+    app.set_denoise(scene.settings.denoise.enabled);
+    app.set_denoise_iterations(scene.settings.denoise.iterations);
+    app.set_channel(scene.settings.channel as i32);
+    app.set_aperture(scene.camera.aperture);
+    app.set_focus_distance(scene.camera.focus_distance);
+    app.set_blades(scene.camera.blades);
+    let display = &scene.settings.display;
+    app.set_srgb(display.srgb);
+    app.set_tonemapper(display.tonemapper as i32);
+    app.set_exposure(display.exposure);
+
+    let input = Rc::new(RefCell::new(Input::new(Bindings::load(BINDINGS))));
+    app.on_key_event({
+        let app_weak = app.as_weak();
+        let input = input.clone();
+        move |text, pressed| match app_weak.upgrade() {
+            Some(app) => input.borrow_mut().key_event(&app, &text, pressed),
+            None => false,
+        }
+    });
+    app.on_focus_lost({
+        let app_weak = app.as_weak();
+        move || {
+            if let Some(app) = app_weak.upgrade() {
+                input.borrow_mut().release_all(&app);
+            }
+        }
+    });
 
     let mut renderer = None;
 
@@ -21,7 +72,12 @@ pub fn main() {
 
                         _ => return,
                     };
-                    renderer = Some(Renderer::new(context))
+                    let mut new_renderer = Renderer::new(context, &scene);
+                    if let Some(app) = app_weak.upgrade() {
+                        new_renderer.set_bookmarks(&app, Bookmarks::load(&bookmarks_path));
+                        app.set_bvh_stats(new_renderer.bvh_stats().to_string().into());
+                    }
+                    renderer = Some(new_renderer)
                 },
                 slint::RenderingState::BeforeRendering => {
                     if let (Some(renderer), Some(app)) = (renderer.as_mut(), app_weak.upgrade()) {
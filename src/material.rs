@@ -0,0 +1,91 @@
+use serde::de::Error;
+use serde::{Deserialize, Deserializer};
+
+/// Texels per entry of the material table that follows the primitives in
+/// `vertices_texture`: (metallic, roughness, specular) and (ior, 0, 0).
+pub const MATERIAL_STRIDE: i32 = 2;
+
+/// Parameters of the `pbr` material, a GGX microfacet metallic-roughness
+/// model. The base color is the albedo of the object, or its diffuse texture.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PbrMaterial {
+    pub metallic: f32,
+    pub roughness: f32,
+    /// Scales the reflectance of non-metals, 0.5 gives exactly the one of `ior`.
+    pub specular: f32,
+    pub ior: f32,
+}
+
+impl Default for PbrMaterial {
+    fn default() -> Self {
+        PbrMaterial {
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            ior: 1.5,
+        }
+    }
+}
+
+impl PbrMaterial {
+    pub fn to_texels(self) -> [f32; 6] {
+        [
+            self.metallic,
+            self.roughness,
+            self.specular,
+            self.ior,
+            0.0,
+            0.0,
+        ]
+    }
+}
+
+/// Texels per entry of the medium table that follows the material table:
+/// absorption, scattering and (anisotropy, 0, 0).
+pub const MEDIUM_STRIDE: i32 = 3;
+
+/// A homogeneous participating medium filling the inside of a closed object.
+/// Rays entering the object through a `dielectric` or `interface` surface
+/// travel through it until they leave again, so media nest like the objects
+/// do. All zero is a vacuum that clears the media around it.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct Medium {
+    /// Per color channel and unit of length.
+    pub absorption: [f32; 3],
+    /// Per color channel and unit of length.
+    pub scattering: [f32; 3],
+    /// Henyey-Greenstein asymmetry of the phase function, from -1 for back
+    /// scattering over 0 for isotropic to 1 for forward scattering, both
+    /// excluded.
+    #[serde(deserialize_with = "deserialize_anisotropy")]
+    pub anisotropy: f32,
+}
+
+/// Rejects Henyey-Greenstein asymmetries of magnitude one or more, where the
+/// phase function is no longer finite.
+pub fn deserialize_anisotropy<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f32, D::Error> {
+    let g = f32::deserialize(deserializer)?;
+    if g.abs() < 1.0 {
+        Ok(g)
+    } else {
+        Err(D::Error::custom(format!(
+            "anisotropy must be between -1 and 1, got {}",
+            g
+        )))
+    }
+}
+
+impl Medium {
+    pub fn to_texels(self) -> [f32; 9] {
+        let [a, b, c] = self.absorption;
+        let [d, e, f] = self.scattering;
+        [a, b, c, d, e, f, self.anisotropy, 0.0, 0.0]
+    }
+
+    /// Absorption plus scattering.
+    pub fn extinction(&self) -> [f32; 3] {
+        [0, 1, 2].map(|i| self.absorption[i] + self.scattering[i])
+    }
+}
@@ -1,5 +1,4 @@
 use bytemuck::{Pod, Zeroable};
-use glow::*;
 
 #[repr(C)]
 #[derive(Copy, Clone, Pod, Zeroable)]
@@ -25,7 +24,7 @@ impl Default for Vertex {
 
 #[derive(Clone)]
 pub struct Texture {
-    pub id: NativeTexture,
+    pub layer: i32,
     pub type_: String,
     pub path: String,
 }
@@ -34,16 +33,21 @@ pub struct Mesh {
     pub vertices: Vec<Vertex>,
     pub textures: Vec<Texture>,
     pub indices: Vec<u32>,
+    pub albedo: Option<[f32; 3]>,
 }
 
 impl Mesh {
-    pub fn new(vertices: Vec<Vertex>, textures: Vec<Texture>, indices: Vec<u32>) -> Mesh {
-        let mesh = Mesh {
+    pub fn new(
+        vertices: Vec<Vertex>,
+        textures: Vec<Texture>,
+        indices: Vec<u32>,
+        albedo: Option<[f32; 3]>,
+    ) -> Mesh {
+        Mesh {
             vertices,
             textures,
             indices,
-        };
-
-        mesh
+            albedo,
+        }
     }
 }
@@ -1,127 +1,134 @@
-use cgmath::Vector3;
-use glow::*;
-use image::DynamicImage::{ImageLuma8, ImageLumaA8, ImageRgb8, ImageRgba8};
+use bevy_mikktspace::Geometry;
+use cgmath::{vec3, vec4, InnerSpace, Matrix, SquareMatrix, Vector3};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
 use tobj::{load_obj, GPU_LOAD_OPTIONS};
 
 use crate::mesh::{Mesh, Texture, Vertex};
 use crate::object::Object;
-use crate::shader::Shader;
-use crate::utils::{trans, translated, translated_normal, MATERIAL};
+use crate::texture::{Encoding, TextureArray};
+use crate::utils::{trans, translated, MATERIAL};
+
+/// Where the shading normals of a model come from.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NormalMode {
+    /// The normals of the OBJ file, or smooth ones when it has none.
+    #[default]
+    File,
+    /// Area weighted vertex normals, ignoring the file.
+    Smooth,
+    /// The geometric normal of each triangle.
+    Flat,
+}
 
 #[derive(Default)]
 pub struct Model {
     pub mesh: Vec<Mesh>,
-    pub texture_loaded: Vec<Texture>,
     directory: String,
+    normals: NormalMode,
+    normal_map_gamma: f32,
 }
 
 impl Model {
-    pub unsafe fn new(gl: &Context, path: &str) -> Model {
-        let mut model = Model::default();
-        model.load_model(gl, path);
+    /// Loads the OBJ file at `path`, adding its material textures to
+    /// `textures`, its normal maps raised to `normal_map_gamma`.
+    pub fn new(
+        path: &str,
+        textures: &mut TextureArray,
+        normals: NormalMode,
+        normal_map_gamma: f32,
+    ) -> Model {
+        assert!(
+            normal_map_gamma > 0.0,
+            "The normal map gamma of {} must be positive",
+            path
+        );
+        let mut model = Model {
+            normals,
+            normal_map_gamma,
+            ..Model::default()
+        };
+        model.load_model(path, textures);
         model
     }
 
     pub fn get_primitives(
         &self,
         primitives: &mut Vec<Object>,
-        transform: &Vec<Vector3<f32>>,
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
         constant: f32,
         material: MATERIAL,
     ) {
-        let mut vertices = Vec::new();
-        let mut vertices_num = 0;
-        let mut texture_index = [-1.0, -1.0, -1.0, -1.0];
         let model = trans(transform[0], transform[1], transform[2]);
-        for (_i, mesh) in self.mesh.iter().enumerate() {
-            for (_i, texture) in mesh.textures.iter().enumerate() {
-                let name = &texture.type_;
-                match name.as_str() {
-                    "diffuse_texture" => {
-                        texture_index[0] += 1.0;
-                    }
-                    "specular_texture" => {
-                        texture_index[1] += 1.0;
-                    }
-                    "normal_texture" => {
-                        texture_index[2] += 1.0;
-                    }
-                    "height_texture" => {
-                        texture_index[3] += 1.0;
+        let normal_matrix = model.invert().unwrap().transpose();
+        let transform_normal = |n: [f32; 3]| {
+            let n = normal_matrix * vec4(n[0], n[1], n[2], 0.0);
+            let n = vec3(n.x, n.y, n.z).normalize();
+            [n.x, n.y, n.z]
+        };
+        let transform_tangent = |t: [f32; 3]| {
+            let t = model * vec4(t[0], t[1], t[2], 0.0);
+            [t.x, t.y, t.z]
+        };
+        for mesh in &self.mesh {
+            let layer = |type_: &str| {
+                mesh.textures
+                    .iter()
+                    .find(|t| t.type_ == type_)
+                    .map_or(-1.0, |t| t.layer as f32)
+            };
+            let texture_layers = [
+                layer("diffuse_texture"),
+                layer("specular_texture"),
+                layer("normal_texture"),
+            ];
+            let albedo = mesh.albedo.unwrap_or(albedo);
+            for face in mesh.indices.chunks_exact(3) {
+                let corners = [0, 1, 2].map(|k| &mesh.vertices[face[k] as usize]);
+                let positions = corners.map(|v| translated(&v.position, &model));
+                let flat_normal = if self.normals == NormalMode::Flat {
+                    let [a, b, c] = positions.map(Vector3::from);
+                    let n = (b - a).cross(c - a);
+                    if n.magnitude2() == 0.0 {
+                        continue;
                     }
-                    _ => {}
+                    let n = n.normalize();
+                    Some([n.x, n.y, n.z])
+                } else {
+                    None
                 };
-            }
-            for vertex in &mesh.vertices {
-                let tex_coord = [vertex.tex_coord[0], vertex.tex_coord[1], 0.0];
-
-                vertices.push(vertex.position);
-                vertices.push(vertex.normal);
-                vertices.push(tex_coord);
-                if vertices_num % 3 == 2 {
-                    let mut vertex = Vec::new();
-                    for i in 0..3 {
-                        vertex.push(translated(
-                            &vertices[(vertices_num - (2 - i)) as usize * 3],
-                            &model,
-                        ));
-                        vertex.push(translated_normal(
-                            &vertices[(vertices_num - (2 - i)) as usize * 3 + 1],
-                            &model,
-                        ));
-                        vertex.push(vertices[(vertices_num - (2 - i)) as usize * 3 + 2]);
-                    }
-                    let texture_index_temp = [texture_index[0], texture_index[1], texture_index[2]];
-                    vertex.push(texture_index_temp);
-                    vertex.push([texture_index[3], 0.0, 0.0]);
-                    let triangle = Object::new_mesh(vertex, constant, material.clone());
-                    primitives.push(triangle);
+                // Per corner the position, the normal and (u, v, bitangent
+                // sign), then the texture layers and the tangents.
+                let mut vertex = Vec::with_capacity(13);
+                for (position, corner) in positions.iter().zip(corners) {
+                    let [u, v] = corner.tex_coord;
+                    let normal = flat_normal.unwrap_or_else(|| transform_normal(corner.normal));
+                    // Taken after the transform, as a mirroring one flips the
+                    // bitangents.
+                    let bitangent =
+                        Vector3::from(normal).cross(transform_tangent(corner.tangent).into());
+                    let sign = if bitangent.dot(transform_tangent(corner.bi_tangent).into()) < 0.0 {
+                        -1.0
+                    } else {
+                        1.0
+                    };
+                    vertex.push(*position);
+                    vertex.push(normal);
+                    vertex.push([u, v, sign]);
                 }
-                vertices_num += 1;
-            }
-        }
-    }
-
-    pub fn use_textures(&self, gl: &Context, shader: &Shader) {
-        let mut diffuse_nr = -1;
-        let mut specular_nr = -1;
-        let mut normal_nr = -1;
-        let mut height_nr = -1;
-
-        for (i, texture) in self.texture_loaded.iter().enumerate() {
-            unsafe {
-                gl.active_texture(TEXTURE3 + i as u32);
-                gl.bind_texture(TEXTURE_2D, Some(texture.id));
-            }
-            let name = &texture.type_;
-            let number = match name.as_str() {
-                "diffuse_texture" => {
-                    diffuse_nr += 1;
-                    diffuse_nr
-                }
-                "specular_texture" => {
-                    specular_nr += 1;
-                    specular_nr
-                }
-                "normal_texture" => {
-                    normal_nr += 1;
-                    normal_nr
-                }
-                "height_texture" => {
-                    height_nr += 1;
-                    height_nr
-                }
-                _ => {
-                    panic!("unknown texture type");
+                vertex.push(texture_layers);
+                for corner in corners {
+                    vertex.push(transform_tangent(corner.tangent));
                 }
-            };
-
-            shader.set_int(gl, &format!("{}{}", name, number), (i + 3) as i32);
+                primitives.push(Object::new_mesh(vertex, albedo, constant, material.clone()));
+            }
         }
     }
 
-    unsafe fn load_model(&mut self, gl: &Context, path: &str) {
+    fn load_model(&mut self, path: &str, textures: &mut TextureArray) {
         let path = Path::new(path);
 
         self.directory = path
@@ -131,113 +138,418 @@ impl Model {
             .unwrap()
             .into();
 
-        let obj = load_obj(path, &GPU_LOAD_OPTIONS);
-        let (models, materials) = obj.unwrap();
-        let materials = materials.unwrap();
+        let (models, materials) = load_obj(path, &GPU_LOAD_OPTIONS)
+            .unwrap_or_else(|e| panic!("Failed to load {}: {}", path.display(), e));
+        let materials = materials.unwrap_or_default();
 
         for model in models {
             let mesh = &model.mesh;
             let num_vertices = mesh.positions.len() / 3;
 
             let mut vertices: Vec<Vertex> = Vec::with_capacity(num_vertices);
-            let indices = mesh.indices.clone();
+            let mut indices = mesh.indices.clone();
 
             let (p, n, t) = (&mesh.positions, &mesh.normals, &mesh.texcoords);
             for i in 0..num_vertices {
-                vertices.push(Vertex {
+                let mut vertex = Vertex {
                     position: [p[i * 3], p[i * 3 + 1], p[i * 3 + 2]],
-                    normal: [n[i * 3], n[i * 3 + 1], n[i * 3 + 2]],
-                    tex_coord: [t[i * 2], t[i * 2 + 1]],
                     ..Vertex::default()
-                })
+                };
+                if n.len() >= num_vertices * 3 {
+                    vertex.normal = [n[i * 3], n[i * 3 + 1], n[i * 3 + 2]];
+                }
+                if t.len() >= num_vertices * 2 {
+                    vertex.tex_coord = [t[i * 2], t[i * 2 + 1]];
+                }
+                vertices.push(vertex);
+            }
+            let needs_normals = n.len() < num_vertices * 3 || self.normals == NormalMode::Smooth;
+            if needs_normals && self.normals != NormalMode::Flat {
+                smooth_normals(&mut vertices, &indices);
+            }
+            if t.len() >= num_vertices * 2 {
+                let flat = self.normals == NormalMode::Flat;
+                (vertices, indices) = generate_tangents(&vertices, &indices, flat);
             }
-            let mut textures: Vec<Texture> = Vec::new();
 
-            if let Some(material_id) = mesh.material_id {
-                let material = &materials[material_id];
+            let mut mesh_textures: Vec<Texture> = Vec::new();
+            let mut albedo = None;
+
+            if let Some(material) = mesh.material_id.and_then(|id| materials.get(id)) {
+                albedo = material.diffuse;
 
                 if let Some(path) = &material.diffuse_texture {
-                    let texture = self.load_material_texture(gl, path, "diffuse_texture");
-                    textures.push(texture);
+                    mesh_textures.push(self.load_material_texture(
+                        textures,
+                        path,
+                        "diffuse_texture",
+                    ));
                 }
 
                 if let Some(path) = &material.specular_texture {
-                    let texture = self.load_material_texture(gl, path, "specular_texture");
-                    textures.push(texture);
+                    mesh_textures.push(self.load_material_texture(
+                        textures,
+                        path,
+                        "specular_texture",
+                    ));
                 }
 
                 if let Some(path) = &material.normal_texture {
-                    let texture = self.load_material_texture(gl, path, "normal_texture");
-                    textures.push(texture);
+                    mesh_textures.push(self.load_material_texture(
+                        textures,
+                        path,
+                        "normal_texture",
+                    ));
                 }
             }
-            self.mesh.push(Mesh::new(vertices, textures, indices))
+            self.mesh
+                .push(Mesh::new(vertices, mesh_textures, indices, albedo))
         }
     }
 
-    fn load_material_texture(&mut self, gl: &Context, path: &str, type_name: &str) -> Texture {
-        let texture = self.texture_loaded.iter().find(|t| t.path == path);
-        if let Some(texture) = texture {
-            return texture.clone();
-        }
-        let texture = Texture {
-            id: unsafe { texture_from_file(gl, path, &self.directory) },
+    fn load_material_texture(
+        &self,
+        textures: &mut TextureArray,
+        path: &str,
+        type_name: &str,
+    ) -> Texture {
+        let file_path = format!("{}/{}", self.directory, path);
+        let encoding = match type_name {
+            "diffuse_texture" => Encoding::Srgb,
+            "normal_texture" => Encoding::Gamma(self.normal_map_gamma),
+            _ => Encoding::Linear,
+        };
+        let layer = textures.add(&file_path, encoding);
+        Texture {
+            layer,
             type_: type_name.into(),
-            path: path.into(),
+            path: file_path,
+        }
+    }
+}
+
+/// Replaces the vertex normals by the sum of the adjacent face normals, which
+/// weights every face by its area.
+fn smooth_normals(vertices: &mut [Vertex], indices: &[u32]) {
+    let mut normals = vec![vec3(0.0, 0.0, 0.0); vertices.len()];
+    for face in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|k| Vector3::from(vertices[face[k] as usize].position));
+        let n = (b - a).cross(c - a);
+        for &i in face {
+            normals[i as usize] += n;
+        }
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        if normal.magnitude2() > 0.0 {
+            let n = normal.normalize();
+            vertex.normal = [n.x, n.y, n.z];
+        }
+    }
+}
+
+/// MikkTSpace tangents, the ones normal maps are usually baked against, of
+/// the faces `indices` of `vertices` with their vertex normals or, if `flat`,
+/// the normal of each face, which is what those faces are shaded with. The
+/// bitangent is the cross product of that normal and the tangent, negated
+/// where the texture coordinates are mirrored. Vertices whose corners get
+/// different tangents are split, so the returned mesh has one per tangent.
+fn generate_tangents(vertices: &[Vertex], indices: &[u32], flat: bool) -> (Vec<Vertex>, Vec<u32>) {
+    let mut corners = Corners {
+        vertices,
+        indices,
+        flat,
+        tangents: vec![[0.0; 4]; indices.len()],
+    };
+    if !bevy_mikktspace::generate_tangents(&mut corners) {
+        corners.tangents.fill([0.0; 4]);
+    }
+    // MikkTSpace leaves tangents out of the surface where the texture
+    // coordinates are degenerate, and none at all when it fails. Those
+    // corners take the tangent of their triangle instead, so normal maps
+    // never read a zero or skewed frame.
+    for corner in 0..indices.len() {
+        let [x, y, z, sign] = corners.tangents[corner];
+        let n = Vector3::from(corners.normal(corner / 3, corner % 3));
+        let n = if n.magnitude2() > 0.0 {
+            n.normalize()
+        } else {
+            n
+        };
+        let t = vec3(x, y, z) - n * n.dot(vec3(x, y, z));
+        corners.tangents[corner] = if t.magnitude2() > 1E-12 && t.magnitude2().is_finite() {
+            let t = t.normalize();
+            [t.x, t.y, t.z, sign]
+        } else {
+            corners.triangle_tangent(corner / 3, corner % 3)
         };
-        self.texture_loaded.push(texture.clone());
-        texture
     }
 
-    pub fn delete(&self, gl: &Context) {
-        for texture in &self.texture_loaded {
-            unsafe {
-                gl.delete_texture(texture.id);
-            }
+    let mut split = HashMap::new();
+    let mut tangent_vertices = Vec::with_capacity(vertices.len());
+    let mut tangent_indices = Vec::with_capacity(indices.len());
+    for (corner, &index) in indices.iter().enumerate() {
+        let tangent = corners.tangents[corner];
+        let key = (index, tangent.map(f32::to_bits));
+        let index = *split.entry(key).or_insert_with(|| {
+            let [x, y, z, sign] = tangent;
+            let n = Vector3::from(corners.normal(corner / 3, corner % 3));
+            let t = vec3(x, y, z);
+            let b = n.cross(t) * sign;
+            tangent_vertices.push(Vertex {
+                tangent: [t.x, t.y, t.z],
+                bi_tangent: [b.x, b.y, b.z],
+                ..vertices[index as usize]
+            });
+            tangent_vertices.len() as u32 - 1
+        });
+        tangent_indices.push(index);
+    }
+    (tangent_vertices, tangent_indices)
+}
+
+/// The triangles of a mesh as `bevy_mikktspace` reads them, collecting a
+/// tangent and bitangent sign per corner.
+struct Corners<'a> {
+    vertices: &'a [Vertex],
+    indices: &'a [u32],
+    flat: bool,
+    tangents: Vec<[f32; 4]>,
+}
+
+impl Corners<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> &Vertex {
+        &self.vertices[self.indices[face * 3 + vert] as usize]
+    }
+
+    /// The direction u grows in across a triangle, made orthogonal to the
+    /// normal at `vert`, with the bitangent sign of its texture winding. Any
+    /// direction in the surface when the texture coordinates don't span it.
+    fn triangle_tangent(&self, face: usize, vert: usize) -> [f32; 4] {
+        let [p0, p1, p2] = [0, 1, 2].map(|k| Vector3::from(self.position(face, k)));
+        let [t0, t1, t2] = [0, 1, 2].map(|k| self.tex_coord(face, k));
+        let (e1, e2) = (p1 - p0, p2 - p0);
+        let [du1, dv1, du2, dv2] = [t1[0] - t0[0], t1[1] - t0[1], t2[0] - t0[0], t2[1] - t0[1]];
+        let det = du1 * dv2 - du2 * dv1;
+        let n = Vector3::from(self.normal(face, vert));
+        let n = if n.magnitude2() > 0.0 {
+            n.normalize()
+        } else {
+            vec3(0.0, 0.0, 1.0)
+        };
+        let orthogonal = |v: Vector3<f32>| v - n * n.dot(v);
+        let t = orthogonal((e1 * dv2 - e2 * dv1) * det.signum());
+        if det != 0.0 && t.magnitude2() > 0.0 && t.magnitude2().is_finite() {
+            let t = t.normalize();
+            return [t.x, t.y, t.z, det.signum()];
         }
+        let axis = if n.x.abs() < 0.9 {
+            vec3(1.0, 0.0, 0.0)
+        } else {
+            vec3(0.0, 1.0, 0.0)
+        };
+        let t = orthogonal(axis).normalize();
+        [t.x, t.y, t.z, 1.0]
     }
 }
-unsafe fn texture_from_file(gl: &Context, path: &str, directory: &str) -> NativeTexture {
-    let file_path = format!("{}/{}", directory, path);
 
-    let texture_id = load_texture(gl, file_path.as_str());
+impl Geometry for Corners<'_> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.vertex(face, vert).position
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        if !self.flat {
+            return self.vertex(face, vert).normal;
+        }
+        let [a, b, c] = [0, 1, 2].map(|k| Vector3::from(self.position(face, k)));
+        let n = (b - a).cross(c - a);
+        if n.magnitude2() == 0.0 {
+            return [0.0; 3];
+        }
+        n.normalize().into()
+    }
 
-    texture_id
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        self.vertex(face, vert).tex_coord
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.tangents[face * 3 + vert] = tangent;
+    }
 }
 
-pub unsafe fn load_texture(gl: &Context, file_path: &str) -> NativeTexture {
-    let texture_id = gl.create_texture().unwrap();
-
-    gl.bind_texture(TEXTURE_2D, Some(texture_id));
-    gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_S, REPEAT as i32);
-    gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_WRAP_T, REPEAT as i32);
-    gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, LINEAR_MIPMAP_LINEAR as i32);
-    gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, LINEAR as i32);
-
-    let img = image::open(&Path::new(file_path)).expect("Failed to load texture");
-    let format = match img {
-        ImageLuma8(_) => RED,
-        ImageLumaA8(_) => RG,
-        ImageRgb8(_) => RGB,
-        ImageRgba8(_) => RGBA,
-        _ => RGB,
-    };
-    let img = img.flipv();
-    let data = img.clone().into_bytes();
-
-    gl.tex_image_2d(
-        TEXTURE_2D,
-        0,
-        format as i32,
-        img.width() as i32,
-        img.height() as i32,
-        0,
-        format,
-        UNSIGNED_BYTE,
-        Some(&data),
-    );
-    gl.generate_mipmap(TEXTURE_2D);
-
-    gl.bind_texture(TEXTURE_2D, None);
-    texture_id
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The corners of the unit square from `x` along x and y, facing +z,
+    /// with the u texture coordinate `u` at its left and right edges and v
+    /// along y.
+    fn quad(x: f32, u: [f32; 2]) -> Vec<Vertex> {
+        [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]
+            .map(|(dx, y)| Vertex {
+                position: [x + dx, y, 0.0],
+                normal: [0.0, 0.0, 1.0],
+                tex_coord: [u[dx as usize], y],
+                ..Vertex::default()
+            })
+            .to_vec()
+    }
+
+    const QUAD_INDICES: [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        let d = Vector3::from(a) - Vector3::from(b);
+        assert!(d.magnitude() < 1E-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn tangents_follow_u_and_bitangents_v() {
+        let (vertices, indices) = generate_tangents(&quad(0.0, [0.0, 1.0]), &QUAD_INDICES, false);
+        assert_eq!((vertices.len(), indices.len()), (4, 6));
+        for vertex in vertices {
+            assert_close(vertex.tangent, [1.0, 0.0, 0.0]);
+            assert_close(vertex.bi_tangent, [0.0, 1.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn mirrored_texture_coordinates_flip_the_tangent() {
+        let (vertices, _) = generate_tangents(&quad(0.0, [1.0, 0.0]), &QUAD_INDICES, false);
+        for vertex in vertices {
+            assert_close(vertex.tangent, [-1.0, 0.0, 0.0]);
+            // v still runs along y, against the cross product of normal and
+            // tangent.
+            assert_close(vertex.bi_tangent, [0.0, 1.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn seams_between_mirrored_halves_are_split() {
+        // Two quads sharing their edge at x = 1, the right one mirroring the
+        // texture of the left.
+        let mut vertices = quad(0.0, [0.0, 1.0]);
+        vertices.extend([quad(1.0, [1.0, 0.0])[1], quad(1.0, [1.0, 0.0])[2]]);
+        let indices = [0, 1, 2, 0, 2, 3, 1, 4, 5, 1, 5, 2];
+        let (vertices, indices) = generate_tangents(&vertices, &indices, false);
+        assert_eq!(vertices.len(), 8);
+        for (face, corners) in indices.chunks_exact(3).enumerate() {
+            let u = if face < 2 { 1.0 } else { -1.0 };
+            for &corner in corners {
+                assert_close(vertices[corner as usize].tangent, [u, 0.0, 0.0]);
+                assert_close(vertices[corner as usize].bi_tangent, [0.0, 1.0, 0.0]);
+            }
+        }
+    }
+
+    #[test]
+    fn triangle_tangents_match_mikktspace() {
+        for u in [[0.0, 1.0], [1.0, 0.0]] {
+            let vertices = quad(0.0, u);
+            let (mikktspace, _) = generate_tangents(&vertices, &QUAD_INDICES, false);
+            let corners = Corners {
+                vertices: &vertices,
+                indices: &QUAD_INDICES,
+                flat: false,
+                tangents: Vec::new(),
+            };
+            for corner in 0..6 {
+                let [x, y, z, sign] = corners.triangle_tangent(corner / 3, corner % 3);
+                let vertex = mikktspace[QUAD_INDICES[corner] as usize];
+                assert_close([x, y, z], vertex.tangent);
+                let b = vec3(0.0, 0.0, 1.0).cross(vec3(x, y, z)) * sign;
+                assert_close(b.into(), vertex.bi_tangent);
+            }
+        }
+    }
+
+    #[test]
+    fn degenerate_texture_coordinates_still_give_tangents() {
+        let mut vertices = quad(0.0, [0.5, 0.5]);
+        for vertex in &mut vertices {
+            vertex.tex_coord = [0.5, 0.5];
+            vertex.normal = [0.6, 0.0, 0.8];
+        }
+        for flat in [false, true] {
+            let (vertices, _) = generate_tangents(&vertices, &QUAD_INDICES, flat);
+            for vertex in vertices {
+                let n = if flat {
+                    vec3(0.0, 0.0, 1.0)
+                } else {
+                    Vector3::from(vertex.normal)
+                };
+                let (t, b) = (
+                    Vector3::from(vertex.tangent),
+                    Vector3::from(vertex.bi_tangent),
+                );
+                assert!((t.magnitude() - 1.0).abs() < 1E-5, "{:?}", t);
+                assert!((b.magnitude() - 1.0).abs() < 1E-5, "{:?}", b);
+                assert!(t.dot(n).abs() < 1E-5 && b.dot(n).abs() < 1E-5);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_tangents_lie_in_the_face() {
+        let mut vertices = quad(0.0, [0.0, 1.0]);
+        for vertex in &mut vertices {
+            vertex.normal = [0.6, 0.0, 0.8];
+        }
+        let (flat, _) = generate_tangents(&vertices, &QUAD_INDICES, true);
+        for vertex in flat {
+            assert_close(vertex.tangent, [1.0, 0.0, 0.0]);
+            assert_close(vertex.bi_tangent, [0.0, 1.0, 0.0]);
+        }
+        let (smooth, _) = generate_tangents(&vertices, &QUAD_INDICES, false);
+        for vertex in smooth {
+            assert_close(vertex.tangent, [0.8, 0.0, -0.6]);
+        }
+    }
+
+    #[test]
+    fn bitangents_follow_v_through_mirroring_transforms() {
+        let (vertices, indices) = generate_tangents(&quad(0.0, [0.0, 1.0]), &QUAD_INDICES, false);
+        for normals in [NormalMode::File, NormalMode::Flat] {
+            let model = Model {
+                mesh: vec![Mesh::new(vertices.clone(), vec![], indices.clone(), None)],
+                normals,
+                ..Model::default()
+            };
+            for scale in [1.0, -1.0] {
+                let mut primitives = Vec::new();
+                let transform = [
+                    vec3(0.0, 0.0, 0.0),
+                    vec3(0.0, 0.0, 0.0),
+                    vec3(scale, 1.0, 1.0),
+                ];
+                model.get_primitives(
+                    &mut primitives,
+                    [1.0; 3],
+                    &transform,
+                    0.0,
+                    MATERIAL::DIFFUSE,
+                );
+                assert_eq!(primitives.len(), 2);
+                for primitive in primitives {
+                    // The position, normal and (u, v, sign) of each corner,
+                    // the texture layers and the tangents.
+                    let v = &primitive.vertices;
+                    for corner in 0..3 {
+                        let n = Vector3::from(v[corner * 3 + 1]);
+                        let t = Vector3::from(v[10 + corner]);
+                        let b = n.cross(t) * v[corner * 3 + 2][2];
+                        assert_close(b.into(), [0.0, 1.0, 0.0]);
+                    }
+                }
+            }
+        }
+    }
 }
@@ -1,5 +1,9 @@
-use cgmath::Vector3;
+use cgmath::{InnerSpace, Matrix4, SquareMatrix, Vector3};
+use std::f32::consts::PI;
 
+use crate::grid::DensityGrid;
+use crate::material::{Medium, PbrMaterial};
+use crate::texture::TextureSet;
 use crate::utils::{trans, translated, translated_normal, MATERIAL, SHAPE};
 
 #[derive(Clone)]
@@ -11,6 +15,14 @@ pub struct Object {
     pub albedo: [f32; 3],
     pub constant: f32,
     pub material: MATERIAL,
+    pub pbr: PbrMaterial,
+    /// What fills the inside of the scene object.
+    pub medium: Option<Medium>,
+    /// Textures of the scene object overriding its albedo, roughness,
+    /// emission and normal.
+    pub textures: TextureSet,
+    /// Index of the scene object it was built from.
+    pub object: usize,
 }
 
 impl Object {
@@ -18,7 +30,7 @@ impl Object {
         center: [f32; 3],
         radius: f32,
         albedo: [f32; 3],
-        transform: &Vec<Vector3<f32>>,
+        transform: &[Vector3<f32>],
         constant: f32,
         material: MATERIAL,
     ) -> Object {
@@ -30,25 +42,38 @@ impl Object {
             albedo,
             constant,
             material,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
         }
     }
 
-    pub fn new_mesh(vertices: Vec<[f32; 3]>, constant: f32, material: MATERIAL) -> Object {
+    pub fn new_mesh(
+        vertices: Vec<[f32; 3]>,
+        albedo: [f32; 3],
+        constant: f32,
+        material: MATERIAL,
+    ) -> Object {
         Object {
             shape: SHAPE::RT_MESH,
             vertices,
             center: [0.0, 0.0, 0.0],
             radius: 0.0,
-            albedo: [0.0, 0.0, 0.0],
+            albedo,
             constant,
             material,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
         }
     }
 
     pub fn new_triangle(
-        vertices: &Vec<[f32; 3]>,
+        vertices: &[[f32; 3]],
         albedo: [f32; 3],
-        transform: &Vec<Vector3<f32>>,
+        transform: &[Vector3<f32>],
         constant: f32,
         material: MATERIAL,
     ) -> Object {
@@ -67,13 +92,17 @@ impl Object {
             albedo,
             constant,
             material,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
         }
     }
 
     pub fn new_rectangle(
-        vertices: &Vec<[f32; 3]>,
+        vertices: &[[f32; 3]],
         albedo: [f32; 3],
-        transform: &Vec<Vector3<f32>>,
+        transform: &[Vector3<f32>],
         constant: f32,
         material: MATERIAL,
     ) -> Object {
@@ -93,13 +122,17 @@ impl Object {
             albedo,
             constant,
             material,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
         }
     }
 
     pub fn new_box(
-        vertices: &Vec<[f32; 3]>,
+        vertices: &[[f32; 3]],
         albedo: [f32; 3],
-        transform: &Vec<Vector3<f32>>,
+        transform: &[Vector3<f32>],
         constant: f32,
         material: MATERIAL,
     ) -> Vec<Object> {
@@ -122,6 +155,10 @@ impl Object {
                 albedo,
                 constant,
                 material: material.clone(),
+                pbr: PbrMaterial::default(),
+                medium: None,
+                textures: TextureSet::default(),
+                object: 0,
             };
             objects.push(object);
         }
@@ -129,9 +166,9 @@ impl Object {
     }
 
     pub fn new_box_volume(
-        vertices: &Vec<[f32; 3]>,
+        vertices: &[[f32; 3]],
         albedo: [f32; 3],
-        transform: &Vec<Vector3<f32>>,
+        transform: &[Vector3<f32>],
         constant: f32,
         material: MATERIAL,
     ) -> Object {
@@ -153,16 +190,183 @@ impl Object {
                 &trans(transform[0], transform[1], transform[2]),
             ),
         ];
-        let object = Object {
+        Object {
             shape: SHAPE::RT_VOLUME,
             vertices,
             center: [0.0, 0.0, 0.0],
             radius: 0.0,
             albedo,
             constant,
-            material: material.clone(),
-        };
+            material,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
+        }
+    }
+
+    /// A disk of `radius` in the xz plane around the origin, facing up.
+    pub fn new_disk(
+        radius: f32,
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
+        constant: f32,
+        material: MATERIAL,
+    ) -> Object {
+        let size = [radius, 0.0, 0.0];
+        Object::new_quadric(SHAPE::RT_DISK, size, albedo, transform, constant, material)
+    }
 
-        object
+    /// An open cylinder of `radius` around the y axis from the origin up to
+    /// `height`.
+    pub fn new_cylinder(
+        radius: f32,
+        height: f32,
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
+        constant: f32,
+        material: MATERIAL,
+    ) -> Object {
+        let size = [radius, height, 0.0];
+        Object::new_quadric(
+            SHAPE::RT_CYLINDER,
+            size,
+            albedo,
+            transform,
+            constant,
+            material,
+        )
+    }
+
+    /// An open cone around the y axis with a base of `radius` at the origin
+    /// and its apex at `height`.
+    pub fn new_cone(
+        radius: f32,
+        height: f32,
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
+        constant: f32,
+        material: MATERIAL,
+    ) -> Object {
+        let size = [radius, height, 0.0];
+        Object::new_quadric(SHAPE::RT_CONE, size, albedo, transform, constant, material)
+    }
+
+    /// A torus in the xz plane around the origin, `radius` from its center
+    /// to the middle of a tube of `minor_radius`.
+    pub fn new_torus(
+        radius: f32,
+        minor_radius: f32,
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
+        constant: f32,
+        material: MATERIAL,
+    ) -> Object {
+        let size = [radius, 0.0, minor_radius];
+        Object::new_quadric(SHAPE::RT_TORUS, size, albedo, transform, constant, material)
+    }
+
+    /// A shape around the y axis of its own frame, intersected there by
+    /// `hitQuadric` in the shader. `vertices` holds the columns of the world
+    /// to object transform followed by `size`, the radius, height and minor
+    /// radius.
+    fn new_quadric(
+        shape: SHAPE,
+        size: [f32; 3],
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
+        constant: f32,
+        material: MATERIAL,
+    ) -> Object {
+        let to_object = trans(transform[0], transform[1], transform[2])
+            .invert()
+            .unwrap();
+        Object {
+            shape,
+            vertices: vec![
+                to_object.x.truncate().into(),
+                to_object.y.truncate().into(),
+                to_object.z.truncate().into(),
+                to_object.w.truncate().into(),
+                size,
+            ],
+            center: [0.0, 0.0, 0.0],
+            radius: 0.0,
+            albedo,
+            constant,
+            material,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
+        }
+    }
+
+    /// The density grid `grid` of `atlas` filling a box as long as its
+    /// resolution along each axis relative to the longest, centered on the y
+    /// axis and standing on the xz plane. Densities are scaled by `density`
+    /// and the absorbed part glows with `emission`. `vertices` holds the
+    /// columns of the world to grid transform, which maps the box to
+    /// [0, 1]^3, the resolution, (grid, first slice in the atlas, largest
+    /// density), the emission and (anisotropy, 0, 0).
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_grid_volume(
+        index: usize,
+        grid: &DensityGrid,
+        offset: usize,
+        density: f32,
+        emission: [f32; 3],
+        anisotropy: f32,
+        albedo: [f32; 3],
+        transform: &[Vector3<f32>],
+    ) -> Object {
+        let longest = grid.resolution.iter().copied().max().unwrap_or(1) as f32;
+        let [x, y, z] = grid.resolution.map(|n| n as f32 / longest);
+        let to_grid = Matrix4::from_nonuniform_scale(1.0 / x, 1.0 / y, 1.0 / z)
+            * Matrix4::from_translation(Vector3::new(0.5 * x, 0.0, 0.5 * z))
+            * trans(transform[0], transform[1], transform[2])
+                .invert()
+                .unwrap();
+        Object {
+            shape: SHAPE::RT_GRID_VOLUME,
+            vertices: vec![
+                to_grid.x.truncate().into(),
+                to_grid.y.truncate().into(),
+                to_grid.z.truncate().into(),
+                to_grid.w.truncate().into(),
+                grid.resolution.map(|n| n as f32),
+                [index as f32, offset as f32, grid.max],
+                emission,
+                [anisotropy, 0.0, 0.0],
+            ],
+            center: [0.0, 0.0, 0.0],
+            radius: 0.0,
+            albedo,
+            constant: density,
+            material: MATERIAL::ISOTROPIC,
+            pbr: PbrMaterial::default(),
+            medium: None,
+            textures: TextureSet::default(),
+            object: 0,
+        }
+    }
+
+    /// Surface area of the primitive, zero for shapes that are not surfaces.
+    /// Quadrics count as zero too as their transform may stretch them, so
+    /// emissive ones are hit but never sampled as lights.
+    pub fn area(&self) -> f32 {
+        let triangle = |a: [f32; 3], b: [f32; 3], c: [f32; 3]| {
+            let (a, b, c) = (Vector3::from(a), Vector3::from(b), Vector3::from(c));
+            (b - a).cross(c - a).magnitude() * 0.5
+        };
+        let v = &self.vertices;
+        match self.shape {
+            SHAPE::RT_SPHERE => 4.0 * PI * self.radius * self.radius,
+            SHAPE::RT_MESH => triangle(v[0], v[3], v[6]),
+            SHAPE::RT_TRIANGLE => triangle(v[0], v[1], v[2]),
+            SHAPE::RT_RECTANGLE => triangle(v[0], v[1], v[2]) + triangle(v[0], v[2], v[3]),
+            SHAPE::RT_DISK | SHAPE::RT_CYLINDER | SHAPE::RT_CONE | SHAPE::RT_TORUS => 0.0,
+            SHAPE::RT_VOLUME | SHAPE::RT_GRID_VOLUME | SHAPE::NONE => 0.0,
+        }
     }
 }
@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::Path;
+
+use image::{ImageResult, Rgb, Rgb32FImage, RgbImage};
+use serde::Serialize;
+
+use crate::channel::Channel;
+use crate::denoiser::Denoise;
+use crate::display::Display;
+use crate::film::{Adaptive, Filter};
+use crate::sampler::Sampler;
+use crate::scene::CameraDesc;
+
+/// Everything needed to reproduce a saved render, written next to it.
+#[derive(Serialize)]
+pub struct RenderInfo {
+    pub width: i32,
+    pub height: i32,
+    pub samples: i32,
+    pub depths: i32,
+    pub sampler: Sampler,
+    pub filter: Filter,
+    pub adaptive: Adaptive,
+    pub denoise: Denoise,
+    pub channel: Channel,
+    /// Applied to the 8 bit image, the float one is linear.
+    pub display: Display,
+    pub camera: CameraDesc,
+}
+
+/// Writes RGB float pixels (bottom row first, as read back from OpenGL) to
+/// `path`. `.exr` and `.hdr` keep the linear float values, any other
+/// extension goes through `display` to 8 bits.
+pub fn save_image(path: &str, width: u32, height: u32, pixels: &[f32], display: &Display) {
+    write_image(path, width, height, pixels, display)
+        .unwrap_or_else(|e| panic!("Failed to write {}: {}", path, e));
+}
+
+/// Saves `pixels` as `<stem>.png` and `<stem>.exr` plus a `<stem>.toml`
+/// sidecar described by `info`, where `<stem>` is `path` without extension.
+/// Returns the written files.
+pub fn save_render(
+    path: &str,
+    width: u32,
+    height: u32,
+    pixels: &[f32],
+    info: &RenderInfo,
+) -> Result<Vec<String>, String> {
+    let stem = Path::new(path).with_extension("");
+    let stem = stem.to_string_lossy();
+    let files = vec![
+        format!("{}.png", stem),
+        format!("{}.exr", stem),
+        format!("{}.toml", stem),
+    ];
+    for file in &files[..2] {
+        write_image(file, width, height, pixels, &info.display)
+            .map_err(|e| format!("{}: {}", file, e))?;
+    }
+    let sidecar = toml::to_string(info).map_err(|e| e.to_string())?;
+    fs::write(&files[2], sidecar).map_err(|e| format!("{}: {}", files[2], e))?;
+    Ok(files)
+}
+
+fn write_image(
+    path: &str,
+    width: u32,
+    height: u32,
+    pixels: &[f32],
+    display: &Display,
+) -> ImageResult<()> {
+    let float_image = Rgb32FImage::from_fn(width, height, |x, y| {
+        let index = (((height - 1 - y) * width + x) * 3) as usize;
+        Rgb([pixels[index], pixels[index + 1], pixels[index + 2]])
+    });
+
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    match extension.as_str() {
+        "exr" | "hdr" => float_image.save(path),
+        _ => {
+            let image = RgbImage::from_fn(width, height, |x, y| {
+                let pixel = float_image.get_pixel(x, y);
+                Rgb(display.apply(pixel.0).map(|c| (c * 255.0).round() as u8))
+            });
+            image.save(path)
+        }
+    }
+}
@@ -1,319 +1,281 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use cgmath::{point3, vec3};
+use cgmath::{EuclideanSpace, InnerSpace, Point3};
 use glow::{Context, HasContext, COLOR_BUFFER_BIT, FRAMEBUFFER};
-use slint::ComponentHandle;
+use slint::{ComponentHandle, ModelRc, PhysicalSize, SharedString, VecModel};
 
-use crate::bvh::BVHTree;
-use crate::camera::Camera;
-use crate::model::Model;
-use crate::object::Object;
+use crate::accumulation::Accumulation;
+use crate::bookmarks::Bookmarks;
+use crate::bvh::{BVHStats, BVHTree};
+use crate::camera::{Camera, CameraMode};
+use crate::channel::{depth_scale, Channel};
+use crate::cpu_renderer::{intersect, HitRecord, Ray};
+use crate::denoiser::{Denoise, Denoiser};
+use crate::display::{Display, Tonemapper};
+use crate::environment::Environment;
+use crate::fbo::ScreenFBO;
+use crate::film::{resolve, Adaptive, Filter};
+use crate::grid::GridAtlas;
+use crate::output::{save_render, RenderInfo};
+use crate::sampler::Sampler;
+use crate::scene::{CameraDesc, Scene};
 use crate::screen::{Screen, ScreenBuffer};
 use crate::shader::Shader;
-use crate::utils::MATERIAL::*;
+use crate::texture::TextureArray;
 use crate::App;
 
+/// The preview counts as at most this many samples, so it fades out over the
+/// first samples of the new image.
+const PREVIEW_SAMPLES: i32 = 4;
+
 pub struct Renderer {
     gl: Context,
     camera: Camera,
     screen: Screen,
     shader: Shader,
-    model: Model,
+    textures: TextureArray,
+    grids: GridAtlas,
     bvh_tree: BVHTree,
-    primitives: Vec<Object>,
     screen_buffer: ScreenBuffer,
+    accumulation: Accumulation,
+    /// Keep the last image as a preview when the accumulation restarts.
+    preview: bool,
+    /// How many samples the preview counts as, zero without one.
+    preview_samples: i32,
     frame_time: f32,
     frame_count: i32,
     last_frame: Instant,
     depths: f32,
     face_cull: bool,
-    gamma: bool,
+    sampler: Sampler,
+    filter: Filter,
+    adaptive: Adaptive,
+    denoise: Denoise,
+    denoiser: Denoiser,
+    channel: Channel,
+    display: Display,
+    bookmarks: Bookmarks,
+    /// Scene object framed by "Frame selection".
+    selection: Option<usize>,
+    /// The camera of the scene file, restored by the reset key.
+    home: CameraDesc,
     width: i32,
     height: i32,
 }
 
 impl Renderer {
-    pub fn new(gl: Context) -> Renderer {
+    pub fn new(gl: Context, scene: &Scene) -> Renderer {
         let screen = Screen::new(&gl);
-        let camera = Camera {
-            position: point3(0.0, 1.0, 3.0),
-            ..Camera::default()
-        };
+        let camera = scene.camera();
         let shader = Shader::new(
             &gl,
             "shaders/path_tracing.vert",
             "shaders/path_tracing.frag",
         );
-        let screen_buffer = ScreenBuffer::new(&gl, 1600, 1200);
-        let model = unsafe { Model::new(&gl, "models/furina_w/furina.obj") };
-        let mut bvh_tree = BVHTree::new(&gl);
-        let mut primitives = Vec::new();
-        let basic_transform = vec![
-            vec3(0.0, 0.0, 0.0),
-            vec3(0.0, 0.0, 0.0),
-            vec3(1.0, 1.0, 1.0),
-        ];
-        let floor_vert = vec![
-            [-1.0, 0.0, -1.0],
-            [-1.0, 0.0, 1.0],
-            [1.0, 0.0, 1.0],
-            [1.0, 0.0, -1.0],
-            [0.0, 1.0, 0.0],
-        ];
-        let floor = Object::new_rectangle(
-            &floor_vert,
-            [0.73, 0.73, 0.73],
-            &basic_transform,
-            0.0,
-            DIFFUSE,
-        );
-        let right_wall_vert = vec![
-            [1.0, 0.0, -1.0],
-            [1.0, 0.0, 1.0],
-            [1.0, 2.0, 1.0],
-            [1.0, 2.0, -1.0],
-            [-1.0, 0.0, 0.0],
-        ];
-        let right_wall = Object::new_rectangle(
-            &right_wall_vert,
-            [0.65, 0.05, 0.05],
-            &basic_transform,
-            0.0,
-            DIFFUSE,
-        );
-        let left_wall_vert = vec![
-            [-1.0, 0.0, -1.0],
-            [-1.0, 0.0, 1.0],
-            [-1.0, 2.0, 1.0],
-            [-1.0, 2.0, -1.0],
-            [1.0, 0.0, 0.0],
-        ];
-        let left_wall = Object::new_rectangle(
-            &left_wall_vert,
-            [0.12, 0.45, 0.15],
-            &basic_transform,
-            0.0,
-            DIFFUSE,
-        );
-        let ceiling_vert = vec![
-            [-1.0, 2.0, -1.0],
-            [-1.0, 2.0, 1.0],
-            [1.0, 2.0, 1.0],
-            [1.0, 2.0, -1.0],
-            [0.0, -1.0, 0.0],
-        ];
-        let ceiling = Object::new_rectangle(
-            &ceiling_vert,
-            [0.73, 0.73, 0.73],
-            &basic_transform,
-            0.0,
-            DIFFUSE,
-        );
-        let back_wall_vert = vec![
-            [-1.0, 0.0, -1.0],
-            [1.0, 0.0, -1.0],
-            [1.0, 2.0, -1.0],
-            [-1.0, 2.0, -1.0],
-            [0.0, 0.0, 1.0],
-        ];
-        let back_wall = Object::new_rectangle(
-            &back_wall_vert,
-            [1.0, 1.0, 1.0],
-            &basic_transform,
-            0.0,
-            DIFFUSE,
-        );
-        let ceiling_light_vert = vec![
-            [-0.52, 1.99, -0.52],
-            [-0.52, 1.99, 0.52],
-            [0.52, 1.99, 0.52],
-            [0.52, 1.99, -0.52],
-            [0.0, -1.0, 0.0],
-        ];
-        let ceiling_light = Object::new_rectangle(
-            &ceiling_light_vert,
-            [7.0, 7.0, 7.0],
-            &basic_transform,
-            0.0,
-            DIFFUSE_LIGHT,
-        );
-
-        let cube_vert = vec![
-            //第一个面
-            [-1.0, 0.0, -1.0],
-            [-1.0, 0.0, 1.0],
-            [1.0, 0.0, 1.0],
-            [1.0, 0.0, -1.0],
-            [0.0, -1.0, 0.0],
-            //第二个面
-            [-1.0, 0.0, -1.0],
-            [-1.0, 0.0, 1.0],
-            [-1.0, 1.0, 1.0],
-            [-1.0, 1.0, -1.0],
-            [-1.0, 0.0, 0.0],
-            //第三个面
-            [-1.0, 0.0, -1.0],
-            [1.0, 0.0, -1.0],
-            [1.0, 1.0, -1.0],
-            [-1.0, 1.0, -1.0],
-            [0.0, 0.0, -1.0],
-            //第四个面
-            [-1.0, 0.0, 1.0],
-            [1.0, 0.0, 1.0],
-            [1.0, 1.0, 1.0],
-            [-1.0, 1.0, 1.0],
-            [0.0, 0.0, 1.0],
-            //第五个面
-            [1.0, 0.0, -1.0],
-            [1.0, 0.0, 1.0],
-            [1.0, 1.0, 1.0],
-            [1.0, 1.0, -1.0],
-            [1.0, 0.0, 0.0],
-            //第六个面
-            [1.0, 1.0, -1.0],
-            [1.0, 1.0, 1.0],
-            [-1.0, 1.0, 1.0],
-            [-1.0, 1.0, -1.0],
-            [0.0, 1.0, 0.0],
-        ];
-
-        let box_volume_vert = vec![
-            [1.0, 0.0, 1.0],
-            [-1.0, 1.0, 1.0],
-            [-1.0, 0.0, -1.0],
-            [-1.0, 0.0, 1.0],
-        ];
-
-        let box_tall_transform = vec![
-            vec3(-0.4, 0.0, -0.35),
-            vec3(0.0, 15.0, 0.0),
-            vec3(0.25, 1.2, 0.25),
-        ];
-        let mut box_tall = Object::new_box(
-            &cube_vert,
-            [0.73, 0.73, 0.73],
-            &box_tall_transform,
-            0.0,
-            DIFFUSE,
-        );
-
-        let box_short_transform = vec![
-            vec3(0.3, 0.0, 0.45),
-            vec3(0.0, -18.0, 0.0),
-            vec3(0.35, 0.7, 0.35),
-        ];
-        let mut box_short = Object::new_box(
-            &cube_vert,
-            [0.73, 0.73, 0.73],
-            &box_short_transform,
-            1.5,
-            DIELECTRIC,
-        );
-
-        let box_volume_tall_transform = vec![
-            vec3(-0.4, 0.01, -0.35),
-            vec3(0.0, 15.0, 0.0),
-            vec3(0.25, 1.2, 0.25),
-        ];
-
-        let box_volume_tall = Object::new_box_volume(
-            &box_volume_vert,
-            [1.0, 1.0, 1.0],
-            &box_volume_tall_transform,
-            2.0,
-            ISOTROPIC,
-        );
-
-        let box_volume_short_transform = vec![
-            vec3(0.3, 0.01, 0.45),
-            vec3(0.0, -18.0, 0.0),
-            vec3(0.35, 0.7, 0.35),
-        ];
-        let box_volume_short = Object::new_box_volume(
-            &box_volume_vert,
-            [0.0, 0.0, 0.0],
-            &box_volume_short_transform,
-            2.0,
-            ISOTROPIC,
-        );
-
-        //primitives.append(&mut box_tall);
-        //primitives.append(&mut box_short);
-        primitives.push(box_volume_short);
-        primitives.push(box_volume_tall);
-        primitives.push(floor);
-        primitives.push(right_wall);
-        primitives.push(left_wall);
-        primitives.push(ceiling);
-        primitives.push(back_wall);
-        primitives.push(ceiling_light);
-        //model.get_primitives(&mut primitives, &basic_transform, 0.0, DIFFUSE);
+        let settings = &scene.settings;
+        let screen_buffer = ScreenBuffer::new(&gl, settings.width, settings.height);
+        let (primitives, mut textures, mut grids) = scene.build();
+        textures.set_texture(&gl);
+        grids.set_texture(&gl);
+        let mut bvh_tree = BVHTree::with_settings(scene.bvh.clone());
         bvh_tree.build(&primitives);
+        bvh_tree.set_environment(Environment::new(&scene.environment));
         bvh_tree.set_texture(&gl);
-        let renderer = Renderer {
+        let denoiser = Denoiser::new(&gl, settings.width, settings.height);
+        Renderer {
             gl,
             camera,
             screen,
             shader,
-            model,
+            textures,
+            grids,
             bvh_tree,
-            primitives,
             screen_buffer,
+            accumulation: Accumulation::new(settings.target_samples),
+            preview: false,
+            preview_samples: 0,
             frame_time: 0.0,
             frame_count: 0,
             last_frame: Instant::now(),
-            depths: 0.0,
-            face_cull: false,
-            gamma: false,
-            width: 1600,
-            height: 1200,
-        };
-        renderer
+            depths: settings.depths as f32,
+            face_cull: settings.face_cull,
+            sampler: settings.sampler,
+            filter: settings.filter,
+            adaptive: settings.adaptive,
+            denoise: settings.denoise,
+            denoiser,
+            channel: settings.channel,
+            display: settings.display,
+            bookmarks: Bookmarks::default(),
+            selection: None,
+            home: scene.camera.clone(),
+            width: settings.width,
+            height: settings.height,
+        }
     }
+
     pub fn render(&mut self, app: &App) {
-        self.initialize(app);
+        let delta_time = self.initialize(app);
         if app.get_real_time() {
-            self.real_time_render(app);
+            self.real_time_render(app, delta_time);
         } else {
-            self.static_render(app);
+            self.static_render(app, delta_time);
+        }
+        app.set_accumulation_status(self.accumulation.status().into());
+        if app.get_save_requested() {
+            app.set_save_requested(false);
+            self.save(app);
+        }
+        if app.get_bookmark_save_requested() {
+            app.set_bookmark_save_requested(false);
+            self.save_bookmark(app);
+        }
+        if app.get_bookmark_restore_requested() {
+            app.set_bookmark_restore_requested(false);
+            self.restore_bookmark(app);
         }
     }
 
-    fn real_time_render(&mut self, app: &App) {
-        self.camera.render_loop = 0;
+    /// Uses `bookmarks` for the bookmark list of the UI.
+    pub fn set_bookmarks(&mut self, app: &App, bookmarks: Bookmarks) {
+        self.bookmarks = bookmarks;
+        let names: Vec<SharedString> = self
+            .bookmarks
+            .names()
+            .into_iter()
+            .map(SharedString::from)
+            .collect();
+        app.set_bookmarks(ModelRc::new(VecModel::from(names)));
+    }
+
+    fn save_bookmark(&mut self, app: &App) {
+        let name = app.get_bookmark_name();
+        if name.trim().is_empty() {
+            app.set_bookmark_status("Enter a name for the bookmark".into());
+            return;
+        }
+        self.bookmarks
+            .set(name.trim(), CameraDesc::from(&self.camera));
+        let status = match self.bookmarks.save() {
+            Ok(()) => format!("Saved bookmark {}", name.trim()),
+            Err(e) => format!("Failed to save bookmarks {}", e),
+        };
+        let bookmarks = std::mem::take(&mut self.bookmarks);
+        self.set_bookmarks(app, bookmarks);
+        app.set_bookmark_status(status.into());
+    }
+
+    fn restore_bookmark(&mut self, app: &App) {
+        let names = self.bookmarks.names();
+        let camera = match names.get(app.get_bookmark_index() as usize) {
+            Some(name) => self.bookmarks.get(name).cloned(),
+            None => None,
+        };
+        if let Some(camera) = camera {
+            self.apply_camera(app, &camera);
+        }
+    }
+
+    /// Moves the camera and shows its lens settings in the UI.
+    fn apply_camera(&mut self, app: &App, camera: &CameraDesc) {
+        self.set_camera(camera);
+        app.set_aperture(camera.aperture);
+        app.set_focus_distance(camera.focus_distance);
+        app.set_blades(camera.blades);
+    }
+
+    /// Writes what the window has accumulated so far to the path set in the UI.
+    fn save(&self, app: &App) {
+        let info = RenderInfo {
+            width: self.width,
+            height: self.height,
+            samples: self.accumulation.samples(),
+            depths: self.depths as i32,
+            sampler: self.sampler,
+            filter: self.filter,
+            adaptive: self.adaptive,
+            denoise: self.denoise,
+            channel: self.channel,
+            display: self.channel.display(self.display),
+            camera: CameraDesc::from(&self.camera),
+        };
+        let status = match save_render(
+            &app.get_save_path(),
+            self.width as u32,
+            self.height as u32,
+            &self.read_pixels(),
+            &info,
+        ) {
+            Ok(files) => format!("Saved {}", files.join(", ")),
+            Err(e) => format!("Failed to save {}", e),
+        };
+        app.set_save_status(status.into());
+    }
+
+    /// Renders a fresh image with the sample count of the UI every frame.
+    fn real_time_render(&mut self, app: &App, delta_time: Duration) {
+        self.accumulation.reset();
+        self.preview_samples = 0;
         self.depths = app.get_depths();
         self.face_cull = app.get_face_cull();
-        self.gamma = app.get_gamma();
-        for _i in 0..app.get_sample_counts() as i32 {
-            self.renderer_core(app);
+        self.sampler = Sampler::from_index(app.get_sampler());
+        self.filter = Filter::from_index(app.get_filter());
+        self.channel = Channel::from_index(app.get_channel());
+        if !self.accumulation.paused {
+            for _i in 0..app.get_sample_counts() as i32 {
+                self.renderer_core();
+            }
+            self.accumulation.add_time(delta_time);
         }
         self.renderer_draw();
-        self.camera.render_loop = 0;
     }
 
-    fn static_render(&mut self, app: &App) {
+    /// Adds a sample per frame to the image until the target is reached.
+    fn static_render(&mut self, app: &App, delta_time: Duration) {
         if self.depths != app.get_depths() {
-            self.camera.render_loop = 0;
             self.depths = app.get_depths();
+            self.restart();
         }
         if self.face_cull != app.get_face_cull() {
-            self.camera.render_loop = 0;
             self.face_cull = app.get_face_cull();
+            self.restart();
+        }
+        let sampler = Sampler::from_index(app.get_sampler());
+        let filter = Filter::from_index(app.get_filter());
+        let channel = Channel::from_index(app.get_channel());
+        if self.sampler != sampler || self.filter != filter || self.channel != channel {
+            self.sampler = sampler;
+            self.filter = filter;
+            self.channel = channel;
+            self.restart();
         }
-        if self.gamma != app.get_gamma() {
-            self.camera.render_loop = 0;
-            self.gamma = app.get_gamma();
+        if self.accumulation.wants_samples() {
+            self.renderer_core();
+            self.accumulation.add_time(delta_time);
         }
-        println!("render_loop: {}", self.camera.render_loop);
-        //println!("{}",-1.0/0.01*random_float().ln());
-        self.renderer_core(app);
         self.renderer_draw();
     }
 
-    fn initialize(&mut self, app: &App) {
+    /// Starts accumulating a new image, after a change that makes the current
+    /// one stale.
+    fn restart(&mut self) {
+        let samples = self.accumulation.samples();
+        if !self.preview {
+            self.preview_samples = 0;
+        } else if samples > 0 {
+            self.screen_buffer.capture_preview(&self.gl, samples + 1);
+            self.preview_samples = samples.min(PREVIEW_SAMPLES);
+        }
+        self.accumulation.reset();
+    }
+
+    /// Applies the input and settings of the UI, returns the time since the
+    /// last frame.
+    fn initialize(&mut self, app: &App) -> Duration {
         let size = app.window().size();
         let current_frame = Instant::now();
-        let delta_time = current_frame.duration_since(self.last_frame).as_secs_f32();
+        let delta = current_frame.duration_since(self.last_frame);
+        let delta_time = delta.as_secs_f32();
 
         if self.frame_time > 1.0 {
             app.set_fps(format!("{:.2} fps", self.frame_count as f32 / self.frame_time).into());
@@ -324,59 +286,287 @@ impl Renderer {
             self.frame_count += 1;
         }
         self.last_frame = current_frame;
+        if app.get_reset_camera_requested() {
+            app.set_reset_camera_requested(false);
+            let home = self.home.clone();
+            self.apply_camera(app, &home);
+        }
+        self.camera.movement_speed = app.get_movement_speed();
+        self.camera.mouse_sensitivity = app.get_mouse_sensitivity();
+        self.camera.invert_mouse = app.get_invert_mouse();
+        self.accumulation.target = app.get_target_samples();
+        self.accumulation.paused = app.get_paused();
+        self.preview = app.get_preview();
+        // Pixels are tested again every sample, so no restart is needed.
+        self.adaptive.enabled = app.get_adaptive();
+        self.adaptive.threshold = app.get_adaptive_threshold();
+        // Like the display, only changes the presentation.
+        self.denoise.enabled = app.get_denoise();
+        self.denoise.iterations = app.get_denoise_iterations();
+        // Only changes the presentation, the accumulation keeps going.
+        self.display = Display {
+            tonemapper: Tonemapper::from_index(app.get_tonemapper()),
+            exposure: app.get_exposure(),
+            srgb: app.get_srgb(),
+        };
         self.camera.process_keyboard(app, delta_time);
         self.camera.process_mouse_movement(app);
         self.camera.process_mouse_wheel(app);
+        self.update_lens(app);
+        self.update_navigation(app);
         self.camera
             .update_ratio(size.width as i32, size.height as i32);
         if self.width != size.width as i32 || self.height != size.height as i32 {
             self.width = size.width as i32;
             self.height = size.height as i32;
+            let samples = self.accumulation.samples();
+            let keep_preview = self.preview && samples > 0;
             self.screen_buffer
-                .resize(&self.gl, size.width as i32, size.height as i32);
-            self.camera.render_loop = 0;
+                .resize(&self.gl, self.width, self.height, samples + 1, keep_preview);
+            self.denoiser.resize(&self.gl, self.width, self.height);
+            self.preview_samples = if keep_preview {
+                samples.min(PREVIEW_SAMPLES)
+            } else {
+                0
+            };
+            self.accumulation.reset();
+        }
+        if self.camera.take_changed() {
+            self.restart();
         }
+        delta
     }
 
-    fn renderer_core(&mut self, app: &App) {
-        let size = app.window().size();
-        self.camera.update_loop();
+    /// Applies the lens settings of the UI, first focusing on the clicked
+    /// point when there is one.
+    fn update_lens(&mut self, app: &App) {
+        if app.get_focus_requested() {
+            app.set_focus_requested(false);
+            let (u, v) = self.click_position(app);
+            if let Some(rec) = self.pick(u, v) {
+                app.set_focus_distance(
+                    (rec.p - self.camera.position.to_vec()).dot(self.camera.front),
+                );
+            }
+        }
+        let (aperture, focus_distance, blades) = (
+            app.get_aperture(),
+            app.get_focus_distance(),
+            app.get_blades(),
+        );
+        let camera = &mut self.camera;
+        if camera.aperture != aperture
+            || camera.focus_distance != focus_distance
+            || camera.blades != blades
+        {
+            camera.aperture = aperture;
+            camera.focus_distance = focus_distance;
+            camera.blades = blades;
+            self.restart();
+        }
+    }
+
+    /// Image coordinates of the last click in the UI.
+    fn click_position(&self, app: &App) -> (f32, f32) {
+        let scale = app.window().scale_factor();
+        let u = app.get_click_x() * scale / self.width as f32;
+        let v = 1.0 - app.get_click_y() * scale / self.height as f32;
+        (u, v)
+    }
+
+    /// The surface seen through (`u`, `v`), found by tracing the BVH on the
+    /// CPU.
+    fn pick(&self, u: f32, v: f32) -> Option<HitRecord> {
+        let ray = Ray::new(self.camera.position.to_vec(), self.camera.direction(u, v));
+        intersect(
+            &self.bvh_tree,
+            &self.textures,
+            &self.grids,
+            self.face_cull,
+            &ray,
+        )
+    }
+
+    /// Switches between fly and orbit mode and frames the scene or the
+    /// selected object on request.
+    fn update_navigation(&mut self, app: &App) {
+        let mode = if app.get_orbit() {
+            CameraMode::Orbit
+        } else {
+            CameraMode::Fly
+        };
+        if mode != self.camera.mode {
+            // Orbit around what is in the middle of the view.
+            let target = match self.pick(0.5, 0.5) {
+                Some(rec) => Point3::from_vec(rec.p),
+                None => self.camera.position + self.camera.front * self.camera.focus_distance,
+            };
+            self.camera.set_mode(mode, target);
+        }
+        if app.get_select_requested() {
+            app.set_select_requested(false);
+            let (u, v) = self.click_position(app);
+            if let Some(rec) = self.pick(u, v) {
+                self.selection = Some(self.bvh_tree.primitives()[rec.primitive].object);
+                self.frame_selection();
+            }
+        }
+        if app.get_frame_selection_requested() {
+            app.set_frame_selection_requested(false);
+            self.frame_selection();
+        }
+        if app.get_frame_scene_requested() {
+            app.set_frame_scene_requested(false);
+            if let Some(bounds) = self.bvh_tree.bounds() {
+                self.camera.frame(bounds.min, bounds.max);
+            }
+        }
+    }
+
+    /// Frames the selected object, or the scene when nothing is selected.
+    fn frame_selection(&mut self) {
+        let bounds = match self.selection {
+            Some(object) => self.bvh_tree.object_bounds(object),
+            None => self.bvh_tree.bounds().cloned(),
+        };
+        if let Some(bounds) = bounds {
+            self.camera.frame(bounds.min, bounds.max);
+        }
+    }
+
+    /// Accumulates `samples` frames into the screen buffer without presenting them.
+    pub fn render_samples(&mut self, samples: i32) {
+        unsafe {
+            self.gl.viewport(0, 0, self.width, self.height);
+        }
+        self.camera.update_ratio(self.width, self.height);
+        for _i in 0..samples {
+            self.renderer_core();
+        }
+    }
+
+    /// Moves the camera and restarts the accumulation.
+    pub fn set_camera(&mut self, camera: &CameraDesc) {
+        self.camera = camera.to_camera();
+        self.camera.update_ratio(self.width, self.height);
+        self.restart();
+    }
+
+    /// Returns the accumulated image as RGB floats, bottom row first, denoised
+    /// when the denoiser is enabled and the image is the radiance.
+    pub fn read_pixels(&self) -> Vec<f32> {
+        match self.denoised() {
+            Some(buffer) => buffer
+                .read_pixels(&self.gl)
+                .chunks(4)
+                .flat_map(|p| resolve([p[0], p[1], p[2], p[3]]))
+                .collect(),
+            None => self
+                .screen_buffer
+                .read_pixels(&self.gl, self.accumulation.samples() + 1),
+        }
+    }
+
+    /// Runs the denoiser on the latest image if it is enabled and the image
+    /// is the radiance.
+    fn denoised(&self) -> Option<&ScreenFBO> {
+        if !self.denoise.enabled || self.channel != Channel::Beauty {
+            return None;
+        }
         self.screen_buffer
-            .set_current_buffer(&self.gl, self.camera.render_loop);
+            .bind_latest(&self.gl, self.accumulation.samples() + 1);
+        Some(self.denoiser.apply(&self.gl, &self.screen, &self.denoise))
+    }
+
+    pub fn bvh_stats(&self) -> BVHStats {
+        self.bvh_tree.stats()
+    }
+
+    pub fn width(&self) -> i32 {
+        self.width
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    fn renderer_core(&mut self) {
+        let size = PhysicalSize::new(self.width as u32, self.height as u32);
+        self.accumulation.add_sample();
+        let sample = self.accumulation.samples();
+        self.screen_buffer.set_current_buffer(&self.gl, sample);
 
         self.shader.use_program(&self.gl);
         self.bvh_tree.use_texture(&self.gl, &self.shader);
-        self.model.use_textures(&self.gl, &self.shader);
-        self.camera.use_camera(&self.gl, &self.shader, &size);
+        self.textures.use_texture(&self.gl, &self.shader);
+        self.grids.use_texture(&self.gl, &self.shader);
+        self.camera
+            .use_camera(&self.gl, &self.shader, &size, sample);
+        self.shader
+            .set_int(&self.gl, "samplerType", self.sampler as i32);
+        self.shader
+            .set_int(&self.gl, "filterType", self.filter as i32);
+        self.shader
+            .set_float(&self.gl, "filterRadius", self.filter.radius());
+        self.shader
+            .set_bool(&self.gl, "adaptive.enabled", self.adaptive.enabled);
+        self.shader
+            .set_float(&self.gl, "adaptive.threshold", self.adaptive.threshold);
+        self.shader.set_int(
+            &self.gl,
+            "adaptive.minSamples",
+            self.adaptive.min_samples.max(2),
+        );
+        self.shader
+            .set_int(&self.gl, "channel", self.channel as i32);
+        self.shader.set_float(
+            &self.gl,
+            "depthScale",
+            depth_scale(self.bvh_tree.bounds(), self.camera.position.into()),
+        );
 
         self.shader.set_int(&self.gl, "depths", self.depths as i32);
         self.shader.set_bool(&self.gl, "faceCull", self.face_cull);
-        self.shader.set_bool(&self.gl, "gamma", self.gamma);
 
         self.screen.draw_shader(&self.gl, &self.shader);
 
-        self.screen_buffer
-            .set_current_buffer(&self.gl, self.camera.render_loop + 1);
-        self.screen.draw(&self.gl);
+        self.screen_buffer.set_current_buffer(&self.gl, sample + 1);
+        self.screen.copy(&self.gl);
     }
 
     fn renderer_draw(&mut self) {
+        let denoised = self.denoised();
         unsafe {
             self.gl.bind_framebuffer(FRAMEBUFFER, None);
             self.gl.clear_color(0.0, 0.0, 0.0, 1.0);
             self.gl.clear(COLOR_BUFFER_BIT);
-            self.screen_buffer
-                .set_current_texture(&self.gl, self.camera.render_loop + 1);
-            self.screen.draw(&self.gl);
+            match denoised {
+                Some(buffer) => buffer.bind_texture(&self.gl),
+                None => self
+                    .screen_buffer
+                    .set_current_texture(&self.gl, self.accumulation.samples() + 1),
+            }
+            self.screen_buffer.bind_preview(&self.gl);
+            // The preview fades out as samples come in.
+            let preview_weight = if self.preview_samples > 0 {
+                self.preview_samples as f32
+                    / (self.preview_samples + self.accumulation.samples()) as f32
+            } else {
+                0.0
+            };
+            let display = self.channel.display(self.display);
+            self.screen.draw(&self.gl, &display, preview_weight);
         }
     }
 }
 
 impl Drop for Renderer {
     fn drop(&mut self) {
-        self.model.delete(&self.gl);
+        self.textures.delete_texture(&self.gl);
+        self.grids.delete_texture(&self.gl);
         self.bvh_tree.delete_texture(&self.gl);
         self.screen_buffer.delete(&self.gl);
+        self.denoiser.delete(&self.gl);
         self.shader.delete(&self.gl);
     }
 }
@@ -0,0 +1,222 @@
+use rand::{Error, RngCore};
+use serde::{Deserialize, Serialize};
+
+/// Where the random numbers of a path come from. Mirrored by `rand` in
+/// `shaders/path_tracing.frag`, so both renderers draw the same numbers.
+#[derive(Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sampler {
+    /// Independent numbers hashed from the pixel, sample and dimension.
+    Random,
+    /// Owen scrambled Sobol points, decorrelated between pixels.
+    #[default]
+    Sobol,
+}
+
+impl Sampler {
+    pub const ALL: [Sampler; 2] = [Sampler::Random, Sampler::Sobol];
+
+    pub fn from_index(index: i32) -> Sampler {
+        Sampler::ALL[index.clamp(0, 1) as usize]
+    }
+
+    pub fn from_name(name: &str) -> Option<Sampler> {
+        match name {
+            "random" => Some(Sampler::Random),
+            "sobol" => Some(Sampler::Sobol),
+            _ => None,
+        }
+    }
+}
+
+/// Direction numbers of Sobol dimensions 1 to 3, dimension 0 is the bit
+/// reversed index.
+const SOBOL_DIRECTIONS: [[u32; 32]; 3] = [
+    [
+        0x80000000, 0xc0000000, 0xa0000000, 0xf0000000, 0x88000000, 0xcc000000, 0xaa000000,
+        0xff000000, 0x80800000, 0xc0c00000, 0xa0a00000, 0xf0f00000, 0x88880000, 0xcccc0000,
+        0xaaaa0000, 0xffff0000, 0x80008000, 0xc000c000, 0xa000a000, 0xf000f000, 0x88008800,
+        0xcc00cc00, 0xaa00aa00, 0xff00ff00, 0x80808080, 0xc0c0c0c0, 0xa0a0a0a0, 0xf0f0f0f0,
+        0x88888888, 0xcccccccc, 0xaaaaaaaa, 0xffffffff,
+    ],
+    [
+        0x80000000, 0xc0000000, 0x60000000, 0x90000000, 0xe8000000, 0x5c000000, 0x8e000000,
+        0xc5000000, 0x68800000, 0x9cc00000, 0xee600000, 0x55900000, 0x80680000, 0xc09c0000,
+        0x60ee0000, 0x90550000, 0xe8808000, 0x5cc0c000, 0x8e606000, 0xc5909000, 0x6868e800,
+        0x9c9c5c00, 0xeeee8e00, 0x5555c500, 0x8000e880, 0xc0005cc0, 0x60008e60, 0x9000c590,
+        0xe8006868, 0x5c009c9c, 0x8e00eeee, 0xc5005555,
+    ],
+    [
+        0x80000000, 0xc0000000, 0x20000000, 0x50000000, 0xf8000000, 0x74000000, 0xa2000000,
+        0x93000000, 0xd8800000, 0x25400000, 0x59e00000, 0xe6d00000, 0x78080000, 0xb40c0000,
+        0x82020000, 0xc3050000, 0x208f8000, 0x51474000, 0xfbea2000, 0x75d93000, 0xa0858800,
+        0x914e5400, 0xdbe79e00, 0x25db6d00, 0x58800080, 0xe54000c0, 0x79e00020, 0xb6d00050,
+        0x800800f8, 0xc00c0074, 0x200200a2, 0x50050093,
+    ],
+];
+
+/// The numbers of one sample of one pixel, each call to `next_u32` moves to
+/// the next dimension.
+///
+/// Sobol points are padded four dimensions at a time: every group of four
+/// shuffles the sample index with its own seed, so consecutive dimensions stay
+/// stratified against each other while groups stay uncorrelated.
+pub struct PixelSampler {
+    sampler: Sampler,
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl PixelSampler {
+    pub fn new(sampler: Sampler, x: u32, y: u32, index: u32) -> PixelSampler {
+        PixelSampler {
+            sampler,
+            seed: hash(x ^ hash(y)),
+            index,
+            dimension: 0,
+        }
+    }
+
+    fn sample(&self, dimension: u32) -> u32 {
+        match self.sampler {
+            Sampler::Random => hash(self.seed ^ hash(self.index ^ hash(dimension))),
+            Sampler::Sobol => {
+                let group_seed = hash(self.seed ^ hash(dimension / 4));
+                let index = nested_uniform_scramble(self.index, group_seed);
+                let x = sobol(index, dimension % 4);
+                nested_uniform_scramble(x, hash(self.seed ^ hash(dimension ^ 0x9e3779b9)))
+            }
+        }
+    }
+}
+
+impl RngCore for PixelSampler {
+    fn next_u32(&mut self) -> u32 {
+        let x = self.sample(self.dimension);
+        self.dimension += 1;
+        x
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+fn sobol(index: u32, dimension: u32) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    let directions = &SOBOL_DIRECTIONS[dimension as usize - 1];
+    let mut x = 0;
+    let mut i = index;
+    let mut bit = 0;
+    while i != 0 {
+        if i & 1 != 0 {
+            x ^= directions[bit];
+        }
+        i >>= 1;
+        bit += 1;
+    }
+    x
+}
+
+/// Owen scrambling by Burley, "Practical Hash-based Owen Scrambling".
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+/// Integer hash by Chris Wellons (lowbias32).
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first `dimensions` numbers of sample `index` of pixel (3, 5).
+    fn numbers(sampler: Sampler, index: u32, dimensions: usize) -> Vec<u32> {
+        let mut pixel = PixelSampler::new(sampler, 3, 5, index);
+        (0..dimensions).map(|_| pixel.next_u32()).collect()
+    }
+
+    #[test]
+    fn samples_are_deterministic() {
+        for sampler in Sampler::ALL {
+            assert_eq!(numbers(sampler, 7, 12), numbers(sampler, 7, 12));
+            assert_ne!(numbers(sampler, 7, 12), numbers(sampler, 8, 12));
+            let mut other = PixelSampler::new(sampler, 4, 5, 7);
+            let other: Vec<u32> = (0..12).map(|_| other.next_u32()).collect();
+            assert_ne!(numbers(sampler, 7, 12), other);
+        }
+    }
+
+    #[test]
+    fn sobol_samples_are_stratified() {
+        // The first 2^m samples of a pixel fill each of 2^m intervals of every
+        // dimension once, and the 4 x 4 cells of consecutive dimension pairs
+        // of the same group once for 16 samples.
+        let samples: Vec<Vec<u32>> = (0..16).map(|i| numbers(Sampler::Sobol, i, 8)).collect();
+        for dimension in 0..8 {
+            let mut strata: Vec<u32> = samples.iter().map(|s| s[dimension] >> 28).collect();
+            strata.sort_unstable();
+            assert_eq!(
+                strata,
+                (0..16).collect::<Vec<_>>(),
+                "dimension {}",
+                dimension
+            );
+        }
+        for pair in [0, 2, 4, 6] {
+            let mut cells: Vec<u32> = samples
+                .iter()
+                .map(|s| (s[pair] >> 30) * 4 + (s[pair + 1] >> 30))
+                .collect();
+            cells.sort_unstable();
+            assert_eq!(cells, (0..16).collect::<Vec<_>>(), "dimensions {}", pair);
+        }
+    }
+
+    #[test]
+    fn random_samples_are_uniform() {
+        let mut strata = [0; 8];
+        for i in 0..4096 {
+            for x in numbers(Sampler::Random, i, 4) {
+                strata[(x >> 29) as usize] += 1;
+            }
+        }
+        // 2048 expected in each, a standard deviation of about 42.
+        assert!(
+            strata.iter().all(|&n| (n - 2048i32).abs() < 250),
+            "{:?}",
+            strata
+        );
+    }
+}
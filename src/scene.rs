@@ -0,0 +1,698 @@
+use std::fs;
+
+use cgmath::{point3, SquareMatrix, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::animation::Animation;
+use crate::bvh::BVHSettings;
+use crate::camera::Camera;
+use crate::channel::Channel;
+use crate::denoiser::Denoise;
+use crate::display::Display;
+use crate::environment::EnvironmentDesc;
+use crate::film::{Adaptive, Filter};
+use crate::grid::GridAtlas;
+use crate::material::{deserialize_anisotropy, Medium, PbrMaterial};
+use crate::model::{Model, NormalMode};
+use crate::object::Object;
+use crate::sampler::Sampler;
+use crate::texture::{TextureArray, TextureSetDesc};
+use crate::utils::{trans, MATERIAL};
+
+pub const CUBE_VERTICES: [[f32; 3]; 30] = [
+    [-1.0, 0.0, -1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [0.0, -1.0, 0.0],
+    [-1.0, 0.0, -1.0],
+    [-1.0, 0.0, 1.0],
+    [-1.0, 1.0, 1.0],
+    [-1.0, 1.0, -1.0],
+    [-1.0, 0.0, 0.0],
+    [-1.0, 0.0, -1.0],
+    [1.0, 0.0, -1.0],
+    [1.0, 1.0, -1.0],
+    [-1.0, 1.0, -1.0],
+    [0.0, 0.0, -1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0],
+    [-1.0, 1.0, 1.0],
+    [0.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [1.0, 0.0, 1.0],
+    [1.0, 1.0, 1.0],
+    [1.0, 1.0, -1.0],
+    [1.0, 0.0, 0.0],
+    [1.0, 1.0, -1.0],
+    [1.0, 1.0, 1.0],
+    [-1.0, 1.0, 1.0],
+    [-1.0, 1.0, -1.0],
+    [0.0, 1.0, 0.0],
+];
+
+pub const BOX_VOLUME_VERTICES: [[f32; 3]; 4] = [
+    [1.0, 0.0, 1.0],
+    [-1.0, 1.0, 1.0],
+    [-1.0, 0.0, -1.0],
+    [-1.0, 0.0, 1.0],
+];
+
+#[derive(Deserialize)]
+pub struct Scene {
+    #[serde(default)]
+    pub camera: CameraDesc,
+    #[serde(default)]
+    pub settings: RenderSettings,
+    #[serde(default)]
+    pub bvh: BVHSettings,
+    #[serde(default)]
+    pub environment: EnvironmentDesc,
+    #[serde(default)]
+    pub objects: Vec<ObjectDesc>,
+    pub animation: Option<Animation>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CameraDesc {
+    pub position: [f32; 3],
+    pub yaw: f32,
+    pub pitch: f32,
+    pub fov: f32,
+    /// Lens radius, zero for a pinhole camera.
+    pub aperture: f32,
+    pub focus_distance: f32,
+    /// Aperture blades for polygonal bokeh, round below 3.
+    pub blades: i32,
+}
+
+impl Default for CameraDesc {
+    fn default() -> Self {
+        CameraDesc {
+            position: [0.0, 1.0, 3.0],
+            yaw: -90.0,
+            pitch: 0.0,
+            fov: 60.0,
+            aperture: 0.0,
+            focus_distance: 3.0,
+            blades: 0,
+        }
+    }
+}
+
+impl CameraDesc {
+    pub fn to_camera(&self) -> Camera {
+        let [x, y, z] = self.position;
+        let mut camera = Camera::new(point3(x, y, z), self.yaw, self.pitch, self.fov);
+        camera.aperture = self.aperture;
+        camera.focus_distance = self.focus_distance;
+        camera.blades = self.blades;
+        camera
+    }
+}
+
+impl From<&Camera> for CameraDesc {
+    fn from(camera: &Camera) -> Self {
+        CameraDesc {
+            position: camera.position.into(),
+            yaw: camera.yaw,
+            pitch: camera.pitch,
+            fov: camera.fov,
+            aperture: camera.aperture,
+            focus_distance: camera.focus_distance,
+            blades: camera.blades,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    pub width: i32,
+    pub height: i32,
+    pub depths: i32,
+    pub samples: i32,
+    /// Samples per pixel the window stops accumulating at, 0 for no limit.
+    pub target_samples: i32,
+    pub face_cull: bool,
+    pub sampler: Sampler,
+    pub filter: Filter,
+    pub adaptive: Adaptive,
+    pub denoise: Denoise,
+    pub channel: Channel,
+    /// Tonemapping of the presented and 8 bit images, rendering stays linear.
+    #[serde(flatten)]
+    pub display: Display,
+    /// Image textures longer along either side are scaled down to fit,
+    /// smaller ones keep their size. Every layer of the texture array takes
+    /// the memory of the largest image.
+    pub texture_size: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        RenderSettings {
+            width: 1600,
+            height: 1200,
+            depths: 5,
+            samples: 1,
+            target_samples: 0,
+            face_cull: false,
+            sampler: Sampler::default(),
+            filter: Filter::default(),
+            adaptive: Adaptive::default(),
+            denoise: Denoise::default(),
+            channel: Channel::default(),
+            display: Display::default(),
+            texture_size: 4096,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TransformDesc {
+    pub translation: [f32; 3],
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl Default for TransformDesc {
+    fn default() -> Self {
+        TransformDesc {
+            translation: [0.0, 0.0, 0.0],
+            rotation: [0.0, 0.0, 0.0],
+            scale: [1.0, 1.0, 1.0],
+        }
+    }
+}
+
+impl TransformDesc {
+    pub fn to_vec(&self) -> Vec<Vector3<f32>> {
+        vec![
+            Vector3::from(self.translation),
+            Vector3::from(self.rotation),
+            Vector3::from(self.scale),
+        ]
+    }
+
+    /// Whether the objects can invert it, which a zero scale rules out.
+    pub fn is_invertible(&self) -> bool {
+        let [translation, rotation, scale] = [self.translation, self.rotation, self.scale];
+        trans(translation.into(), rotation.into(), scale.into())
+            .invert()
+            .is_some()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShapeDesc {
+    Sphere {
+        center: [f32; 3],
+        radius: f32,
+    },
+    Triangle {
+        vertices: Vec<[f32; 3]>,
+    },
+    Rectangle {
+        vertices: Vec<[f32; 3]>,
+    },
+    Box {
+        #[serde(default = "default_cube_vertices")]
+        vertices: Vec<[f32; 3]>,
+    },
+    BoxVolume {
+        #[serde(default = "default_box_volume_vertices")]
+        vertices: Vec<[f32; 3]>,
+    },
+    /// The quadrics sit at the origin around the y axis and are placed by
+    /// the object transform.
+    Disk {
+        radius: f32,
+    },
+    Cylinder {
+        radius: f32,
+        height: f32,
+    },
+    Cone {
+        radius: f32,
+        height: f32,
+    },
+    Torus {
+        radius: f32,
+        minor_radius: f32,
+    },
+    /// Smoke or clouds from a density grid, see `Object::new_grid_volume`.
+    /// Scatters with the `albedo` of the object whatever its material.
+    GridVolume {
+        path: String,
+        /// Of a raw grid, .vol files store theirs.
+        #[serde(default)]
+        resolution: Option<[usize; 3]>,
+        /// Scales the densities of the grid to extinction per unit length.
+        #[serde(default = "default_density")]
+        density: f32,
+        /// Emitted in proportion to what the volume absorbs, that is where
+        /// `albedo` is below one.
+        #[serde(default)]
+        emission: [f32; 3],
+        /// Of the phase function, as for `Medium::anisotropy`.
+        #[serde(default, deserialize_with = "deserialize_anisotropy")]
+        anisotropy: f32,
+    },
+    Model {
+        path: String,
+        #[serde(default)]
+        normals: NormalMode,
+        /// The power the texels of the normal maps are raised to, for maps
+        /// saved with a gamma. Those of the nanosuit were saved with 2.4,
+        /// so their flat texels read (191, 191, 255), 0.5 to the power of
+        /// 1 / 2.4, instead of (128, 128, 255).
+        #[serde(default = "default_normal_map_gamma")]
+        normal_map_gamma: f32,
+    },
+}
+
+impl ShapeDesc {
+    /// The `type` of the shape in the scene file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShapeDesc::Sphere { .. } => "sphere",
+            ShapeDesc::Triangle { .. } => "triangle",
+            ShapeDesc::Rectangle { .. } => "rectangle",
+            ShapeDesc::Box { .. } => "box",
+            ShapeDesc::BoxVolume { .. } => "box_volume",
+            ShapeDesc::Disk { .. } => "disk",
+            ShapeDesc::Cylinder { .. } => "cylinder",
+            ShapeDesc::Cone { .. } => "cone",
+            ShapeDesc::Torus { .. } => "torus",
+            ShapeDesc::GridVolume { .. } => "grid_volume",
+            ShapeDesc::Model { .. } => "model",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ObjectDesc {
+    #[serde(flatten)]
+    pub shape: ShapeDesc,
+    #[serde(default = "default_albedo")]
+    pub albedo: [f32; 3],
+    #[serde(default = "default_material")]
+    pub material: MATERIAL,
+    #[serde(default)]
+    pub constant: f32,
+    #[serde(default)]
+    pub transform: TransformDesc,
+    /// Only used by the `pbr` material.
+    #[serde(flatten)]
+    pub pbr: PbrMaterial,
+    /// Inside of a closed object, entered through its `dielectric` or
+    /// `interface` surface.
+    #[serde(default)]
+    pub medium: Option<Medium>,
+    /// `albedo_texture`, `roughness_texture`, `emission_texture` and
+    /// `normal_texture`.
+    #[serde(flatten)]
+    pub textures: TextureSetDesc,
+}
+
+fn default_cube_vertices() -> Vec<[f32; 3]> {
+    CUBE_VERTICES.to_vec()
+}
+
+fn default_box_volume_vertices() -> Vec<[f32; 3]> {
+    BOX_VOLUME_VERTICES.to_vec()
+}
+
+fn default_density() -> f32 {
+    1.0
+}
+
+fn default_normal_map_gamma() -> f32 {
+    1.0
+}
+
+fn default_albedo() -> [f32; 3] {
+    [0.73, 0.73, 0.73]
+}
+
+fn default_material() -> MATERIAL {
+    MATERIAL::DIFFUSE
+}
+
+impl Scene {
+    /// Parses the scene at `path`, rejecting objects whose transform cannot
+    /// be inverted as their shapes need the inverse.
+    pub fn load(path: &str) -> Scene {
+        let source = fs::read_to_string(path).unwrap_or_else(|_| panic!("Failed to open {}", path));
+        let scene: Scene =
+            toml::from_str(&source).unwrap_or_else(|e| panic!("Failed to parse {}: {}", path, e));
+        scene.check_transforms(path);
+        scene
+    }
+
+    fn check_transforms(&self, path: &str) {
+        for (index, object) in self.objects.iter().enumerate() {
+            if !object.transform.is_invertible() {
+                panic!(
+                    "Object {} of {}, a {}, has a transform that cannot be inverted, \
+                     scale {:?}",
+                    index,
+                    path,
+                    object.shape.name(),
+                    object.transform.scale
+                );
+            }
+        }
+    }
+
+    pub fn camera(&self) -> Camera {
+        self.camera.to_camera()
+    }
+
+    /// Builds the primitives, loading model and object textures into a
+    /// `TextureArray` and density grids into a `GridAtlas` that are uploaded
+    /// later by whichever renderer needs them.
+    pub fn build(&self) -> (Vec<Object>, TextureArray, GridAtlas) {
+        let mut textures = TextureArray::new(self.settings.texture_size);
+        let mut grids = GridAtlas::new();
+        let mut primitives = Vec::new();
+        for (index, object) in self.objects.iter().enumerate() {
+            let transform = object.transform.to_vec();
+            let material = object.material.clone();
+            let texture_set = object.textures.build(&mut textures);
+            let first = primitives.len();
+            match &object.shape {
+                ShapeDesc::Sphere { center, radius } => {
+                    primitives.push(Object::new_sphere(
+                        *center,
+                        *radius,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Disk { radius } => {
+                    primitives.push(Object::new_disk(
+                        *radius,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Cylinder { radius, height } => {
+                    primitives.push(Object::new_cylinder(
+                        *radius,
+                        *height,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Cone { radius, height } => {
+                    primitives.push(Object::new_cone(
+                        *radius,
+                        *height,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Torus {
+                    radius,
+                    minor_radius,
+                } => {
+                    primitives.push(Object::new_torus(
+                        *radius,
+                        *minor_radius,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Triangle { vertices } => {
+                    check_vertices("triangle", vertices, 4);
+                    primitives.push(Object::new_triangle(
+                        vertices,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Rectangle { vertices } => {
+                    check_vertices("rectangle", vertices, 5);
+                    primitives.push(Object::new_rectangle(
+                        vertices,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::Box { vertices } => {
+                    check_vertices("box", vertices, 30);
+                    primitives.append(&mut Object::new_box(
+                        vertices,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::BoxVolume { vertices } => {
+                    check_vertices("box_volume", vertices, 4);
+                    primitives.push(Object::new_box_volume(
+                        vertices,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    ));
+                }
+                ShapeDesc::GridVolume {
+                    path,
+                    resolution,
+                    density,
+                    emission,
+                    anisotropy,
+                } => {
+                    let index = grids.add(path, *resolution);
+                    primitives.push(Object::new_grid_volume(
+                        index,
+                        grids.grid(index),
+                        grids.offset(index),
+                        *density,
+                        *emission,
+                        *anisotropy,
+                        object.albedo,
+                        &transform,
+                    ));
+                }
+                ShapeDesc::Model {
+                    path,
+                    normals,
+                    normal_map_gamma,
+                } => {
+                    let model = Model::new(path, &mut textures, *normals, *normal_map_gamma);
+                    model.get_primitives(
+                        &mut primitives,
+                        object.albedo,
+                        &transform,
+                        object.constant,
+                        material,
+                    );
+                }
+            }
+            for primitive in &mut primitives[first..] {
+                primitive.pbr = object.pbr;
+                primitive.medium = object.medium;
+                primitive.textures = texture_set;
+                primitive.object = index;
+            }
+        }
+        (primitives, textures, grids)
+    }
+}
+
+fn check_vertices(shape: &str, vertices: &[[f32; 3]], expected: usize) {
+    if vertices.len() != expected {
+        panic!(
+            "A {} needs {} vertices, got {}",
+            shape,
+            expected,
+            vertices.len()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SHAPE;
+
+    const SHAPES: &str = r#"
+        [[objects]]
+        type = "sphere"
+        center = [0.0, 1.0, 0.0]
+        radius = 0.5
+
+        [[objects]]
+        type = "triangle"
+        vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+
+        [[objects]]
+        type = "rectangle"
+        vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+
+        [[objects]]
+        type = "box"
+
+        [[objects]]
+        type = "box_volume"
+        material = "isotropic"
+        constant = 2.0
+
+        [[objects]]
+        type = "disk"
+        radius = 0.5
+
+        [[objects]]
+        type = "cylinder"
+        radius = 0.5
+        height = 1.0
+
+        [[objects]]
+        type = "cone"
+        radius = 0.5
+        height = 1.0
+
+        [[objects]]
+        type = "torus"
+        radius = 0.5
+        minor_radius = 0.1
+        transform = { translation = [0.0, 0.5, 0.0], scale = [2.0, 2.0, 2.0] }
+
+        [[objects]]
+        type = "grid_volume"
+        path = "volumes/smoke.vol"
+        density = 4.0
+
+        [[objects]]
+        type = "model"
+        path = "models/nanosuit/nanosuit.obj"
+        normals = "flat"
+    "#;
+
+    #[test]
+    fn parses_every_shape() {
+        let scene: Scene = toml::from_str(SHAPES).unwrap();
+        let shapes = &scene.objects;
+        assert_eq!(shapes.len(), 11);
+        assert!(matches!(shapes[0].shape, ShapeDesc::Sphere { radius, .. } if radius == 0.5));
+        assert!(
+            matches!(&shapes[1].shape, ShapeDesc::Triangle { vertices } if vertices.len() == 4)
+        );
+        assert!(
+            matches!(&shapes[2].shape, ShapeDesc::Rectangle { vertices } if vertices.len() == 5)
+        );
+        assert!(matches!(&shapes[3].shape, ShapeDesc::Box { vertices } if vertices.len() == 30));
+        assert!(
+            matches!(&shapes[4].shape, ShapeDesc::BoxVolume { vertices } if vertices.len() == 4)
+        );
+        assert!(matches!(shapes[5].shape, ShapeDesc::Disk { .. }));
+        assert!(matches!(shapes[6].shape, ShapeDesc::Cylinder { height, .. } if height == 1.0));
+        assert!(matches!(shapes[7].shape, ShapeDesc::Cone { height, .. } if height == 1.0));
+        assert!(
+            matches!(shapes[8].shape, ShapeDesc::Torus { minor_radius, .. } if minor_radius == 0.1)
+        );
+        assert_eq!(shapes[8].transform.scale, [2.0, 2.0, 2.0]);
+        assert!(matches!(
+            &shapes[9].shape,
+            ShapeDesc::GridVolume { resolution: None, density, .. } if *density == 4.0
+        ));
+        assert!(matches!(
+            shapes[10].shape,
+            ShapeDesc::Model {
+                normals: NormalMode::Flat,
+                normal_map_gamma,
+                ..
+            } if normal_map_gamma == 1.0
+        ));
+        assert!(matches!(shapes[4].material, MATERIAL::ISOTROPIC));
+        assert!(matches!(shapes[0].material, MATERIAL::DIFFUSE));
+        assert_eq!(shapes[0].albedo, default_albedo());
+    }
+
+    #[test]
+    fn builds_the_analytic_shapes() {
+        let scene: Scene = toml::from_str(SHAPES).unwrap();
+        let scene = Scene {
+            objects: scene.objects.into_iter().take(9).collect(),
+            ..scene
+        };
+        let (primitives, textures, grids) = scene.build();
+        assert!(textures.is_empty() && grids.is_empty());
+        // One primitive per object but the six faces of the box.
+        assert_eq!(primitives.len(), 14);
+        let shapes: Vec<SHAPE> = primitives.iter().map(|p| p.shape.clone()).collect();
+        assert!(shapes[0] == SHAPE::RT_SPHERE);
+        assert!(shapes[3..9].iter().all(|s| *s == SHAPE::RT_RECTANGLE));
+        assert!(shapes[9] == SHAPE::RT_VOLUME);
+        assert!(shapes[13] == SHAPE::RT_TORUS);
+        assert!(primitives[3..9].iter().all(|p| p.object == 3));
+        assert_eq!(primitives[13].object, 8);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Object 8 of shapes.toml, a torus, has a transform that cannot be \
+                               inverted, scale [2.0, 0.0, 2.0]"
+    )]
+    fn rejects_zero_scales() {
+        let source = SHAPES.replace("scale = [2.0, 2.0, 2.0]", "scale = [2.0, 0.0, 2.0]");
+        let scene: Scene = toml::from_str(&source).unwrap();
+        for object in &scene.objects[..8] {
+            assert!(object.transform.is_invertible());
+        }
+        scene.check_transforms("shapes.toml");
+    }
+
+    #[test]
+    fn rejects_anisotropies_of_one() {
+        let medium = SHAPES.replace("constant = 2.0", "medium = { anisotropy = 1.0 }");
+        let grid = SHAPES.replace("density = 4.0", "anisotropy = -1.5");
+        for source in [medium, grid] {
+            let error = toml::from_str::<Scene>(&source).err().unwrap();
+            assert!(error
+                .to_string()
+                .contains("anisotropy must be between -1 and 1"));
+        }
+        let source = SHAPES.replace("constant = 2.0", "medium = { anisotropy = -0.99 }");
+        let scene: Scene = toml::from_str(&source).unwrap();
+        assert_eq!(scene.objects[4].medium.unwrap().anisotropy, -0.99);
+    }
+
+    #[test]
+    #[should_panic(expected = "A rectangle needs 5 vertices, got 4")]
+    fn rejects_short_vertex_lists() {
+        let scene: Scene = toml::from_str(
+            r#"
+            [[objects]]
+            type = "rectangle"
+            vertices = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]]
+            "#,
+        )
+        .unwrap();
+        scene.build();
+    }
+}
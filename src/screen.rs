@@ -1,12 +1,19 @@
 use std::mem::size_of;
 
+use crate::display::Display;
 use crate::fbo::ScreenFBO;
+use crate::film::resolve;
 use crate::shader::Shader;
 use bytemuck::cast_slice;
 use glow::{
-    Context, HasContext, VertexArray, ARRAY_BUFFER, FLOAT, NO_ERROR, STATIC_DRAW, TRIANGLES,
+    Context, HasContext, VertexArray, ARRAY_BUFFER, FLOAT, NO_ERROR, STATIC_DRAW, TEXTURE0,
+    TEXTURE1, TRIANGLES,
 };
 
+/// Texture units of the attachments of the accumulation buffer being read:
+/// color, moments, albedo and normal. Units 1 to 5 hold the scene.
+pub const ATTACHMENT_UNITS: [i32; 4] = [0, 6, 7, 8];
+
 pub struct Screen {
     pub shader: Shader,
     vao: VertexArray,
@@ -46,10 +53,36 @@ impl Screen {
         Self { shader, vao }
     }
 
-    pub fn draw(&self, gl: &Context) {
+    /// Presents the bound accumulation texture through `display`, blended
+    /// with `preview_weight` of the preview bound by `ScreenBuffer::bind_preview`.
+    pub fn draw(&self, gl: &Context, display: &Display, preview_weight: f32) {
+        self.shader.use_program(gl);
+        self.shader.set_int(gl, "previewTexture", 1);
+        self.shader.set_float(gl, "previewWeight", preview_weight);
+        self.shader.set_bool(gl, "tonemap", true);
+        self.shader
+            .set_int(gl, "tonemapper", display.tonemapper as i32);
+        self.shader.set_float(gl, "exposure", display.exposure);
+        self.shader.set_bool(gl, "srgb", display.srgb);
+        self.draw_quad(gl);
+    }
+
+    /// Copies all attachments of the bound accumulation buffer unchanged.
+    pub fn copy(&self, gl: &Context) {
+        self.shader.use_program(gl);
+        self.shader.set_bool(gl, "tonemap", false);
+        self.shader
+            .set_int(gl, "momentsTexture", ATTACHMENT_UNITS[1]);
+        self.shader
+            .set_int(gl, "albedoTexture", ATTACHMENT_UNITS[2]);
+        self.shader
+            .set_int(gl, "normalTexture", ATTACHMENT_UNITS[3]);
+        self.draw_quad(gl);
+    }
+
+    fn draw_quad(&self, gl: &Context) {
+        self.shader.set_int(gl, "screenTexture", 0);
         unsafe {
-            self.shader.use_program(gl);
-            self.shader.set_int(gl, "screenTexture", 0);
             gl.bind_vertex_array(Some(self.vao));
             gl.draw_arrays(TRIANGLES, 0, 6);
         }
@@ -59,58 +92,124 @@ impl Screen {
         unsafe {
             shader.use_program(gl);
             shader.set_int(gl, "historyTexture", 0);
+            shader.set_int(gl, "historyMoments", ATTACHMENT_UNITS[1]);
+            shader.set_int(gl, "historyAlbedo", ATTACHMENT_UNITS[2]);
+            shader.set_int(gl, "historyNormal", ATTACHMENT_UNITS[3]);
             gl.bind_vertex_array(Some(self.vao));
             gl.draw_arrays(TRIANGLES, 0, 6);
         }
     }
 }
 
+/// The preview is this many times smaller than the screen in each direction.
+const PREVIEW_SCALE: i32 = 4;
+
+/// Two buffers accumulating the image in turns, plus a low resolution copy of
+/// an earlier image shown while a new one converges. The attachments of the
+/// buffers hold filter weighted color sums with the weights in alpha, the
+/// luminance moments of the samples, and the sums of the first hit albedo with
+/// depth in alpha and of the normal, see `film::FilmPixel`.
 pub struct ScreenBuffer {
     fbo: [ScreenFBO; 2],
+    preview: ScreenFBO,
 }
 
 impl ScreenBuffer {
     pub fn new(gl: &Context, width: i32, height: i32) -> Self {
-        let fbo = [
-            ScreenFBO::new(gl, width, height),
-            ScreenFBO::new(gl, width, height),
-        ];
+        let fbo = [new_buffer(gl, width, height), new_buffer(gl, width, height)];
 
-        Self { fbo }
+        Self {
+            fbo,
+            preview: new_preview(gl, width, height),
+        }
     }
 
     pub fn set_current_buffer(&mut self, gl: &Context, render_loop: i32) {
         let last_buffer = render_loop % 2;
-        if last_buffer == 0 {
-            self.fbo[1].bind(gl);
-            self.fbo[0].bind_texture(gl);
+        let (target, history) = if last_buffer == 0 {
+            (&self.fbo[1], &self.fbo[0])
         } else {
-            self.fbo[0].bind(gl);
-            self.fbo[1].bind_texture(gl);
-        }
+            (&self.fbo[0], &self.fbo[1])
+        };
+        target.bind(gl);
+        bind_attachments(gl, history);
+    }
+
+    /// Binds all attachments of the latest image to `ATTACHMENT_UNITS`.
+    pub fn bind_latest(&self, gl: &Context, render_loop: i32) {
+        bind_attachments(gl, self.latest(render_loop));
     }
 
     pub fn set_current_texture(&mut self, gl: &Context, render_loop: i32) {
-        let last_buffer = render_loop % 2;
-        if last_buffer == 0 {
-            self.fbo[1].bind_texture(gl);
-        } else {
-            self.fbo[0].bind_texture(gl);
-        }
+        self.latest(render_loop).bind_texture(gl);
     }
 
-    pub fn resize(&mut self, gl: &Context, width: i32, height: i32) {
-        self.fbo[0].delete(gl);
-        self.fbo[1].delete(gl);
+    /// The filtered image as RGB floats, bottom row first.
+    pub fn read_pixels(&self, gl: &Context, render_loop: i32) -> Vec<f32> {
+        self.latest(render_loop)
+            .read_pixels(gl)
+            .chunks(4)
+            .flat_map(|p| resolve([p[0], p[1], p[2], p[3]]))
+            .collect()
+    }
 
-        self.fbo = [
-            ScreenFBO::new(gl, width, height),
-            ScreenFBO::new(gl, width, height),
-        ];
+    /// Keeps a low resolution copy of the image as the preview.
+    pub fn capture_preview(&mut self, gl: &Context, render_loop: i32) {
+        self.latest(render_loop).blit_to(gl, &self.preview);
+    }
+
+    /// Binds the preview to texture unit 1, see `Screen::draw`.
+    pub fn bind_preview(&self, gl: &Context) {
+        self.preview.bind_texture_unit(gl, TEXTURE1);
+    }
+
+    /// Recreates the buffers at the new size. The image is lost, but with
+    /// `keep_preview` it becomes the preview first.
+    pub fn resize(
+        &mut self,
+        gl: &Context,
+        width: i32,
+        height: i32,
+        render_loop: i32,
+        keep_preview: bool,
+    ) {
+        let preview = new_preview(gl, width, height);
+        if keep_preview {
+            self.latest(render_loop).blit_to(gl, &preview);
+        }
+        self.delete(gl);
+
+        self.fbo = [new_buffer(gl, width, height), new_buffer(gl, width, height)];
+        self.preview = preview;
     }
 
     pub fn delete(&self, gl: &Context) {
         self.fbo[0].delete(gl);
         self.fbo[1].delete(gl);
+        self.preview.delete(gl);
+    }
+
+    fn latest(&self, render_loop: i32) -> &ScreenFBO {
+        &self.fbo[if render_loop % 2 == 0 { 1 } else { 0 }]
+    }
+}
+
+fn new_buffer(gl: &Context, width: i32, height: i32) -> ScreenFBO {
+    ScreenFBO::with_attachments(gl, width, height, ATTACHMENT_UNITS.len() as u32)
+}
+
+fn bind_attachments(gl: &Context, buffer: &ScreenFBO) {
+    for (attachment, unit) in ATTACHMENT_UNITS.iter().enumerate() {
+        buffer.bind_attachment(gl, attachment, TEXTURE0 + *unit as u32);
     }
 }
+
+fn new_preview(gl: &Context, width: i32, height: i32) -> ScreenFBO {
+    let preview = ScreenFBO::new(
+        gl,
+        (width / PREVIEW_SCALE).max(1),
+        (height / PREVIEW_SCALE).max(1),
+    );
+    preview.set_linear_filter(gl);
+    preview
+}
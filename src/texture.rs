@@ -0,0 +1,596 @@
+use std::path::Path;
+
+use glow::*;
+use image::imageops::{self, FilterType};
+use image::{Rgba32FImage, RgbaImage};
+use serde::Deserialize;
+
+use crate::display::srgb_encode;
+use crate::sampler::hash;
+use crate::shader::Shader;
+
+/// Texels per entry of the texture set table that follows the medium table,
+/// (kind, scale, layer) and two colors for each texture of a `TextureSet`.
+pub const TEXTURE_SET_STRIDE: i32 = 12;
+
+/// How the texels of an image store its values.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Colors, with the sRGB transfer curve.
+    Srgb,
+    /// Data such as roughness or normals, stored as they are.
+    Linear,
+    /// Data stored raised to the inverse of this power.
+    Gamma(f32),
+}
+
+impl Encoding {
+    /// As the shader reads it from the layer table, 0 for sRGB and otherwise
+    /// the power the texels are raised to.
+    fn to_texel(self) -> f32 {
+        match self {
+            Encoding::Srgb => 0.0,
+            Encoding::Linear => 1.0,
+            Encoding::Gamma(gamma) => gamma,
+        }
+    }
+
+    fn decode(self, c: f32) -> f32 {
+        match self {
+            Encoding::Srgb => srgb_decode(c),
+            Encoding::Linear => c,
+            Encoding::Gamma(gamma) => c.powf(gamma),
+        }
+    }
+
+    fn encode(self, c: f32) -> f32 {
+        match self {
+            Encoding::Srgb => srgb_encode(c),
+            Encoding::Linear => c,
+            Encoding::Gamma(gamma) => c.powf(1.0 / gamma),
+        }
+    }
+}
+
+/// An image of a `TextureArray`, in the corner of its layer.
+struct Layer {
+    image: RgbaImage,
+    path: String,
+    encoding: Encoding,
+}
+
+/// Every image texture of the scene, each stored at its own size in the
+/// corner of one layer of a `sampler2DArray` as large as the largest of them,
+/// so the shader can index them freely. A table of the size and `Encoding` of
+/// every layer lets `sampleTexture` in the shader wrap and filter an image
+/// within its corner and decode its texels, as `sample` does here.
+pub struct TextureArray {
+    layers: Vec<Layer>,
+    /// Images longer along either side are scaled down to fit.
+    max_size: u32,
+    texture: Option<NativeTexture>,
+    /// (width, height, encoding) per layer.
+    layers_texture: Option<NativeTexture>,
+}
+
+impl TextureArray {
+    pub fn new(max_size: u32) -> TextureArray {
+        TextureArray {
+            layers: Vec::new(),
+            max_size,
+            texture: None,
+            layers_texture: None,
+        }
+    }
+
+    /// Loads the image at `path` once per encoding and returns its layer.
+    pub fn add(&mut self, path: &str, encoding: Encoding) -> i32 {
+        if let Some(layer) = self
+            .layers
+            .iter()
+            .position(|l| l.path == path && l.encoding == encoding)
+        {
+            return layer as i32;
+        }
+        let image = image::open(Path::new(path))
+            .unwrap_or_else(|e| panic!("Failed to load texture {}: {}", path, e));
+        let (width, height) = (image.width(), image.height());
+        let image = if width.max(height) > self.max_size {
+            let scale = self.max_size as f32 / width.max(height) as f32;
+            let width = ((width as f32 * scale).round() as u32).max(1);
+            let height = ((height as f32 * scale).round() as u32).max(1);
+            resize(&image.into_rgba8(), width, height, encoding)
+        } else {
+            image.into_rgba8()
+        };
+        self.layers.push(Layer {
+            image: imageops::flip_vertical(&image),
+            path: path.into(),
+            encoding,
+        });
+        (self.layers.len() - 1) as i32
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Bilinearly filtered, repeating and decoded lookup matching
+    /// `sampleTexture` in the shader.
+    pub fn sample(&self, layer: i32, uv: [f32; 2]) -> [f32; 3] {
+        let layer = &self.layers[layer as usize];
+        let (width, height) = layer.image.dimensions();
+        let x = uv[0] * width as f32 - 0.5;
+        let y = uv[1] * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(width as i64) as u32;
+            let y = (y as i64).rem_euclid(height as i64) as u32;
+            let [r, g, b, _] = layer.image.get_pixel(x, y).0;
+            [r, g, b].map(|c| layer.encoding.decode(c as f32 / 255.0))
+        };
+        let (a, b) = (texel(x0, y0), texel(x0 + 1.0, y0));
+        let (c, d) = (texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+        let mut color = [0.0; 3];
+        for (i, value) in color.iter_mut().enumerate() {
+            let top = a[i] + (b[i] - a[i]) * fx;
+            let bottom = c[i] + (d[i] - c[i]) * fx;
+            *value = top + (bottom - top) * fy;
+        }
+        color
+    }
+
+    pub fn set_texture(&mut self, gl: &Context) {
+        let width = self
+            .layers
+            .iter()
+            .map(|l| l.image.width())
+            .max()
+            .unwrap_or(1) as usize;
+        let height = self
+            .layers
+            .iter()
+            .map(|l| l.image.height())
+            .max()
+            .unwrap_or(1) as usize;
+        let layers = self.layers.len().max(1);
+        let mut data = vec![0u8; width * height * 4 * layers];
+        let mut table = vec![0.0f32; 3 * layers];
+        for (index, layer) in self.layers.iter().enumerate() {
+            let (layer_width, layer_height) = layer.image.dimensions();
+            let row = layer_width as usize * 4;
+            for (y, source) in layer.image.as_raw().chunks_exact(row).enumerate() {
+                let target = ((index * height + y) * width) * 4;
+                data[target..target + row].copy_from_slice(source);
+            }
+            table[index * 3..index * 3 + 3].copy_from_slice(&[
+                layer_width as f32,
+                layer_height as f32,
+                layer.encoding.to_texel(),
+            ]);
+        }
+        unsafe {
+            if self.texture.is_none() {
+                self.texture = Some(gl.create_texture().unwrap());
+                self.layers_texture = Some(gl.create_texture().unwrap());
+            }
+            gl.bind_texture(TEXTURE_2D_ARRAY, self.texture);
+            gl.tex_image_3d(
+                TEXTURE_2D_ARRAY,
+                0,
+                RGBA8 as i32,
+                width as i32,
+                height as i32,
+                layers as i32,
+                0,
+                RGBA,
+                UNSIGNED_BYTE,
+                Some(&data),
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.tex_parameter_i32(TEXTURE_2D_ARRAY, TEXTURE_MIN_FILTER, NEAREST as i32);
+            gl.tex_parameter_i32(TEXTURE_2D_ARRAY, TEXTURE_MAG_FILTER, NEAREST as i32);
+            gl.bind_texture(TEXTURE_2D_ARRAY, None);
+
+            gl.bind_texture(TEXTURE_2D, self.layers_texture);
+            gl.tex_image_2d(
+                TEXTURE_2D,
+                0,
+                RGB32F as i32,
+                layers as i32,
+                1,
+                0,
+                RGB,
+                FLOAT,
+                Some(bytemuck::cast_slice(&table)),
+            );
+            assert_eq!(gl.get_error(), NO_ERROR);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MIN_FILTER, NEAREST as i32);
+            gl.tex_parameter_i32(TEXTURE_2D, TEXTURE_MAG_FILTER, NEAREST as i32);
+            gl.bind_texture(TEXTURE_2D, None);
+        }
+    }
+
+    pub fn use_texture(&self, gl: &Context, shader: &Shader) {
+        unsafe {
+            gl.active_texture(TEXTURE3);
+            gl.bind_texture(TEXTURE_2D_ARRAY, self.texture);
+            shader.set_int(gl, "textures", 3);
+            gl.active_texture(TEXTURE10);
+            gl.bind_texture(TEXTURE_2D, self.layers_texture);
+            shader.set_int(gl, "textureLayers", 10);
+        }
+    }
+
+    pub fn delete_texture(&self, gl: &Context) {
+        for texture in [self.texture, self.layers_texture].into_iter().flatten() {
+            unsafe {
+                gl.delete_texture(texture);
+            }
+        }
+    }
+}
+
+/// Scales `image` to `width` x `height`, filtering colors without their
+/// transfer curve.
+fn resize(image: &RgbaImage, width: u32, height: u32, encoding: Encoding) -> RgbaImage {
+    let mut linear = Rgba32FImage::new(image.width(), image.height());
+    for (target, source) in linear.pixels_mut().zip(image.pixels()) {
+        let [r, g, b, a] = source.0.map(|c| c as f32 / 255.0);
+        target.0 = [
+            encoding.decode(r),
+            encoding.decode(g),
+            encoding.decode(b),
+            a,
+        ];
+    }
+    let resized = imageops::resize(&linear, width, height, FilterType::Triangle);
+    let mut image = RgbaImage::new(width, height);
+    for (target, source) in image.pixels_mut().zip(resized.pixels()) {
+        let [r, g, b, a] = source.0;
+        let [r, g, b] = [r, g, b].map(|c| encoding.encode(c));
+        target.0 = [r, g, b, a].map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    }
+    image
+}
+
+/// Where a property of a surface comes from instead of the object's own
+/// parameters. Patterns blend between their two colors.
+#[derive(Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TextureDesc {
+    /// Repeated `scale` times along the texture coordinates.
+    Image {
+        path: String,
+        #[serde(default = "default_scale")]
+        scale: f32,
+        /// Raises the texels to this power instead of decoding them as the
+        /// property expects, sRGB for albedo and emission and linear
+        /// otherwise. For data saved with a gamma, such as normal maps whose
+        /// flat texels read 191 instead of 128.
+        #[serde(default)]
+        gamma: Option<f32>,
+    },
+    /// `scale` squares along each of the texture coordinates.
+    Checker {
+        #[serde(default = "default_from")]
+        even: [f32; 3],
+        #[serde(default = "default_to")]
+        odd: [f32; 3],
+        #[serde(default = "default_checker_scale")]
+        scale: f32,
+    },
+    /// Perlin noise of the world position times `scale`.
+    Noise {
+        #[serde(default = "default_from")]
+        from: [f32; 3],
+        #[serde(default = "default_to")]
+        to: [f32; 3],
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+    /// Veins along z, `scale` of them per unit of length, distorted by
+    /// turbulent Perlin noise.
+    Marble {
+        #[serde(default = "default_from")]
+        from: [f32; 3],
+        #[serde(default = "default_to")]
+        to: [f32; 3],
+        #[serde(default = "default_scale")]
+        scale: f32,
+    },
+    /// From `from` at the v texture coordinate 0 to `to` at 1.
+    Gradient {
+        #[serde(default = "default_from")]
+        from: [f32; 3],
+        #[serde(default = "default_to")]
+        to: [f32; 3],
+    },
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_checker_scale() -> f32 {
+    8.0
+}
+
+fn default_from() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+fn default_to() -> [f32; 3] {
+    [0.0, 0.0, 0.0]
+}
+
+impl TextureDesc {
+    /// Loads an image into `textures` with the `encoding` of the property
+    /// it is for.
+    pub fn build(&self, textures: &mut TextureArray, encoding: Encoding) -> Texture {
+        let (kind, scale, layer, colors) = match self {
+            TextureDesc::Image { path, scale, gamma } => {
+                if let Some(gamma) = gamma {
+                    assert!(*gamma > 0.0, "The gamma of {} must be positive", path);
+                }
+                let encoding = gamma.map_or(encoding, Encoding::Gamma);
+                let layer = textures.add(path, encoding);
+                (TextureKind::Image, *scale, layer, [[0.0; 3]; 2])
+            }
+            TextureDesc::Checker { even, odd, scale } => {
+                (TextureKind::Checker, *scale, -1, [*even, *odd])
+            }
+            TextureDesc::Noise { from, to, scale } => {
+                (TextureKind::Noise, *scale, -1, [*from, *to])
+            }
+            TextureDesc::Marble { from, to, scale } => {
+                (TextureKind::Marble, *scale, -1, [*from, *to])
+            }
+            TextureDesc::Gradient { from, to } => (TextureKind::Gradient, 1.0, -1, [*from, *to]),
+        };
+        Texture {
+            kind,
+            scale,
+            layer,
+            colors,
+        }
+    }
+}
+
+/// Mirrored by `evalTexture` in the shader, zero is no texture.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TextureKind {
+    Image = 1,
+    Checker = 2,
+    Noise = 3,
+    Marble = 4,
+    Gradient = 5,
+}
+
+/// A `TextureDesc` with its image loaded into a `TextureArray`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Texture {
+    pub kind: TextureKind,
+    pub scale: f32,
+    /// Of images, -1 for patterns.
+    pub layer: i32,
+    pub colors: [[f32; 3]; 2],
+}
+
+impl Texture {
+    pub fn to_texels(self) -> [f32; 9] {
+        let [[a, b, c], [d, e, f]] = self.colors;
+        [
+            self.kind as u32 as f32,
+            self.scale,
+            self.layer as f32,
+            a,
+            b,
+            c,
+            d,
+            e,
+            f,
+        ]
+    }
+
+    /// At texture coordinates `uv` of the world position `p`, like
+    /// `evalTexture` in the shader.
+    pub fn eval(&self, textures: &TextureArray, uv: [f32; 2], p: [f32; 3]) -> [f32; 3] {
+        let [from, to] = self.colors;
+        let t = match self.kind {
+            TextureKind::Image => {
+                return textures.sample(self.layer, uv.map(|x| x * self.scale));
+            }
+            TextureKind::Checker => {
+                let squares = (uv[0] * self.scale).floor() + (uv[1] * self.scale).floor();
+                if squares.rem_euclid(2.0) == 0.0 {
+                    return from;
+                }
+                return to;
+            }
+            TextureKind::Noise => 0.5 * (1.0 + perlin(p.map(|x| x * self.scale))),
+            TextureKind::Marble => 0.5 * (1.0 + (self.scale * p[2] + 10.0 * turbulence(p)).sin()),
+            TextureKind::Gradient => uv[1].clamp(0.0, 1.0),
+        };
+        [0, 1, 2].map(|i| from[i] + (to[i] - from[i]) * t)
+    }
+}
+
+/// The textures of a scene object, each replacing the object's own
+/// property where it is given.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct TextureSet {
+    pub albedo: Option<Texture>,
+    /// Of the `pbr` material, from the red channel.
+    pub roughness: Option<Texture>,
+    /// Radiance of `diffuse_light` surfaces, added to the light any other
+    /// surface reflects.
+    pub emission: Option<Texture>,
+    /// Tangent space normal, with x along u, y along v and z out of the
+    /// surface mapped from [-1, 1] to [0, 1]. Needs the tangents of a
+    /// rectangle, triangle, sphere or mesh.
+    pub normal: Option<Texture>,
+}
+
+impl TextureSet {
+    pub fn is_empty(&self) -> bool {
+        *self == TextureSet::default()
+    }
+
+    pub fn to_texels(self) -> [f32; 36] {
+        let mut texels = [0.0; 36];
+        let slots = [self.albedo, self.roughness, self.emission, self.normal];
+        for (slot, texture) in texels.chunks_exact_mut(9).zip(slots) {
+            if let Some(texture) = texture {
+                slot.copy_from_slice(&texture.to_texels());
+            }
+        }
+        texels
+    }
+}
+
+/// The `TextureSet` of a scene object as it is written in the scene file.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TextureSetDesc {
+    pub albedo_texture: Option<TextureDesc>,
+    pub roughness_texture: Option<TextureDesc>,
+    pub emission_texture: Option<TextureDesc>,
+    pub normal_texture: Option<TextureDesc>,
+}
+
+impl TextureSetDesc {
+    pub fn build(&self, textures: &mut TextureArray) -> TextureSet {
+        let mut build = |desc: &Option<TextureDesc>, encoding| {
+            desc.as_ref().map(|d| d.build(textures, encoding))
+        };
+        TextureSet {
+            albedo: build(&self.albedo_texture, Encoding::Srgb),
+            roughness: build(&self.roughness_texture, Encoding::Linear),
+            emission: build(&self.emission_texture, Encoding::Srgb),
+            normal: build(&self.normal_texture, Encoding::Linear),
+        }
+    }
+}
+
+fn srgb_decode(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Edge midpoint directions of a cube, the gradients Perlin noise picks from.
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+/// Gradient noise in about [-1, 1] with hashed lattice gradients, matching
+/// `perlin` in the shader.
+pub fn perlin(p: [f32; 3]) -> f32 {
+    let cell = p.map(f32::floor);
+    let f = [0, 1, 2].map(|i| p[i] - cell[i]);
+    let fade = f.map(|x| x * x * x * (x * (x * 6.0 - 15.0) + 10.0));
+    let mut value = 0.0;
+    for corner in 0..8 {
+        let mut weight = 1.0;
+        let mut lattice = [0; 3];
+        let mut offset = [0.0; 3];
+        for axis in 0..3 {
+            let side = corner >> axis & 1;
+            lattice[axis] = cell[axis] as i32 + side;
+            offset[axis] = f[axis] - side as f32;
+            weight *= if side == 1 {
+                fade[axis]
+            } else {
+                1.0 - fade[axis]
+            };
+        }
+        let [x, y, z] = lattice.map(|c| c as u32);
+        let gradient = GRADIENTS[(hash(x ^ hash(y ^ hash(z))) % 12) as usize];
+        let dot: f32 = (0..3).map(|i| gradient[i] * offset[i]).sum();
+        value += weight * dot;
+    }
+    value
+}
+
+/// Seven octaves of the magnitude of `perlin`.
+pub fn turbulence(p: [f32; 3]) -> f32 {
+    let mut sum = 0.0;
+    let mut p = p;
+    let mut weight = 1.0;
+    for _ in 0..7 {
+        sum += weight * perlin(p).abs();
+        weight *= 0.5;
+        p = p.map(|x| x * 2.0);
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4 x 2 image of a dark red texel, with black elsewhere, saved to a
+    /// temporary file.
+    fn image_file(name: &str) -> String {
+        let mut image = RgbaImage::new(4, 2);
+        image.put_pixel(0, 1, image::Rgba([64, 0, 0, 255]));
+        let path = std::env::temp_dir().join(name);
+        image.save(&path).unwrap();
+        path.to_str().unwrap().into()
+    }
+
+    #[test]
+    fn samples_decode_and_repeat_each_image() {
+        let path = image_file("texture_array_test.png");
+        let mut textures = TextureArray::new(4096);
+        let color = textures.add(&path, Encoding::Srgb);
+        let data = textures.add(&path, Encoding::Linear);
+        let gamma = textures.add(&path, Encoding::Gamma(2.4));
+        assert_eq!((color, data, gamma), (0, 1, 2));
+        assert_eq!(textures.add(&path, Encoding::Srgb), color);
+
+        // The top left texel of the file is the bottom left one of the layer,
+        // its center at (1/8, 1/4) and again one image further along u.
+        let c = 64.0 / 255.0;
+        for u in [0.125, 1.125, -0.875] {
+            let [r, g, _] = textures.sample(data, [u, 0.25]);
+            assert!((r - c).abs() < 1E-6 && g == 0.0, "{} {}", u, r);
+            let [r, _, _] = textures.sample(color, [u, 0.25]);
+            assert!((r - srgb_decode(c)).abs() < 1E-6);
+            let [r, _, _] = textures.sample(gamma, [u, 0.25]);
+            assert!((r - c.powf(2.4)).abs() < 1E-6);
+        }
+        // Halfway to the next texel along u, filtered after decoding.
+        let [r, _, _] = textures.sample(color, [0.25, 0.25]);
+        assert!((r - 0.5 * srgb_decode(c)).abs() < 1E-6);
+        // The bottom row of the file.
+        assert_eq!(textures.sample(data, [0.125, 0.75]), [0.0; 3]);
+    }
+
+    #[test]
+    fn large_images_are_scaled_down() {
+        let path = image_file("texture_array_scaled_test.png");
+        let mut textures = TextureArray::new(2);
+        let layer = textures.add(&path, Encoding::Srgb);
+        assert_eq!(textures.layers[layer as usize].image.dimensions(), (2, 1));
+    }
+}
@@ -2,6 +2,7 @@
 
 use cgmath::{vec4, Deg, Matrix, Matrix4, SquareMatrix, Vector3};
 use rand::Rng;
+use serde::Deserialize;
 
 #[derive(Clone, PartialEq)]
 pub enum SHAPE {
@@ -11,9 +12,15 @@ pub enum SHAPE {
     RT_TRIANGLE = 3,
     RT_RECTANGLE = 4,
     RT_VOLUME = 5,
+    RT_DISK = 6,
+    RT_CYLINDER = 7,
+    RT_CONE = 8,
+    RT_TORUS = 9,
+    RT_GRID_VOLUME = 10,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum MATERIAL {
     NONE = 0,
     DIFFUSE = 1,
@@ -21,10 +28,13 @@ pub enum MATERIAL {
     DIELECTRIC = 3,
     DIFFUSE_LIGHT = 4,
     ISOTROPIC = 5,
+    PBR = 6,
+    /// Invisible, only bounds the medium of an object.
+    INTERFACE = 7,
 }
 
-pub const MAX_FLOAT: f32 = 3.402823466e+38;
-pub const MIN_FLOAT: f32 = -3.402823466e+38;
+pub const MAX_FLOAT: f32 = f32::MAX;
+pub const MIN_FLOAT: f32 = f32::MIN;
 
 pub fn random_float() -> f32 {
     let mut rng = rand::thread_rng();